@@ -0,0 +1,197 @@
+use std::collections::HashSet;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use sp_client::bitcoin::{
+    absolute::LockTime,
+    hashes::Hash,
+    key::TapTweak,
+    secp256k1::{rand::thread_rng, rand::Rng, PublicKey, Secp256k1, SecretKey},
+    transaction::Version,
+    Amount, Network, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+    XOnlyPublicKey,
+};
+use sp_client::silentpayments::{sending::generate_recipient_pubkeys, utils::sending};
+use sp_client::spclient::{SpClient, SpWallet, SpendKey};
+
+/// Shape of the synthetic blocks we scan
+#[derive(Debug, Clone, Copy)]
+struct BlockConfig {
+    tx_count: usize,
+    outputs_per_tx: usize,
+    /// Share of outputs that are taproot, the only ones we need to check
+    taproot_density: f64,
+    /// Share of transactions paying us
+    our_payment_rate: f64,
+}
+
+struct SyntheticBlock {
+    /// Each transaction comes with its tweak data, as an index server would provide it
+    txs: Vec<(Transaction, PublicKey)>,
+}
+
+fn random_sk() -> SecretKey {
+    SecretKey::new(&mut thread_rng())
+}
+
+fn random_p2tr() -> ScriptBuf {
+    let secp = Secp256k1::signing_only();
+    let (xonly, _) = random_sk().x_only_public_key(&secp);
+    ScriptBuf::new_p2tr_tweaked(xonly.dangerous_assume_tweaked())
+}
+
+fn random_p2wpkh() -> ScriptBuf {
+    let mut hash = [0u8; 20];
+    thread_rng().fill(&mut hash);
+    ScriptBuf::new_p2wpkh(&sp_client::bitcoin::WPubkeyHash::from_byte_array(hash))
+}
+
+fn generate_block(config: BlockConfig, our_address: &str) -> SyntheticBlock {
+    let secp = Secp256k1::signing_only();
+    let mut rng = thread_rng();
+
+    let txs = (0..config.tx_count)
+        .map(|_| {
+            let mut txid = [0u8; 32];
+            rng.fill(&mut txid);
+            let outpoint = OutPoint::new(Txid::from_byte_array(txid), 0);
+            let input_sk = random_sk();
+
+            let partial_secret = sending::calculate_partial_secret(
+                &[(input_sk, true)],
+                &[(outpoint.txid.to_string(), outpoint.vout)],
+            )
+            .unwrap();
+            let tweak_data = partial_secret.public_key(&secp);
+
+            let mut output: Vec<TxOut> = (0..config.outputs_per_tx)
+                .map(|_| TxOut {
+                    value: Amount::from_sat(10_000),
+                    script_pubkey: if rng.gen_bool(config.taproot_density) {
+                        random_p2tr()
+                    } else {
+                        random_p2wpkh()
+                    },
+                })
+                .collect();
+
+            if rng.gen_bool(config.our_payment_rate) {
+                let key: XOnlyPublicKey =
+                    generate_recipient_pubkeys(vec![our_address.to_owned()], partial_secret)
+                        .unwrap()
+                        .remove(our_address)
+                        .unwrap()
+                        .remove(0);
+                output[0].script_pubkey =
+                    ScriptBuf::new_p2tr_tweaked(key.dangerous_assume_tweaked());
+            }
+
+            let tx = Transaction {
+                version: Version::TWO,
+                lock_time: LockTime::ZERO,
+                input: vec![TxIn {
+                    previous_output: outpoint,
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                }],
+                output,
+            };
+
+            (tx, tweak_data)
+        })
+        .collect();
+
+    SyntheticBlock { txs }
+}
+
+fn new_wallet() -> SpWallet {
+    let client = SpClient::new(
+        "bench".to_owned(),
+        random_sk(),
+        SpendKey::Secret(random_sk()),
+        None,
+        Network::Regtest,
+    )
+    .unwrap();
+    SpWallet::new(client, None).unwrap()
+}
+
+/// Same steps as a wallet scanning a block: derive the scripts we could have received,
+/// match them against the block outputs, then process the transactions that matched
+fn scan_block(wallet: &mut SpWallet, block: &SyntheticBlock, height: u32) {
+    let tweak_data: Vec<PublicKey> = block.txs.iter().map(|(_, tweak)| *tweak).collect();
+    let candidates = wallet
+        .get_client()
+        .get_script_to_secret_map(tweak_data)
+        .unwrap();
+
+    let candidates: HashSet<&[u8]> = candidates.keys().map(|spk| &spk[..]).collect();
+
+    for (tx, tweak) in block.txs.iter() {
+        if tx
+            .output
+            .iter()
+            .any(|o| candidates.contains(o.script_pubkey.as_bytes()))
+        {
+            wallet
+                .update_wallet_with_transaction(tx, height, *tweak)
+                .unwrap();
+        }
+    }
+}
+
+fn bench_scan(c: &mut Criterion) {
+    let configs = [
+        (
+            "typical",
+            BlockConfig {
+                tx_count: 3000,
+                outputs_per_tx: 2,
+                taproot_density: 0.3,
+                our_payment_rate: 0.001,
+            },
+        ),
+        (
+            "taproot_heavy",
+            BlockConfig {
+                tx_count: 3000,
+                outputs_per_tx: 2,
+                taproot_density: 0.9,
+                our_payment_rate: 0.001,
+            },
+        ),
+        (
+            "many_payments",
+            BlockConfig {
+                tx_count: 3000,
+                outputs_per_tx: 2,
+                taproot_density: 0.3,
+                our_payment_rate: 0.05,
+            },
+        ),
+    ];
+
+    let mut group = c.benchmark_group("scan_block");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(1));
+
+    for (name, config) in configs {
+        let wallet = new_wallet();
+        let address = wallet.get_client().get_receiving_address();
+        let block = generate_block(config, &address);
+
+        group.bench_with_input(BenchmarkId::from_parameter(name), &block, |b, block| {
+            b.iter_batched(
+                || wallet.clone(),
+                |mut wallet| scan_block(&mut wallet, block, 1),
+                criterion::BatchSize::LargeInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_scan);
+criterion_main!(benches);
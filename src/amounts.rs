@@ -0,0 +1,177 @@
+//! Parsing and formatting of amounts typed or read by users
+//!
+//! Every front-end goes through these, so they all agree on what "3,5 mBTC" means.
+
+use bitcoin::{Amount, Denomination};
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Error, Result};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum AmountUnit {
+    Btc,
+    MilliBtc,
+    /// 100 sats, also known as uBTC
+    Bits,
+    Sats,
+}
+
+impl AmountUnit {
+    fn get_denomination(&self) -> Denomination {
+        match self {
+            Self::Btc => Denomination::Bitcoin,
+            Self::MilliBtc => Denomination::MilliBitcoin,
+            Self::Bits => Denomination::Bit,
+            Self::Sats => Denomination::Satoshi,
+        }
+    }
+
+    /// Number of decimals needed to express a satoshi
+    fn get_decimals(&self) -> u32 {
+        match self {
+            Self::Btc => 8,
+            Self::MilliBtc => 5,
+            Self::Bits => 2,
+            Self::Sats => 0,
+        }
+    }
+
+    pub fn get_symbol(&self) -> &'static str {
+        match self {
+            Self::Btc => "BTC",
+            Self::MilliBtc => "mBTC",
+            Self::Bits => "bits",
+            Self::Sats => "sats",
+        }
+    }
+
+    fn from_symbol(symbol: &str) -> Option<Self> {
+        match symbol.to_lowercase().as_str() {
+            "btc" | "₿" => Some(Self::Btc),
+            "mbtc" => Some(Self::MilliBtc),
+            "bits" | "bit" | "ubtc" | "µbtc" | "μbtc" => Some(Self::Bits),
+            "sats" | "sat" | "satoshi" | "satoshis" => Some(Self::Sats),
+            _ => None,
+        }
+    }
+}
+
+fn is_group_separator(c: char) -> bool {
+    matches!(c, ' ' | '_' | '\'' | '\u{a0}' | '\u{202f}')
+}
+
+/// Without a unit in `input`, `default_unit` is assumed, and if there's none it's an error
+/// Both "." and "," are accepted as decimal separator, when both appear the last one is the decimal separator.
+/// A number of sats can't have decimals, so in that case "." and "," can only be group separators
+pub fn parse_amount(input: &str, default_unit: Option<AmountUnit>) -> Result<Amount> {
+    let input = input.trim();
+    let unit_start = input
+        .find(|c: char| c.is_alphabetic() || c == '₿')
+        .unwrap_or(input.len());
+    let (number, symbol) = input.split_at(unit_start);
+
+    let unit = if symbol.is_empty() {
+        default_unit.ok_or_else(|| Error::msg(format!("Missing unit in {}", input)))?
+    } else {
+        AmountUnit::from_symbol(symbol.trim())
+            .ok_or_else(|| Error::msg(format!("Unknown unit {}", symbol.trim())))?
+    };
+
+    let number: String = number.chars().filter(|c| !is_group_separator(*c)).collect();
+    if number.is_empty() {
+        return Err(Error::msg(format!("Missing number in {}", input)));
+    }
+
+    let number = if unit == AmountUnit::Sats {
+        let groups: Vec<&str> = number.split(['.', ',']).collect();
+        if groups[1..].iter().any(|g| g.len() != 3) {
+            return Err(Error::msg("An amount of sats can't have decimals"));
+        }
+        groups.concat()
+    } else {
+        match number.rfind(['.', ',']) {
+            Some(pos) => {
+                let integer: String = number[..pos]
+                    .chars()
+                    .filter(|c| *c != '.' && *c != ',')
+                    .collect();
+                format!("{}.{}", integer, &number[pos + 1..])
+            }
+            None => number,
+        }
+    };
+
+    Ok(Amount::from_str_in(&number, unit.get_denomination())?)
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct AmountFormat {
+    pub unit: AmountUnit,
+    pub decimal_separator: char,
+    pub group_separator: Option<char>,
+    pub show_unit: bool,
+}
+
+impl Default for AmountFormat {
+    fn default() -> Self {
+        Self::for_locale("en", AmountUnit::Btc)
+    }
+}
+
+impl AmountFormat {
+    /// `locale` is a BCP 47 tag like "en-US" or "fr", unknown locales get the english format
+    pub fn for_locale(locale: &str, unit: AmountUnit) -> Self {
+        let locale = locale.to_lowercase().replace('_', "-");
+        let language = locale.split('-').next().unwrap_or("");
+
+        let (decimal_separator, group_separator) = match language {
+            _ if locale == "de-ch" => ('.', '\''),
+            "fr" | "ru" | "pl" | "cs" | "sk" | "sv" | "nb" | "no" | "fi" | "uk" | "hu" => {
+                (',', '\u{202f}')
+            }
+            "de" | "es" | "it" | "nl" | "pt" | "id" | "tr" | "da" | "el" | "ro" => (',', '.'),
+            _ => ('.', ','),
+        };
+
+        Self {
+            unit,
+            decimal_separator,
+            group_separator: Some(group_separator),
+            show_unit: true,
+        }
+    }
+}
+
+/// Trailing zeros of the decimals are dropped
+pub fn format_amount(amount: Amount, format: &AmountFormat) -> String {
+    let decimals = format.unit.get_decimals();
+    let factor = 10u64.pow(decimals);
+    let sats = amount.to_sat();
+
+    let integer = (sats / factor).to_string();
+    let mut res = String::new();
+    for (i, c) in integer.chars().enumerate() {
+        if i > 0 && (integer.len() - i).is_multiple_of(3) {
+            if let Some(separator) = format.group_separator {
+                res.push(separator);
+            }
+        }
+        res.push(c);
+    }
+
+    if decimals > 0 {
+        let fraction = format!("{:0width$}", sats % factor, width = decimals as usize);
+        let fraction = fraction.trim_end_matches('0');
+        if !fraction.is_empty() {
+            res.push(format.decimal_separator);
+            res.push_str(fraction);
+        }
+    }
+
+    if format.show_unit {
+        res.push(' ');
+        res.push_str(format.unit.get_symbol());
+    }
+
+    res
+}
@@ -0,0 +1,320 @@
+//! Anti-exfil protocol for external signers
+//!
+//! A malicious signer could leak the spend key through the nonces of its signatures.
+//! To prevent that, the host commits to some randomness before the signer picks its nonce,
+//! then the signer must tweak its nonce with that randomness. The host checks that it did,
+//! so the final nonce is out of the signer control.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use bitcoin::secp256k1::{schnorr, Message, PublicKey, Secp256k1, SecretKey};
+
+use anyhow::{Error, Result};
+
+use crate::rng::{SharedRng, SpRng};
+use crate::signer::{has_even_y, hash_to_scalar, tagged_hash, xbytes, Signer};
+
+const COMMITMENT_TAG: &str = "sp_client/anti_exfil/commitment";
+const NONCE_TWEAK_TAG: &str = "sp_client/anti_exfil/nonce_tweak";
+const NONCE_TAG: &str = "sp_client/anti_exfil/nonce";
+
+pub fn host_commitment(host_rand: &[u8; 32]) -> [u8; 32] {
+    tagged_hash(COMMITMENT_TAG, &[&host_rand[..]])
+}
+
+fn nonce_tweak(signer_nonce: &PublicKey, host_rand: &[u8; 32]) -> Result<SecretKey> {
+    hash_to_scalar(tagged_hash(
+        NONCE_TWEAK_TAG,
+        &[&signer_nonce.serialize()[..], &host_rand[..]],
+    ))
+}
+
+/// An external signer implementing the protocol
+pub trait AntiExfilSigner {
+    fn get_spend_pubkey(&self) -> Result<PublicKey>;
+
+    /// First round: the signer gets the commitment to the host randomness
+    /// and returns the nonce it commits to
+    fn commit_nonce(
+        &self,
+        msg: &Message,
+        tweak: &SecretKey,
+        host_commitment: &[u8; 32],
+    ) -> Result<PublicKey>;
+
+    /// Second round: the host reveals its randomness,
+    /// the signer tweaks its nonce with it and signs
+    fn sign_with_host_randomness(
+        &self,
+        msg: &Message,
+        tweak: &SecretKey,
+        host_rand: &[u8; 32],
+    ) -> Result<schnorr::Signature>;
+}
+
+/// Host side of the protocol, wraps an `AntiExfilSigner` so it can be used to sign psbts
+pub struct AntiExfilHost {
+    signer: Box<dyn AntiExfilSigner>,
+    rng: SharedRng,
+}
+
+impl AntiExfilHost {
+    pub fn new(signer: Box<dyn AntiExfilSigner>) -> Self {
+        Self {
+            signer,
+            rng: SharedRng::default(),
+        }
+    }
+
+    pub fn with_rng(mut self, rng: impl SpRng + 'static) -> Self {
+        self.rng = SharedRng::new(rng);
+        self
+    }
+}
+
+impl Signer for AntiExfilHost {
+    fn get_spend_pubkey(&self) -> Result<PublicKey> {
+        self.signer.get_spend_pubkey()
+    }
+
+    fn sign_tweaked(
+        &self,
+        msg: &Message,
+        tweak: &SecretKey,
+        aux_rand: &[u8; 32],
+    ) -> Result<schnorr::Signature> {
+        let rand: [u8; 32] = self.rng.gen_bytes();
+        let host_rand = tagged_hash(NONCE_TAG, &[&rand[..], &aux_rand[..]]);
+
+        let signer_nonce = self
+            .signer
+            .commit_nonce(msg, tweak, &host_commitment(&host_rand))?;
+
+        let sig = self
+            .signer
+            .sign_with_host_randomness(msg, tweak, &host_rand)?;
+
+        // the nonce of the signature must be the one the signer committed to, tweaked with our randomness
+        let secp = Secp256k1::verification_only();
+        let expected_nonce =
+            signer_nonce.add_exp_tweak(&secp, &nonce_tweak(&signer_nonce, &host_rand)?.into())?;
+        if sig[..32] != xbytes(&expected_nonce) {
+            return Err(Error::msg("Signer didn't use the host randomness"));
+        }
+
+        Ok(sig)
+    }
+}
+
+struct PendingNonce {
+    nonce: SecretKey,
+    host_commitment: [u8; 32],
+}
+
+impl Drop for PendingNonce {
+    fn drop(&mut self) {
+        self.nonce.non_secure_erase();
+    }
+}
+
+/// Reference implementation of the signer side, holding the spend key in memory
+pub struct LocalAntiExfilSigner {
+    spend_sk: SecretKey,
+    pending: Mutex<HashMap<[u8; 32], PendingNonce>>,
+    rng: SharedRng,
+}
+
+impl LocalAntiExfilSigner {
+    pub fn new(spend_sk: SecretKey) -> Self {
+        Self {
+            spend_sk,
+            pending: Mutex::new(HashMap::new()),
+            rng: SharedRng::default(),
+        }
+    }
+
+    pub fn with_rng(mut self, rng: impl SpRng + 'static) -> Self {
+        self.rng = SharedRng::new(rng);
+        self
+    }
+
+    fn session_id(msg: &Message, tweak: &SecretKey) -> [u8; 32] {
+        tagged_hash(NONCE_TAG, &[&msg[..], &tweak.secret_bytes()[..]])
+    }
+}
+
+impl Drop for LocalAntiExfilSigner {
+    fn drop(&mut self) {
+        self.spend_sk.non_secure_erase();
+    }
+}
+
+impl AntiExfilSigner for LocalAntiExfilSigner {
+    fn get_spend_pubkey(&self) -> Result<PublicKey> {
+        Ok(self.spend_sk.public_key(&Secp256k1::signing_only()))
+    }
+
+    fn commit_nonce(
+        &self,
+        msg: &Message,
+        tweak: &SecretKey,
+        host_commitment: &[u8; 32],
+    ) -> Result<PublicKey> {
+        let rand: [u8; 32] = self.rng.gen_bytes();
+
+        let nonce = hash_to_scalar(tagged_hash(
+            NONCE_TAG,
+            &[&rand[..], &self.spend_sk.secret_bytes()[..], &msg[..]],
+        ))?;
+        let public_nonce = nonce.public_key(&Secp256k1::signing_only());
+
+        self.pending
+            .lock()
+            .map_err(|_| Error::msg("Failed to lock pending nonces"))?
+            .insert(
+                Self::session_id(msg, tweak),
+                PendingNonce {
+                    nonce,
+                    host_commitment: *host_commitment,
+                },
+            );
+
+        Ok(public_nonce)
+    }
+
+    fn sign_with_host_randomness(
+        &self,
+        msg: &Message,
+        tweak: &SecretKey,
+        host_rand: &[u8; 32],
+    ) -> Result<schnorr::Signature> {
+        let secp = Secp256k1::signing_only();
+
+        // a nonce is used only once
+        let pending = self
+            .pending
+            .lock()
+            .map_err(|_| Error::msg("Failed to lock pending nonces"))?
+            .remove(&Self::session_id(msg, tweak))
+            .ok_or_else(|| Error::msg("No nonce committed for this message"))?;
+
+        if host_commitment(host_rand) != pending.host_commitment {
+            return Err(Error::msg("Host randomness doesn't match its commitment"));
+        }
+
+        let signer_nonce = pending.nonce.public_key(&secp);
+        let mut k = pending
+            .nonce
+            .add_tweak(&nonce_tweak(&signer_nonce, host_rand)?.into())?;
+        let final_nonce = k.public_key(&secp);
+        if !has_even_y(&final_nonce) {
+            k = k.negate();
+        }
+
+        let mut d = self.spend_sk.add_tweak(&(*tweak).into())?;
+        let output_key = d.public_key(&secp);
+        if !has_even_y(&output_key) {
+            d = d.negate();
+        }
+
+        // BIP340 signature with our own nonce
+        let challenge = hash_to_scalar(tagged_hash(
+            "BIP0340/challenge",
+            &[
+                &xbytes(&final_nonce)[..],
+                &xbytes(&output_key)[..],
+                &msg[..],
+            ],
+        ))?;
+        let s = k.add_tweak(&d.mul_tweak(&challenge.into())?.into())?;
+
+        d.non_secure_erase();
+        k.non_secure_erase();
+
+        let mut sig = [0u8; 64];
+        sig[..32].copy_from_slice(&xbytes(&final_nonce));
+        sig[32..].copy_from_slice(&s.secret_bytes());
+
+        Ok(schnorr::Signature::from_slice(&sig)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::secp256k1::Keypair;
+
+    fn spend_sk() -> SecretKey {
+        SecretKey::from_slice(&[0x22; 32]).unwrap()
+    }
+
+    /// Signs with a nonce of its own choosing, e.g. to leak the key
+    struct CheatingSigner(LocalAntiExfilSigner);
+
+    impl AntiExfilSigner for CheatingSigner {
+        fn get_spend_pubkey(&self) -> Result<PublicKey> {
+            self.0.get_spend_pubkey()
+        }
+
+        fn commit_nonce(
+            &self,
+            msg: &Message,
+            tweak: &SecretKey,
+            host_commitment: &[u8; 32],
+        ) -> Result<PublicKey> {
+            self.0.commit_nonce(msg, tweak, host_commitment)
+        }
+
+        fn sign_with_host_randomness(
+            &self,
+            msg: &Message,
+            tweak: &SecretKey,
+            _host_rand: &[u8; 32],
+        ) -> Result<schnorr::Signature> {
+            let secp = Secp256k1::new();
+            let keypair = Keypair::from_secret_key(&secp, &spend_sk().add_tweak(&(*tweak).into())?);
+            Ok(secp.sign_schnorr_with_aux_rand(msg, &keypair, &[0x99; 32]))
+        }
+    }
+
+    #[test]
+    fn host_checks_the_nonce() {
+        let secp = Secp256k1::new();
+        let msg = Message::from_digest([0x01; 32]);
+        let tweak = SecretKey::from_slice(&[0x05; 32]).unwrap();
+        let output_key = spend_sk()
+            .add_tweak(&tweak.into())
+            .unwrap()
+            .x_only_public_key(&secp)
+            .0;
+
+        let host = AntiExfilHost::new(Box::new(LocalAntiExfilSigner::new(spend_sk())));
+        let sig = host.sign_tweaked(&msg, &tweak, &[0; 32]).unwrap();
+        secp.verify_schnorr(&sig, &msg, &output_key).unwrap();
+
+        let host = AntiExfilHost::new(Box::new(CheatingSigner(LocalAntiExfilSigner::new(
+            spend_sk(),
+        ))));
+        assert!(host.sign_tweaked(&msg, &tweak, &[0; 32]).is_err());
+    }
+
+    #[test]
+    fn signer_checks_the_host_randomness() {
+        let msg = Message::from_digest([0x01; 32]);
+        let tweak = SecretKey::from_slice(&[0x05; 32]).unwrap();
+        let signer = LocalAntiExfilSigner::new(spend_sk());
+        let host_rand = [0x07; 32];
+
+        signer
+            .commit_nonce(&msg, &tweak, &host_commitment(&host_rand))
+            .unwrap();
+        assert!(signer
+            .sign_with_host_randomness(&msg, &tweak, &[0x08; 32])
+            .is_err());
+        // the nonce is gone after a failed attempt too
+        assert!(signer
+            .sign_with_host_randomness(&msg, &tweak, &host_rand)
+            .is_err());
+    }
+}
@@ -0,0 +1,320 @@
+use std::str::FromStr;
+
+use bitcoin::{
+    hex::DisplayHex,
+    secp256k1::{rand::seq::SliceRandom, PublicKey, Secp256k1, SecretKey},
+    BlockHash, OutPoint, Txid,
+};
+use serde::{Deserialize, Serialize};
+
+use anyhow::Result;
+
+use crate::chain::ChainBackend;
+use crate::descriptors::check_output_key;
+use crate::spclient::{OutputSpendStatus, OwnedOutput, SpWallet, UNCONFIRMED_HEIGHT};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum AuditIssueKind {
+    BirthdayAfterLastScan,
+    /// Output found in a block we're not supposed to have scanned yet
+    OutputAboveLastScan,
+    OutputBelowBirthday,
+    InvalidTweak,
+    /// The script isn't the one we get from the spend key and the tweak
+    ScriptMismatch,
+    InvalidSpendStatus,
+    ZeroAmount,
+    /// The chain backend doesn't know about this output
+    MissingOnChain,
+    AmountMismatch,
+    /// The output on chain has another script than the one we stored
+    ScriptMismatchOnChain,
+    /// We think the output is unspent but the chain says otherwise
+    SpentOnChain,
+    /// We think the output is spent in a block but the chain says otherwise
+    UnspentOnChain,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum RepairSuggestion {
+    /// Rescan from this height, dropping everything above
+    ResetToHeight(u32),
+    RevertSpentStatus(OutPoint),
+    RemoveOutput(OutPoint),
+    /// We can't fix it ourselves, the spending transaction must be found by scanning
+    Rescan,
+}
+
+/// How many outputs to check against the chain
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum AuditSample {
+    All,
+    Random(usize),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AuditIssue {
+    pub outpoint: Option<OutPoint>,
+    pub kind: AuditIssueKind,
+    pub repair: RepairSuggestion,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AuditReport {
+    pub checked_outputs: usize,
+    pub checked_on_chain: usize,
+    pub issues: Vec<AuditIssue>,
+}
+
+impl AuditReport {
+    pub fn is_consistent(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    fn push(&mut self, outpoint: Option<OutPoint>, kind: AuditIssueKind, repair: RepairSuggestion) {
+        self.issues.push(AuditIssue {
+            outpoint,
+            kind,
+            repair,
+        });
+    }
+}
+
+impl SpWallet {
+    /// Check the wallet internal invariants
+    /// If a `backend` is provided, up to `sample_size` random outputs are also checked against the chain
+    pub fn audit_wallet(
+        &self,
+        backend: Option<&dyn ChainBackend>,
+        sample_size: usize,
+    ) -> Result<AuditReport> {
+        let mut report = AuditReport::default();
+
+        let outputs = self.get_outputs();
+        let birthday = outputs.get_birthday();
+        let last_scan = outputs.get_last_scan();
+
+        if birthday > last_scan {
+            report.push(
+                None,
+                AuditIssueKind::BirthdayAfterLastScan,
+                RepairSuggestion::ResetToHeight(birthday),
+            );
+        }
+
+        let spend_pk: PublicKey = self.get_client().get_spend_key().into();
+        let secp = Secp256k1::verification_only();
+
+        let list = outputs.to_outpoints_list();
+        for (outpoint, output) in list.iter() {
+            report.checked_outputs += 1;
+            audit_output(
+                &mut report,
+                *outpoint,
+                output,
+                birthday,
+                last_scan,
+                |tweak| {
+                    let output_key = spend_pk.add_exp_tweak(&secp, &tweak.into())?;
+                    check_output_key(output, &output_key)
+                },
+            );
+        }
+
+        if let Some(backend) = backend {
+            let mut sample: Vec<(&OutPoint, &OwnedOutput)> = list.iter().collect();
+            sample.shuffle(&mut self.get_client().get_rng());
+            sample.truncate(sample_size);
+
+            for (outpoint, output) in sample {
+                report.checked_on_chain += 1;
+                audit_output_on_chain(&mut report, backend, *outpoint, output)?;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Audit to run after restoring from a backup rather than a rescan, before the balance is trusted
+    /// Unspent outputs, the ones that make the balance, are checked on chain first
+    pub fn audit_restore(
+        &self,
+        backend: &dyn ChainBackend,
+        sample: AuditSample,
+    ) -> Result<AuditReport> {
+        let mut report = self.audit_wallet(None, 0)?;
+
+        let list = self.get_outputs().to_outpoints_list();
+        let mut outputs: Vec<(&OutPoint, &OwnedOutput)> = list.iter().collect();
+        outputs.shuffle(&mut self.get_client().get_rng());
+        // stable, so the order stays random among unspent and among spent outputs
+        outputs.sort_by_key(|(_, o)| o.spend_status != OutputSpendStatus::Unspent);
+        if let AuditSample::Random(size) = sample {
+            outputs.truncate(size);
+        }
+
+        for (outpoint, output) in outputs {
+            report.checked_on_chain += 1;
+            audit_output_on_chain(&mut report, backend, *outpoint, output)?;
+        }
+
+        Ok(report)
+    }
+}
+
+fn audit_output(
+    report: &mut AuditReport,
+    outpoint: OutPoint,
+    output: &OwnedOutput,
+    birthday: u32,
+    last_scan: u32,
+    check_script: impl Fn(SecretKey) -> Result<()>,
+) {
+    // pending outputs don't have a block yet
+    let confirmed = output.blockheight != UNCONFIRMED_HEIGHT;
+
+    if confirmed && output.blockheight > last_scan {
+        report.push(
+            Some(outpoint),
+            AuditIssueKind::OutputAboveLastScan,
+            RepairSuggestion::ResetToHeight(last_scan),
+        );
+    }
+
+    if confirmed && output.blockheight < birthday {
+        report.push(
+            Some(outpoint),
+            AuditIssueKind::OutputBelowBirthday,
+            RepairSuggestion::ResetToHeight(output.blockheight),
+        );
+    }
+
+    if output.amount.to_sat() == 0 {
+        report.push(
+            Some(outpoint),
+            AuditIssueKind::ZeroAmount,
+            RepairSuggestion::RemoveOutput(outpoint),
+        );
+    }
+
+    match SecretKey::from_str(&output.tweak) {
+        Ok(tweak) => {
+            if check_script(tweak).is_err() {
+                report.push(
+                    Some(outpoint),
+                    AuditIssueKind::ScriptMismatch,
+                    RepairSuggestion::RemoveOutput(outpoint),
+                );
+            }
+        }
+        Err(_) => report.push(
+            Some(outpoint),
+            AuditIssueKind::InvalidTweak,
+            RepairSuggestion::RemoveOutput(outpoint),
+        ),
+    }
+
+    let status_is_valid = match &output.spend_status {
+        OutputSpendStatus::Unspent => true,
+        OutputSpendStatus::Spent(txid) => Txid::from_str(txid).is_ok(),
+        OutputSpendStatus::Mined(block) => BlockHash::from_str(block).is_ok(),
+    };
+    if !status_is_valid {
+        report.push(
+            Some(outpoint),
+            AuditIssueKind::InvalidSpendStatus,
+            RepairSuggestion::RevertSpentStatus(outpoint),
+        );
+    }
+}
+
+fn audit_output_on_chain(
+    report: &mut AuditReport,
+    backend: &dyn ChainBackend,
+    outpoint: OutPoint,
+    output: &OwnedOutput,
+) -> Result<()> {
+    let chain_output = match backend.get_output(&outpoint)? {
+        Some(chain_output) => chain_output,
+        // the backend may not know about the mempool
+        None if output.blockheight == UNCONFIRMED_HEIGHT => return Ok(()),
+        None => {
+            report.push(
+                Some(outpoint),
+                AuditIssueKind::MissingOnChain,
+                RepairSuggestion::ResetToHeight(output.blockheight),
+            );
+            return Ok(());
+        }
+    };
+
+    if chain_output
+        .txout
+        .script_pubkey
+        .as_bytes()
+        .to_lower_hex_string()
+        != output.script
+    {
+        report.push(
+            Some(outpoint),
+            AuditIssueKind::ScriptMismatchOnChain,
+            RepairSuggestion::ResetToHeight(output.blockheight),
+        );
+    }
+
+    if chain_output.txout.value != output.amount {
+        report.push(
+            Some(outpoint),
+            AuditIssueKind::AmountMismatch,
+            RepairSuggestion::ResetToHeight(output.blockheight),
+        );
+    }
+
+    match (&output.spend_status, chain_output.spent) {
+        (OutputSpendStatus::Unspent, true) => report.push(
+            Some(outpoint),
+            AuditIssueKind::SpentOnChain,
+            RepairSuggestion::Rescan,
+        ),
+        (OutputSpendStatus::Mined(_), false) => report.push(
+            Some(outpoint),
+            AuditIssueKind::UnspentOnChain,
+            RepairSuggestion::RevertSpentStatus(outpoint),
+        ),
+        _ => (),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use bitcoin::Amount;
+
+    use crate::spclient::OutputList;
+    use crate::test_utils::{owned_output, test_client};
+
+    #[test]
+    fn pending_outputs_are_consistent() {
+        let client = test_client();
+        let secp = Secp256k1::signing_only();
+        let mut outputs = OutputList::new(
+            client.get_scan_key().public_key(&secp),
+            client.get_spend_key().into(),
+            100,
+        );
+        let (outpoint, mut pending) = owned_output(&client, 1, Amount::from_sat(10_000));
+        pending.blockheight = UNCONFIRMED_HEIGHT;
+        outputs.extend_from(HashMap::from([(outpoint, pending)]));
+        outputs.update_last_scan(200);
+        let wallet = SpWallet::new(client, Some(outputs)).unwrap();
+
+        let report = wallet.audit_wallet(None, 0).unwrap();
+        assert_eq!(report.checked_outputs, 1);
+        assert!(report.is_consistent());
+    }
+}
@@ -0,0 +1,179 @@
+//! Authentication of an API controlling the wallet, e.g. the daemon's RPC and webhook configuration
+//!
+//! A cookie file like bitcoind's, readable only by the user running the daemon, static API keys,
+//! and client certificates for mutual TLS. The TLS handshake itself is the server's job,
+//! we only check the certificate it verified against the ones that are allowed.
+//! At least one method must be enabled, the API is never left open, even on localhost.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use bitcoin::{
+    base64::prelude::{Engine as _, BASE64_STANDARD},
+    hashes::{sha256, Hash},
+    hex::DisplayHex,
+};
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Error, Result};
+use zeroize::Zeroizing;
+
+use crate::rng::SpRng;
+
+pub const COOKIE_FILE: &str = ".cookie";
+const COOKIE_USER: &str = "__cookie__";
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AuthConfig {
+    /// Write a new cookie file each time the server starts
+    pub cookie: bool,
+    /// Hex encoded sha256 of each accepted API key, see `generate_api_key`
+    pub api_key_hashes: Vec<String>,
+    /// Hex encoded sha256 of each accepted client certificate, in DER
+    pub client_cert_fingerprints: Vec<String>,
+}
+
+/// What the client presented
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Credentials<'a> {
+    /// Value of the `Authorization` header, `Basic` for the cookie or `Bearer` for an API key
+    Header(&'a str),
+    /// DER certificate the TLS layer verified the client holds the key of
+    ClientCertificate(&'a [u8]),
+}
+
+/// Returned wrapped in an `anyhow::Error`, use `downcast_ref` to answer 401
+/// Doesn't tell what was wrong on purpose
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unauthorized;
+
+impl std::fmt::Display for Unauthorized {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unauthorized")
+    }
+}
+
+impl std::error::Error for Unauthorized {}
+
+/// A new API key, give `key` to the client and put `hash` in `AuthConfig::api_key_hashes`
+pub fn generate_api_key(rng: &mut impl SpRng) -> (Zeroizing<String>, String) {
+    let mut bytes = Zeroizing::new([0u8; 32]);
+    rng.fill_bytes(&mut bytes[..]);
+    let key = Zeroizing::new(bytes[..].to_lower_hex_string());
+    let hash = sha256::Hash::hash(key.as_bytes()).to_string();
+    (key, hash)
+}
+
+/// Secrets are compared by their hash, so that the time it takes doesn't tell how much was right
+fn hash_secret(secret: &str) -> sha256::Hash {
+    sha256::Hash::hash(secret.as_bytes())
+}
+
+pub struct Authenticator {
+    cookie_path: Option<PathBuf>,
+    cookie_hash: Option<sha256::Hash>,
+    api_key_hashes: Vec<sha256::Hash>,
+    client_cert_fingerprints: Vec<sha256::Hash>,
+}
+
+impl Authenticator {
+    /// Writes the cookie file in `data_dir` if the cookie is enabled
+    pub fn new(config: &AuthConfig, data_dir: &Path, rng: &mut impl SpRng) -> Result<Self> {
+        let parse = |hashes: &[String]| -> Result<Vec<sha256::Hash>> {
+            hashes
+                .iter()
+                .map(|h| h.parse().map_err(Error::from))
+                .collect()
+        };
+        let api_key_hashes = parse(&config.api_key_hashes)?;
+        let client_cert_fingerprints = parse(&config.client_cert_fingerprints)?;
+        if !config.cookie && api_key_hashes.is_empty() && client_cert_fingerprints.is_empty() {
+            return Err(Error::msg("No authentication method enabled"));
+        }
+
+        let (cookie_path, cookie_hash) = if config.cookie {
+            let mut bytes = Zeroizing::new([0u8; 32]);
+            rng.fill_bytes(&mut bytes[..]);
+            let password = Zeroizing::new(bytes[..].to_lower_hex_string());
+            let path = data_dir.join(COOKIE_FILE);
+            let content = Zeroizing::new(format!("{}:{}", COOKIE_USER, password.as_str()));
+            write_cookie(&path, &content)?;
+            (Some(path), Some(hash_secret(&password)))
+        } else {
+            (None, None)
+        };
+
+        Ok(Self {
+            cookie_path,
+            cookie_hash,
+            api_key_hashes,
+            client_cert_fingerprints,
+        })
+    }
+
+    pub fn check(&self, credentials: Credentials) -> Result<()> {
+        let ok = match credentials {
+            Credentials::Header(header) => {
+                if let Some(encoded) = header.strip_prefix("Basic ") {
+                    let decoded = Zeroizing::new(
+                        BASE64_STANDARD
+                            .decode(encoded.trim())
+                            .map_err(|_| Unauthorized)?,
+                    );
+                    let decoded = std::str::from_utf8(&decoded).map_err(|_| Unauthorized)?;
+                    match decoded.split_once(':') {
+                        Some((COOKIE_USER, password)) => {
+                            self.cookie_hash == Some(hash_secret(password))
+                        }
+                        _ => false,
+                    }
+                } else if let Some(key) = header.strip_prefix("Bearer ") {
+                    self.api_key_hashes.contains(&hash_secret(key.trim()))
+                } else {
+                    false
+                }
+            }
+            Credentials::ClientCertificate(der) => self
+                .client_cert_fingerprints
+                .contains(&sha256::Hash::hash(der)),
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(Unauthorized.into())
+        }
+    }
+
+    /// On shutdown, e.g. as a `Shutdown` flush, so that a stale cookie isn't left around
+    pub fn remove_cookie(&self) -> Result<()> {
+        if let Some(path) = self.cookie_path.as_ref() {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_cookie(path: &Path, content: &str) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    // the mode only applies to new files, a cookie left by a previous run keeps its own
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
@@ -0,0 +1,76 @@
+//! Conversion between block heights and timestamps, from the headers we synced
+//!
+//! Header timestamps aren't monotonic, a block can be up to 2 hours before its predecessor,
+//! so `time_to_height` works on the highest timestamp seen so far at each height.
+
+use bitcoin::block::Header;
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Error, Result};
+
+/// Persisted next to the headers, fed by the header sync
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BlockTimes {
+    /// Height of the first timestamp, e.g. a checkpoint the headers start from
+    start_height: u32,
+    times: Vec<u32>,
+    /// Max of `times` up to each height
+    max_times: Vec<u32>,
+}
+
+impl BlockTimes {
+    pub fn new(start_height: u32) -> Self {
+        Self {
+            start_height,
+            ..Default::default()
+        }
+    }
+
+    /// None if there's no header yet
+    pub fn get_tip_height(&self) -> Option<u32> {
+        (!self.times.is_empty()).then(|| self.start_height + self.times.len() as u32 - 1)
+    }
+
+    /// `height` must be the next one, or a height we already have, e.g. after a reorg,
+    /// in which case everything above it is dropped
+    pub fn insert(&mut self, height: u32, time: u32) -> Result<()> {
+        let next = self.start_height + self.times.len() as u32;
+        if height < self.start_height || height > next {
+            return Err(Error::msg(format!(
+                "Header at {} doesn't connect, expected {} to {}",
+                height, self.start_height, next
+            )));
+        }
+        let index = (height - self.start_height) as usize;
+        self.times.truncate(index);
+        self.max_times.truncate(index);
+
+        let max = self.max_times.last().copied().unwrap_or(0).max(time);
+        self.times.push(time);
+        self.max_times.push(max);
+        Ok(())
+    }
+
+    pub fn push_header(&mut self, height: u32, header: &Header) -> Result<()> {
+        self.insert(height, header.time)
+    }
+
+    /// Timestamp of the block at `height`, in seconds since the epoch
+    pub fn height_to_time(&self, height: u32) -> Option<u64> {
+        let index = height.checked_sub(self.start_height)? as usize;
+        self.times.get(index).map(|time| *time as u64)
+    }
+
+    /// The first block at or after `timestamp`, e.g. for a wallet birthday
+    /// None if `timestamp` is after the tip, or before the first header we have
+    pub fn time_to_height(&self, timestamp: u64) -> Option<u32> {
+        let first = *self.max_times.first()? as u64;
+        if timestamp < first && self.start_height > 0 {
+            return None;
+        }
+        let index = self
+            .max_times
+            .partition_point(|max| (*max as u64) < timestamp);
+        (index < self.max_times.len()).then_some(self.start_height + index as u32)
+    }
+}
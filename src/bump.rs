@@ -0,0 +1,379 @@
+//! Fee bumping of our transactions that are stuck in the mempool
+//!
+//! A replacement keeps the inputs of the original, so the silent payment outputs keep their scripts
+//! and only our change pays for the bump. Without replaceability or change, a child spends one of
+//! our outputs of the stuck transaction (CPFP).
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use bitcoin::{secp256k1::SecretKey, Amount, OutPoint, ScriptBuf, Transaction, Txid, Witness};
+use serde::{Deserialize, Serialize};
+use silentpayments::receiving::Label;
+
+use anyhow::Result;
+
+use crate::chain::FeeEstimator;
+use crate::psbt_data::{set_psbt_change_output, set_psbt_sp_address};
+use crate::settings::FeeLevel;
+use crate::spclient::{
+    get_psbt_input, ChangePolicy, OutputSpendStatus, OwnedOutput, Psbt, Recipient, SpClient,
+    SpWallet, SubDustChange, UNCONFIRMED_HEIGHT,
+};
+use crate::weight::predict_psbt_vsize;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum BumpKind {
+    Replacement,
+    ChildPaysForParent,
+}
+
+/// To show for confirmation, then sign and broadcast
+#[derive(Debug, Clone, PartialEq)]
+pub struct BumpProposal {
+    /// The stuck transaction
+    pub txid: Txid,
+    pub kind: BumpKind,
+    pub psbt: Psbt,
+    /// In sat/vB
+    pub old_fee_rate: Amount,
+    pub new_fee_rate: Amount,
+    /// On top of what the stuck transaction already pays
+    pub extra_fee: Amount,
+}
+
+impl SpWallet {
+    /// Proposals for each of `pending` that pays less than the estimated rate for `fee_level`
+    /// `pending` are our unconfirmed transactions, as broadcast. The new rate is capped at `max_fee_rate`,
+    /// transactions we can't bump, e.g. that spend outputs of others, are left out
+    pub fn bump_stuck(
+        &self,
+        pending: &[Transaction],
+        fee_estimator: &impl FeeEstimator,
+        fee_level: FeeLevel,
+        max_fee_rate: Amount,
+    ) -> Result<Vec<BumpProposal>> {
+        let target_rate = fee_estimator
+            .get_fee_rate(fee_level.get_target_blocks())?
+            .min(max_fee_rate);
+        let outputs = self.get_outputs().to_outpoints_list();
+
+        let mut res = vec![];
+        for tx in pending {
+            let txid = tx.txid();
+            let Some(fee) = get_fee(tx, &outputs) else {
+                continue;
+            };
+            let vsize = tx.vsize() as u64;
+            let old_fee_rate = Amount::from_sat(fee.to_sat().div_ceil(vsize));
+            if old_fee_rate >= target_rate {
+                continue;
+            }
+
+            let proposal = match self.build_replacement(tx, &outputs, fee, target_rate)? {
+                Some(proposal) => Some(proposal),
+                None => self.build_child(tx, &outputs, fee, target_rate)?,
+            };
+            if let Some((kind, psbt, extra_fee)) = proposal {
+                res.push(BumpProposal {
+                    txid,
+                    kind,
+                    psbt,
+                    old_fee_rate,
+                    new_fee_rate: target_rate,
+                    extra_fee,
+                });
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// None if `tx` doesn't signal replaceability or our change can't pay for it
+    fn build_replacement(
+        &self,
+        tx: &Transaction,
+        outputs: &HashMap<OutPoint, OwnedOutput>,
+        fee: Amount,
+        fee_rate: Amount,
+    ) -> Result<Option<(BumpKind, Psbt, Amount)>> {
+        if !tx.is_explicitly_rbf() {
+            return Ok(None);
+        }
+        let txid = tx.txid();
+        let client = self.get_client();
+        let change_label = Label::new(client.get_scan_key(), 0).as_string();
+        let Some(change_vout) = (0..tx.output.len()).find(|vout| {
+            outputs
+                .get(&OutPoint::new(txid, *vout as u32))
+                .is_some_and(|o| o.label.as_ref() == Some(&change_label))
+        }) else {
+            return Ok(None);
+        };
+
+        let vsize = tx.vsize() as u64;
+        // BIP125 also requires paying for the relay of the replacement itself, at 1 sat/vB
+        let extra_fee = (fee_rate * vsize)
+            .checked_sub(fee)
+            .unwrap_or(Amount::ZERO)
+            .max(Amount::from_sat(vsize));
+
+        let change = &tx.output[change_vout];
+        let dust = client
+            .get_change_policy()
+            .dust
+            .get_threshold(&change.script_pubkey);
+        let new_change = match change.value.checked_sub(extra_fee) {
+            Some(value) if value >= dust => value,
+            _ => return Ok(None),
+        };
+
+        let mut unsigned_tx = tx.clone();
+        for input in unsigned_tx.input.iter_mut() {
+            input.witness = Witness::new();
+        }
+        unsigned_tx.output[change_vout].value = new_change;
+
+        let mut psbt = Psbt::from_unsigned_tx(unsigned_tx)?;
+        for (i, input) in tx.input.iter().enumerate() {
+            let spent = &outputs[&input.previous_output];
+            psbt.inputs[i] = get_psbt_input(
+                &ScriptBuf::from_hex(&spent.script)?,
+                spent.amount,
+                &SecretKey::from_str(&spent.tweak)?.into(),
+            )?;
+        }
+
+        // so that the spending policy knows it's our change and not a payment
+        set_psbt_sp_address(
+            &mut psbt,
+            change_vout,
+            &client.sp_receiver.get_change_address(),
+        )?;
+        set_psbt_change_output(&mut psbt, change_vout)?;
+
+        Ok(Some((BumpKind::Replacement, psbt, extra_fee)))
+    }
+
+    /// A child sending one of our unspent outputs of `tx` back to us, paying for both
+    fn build_child(
+        &self,
+        tx: &Transaction,
+        outputs: &HashMap<OutPoint, OwnedOutput>,
+        fee: Amount,
+        fee_rate: Amount,
+    ) -> Result<Option<(BumpKind, Psbt, Amount)>> {
+        let txid = tx.txid();
+        let Some((outpoint, output)) = (0..tx.output.len() as u32)
+            .map(|vout| OutPoint::new(txid, vout))
+            .filter_map(|outpoint| outputs.get(&outpoint).map(|o| (outpoint, o)))
+            .filter(|(_, o)| o.spend_status == OutputSpendStatus::Unspent)
+            .max_by_key(|(_, o)| o.amount)
+        else {
+            return Ok(None);
+        };
+
+        let client = self.get_client();
+        let change_address = client.sp_receiver.get_change_address();
+        let policy = ChangePolicy {
+            sub_dust_change: SubDustChange::AddToFees,
+            ..client.get_change_policy()
+        };
+        // the whole output goes back to us, the fee is then taken from it
+        let mut psbt = client
+            .create_new_psbt_with_policy(
+                HashMap::from([(outpoint, output.clone())]),
+                vec![Recipient {
+                    address: change_address.clone(),
+                    amount: output.amount,
+                    nb_outputs: 1,
+                }],
+                None,
+                &policy,
+            )?
+            .0;
+
+        // the child pays for its own size, and for what the parent misses at `fee_rate`
+        let child_vsize = predict_psbt_vsize(&psbt)?;
+        let missing = (fee_rate * tx.vsize() as u64)
+            .checked_sub(fee)
+            .unwrap_or(Amount::ZERO);
+        let child_fee_rate = Amount::from_sat(
+            (fee_rate * child_vsize + missing)
+                .to_sat()
+                .div_ceil(child_vsize),
+        );
+        if SpClient::set_fees_with_policy(&mut psbt, child_fee_rate, change_address, &policy)
+            .is_err()
+        {
+            // what's left would be dust
+            return Ok(None);
+        }
+        let extra_fee = output.amount - psbt.unsigned_tx.output[0].value;
+
+        let partial_secret = client.get_partial_secret_from_psbt(&psbt)?;
+        client.fill_and_order(&mut psbt, partial_secret)?;
+
+        Ok(Some((BumpKind::ChildPaysForParent, psbt, extra_fee)))
+    }
+}
+
+/// Only known when all the inputs are ours, and `tx` must still be unconfirmed
+fn get_fee(tx: &Transaction, outputs: &HashMap<OutPoint, OwnedOutput>) -> Option<Amount> {
+    let txid = tx.txid().to_string();
+    let mut total_in = Amount::ZERO;
+    for input in tx.input.iter() {
+        let spent = outputs.get(&input.previous_output)?;
+        match spent.spend_status {
+            OutputSpendStatus::Spent(ref spending) if *spending == txid => (),
+            _ => return None,
+        }
+        total_in += spent.amount;
+    }
+    // mined outputs of this transaction mean it's not stuck anymore
+    if (0..tx.output.len() as u32).any(|vout| {
+        outputs
+            .get(&OutPoint::new(tx.txid(), vout))
+            .is_some_and(|o| o.blockheight != UNCONFIRMED_HEIGHT)
+    }) {
+        return None;
+    }
+    let total_out: Amount = tx.output.iter().map(|o| o.value).sum();
+    total_in.checked_sub(total_out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::{hashes::Hash, Address, Network, WPubkeyHash};
+
+    use crate::policy::SpendingPolicy;
+    use crate::spclient::{get_tweak_data, SpClient};
+    use crate::test_utils::{owned_output, test_client};
+
+    struct FixedRate(Amount);
+
+    impl FeeEstimator for FixedRate {
+        fn get_fee_rate(&self, _target_blocks: u32) -> Result<Amount> {
+            Ok(self.0)
+        }
+    }
+
+    fn regular_address() -> String {
+        let script = ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([0x01; 20]));
+        Address::from_script(&script, Network::Regtest)
+            .unwrap()
+            .to_string()
+    }
+
+    /// A wallet with a transaction paying `regular_address` at 1 sat/vB, broadcast but not mined
+    fn stuck(client: SpClient) -> (SpWallet, Transaction) {
+        let mut wallet = SpWallet::new(client, None).unwrap();
+        let (outpoint, output) = owned_output(wallet.get_client(), 1, Amount::from_sat(50_000));
+        wallet
+            .get_mut_outputs()
+            .extend_from(HashMap::from([(outpoint, output.clone())]));
+
+        let client = wallet.get_client();
+        let mut psbt = client
+            .create_new_psbt(
+                HashMap::from([(outpoint, output)]),
+                vec![Recipient {
+                    address: regular_address(),
+                    amount: Amount::from_sat(30_000),
+                    nb_outputs: 1,
+                }],
+                None,
+            )
+            .unwrap();
+        SpClient::set_fees(
+            &mut psbt,
+            Amount::from_sat(1),
+            client.sp_receiver.get_change_address(),
+        )
+        .unwrap();
+        let partial_secret = client.get_partial_secret_from_psbt(&psbt).unwrap();
+        client.fill_sp_outputs(&mut psbt, partial_secret).unwrap();
+        let mut psbt = client.sign_psbt(psbt, &[0u8; 32], None).unwrap();
+        SpClient::finalize_psbt(&mut psbt).unwrap();
+        let prevouts: Vec<_> = psbt
+            .inputs
+            .iter()
+            .map(|i| i.witness_utxo.clone().unwrap())
+            .collect();
+        let tx = psbt.extract_tx().unwrap();
+
+        let tweak_data = get_tweak_data(&tx, &prevouts).unwrap().unwrap();
+        wallet
+            .update_wallet_with_transaction(&tx, UNCONFIRMED_HEIGHT, tweak_data)
+            .unwrap();
+        (wallet, tx)
+    }
+
+    fn paid_fee(psbt: &Psbt) -> Amount {
+        let total_in: Amount = psbt
+            .inputs
+            .iter()
+            .map(|i| i.witness_utxo.as_ref().unwrap().value)
+            .sum();
+        let total_out: Amount = psbt.unsigned_tx.output.iter().map(|o| o.value).sum();
+        total_in - total_out
+    }
+
+    #[test]
+    fn child_pays_the_extra_fee() {
+        let (wallet, tx) = stuck(test_client());
+        let proposals = wallet
+            .bump_stuck(
+                &[tx],
+                &FixedRate(Amount::from_sat(20)),
+                FeeLevel::default(),
+                Amount::from_sat(100),
+            )
+            .unwrap();
+        assert_eq!(proposals.len(), 1);
+        let proposal = &proposals[0];
+        assert_eq!(proposal.kind, BumpKind::ChildPaysForParent);
+        assert_eq!(proposal.psbt.unsigned_tx.output.len(), 1);
+        assert!(proposal.extra_fee > Amount::ZERO);
+        assert_eq!(paid_fee(&proposal.psbt), proposal.extra_fee);
+    }
+
+    #[test]
+    fn replacement_keeps_our_change_out_of_the_policy() {
+        let mut client = test_client();
+        let mut settings = client.get_settings().clone();
+        settings.rbf = true;
+        client.set_settings(settings);
+        let (wallet, tx) = stuck(client);
+        let old_fee = paid_fee_of(&wallet, &tx);
+
+        let proposals = wallet
+            .bump_stuck(
+                &[tx],
+                &FixedRate(Amount::from_sat(20)),
+                FeeLevel::default(),
+                Amount::from_sat(100),
+            )
+            .unwrap();
+        let proposal = &proposals[0];
+        assert_eq!(proposal.kind, BumpKind::Replacement);
+        assert_eq!(paid_fee(&proposal.psbt), old_fee + proposal.extra_fee);
+
+        let policy = SpendingPolicy {
+            whitelist: Some(vec![regular_address()]),
+            ..Default::default()
+        };
+        assert_eq!(
+            policy
+                .check_psbt(wallet.get_client(), &proposal.psbt)
+                .unwrap(),
+            Amount::from_sat(30_000)
+        );
+    }
+
+    fn paid_fee_of(wallet: &SpWallet, tx: &Transaction) -> Amount {
+        get_fee(tx, &wallet.get_outputs().to_outpoints_list()).unwrap()
+    }
+}
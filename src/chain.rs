@@ -0,0 +1,187 @@
+use bitcoin::{Amount, BlockHash, OutPoint, Transaction, TxOut, Txid};
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Error, Result};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ChainOutput {
+    pub txout: TxOut,
+    /// None if the transaction is still in the mempool
+    pub blockheight: Option<u32>,
+    pub spent: bool,
+}
+
+/// Minimal view of the chain the wallet can use to check its state,
+/// implemented by the app on top of whatever backend it syncs from (electrum, esplora, node...)
+pub trait ChainBackend {
+    fn get_tip_height(&self) -> Result<u32>;
+
+    /// Returns None if the backend doesn't know about this output
+    fn get_output(&self, outpoint: &OutPoint) -> Result<Option<ChainOutput>>;
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum ConfirmationStatus {
+    Confirmed {
+        blockheight: u32,
+        blockhash: BlockHash,
+        /// Timestamp in the block header
+        block_time: u32,
+    },
+    InMempool,
+    /// Never seen or evicted
+    Unknown,
+}
+
+/// Where a transaction stands, e.g. esplora's `/tx/:txid/status`,
+/// or the verbose `blockchain.transaction.get` of an electrum server
+pub trait ConfirmationSource {
+    fn get_confirmation_status(&self, txid: &Txid) -> Result<ConfirmationStatus>;
+}
+
+/// Source of fee rates, in sat/vB
+pub trait FeeEstimator {
+    /// Fee rate to get confirmed within `target_blocks` blocks
+    fn get_fee_rate(&self, target_blocks: u32) -> Result<Amount>;
+}
+
+/// Why the network refused a transaction, parsed from the error string of the node or server
+/// Returned wrapped in an `anyhow::Error` by `Broadcaster::broadcast_tx`, use `downcast_ref` to react to it
+#[derive(Debug, Clone, PartialEq)]
+pub enum BroadcastError {
+    /// An input doesn't exist or is already spent, maybe by another transaction of ours
+    MissingInputs,
+    /// Below the min relay fee, the mempool min fee, or what's needed to replace another transaction,
+    /// the transaction can be created again with a higher fee rate
+    FeeTooLow(String),
+    /// Nothing to do, the transaction is in the mempool or in a block
+    AlreadyKnown,
+    /// Relay policy, see `standardness::check_standardness`
+    NonStandard(String),
+    Rejected(String),
+}
+
+impl BroadcastError {
+    pub fn from_message(message: &str) -> Self {
+        let lower = message.to_lowercase();
+        let reason = message.trim().to_owned();
+
+        if lower.contains("missingorspent")
+            || lower.contains("missing-inputs")
+            || lower.contains("missing inputs")
+            || lower.contains("txn-mempool-conflict")
+        {
+            Self::MissingInputs
+        } else if lower.contains("min relay fee not met")
+            || lower.contains("mempool min fee not met")
+            || lower.contains("insufficient fee")
+            || lower.contains("fee not met")
+        {
+            Self::FeeTooLow(reason)
+        } else if lower.contains("txn-already-in-mempool")
+            || lower.contains("txn-already-known")
+            || lower.contains("already in block chain")
+            || lower.contains("outputs already in utxo set")
+        {
+            Self::AlreadyKnown
+        } else if lower.contains("dust")
+            || lower.contains("scriptpubkey")
+            || lower.contains("scriptsig")
+            || lower.contains("tx-size")
+            || lower.contains("nonstandard")
+            || lower.contains("non-standard")
+            || lower.contains("non-final")
+            || lower.contains("multi-op-return")
+            || lower.contains("version")
+        {
+            Self::NonStandard(reason)
+        } else {
+            Self::Rejected(reason)
+        }
+    }
+}
+
+impl std::fmt::Display for BroadcastError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingInputs => write!(f, "Inputs are missing or already spent"),
+            Self::FeeTooLow(reason) => write!(f, "Fee too low: {}", reason),
+            Self::AlreadyKnown => write!(f, "Transaction already known"),
+            Self::NonStandard(reason) => write!(f, "Non standard transaction: {}", reason),
+            Self::Rejected(reason) => write!(f, "Transaction rejected: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for BroadcastError {}
+
+pub trait Broadcaster {
+    /// Errors from the network must be returned as a `BroadcastError`
+    fn broadcast_tx(&self, tx: &Transaction) -> Result<Txid>;
+}
+
+/// Broadcasting a transaction the network already has succeeds, so that it can be retried safely
+/// Returns the txid we computed, not the one the backend answered
+pub fn broadcast_idempotent(
+    broadcaster: &(impl Broadcaster + ?Sized),
+    tx: &Transaction,
+) -> Result<Txid> {
+    let txid = tx.txid();
+    match broadcaster.broadcast_tx(tx) {
+        Ok(answered) if answered != txid => Err(Error::msg(format!(
+            "Backend answered txid {} for {}",
+            answered, txid
+        ))),
+        Ok(_) => Ok(txid),
+        Err(e) if e.downcast_ref::<BroadcastError>() == Some(&BroadcastError::AlreadyKnown) => {
+            Ok(txid)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum BroadcastMode {
+    /// Through the backend we sync from
+    #[default]
+    Default,
+    /// Through another path, e.g. a one-shot tor circuit or a configured esplora,
+    /// so that what we sync from can't link our broadcasts to our filter and block requests
+    Private,
+}
+
+/// Sends each transaction through the path of `mode`
+/// In private mode, failing to reach the private path is an error, falling back would leak the link
+pub struct BroadcastRouter {
+    pub mode: BroadcastMode,
+    sync_path: Box<dyn Broadcaster + Send + Sync>,
+    private_path: Option<Box<dyn Broadcaster + Send + Sync>>,
+}
+
+impl BroadcastRouter {
+    pub fn new(
+        mode: BroadcastMode,
+        sync_path: Box<dyn Broadcaster + Send + Sync>,
+        private_path: Option<Box<dyn Broadcaster + Send + Sync>>,
+    ) -> Self {
+        Self {
+            mode,
+            sync_path,
+            private_path,
+        }
+    }
+}
+
+impl Broadcaster for BroadcastRouter {
+    fn broadcast_tx(&self, tx: &Transaction) -> Result<Txid> {
+        match self.mode {
+            BroadcastMode::Default => self.sync_path.broadcast_tx(tx),
+            BroadcastMode::Private => match self.private_path {
+                Some(ref private_path) => private_path.broadcast_tx(tx),
+                None => Err(Error::msg(
+                    "Private broadcast enabled without a private path",
+                )),
+            },
+        }
+    }
+}
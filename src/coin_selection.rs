@@ -0,0 +1,195 @@
+//! Choosing the outputs to spend, with privacy in mind
+//!
+//! Outputs received in the same transaction are one payment and are spent together,
+//! outputs of different labels are kept apart when possible, and round change is avoided
+//! since it tells which output goes back to us.
+
+use std::collections::HashMap;
+
+use bitcoin::{Amount, OutPoint};
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Error, Result};
+
+use crate::spclient::{OwnedOutput, SpWallet};
+
+/// vsize of a taproot key path input, rounded up
+const TAPROOT_INPUT_VSIZE: u64 = 58;
+const TAPROOT_OUTPUT_VSIZE: u64 = 43;
+/// Version, locktime, counts and segwit marker
+const TX_OVERHEAD_VSIZE: u64 = 11;
+/// Change that's a multiple of this looks like a payment amount
+const ROUND_AMOUNT: u64 = 10_000;
+
+/// The "privacy vs. fee" knob, for each send
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum SelectionPreference {
+    /// Never mix labels, always spend whole payments, avoid round change even if it takes more inputs
+    MaxPrivacy,
+    /// Same, but labels are mixed if there's no other way to pay
+    #[default]
+    Balanced,
+    /// Fewest inputs, largest outputs first
+    MinFee,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoinSelection {
+    /// To give to `create_new_psbt`
+    pub utxos: HashMap<OutPoint, OwnedOutput>,
+    pub estimated_fee: Amount,
+    /// Zero if there's no change
+    pub change: Amount,
+    pub mixed_labels: bool,
+}
+
+/// Outputs received in the same transaction with the same label
+struct Group {
+    label: Option<String>,
+    outputs: Vec<(OutPoint, OwnedOutput)>,
+    amount: Amount,
+}
+
+fn get_groups(available: HashMap<OutPoint, OwnedOutput>) -> Vec<Group> {
+    let mut groups: HashMap<(Option<String>, bitcoin::Txid), Group> = HashMap::new();
+    for (outpoint, output) in available {
+        let group = groups
+            .entry((output.label.clone(), outpoint.txid))
+            .or_insert_with(|| Group {
+                label: output.label.clone(),
+                outputs: vec![],
+                amount: Amount::ZERO,
+            });
+        group.amount += output.amount;
+        group.outputs.push((outpoint, output));
+    }
+    groups.into_values().collect()
+}
+
+pub(crate) fn estimate_fee(nb_inputs: usize, nb_outputs: usize, fee_rate: Amount) -> Amount {
+    fee_rate
+        * (TX_OVERHEAD_VSIZE
+            + nb_inputs as u64 * TAPROOT_INPUT_VSIZE
+            + nb_outputs as u64 * TAPROOT_OUTPUT_VSIZE)
+}
+
+fn is_round(amount: Amount) -> bool {
+    amount > Amount::ZERO && amount.to_sat().is_multiple_of(ROUND_AMOUNT)
+}
+
+/// Adds groups, largest first, until `target` and the fee are covered
+/// If the change is round and `avoid_round_change`, one more group is added when there's one left
+fn accumulate(
+    mut groups: Vec<&Group>,
+    target: Amount,
+    nb_outputs: usize,
+    fee_rate: Amount,
+    avoid_round_change: bool,
+) -> Option<CoinSelection> {
+    groups.sort_by_key(|g| std::cmp::Reverse(g.amount));
+
+    let mut selected: Vec<&Group> = vec![];
+    let mut remaining = groups.into_iter();
+    let covers = |selected: &[&Group]| {
+        let nb_inputs = selected.iter().map(|g| g.outputs.len()).sum();
+        let total: Amount = selected.iter().map(|g| g.amount).sum();
+        // change output included, it's dropped later if there's none
+        let fee = estimate_fee(nb_inputs, nb_outputs + 1, fee_rate);
+        total.checked_sub(target + fee).map(|change| (fee, change))
+    };
+
+    let (mut fee, mut change) = loop {
+        if let Some(res) = covers(&selected) {
+            break res;
+        }
+        selected.push(remaining.next()?);
+    };
+
+    if avoid_round_change && is_round(change) {
+        // the smallest group left changes the change the least
+        if let Some(group) = remaining.last() {
+            selected.push(group);
+            (fee, change) = covers(&selected)?;
+        }
+    }
+
+    let mixed_labels = selected.windows(2).any(|w| w[0].label != w[1].label);
+    Some(CoinSelection {
+        utxos: selected
+            .iter()
+            .flat_map(|g| g.outputs.iter().cloned())
+            .collect(),
+        estimated_fee: fee,
+        change,
+        mixed_labels,
+    })
+}
+
+/// Choose among `available` what to spend to pay `target` to `nb_outputs` recipients at `fee_rate`
+pub fn select_coins(
+    available: HashMap<OutPoint, OwnedOutput>,
+    target: Amount,
+    nb_outputs: usize,
+    fee_rate: Amount,
+    preference: SelectionPreference,
+) -> Result<CoinSelection> {
+    let not_enough = || Error::msg(format!("Not enough funds to pay {}", target));
+
+    if preference == SelectionPreference::MinFee {
+        // each output on its own
+        let groups: Vec<Group> = available
+            .into_iter()
+            .map(|(outpoint, output)| Group {
+                label: output.label.clone(),
+                amount: output.amount,
+                outputs: vec![(outpoint, output)],
+            })
+            .collect();
+        return accumulate(groups.iter().collect(), target, nb_outputs, fee_rate, false)
+            .ok_or_else(not_enough);
+    }
+
+    let groups = get_groups(available);
+
+    let mut labels: Vec<&Option<String>> = groups.iter().map(|g| &g.label).collect();
+    labels.sort();
+    labels.dedup();
+
+    // best single label selection: non round change first, then the fewest inputs
+    let best = labels
+        .into_iter()
+        .filter_map(|label| {
+            let same_label = groups.iter().filter(|g| g.label == *label).collect();
+            accumulate(same_label, target, nb_outputs, fee_rate, true)
+        })
+        .min_by_key(|s| (is_round(s.change), s.utxos.len()));
+
+    match (best, preference) {
+        (Some(selection), _) => Ok(selection),
+        (None, SelectionPreference::MaxPrivacy) => Err(Error::msg(format!(
+            "Can't pay {} without mixing labels",
+            target
+        ))),
+        (None, _) => accumulate(groups.iter().collect(), target, nb_outputs, fee_rate, true)
+            .ok_or_else(not_enough),
+    }
+}
+
+impl SpWallet {
+    /// Selection among our spendable outputs, quarantined ones are never selected
+    pub fn select_coins(
+        &self,
+        target: Amount,
+        nb_outputs: usize,
+        fee_rate: Amount,
+        preference: SelectionPreference,
+    ) -> Result<CoinSelection> {
+        select_coins(
+            self.get_outputs().to_spendable_list(),
+            target,
+            nb_outputs,
+            fee_rate,
+            preference,
+        )
+    }
+}
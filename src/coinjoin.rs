@@ -0,0 +1,446 @@
+//! Participation in coinjoin rounds run by a coordinator
+//!
+//! The transport to the coordinator is left to the app, implementing `CoinjoinCoordinator`.
+//! We register our inputs along with ownership proofs, register our own outputs,
+//! and only sign once we checked that the final transaction pays us what we expect.
+//!
+//! Our outputs are silent payments to our change address, computed as if our inputs were the only
+//! ones of the transaction, since the keys of the other inputs aren't known when registering them.
+//! The regular scan of the final transaction, which has everyone's inputs, won't find them, but
+//! `SpWallet::update_wallet_with_transaction` derives them again from the inputs we spent, so that
+//! a wallet restored from its seed finds them too. Watch-only wallets can't, the app adds them with
+//! `get_owned_outputs`.
+
+use std::{collections::HashMap, str::FromStr};
+
+use bitcoin::{
+    hashes::Hash,
+    hex::DisplayHex,
+    key::TweakedPublicKey,
+    secp256k1::{Message, PublicKey, Secp256k1, SecretKey},
+    sighash::{Prevouts, SighashCache},
+    Amount, OutPoint, ScriptBuf, TapSighashType, Transaction, TxOut, Witness, XOnlyPublicKey,
+};
+use serde::{Deserialize, Serialize};
+use silentpayments::receiving::Label;
+use silentpayments::utils as sp_utils;
+
+use anyhow::{Error, Result};
+
+use crate::signer::{LocalSigner, Signer};
+use crate::spclient::{OutputSpendStatus, OwnedOutput, SpClient};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CoinjoinRound {
+    pub round_id: String,
+    /// What the ownership proofs must commit to
+    pub commitment_data: Vec<u8>,
+    pub denomination: Amount,
+}
+
+/// Interface to a coordinator, implemented by the app
+pub trait CoinjoinCoordinator {
+    fn register_input(
+        &self,
+        round_id: &str,
+        outpoint: &OutPoint,
+        txout: &TxOut,
+        ownership_proof: &[u8],
+    ) -> Result<()>;
+
+    fn register_output(&self, round_id: &str, txout: &TxOut) -> Result<()>;
+
+    /// The final unsigned transaction, with the outputs spent by each of its inputs
+    fn get_unsigned_transaction(&self, round_id: &str) -> Result<(Transaction, Vec<TxOut>)>;
+
+    /// Proof registered with an input of someone else, checked before we sign
+    fn get_ownership_proof(&self, round_id: &str, outpoint: &OutPoint) -> Result<Vec<u8>>;
+
+    fn submit_witnesses(&self, round_id: &str, witnesses: Vec<(OutPoint, Witness)>) -> Result<()>;
+}
+
+/// Our side of a round we registered to
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CoinjoinParticipation {
+    pub round: CoinjoinRound,
+    pub inputs: HashMap<OutPoint, OwnedOutput>,
+    /// Our outputs, with the tweak to spend them later
+    pub outputs: Vec<(TxOut, String)>,
+    /// Our change label, that the outputs pay
+    #[serde(default)]
+    pub label: String,
+}
+
+impl CoinjoinParticipation {
+    pub fn get_input_amount(&self) -> Amount {
+        self.inputs.values().map(|o| o.amount).sum()
+    }
+
+    pub fn get_output_amount(&self) -> Amount {
+        self.outputs.iter().map(|(txout, _)| txout.value).sum()
+    }
+
+    /// Our outputs in the final transaction, for watch-only wallets whose scan can't find them
+    pub fn get_owned_outputs(
+        &self,
+        tx: &Transaction,
+        blockheight: u32,
+    ) -> HashMap<OutPoint, OwnedOutput> {
+        let txid = tx.txid();
+        let mut res = HashMap::new();
+        for (vout, txout) in tx.output.iter().enumerate() {
+            if let Some((_, tweak)) = self.outputs.iter().find(|(ours, _)| ours == txout) {
+                res.insert(
+                    OutPoint::new(txid, vout as u32),
+                    OwnedOutput {
+                        blockheight,
+                        tweak: tweak.clone(),
+                        amount: txout.value,
+                        script: txout.script_pubkey.to_hex_string(),
+                        label: Some(self.label.clone()),
+                        spend_status: OutputSpendStatus::Unspent,
+                        quarantined: false,
+                        blockhash: None,
+                        block_time: None,
+                    },
+                );
+            }
+        }
+        res
+    }
+}
+
+impl SpClient {
+    /// Tweak data of a transaction whose only inputs would be `inputs`, what our coinjoin outputs are derived from
+    pub(crate) fn get_coinjoin_tweak_data(
+        &self,
+        inputs: &HashMap<OutPoint, OwnedOutput>,
+    ) -> Result<PublicKey> {
+        Ok(self
+            .get_coinjoin_partial_secret(inputs)?
+            .public_key(&Secp256k1::signing_only()))
+    }
+
+    fn get_coinjoin_partial_secret(
+        &self,
+        inputs: &HashMap<OutPoint, OwnedOutput>,
+    ) -> Result<SecretKey> {
+        let mut spend_sk = self.try_get_secret_spend_key()?;
+        let mut input_privkeys = vec![];
+        let mut outpoints = vec![];
+        for (outpoint, output) in inputs.iter() {
+            let tweak = SecretKey::from_str(&output.tweak)?;
+            input_privkeys.push((spend_sk.add_tweak(&tweak.into())?, true));
+            outpoints.push((outpoint.txid.to_string(), outpoint.vout));
+        }
+        let partial_secret =
+            sp_utils::sending::calculate_partial_secret(&input_privkeys, &outpoints);
+        spend_sk.non_secure_erase();
+        for (key, _) in input_privkeys.iter_mut() {
+            key.non_secure_erase();
+        }
+        Ok(partial_secret?)
+    }
+
+    /// Silent payments to our change address with `amounts`, from `inputs` alone
+    fn derive_coinjoin_outputs(
+        &self,
+        inputs: &HashMap<OutPoint, OwnedOutput>,
+        amounts: &[Amount],
+    ) -> Result<Vec<(TxOut, String)>> {
+        let partial_secret = self.get_coinjoin_partial_secret(inputs)?;
+
+        let change_address = self.sp_receiver.get_change_address();
+        let output_keys = silentpayments::sending::generate_recipient_pubkeys(
+            vec![change_address.clone(); amounts.len()],
+            partial_secret,
+        )?
+        .remove(&change_address)
+        .ok_or_else(|| Error::msg("No output key for our change address"))?;
+
+        // the tweaks to spend them, as scanning would find them
+        let secp = Secp256k1::signing_only();
+        let shared_secret = sp_utils::receiving::calculate_ecdh_shared_secret(
+            &partial_secret.public_key(&secp),
+            &self.get_scan_key(),
+        );
+        let tweaks: HashMap<XOnlyPublicKey, String> = self
+            .sp_receiver
+            .scan_transaction(&shared_secret, output_keys.clone())?
+            .into_values()
+            .flatten()
+            .map(|(key, scalar)| (key, scalar.to_be_bytes().to_lower_hex_string()))
+            .collect();
+
+        output_keys
+            .into_iter()
+            .zip(amounts)
+            .map(|(key, amount)| {
+                let tweak = tweaks
+                    .get(&key)
+                    .ok_or_else(|| Error::msg("Failed to find our own coinjoin output"))?;
+                Ok((
+                    TxOut {
+                        value: *amount,
+                        script_pubkey: ScriptBuf::new_p2tr_tweaked(
+                            TweakedPublicKey::dangerous_assume_tweaked(key),
+                        ),
+                    },
+                    tweak.clone(),
+                ))
+            })
+            .collect()
+    }
+
+    /// Register `inputs` to the round, and outputs of the round denomination paying to us
+    /// Whatever is left after `max_fee` and the denominated outputs goes to a change output
+    pub fn join_coinjoin_round(
+        &self,
+        coordinator: &dyn CoinjoinCoordinator,
+        round: CoinjoinRound,
+        inputs: HashMap<OutPoint, OwnedOutput>,
+        max_fee: Amount,
+        aux_rand: &[u8; 32],
+    ) -> Result<CoinjoinParticipation> {
+        if round.denomination == Amount::ZERO {
+            return Err(Error::msg("Invalid denomination"));
+        }
+
+        let mut participation = CoinjoinParticipation {
+            round,
+            inputs,
+            outputs: vec![],
+            label: Label::new(self.get_scan_key(), 0).as_string(),
+        };
+
+        let available = participation
+            .get_input_amount()
+            .checked_sub(max_fee)
+            .ok_or_else(|| Error::msg("Inputs don't cover the fee"))?;
+        let denomination = participation.round.denomination;
+        let count = available.to_sat() / denomination.to_sat();
+        if count == 0 {
+            return Err(Error::msg("Inputs don't cover the denomination"));
+        }
+        let change = available - denomination * count;
+
+        let mut amounts = vec![denomination; count as usize];
+        if change > Amount::ZERO {
+            amounts.push(change);
+        }
+        participation.outputs = self.derive_coinjoin_outputs(&participation.inputs, &amounts)?;
+
+        for (outpoint, output) in participation.inputs.iter() {
+            let txout = TxOut {
+                value: output.amount,
+                script_pubkey: ScriptBuf::from_hex(&output.script)?,
+            };
+            let proof =
+                self.prove_ownership(output, &participation.round.commitment_data, aux_rand)?;
+            coordinator.register_input(&participation.round.round_id, outpoint, &txout, &proof)?;
+        }
+
+        for (txout, _) in participation.outputs.iter() {
+            coordinator.register_output(&participation.round.round_id, txout)?;
+        }
+
+        Ok(participation)
+    }
+
+    /// Check that the final transaction spends our inputs and pays all our outputs, then sign
+    pub fn sign_coinjoin_round(
+        &self,
+        coordinator: &dyn CoinjoinCoordinator,
+        participation: &CoinjoinParticipation,
+        aux_rand: &[u8; 32],
+    ) -> Result<Transaction> {
+        let round_id = &participation.round.round_id;
+        let (tx, prevouts) = coordinator.get_unsigned_transaction(round_id)?;
+
+        Self::check_coinjoin_transaction(participation, &tx, &prevouts)?;
+
+        // an input of ours under someone else's name would get us to sign for it unknowingly
+        for (txin, prevout) in tx.input.iter().zip(prevouts.iter()) {
+            if participation.inputs.contains_key(&txin.previous_output) {
+                continue;
+            }
+            let proof = coordinator.get_ownership_proof(round_id, &txin.previous_output)?;
+            self.verify_foreign_ownership(
+                &proof,
+                &prevout.script_pubkey,
+                &participation.round.commitment_data,
+            )
+            .map_err(|e| {
+                Error::msg(format!(
+                    "Invalid ownership proof for {}: {}",
+                    txin.previous_output, e
+                ))
+            })?;
+        }
+
+        let signer = LocalSigner::new(self.try_get_secret_spend_key()?);
+        let secp = Secp256k1::verification_only();
+        let mut cache = SighashCache::new(&tx);
+
+        let mut witnesses = vec![];
+        for (i, txin) in tx.input.iter().enumerate() {
+            let output = match participation.inputs.get(&txin.previous_output) {
+                Some(output) => output,
+                None => continue,
+            };
+
+            let sighash = cache.taproot_key_spend_signature_hash(
+                i,
+                &Prevouts::All(&prevouts),
+                TapSighashType::Default,
+            )?;
+            let msg = Message::from_digest(sighash.to_byte_array());
+
+            let tweak = SecretKey::from_str(&output.tweak)?;
+            let sig = signer.sign_tweaked(&msg, &tweak, aux_rand)?;
+
+            let output_key =
+                XOnlyPublicKey::from_slice(&prevouts[i].script_pubkey.as_bytes()[2..])?;
+            secp.verify_schnorr(&sig, &msg, &output_key)
+                .map_err(|_| Error::msg(format!("Invalid signature for input {}", i)))?;
+
+            let mut witness = Witness::new();
+            witness.push(&sig[..]);
+            witnesses.push((txin.previous_output, witness));
+        }
+
+        coordinator.submit_witnesses(round_id, witnesses)?;
+
+        Ok(tx)
+    }
+
+    fn check_coinjoin_transaction(
+        participation: &CoinjoinParticipation,
+        tx: &Transaction,
+        prevouts: &[TxOut],
+    ) -> Result<()> {
+        if prevouts.len() != tx.input.len() {
+            return Err(Error::msg("Missing prevouts"));
+        }
+
+        let mut our_inputs = 0;
+        for (txin, prevout) in tx.input.iter().zip(prevouts) {
+            if let Some(output) = participation.inputs.get(&txin.previous_output) {
+                if prevout.value != output.amount
+                    || prevout.script_pubkey != ScriptBuf::from_hex(&output.script)?
+                {
+                    return Err(Error::msg(format!(
+                        "Wrong prevout for {}",
+                        txin.previous_output
+                    )));
+                }
+                our_inputs += 1;
+            }
+        }
+        if our_inputs != participation.inputs.len() {
+            return Err(Error::msg("Some of our inputs are missing"));
+        }
+
+        // each of our outputs must appear once, with the exact amount and script
+        for (txout, _) in participation.outputs.iter() {
+            if tx.output.iter().filter(|o| *o == txout).count() != 1 {
+                return Err(Error::msg(format!(
+                    "Missing output {}",
+                    txout.script_pubkey.to_hex_string()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::{absolute::LockTime, transaction::Version, TxIn};
+
+    use crate::spclient::SpWallet;
+    use crate::test_utils::{other_client, owned_output, test_client};
+
+    #[test]
+    fn outputs_pay_our_change_address() {
+        let client = test_client();
+        let secp = Secp256k1::new();
+        let spend_pk: PublicKey = client.get_spend_key().into();
+        let inputs = HashMap::from([
+            owned_output(&client, 1, Amount::from_sat(100_000)),
+            owned_output(&client, 2, Amount::from_sat(100_000)),
+        ]);
+
+        let amounts = [
+            Amount::from_sat(50_000),
+            Amount::from_sat(50_000),
+            Amount::from_sat(90_000),
+        ];
+        let outputs = client.derive_coinjoin_outputs(&inputs, &amounts).unwrap();
+        assert_eq!(outputs.len(), 3);
+        for ((txout, tweak), amount) in outputs.iter().zip(amounts) {
+            assert_eq!(txout.value, amount);
+            let tweak = SecretKey::from_str(tweak).unwrap();
+            let key = spend_pk.add_exp_tweak(&secp, &tweak.into()).unwrap();
+            assert_eq!(
+                txout.script_pubkey,
+                ScriptBuf::new_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(
+                    key.x_only_public_key().0
+                ))
+            );
+        }
+        // same amounts, still different outputs
+        assert_ne!(outputs[0].0, outputs[1].0);
+    }
+
+    #[test]
+    fn scan_finds_our_outputs() {
+        let client = test_client();
+        let inputs = HashMap::from([
+            owned_output(&client, 1, Amount::from_sat(100_000)),
+            owned_output(&client, 2, Amount::from_sat(100_000)),
+        ]);
+        let amounts = [Amount::from_sat(50_000), Amount::from_sat(140_000)];
+        let ours = client.derive_coinjoin_outputs(&inputs, &amounts).unwrap();
+
+        // someone else's input and output around ours
+        let (foreign_outpoint, foreign) =
+            owned_output(&other_client(), 3, Amount::from_sat(60_000));
+        let txin = |previous_output| TxIn {
+            previous_output,
+            ..Default::default()
+        };
+        let mut input = vec![txin(foreign_outpoint)];
+        input.extend(inputs.keys().copied().map(txin));
+        let mut output = vec![TxOut {
+            value: Amount::from_sat(50_000),
+            script_pubkey: ScriptBuf::from_hex(&foreign.script).unwrap(),
+        }];
+        output.extend(ours.iter().map(|(txout, _)| txout.clone()));
+        let tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input,
+            output,
+        };
+
+        // e.g. restored from the seed, having found the inputs but knowing nothing of the round
+        let mut wallet = SpWallet::new(client.clone(), None).unwrap();
+        wallet.get_mut_outputs().extend_from(inputs);
+        let tweak_data = SecretKey::from_slice(&[0x55; 32])
+            .unwrap()
+            .public_key(&Secp256k1::signing_only());
+        let found = wallet
+            .update_wallet_with_transaction(&tx, 200, tweak_data)
+            .unwrap();
+
+        for (vout, (txout, tweak)) in (1..).zip(ours.iter()) {
+            let output = &found[&OutPoint::new(tx.txid(), vout)];
+            assert_eq!(output.amount, txout.value);
+            assert_eq!(&output.tweak, tweak);
+        }
+    }
+}
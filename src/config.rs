@@ -0,0 +1,102 @@
+//! Where the backend keeps its files, persisted so that every part of the app agrees on it
+
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Error, Result};
+
+pub const CONFIG_FILE: &str = "config.json";
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BackendConfig {
+    /// Headers, filters and blocks, the biggest by far
+    pub chain_data_dir: PathBuf,
+    pub wallets_dir: PathBuf,
+    pub logs_dir: PathBuf,
+}
+
+impl BackendConfig {
+    /// Everything in `base_dir`, e.g. the app files dir
+    pub fn new(base_dir: &Path) -> Self {
+        Self {
+            chain_data_dir: base_dir.join("chain"),
+            wallets_dir: base_dir.join("wallets"),
+            logs_dir: base_dir.join("logs"),
+        }
+    }
+
+    /// The config in `dir`, or the default one for `dir` if there's none yet
+    pub fn load_or_default(dir: &Path) -> Result<Self> {
+        let path = dir.join(CONFIG_FILE);
+        if path.exists() {
+            Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+        } else {
+            Ok(Self::new(dir))
+        }
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(CONFIG_FILE);
+        let tmp_path = dir.join(format!("{}.tmp", CONFIG_FILE));
+
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+
+        Ok(())
+    }
+
+    pub fn create_dirs(&self) -> Result<()> {
+        fs::create_dir_all(&self.chain_data_dir)?;
+        fs::create_dir_all(&self.wallets_dir)?;
+        fs::create_dir_all(&self.logs_dir)?;
+        Ok(())
+    }
+
+    /// Move the chain data to `new_dir`, e.g. to external storage on Android, and save the config in `config_dir`
+    /// The node must be stopped. If the move fails half way, the old directory is left untouched.
+    pub fn relocate_chain_data(&mut self, new_dir: &Path, config_dir: &Path) -> Result<()> {
+        if new_dir == self.chain_data_dir {
+            return Ok(());
+        }
+        if new_dir.exists() && fs::read_dir(new_dir)?.next().is_some() {
+            return Err(Error::msg(format!("{} is not empty", new_dir.display())));
+        }
+
+        if self.chain_data_dir.exists() {
+            // rename fails across filesystems, which is the usual case for external storage
+            if fs::rename(&self.chain_data_dir, new_dir).is_err() {
+                if let Err(e) = copy_dir(&self.chain_data_dir, new_dir) {
+                    let _ = fs::remove_dir_all(new_dir);
+                    return Err(e);
+                }
+                fs::remove_dir_all(&self.chain_data_dir)?;
+            }
+        } else {
+            fs::create_dir_all(new_dir)?;
+        }
+
+        self.chain_data_dir = new_dir.to_owned();
+        self.save(config_dir)
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
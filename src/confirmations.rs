@@ -0,0 +1,231 @@
+//! Follows the transactions we broadcast until they're mined, without waiting for a block scan
+//!
+//! We don't do any networking here, the app calls `BroadcastTracker::update` with its server,
+//! e.g. `MempoolSpaceClient` for esplora, on a timer or when an electrum subscription to the
+//! script of our change tells it something changed.
+
+use std::collections::HashMap;
+
+use bitcoin::{
+    secp256k1::PublicKey, Amount, BlockHash, OutPoint, ScriptBuf, Transaction, TxOut, Txid,
+};
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Error, Result};
+
+use crate::chain::{ConfirmationSource, ConfirmationStatus};
+use crate::spclient::{
+    get_tweak_data, Balance, OutputSpendStatus, OwnedOutput, SpWallet, UNCONFIRMED_HEIGHT,
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PendingTx {
+    pub tx: Transaction,
+    /// None without any input eligible for silent payments, there's no change to find then
+    tweak_data: Option<PublicKey>,
+}
+
+/// Our transactions in the mempool, persist it with the wallet
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BroadcastTracker {
+    pending: HashMap<Txid, PendingTx>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ConfirmationReport {
+    /// With their height, record them again in `TxHistory`
+    pub confirmed: Vec<(Txid, u32)>,
+    /// Spent or created by those, to persist
+    pub outputs: HashMap<OutPoint, OwnedOutput>,
+    /// The server doesn't know them anymore, e.g. evicted, broadcast them again or `untrack` them
+    pub unknown: Vec<Txid>,
+}
+
+impl BroadcastTracker {
+    /// After broadcasting `tx`, all its inputs must be ours
+    pub fn track(&mut self, wallet: &SpWallet, tx: Transaction) -> Result<Txid> {
+        let outputs = wallet.get_outputs();
+        let prevouts = tx
+            .input
+            .iter()
+            .map(|input| {
+                let (_, output) = outputs.get_outpoint(input.previous_output).map_err(|_| {
+                    Error::msg(format!(
+                        "Input {} isn't ours, use track_with_prevouts",
+                        input.previous_output
+                    ))
+                })?;
+                Ok(TxOut {
+                    value: output.amount,
+                    script_pubkey: ScriptBuf::from_hex(&output.script)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.track_with_prevouts(tx, &prevouts)
+    }
+
+    /// Same as `track` with the outputs spent by each input, in order, e.g. for a collaborative transaction
+    pub fn track_with_prevouts(&mut self, tx: Transaction, prevouts: &[TxOut]) -> Result<Txid> {
+        let tweak_data = get_tweak_data(&tx, prevouts)?;
+        let txid = tx.txid();
+        self.pending.insert(txid, PendingTx { tx, tweak_data });
+        Ok(txid)
+    }
+
+    /// Stop following a transaction, e.g. replaced or abandoned
+    pub fn untrack(&mut self, txid: &Txid) -> Option<PendingTx> {
+        self.pending.remove(txid)
+    }
+
+    pub fn list_pending(&self) -> Vec<Txid> {
+        let mut txids: Vec<Txid> = self.pending.keys().copied().collect();
+        txids.sort();
+        txids
+    }
+
+    /// Balance of `wallet` with our transactions that are still pending:
+    /// their change is incoming, and what they spend minus that change is outgoing
+    pub fn get_balance(&self, wallet: &SpWallet) -> Result<Balance> {
+        let outputs = wallet.get_outputs().to_outpoints_list();
+
+        let mut balance = Balance::default();
+        let mut change: HashMap<OutPoint, Amount> = HashMap::new();
+        for (txid, pending) in self.pending.iter() {
+            let spent: Amount = pending
+                .tx
+                .input
+                .iter()
+                .filter_map(|input| outputs.get(&input.previous_output))
+                .map(|o| o.amount)
+                .sum();
+            // the change may already be in the wallet if the transaction was added after broadcasting
+            let mut returned: HashMap<OutPoint, Amount> = match pending.tweak_data {
+                Some(tweak_data) => wallet
+                    .find_tx_outputs(&pending.tx, UNCONFIRMED_HEIGHT, tweak_data)?
+                    .into_iter()
+                    .map(|(outpoint, o)| (outpoint, o.amount))
+                    .collect(),
+                None => HashMap::new(),
+            };
+            for (vout, txout) in (0u32..).zip(pending.tx.output.iter()) {
+                let outpoint = OutPoint::new(*txid, vout);
+                if outputs.contains_key(&outpoint) {
+                    returned.insert(outpoint, txout.value);
+                }
+            }
+            let returned_amt: Amount = returned.values().copied().sum();
+            balance.pending_outgoing += spent.checked_sub(returned_amt).unwrap_or(Amount::ZERO);
+            change.extend(returned);
+        }
+
+        // change spent by another pending transaction is already in what that one spends
+        change.retain(|outpoint, _| {
+            outputs
+                .get(outpoint)
+                .is_none_or(|o| o.spend_status == OutputSpendStatus::Unspent)
+        });
+        balance.pending_incoming += change.values().copied().sum();
+
+        for (outpoint, output) in outputs.iter() {
+            if output.spend_status != OutputSpendStatus::Unspent || change.contains_key(outpoint) {
+                continue;
+            }
+            if output.blockheight == UNCONFIRMED_HEIGHT {
+                balance.pending_incoming += output.amount;
+            } else {
+                balance.confirmed += output.amount;
+            }
+        }
+        Ok(balance)
+    }
+
+    /// Asks `source` about every pending transaction, and applies those that got mined to `wallet`
+    pub fn update(
+        &mut self,
+        wallet: &mut SpWallet,
+        source: &impl ConfirmationSource,
+    ) -> Result<ConfirmationReport> {
+        // all the statuses first, so that a failing request leaves everything as it was
+        let statuses = self
+            .list_pending()
+            .into_iter()
+            .map(|txid| Ok((txid, source.get_confirmation_status(&txid)?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut report = ConfirmationReport::default();
+        for (txid, status) in statuses {
+            match status {
+                ConfirmationStatus::Confirmed {
+                    blockheight,
+                    blockhash,
+                    block_time,
+                } => {
+                    let Some(pending) = self.pending.get(&txid) else {
+                        continue;
+                    };
+                    let updated = wallet.apply_confirmation(
+                        &pending.tx,
+                        pending.tweak_data,
+                        blockheight,
+                        blockhash,
+                        block_time,
+                    )?;
+                    self.pending.remove(&txid);
+                    report.outputs.extend(updated);
+                    report.confirmed.push((txid, blockheight));
+                }
+                ConfirmationStatus::InMempool => (),
+                ConfirmationStatus::Unknown => report.unknown.push(txid),
+            }
+        }
+        Ok(report)
+    }
+}
+
+impl SpWallet {
+    /// `tx` of ours got mined: the outputs it spends become mined, and its outputs of ours,
+    /// e.g. the change, are confirmed or added if we didn't have them yet
+    /// Returns the outputs that changed
+    pub fn apply_confirmation(
+        &mut self,
+        tx: &Transaction,
+        tweak_data: Option<PublicKey>,
+        blockheight: u32,
+        blockhash: BlockHash,
+        block_time: u32,
+    ) -> Result<HashMap<OutPoint, OwnedOutput>> {
+        let txid = tx.txid();
+        let mut found = match tweak_data {
+            Some(tweak_data) => self.find_tx_outputs(tx, blockheight, tweak_data)?,
+            None => HashMap::new(),
+        };
+
+        let outputs = self.get_mut_outputs();
+        let mut res = HashMap::new();
+        for vout in 0..tx.output.len() as u32 {
+            let outpoint = OutPoint::new(txid, vout);
+            // known if the transaction was added to the wallet after broadcasting
+            let mut output = match outputs.get_outpoint(outpoint) {
+                Ok((_, known)) => known,
+                Err(_) => match found.remove(&outpoint) {
+                    Some(new) => new,
+                    None => continue,
+                },
+            };
+            output.blockheight = blockheight;
+            output.blockhash = Some(blockhash);
+            output.block_time = Some(block_time);
+            res.insert(outpoint, output);
+        }
+        outputs.extend_from(res.clone());
+
+        for input in tx.input.iter() {
+            if outputs.get_outpoint(input.previous_output).is_ok() {
+                outputs.mark_mined(input.previous_output, blockhash)?;
+                let (outpoint, output) = outputs.get_outpoint(input.previous_output)?;
+                res.insert(outpoint, output);
+            }
+        }
+        Ok(res)
+    }
+}
@@ -0,0 +1,380 @@
+//! BIP352 conformance runner
+//!
+//! Pushes the official send and receive test vectors (`bip-0352/send_and_receive_test_vectors.json`)
+//! through our own psbt and scanning code, not only through rust-silentpayments.
+//! Only available with the `conformance` feature.
+
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
+
+use bitcoin::{
+    consensus::deserialize,
+    hex::{DisplayHex, FromHex},
+    key::TapTweak,
+    secp256k1::{PublicKey, SecretKey},
+    Amount, Network, OutPoint, ScriptBuf, Transaction, TxIn, TxOut, Txid, Witness, XOnlyPublicKey,
+};
+use serde::Deserialize;
+
+use silentpayments::receiving::Label;
+use silentpayments::utils as sp_utils;
+
+use anyhow::{Error, Result};
+
+use crate::spclient::{
+    get_tweak_data, OutputSpendStatus, OwnedOutput, Recipient, SpClient, SpWallet, SpendKey,
+};
+
+const AMOUNT_PER_RECIPIENT: Amount = Amount::from_sat(1000);
+
+#[derive(Debug, Deserialize)]
+struct TestCase {
+    comment: String,
+    sending: Vec<SendingTest>,
+    receiving: Vec<ReceivingTest>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScriptPubKey {
+    #[serde(rename = "scriptPubKey")]
+    script_pubkey: Hex,
+}
+
+#[derive(Debug, Deserialize)]
+struct Hex {
+    hex: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Vin {
+    txid: String,
+    vout: u32,
+    #[serde(rename = "scriptSig")]
+    script_sig: String,
+    txinwitness: String,
+    prevout: ScriptPubKey,
+    private_key: Option<String>,
+}
+
+/// Recipients are plain addresses in older versions of the vectors
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum VectorRecipient {
+    Address(String),
+    Detailed { address: String },
+}
+
+#[derive(Debug, Deserialize)]
+struct SendingGiven {
+    vin: Vec<Vin>,
+    recipients: Vec<VectorRecipient>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SendingExpected {
+    /// Any of these sets is valid, the order of outputs for the same scan key depends on the inputs order
+    outputs: Vec<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SendingTest {
+    given: SendingGiven,
+    expected: SendingExpected,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyMaterial {
+    scan_priv_key: String,
+    spend_priv_key: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReceivingGiven {
+    vin: Vec<Vin>,
+    key_material: KeyMaterial,
+    labels: Vec<u32>,
+    outputs: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExpectedOutput {
+    pub_key: String,
+    priv_key_tweak: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReceivingExpected {
+    addresses: Vec<String>,
+    outputs: Vec<ExpectedOutput>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReceivingTest {
+    given: ReceivingGiven,
+    expected: ReceivingExpected,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ConformanceReport {
+    pub passed: usize,
+    /// Vectors our code can't express, e.g. spending non taproot inputs
+    pub skipped: Vec<String>,
+    pub failures: Vec<String>,
+}
+
+impl ConformanceReport {
+    pub fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    fn record(&mut self, comment: &str, side: &str, res: Result<bool>) {
+        match res {
+            Ok(true) => self.passed += 1,
+            Ok(false) => self.skipped.push(format!("{} ({})", comment, side)),
+            Err(e) => self.failures.push(format!("{} ({}): {}", comment, side, e)),
+        }
+    }
+}
+
+fn parse_witness(hex: &str) -> Result<Witness> {
+    if hex.is_empty() {
+        return Ok(Witness::new());
+    }
+    Ok(deserialize::<Witness>(&Vec::<u8>::from_hex(hex)?)?)
+}
+
+fn parse_input(vin: &Vin) -> Result<(TxIn, TxOut)> {
+    let txin = TxIn {
+        previous_output: OutPoint::new(Txid::from_str(&vin.txid)?, vin.vout),
+        script_sig: ScriptBuf::from_hex(&vin.script_sig)?,
+        sequence: bitcoin::Sequence::MAX,
+        witness: parse_witness(&vin.txinwitness)?,
+    };
+    let prevout = TxOut {
+        value: Amount::ZERO,
+        script_pubkey: ScriptBuf::from_hex(&vin.prevout.script_pubkey.hex)?,
+    };
+    Ok((txin, prevout))
+}
+
+fn is_eligible(txin: &TxIn, prevout: &TxOut) -> Result<bool> {
+    Ok(sp_utils::receiving::get_pubkey_from_input(
+        txin.script_sig.as_bytes(),
+        &txin.witness.to_vec(),
+        prevout.script_pubkey.as_bytes(),
+    )?
+    .is_some())
+}
+
+/// Our psbts can only spend silent payments outputs, that is taproot keys we get from
+/// our spend key and a tweak. We pick an arbitrary spend key and express each input key as a tweak of it.
+/// Returns false if the vector has inputs we can't spend
+fn run_sending_test(test: &SendingTest) -> Result<bool> {
+    let spend_sk = SecretKey::from_slice(&[0x52; 32])?;
+    let client = SpClient::new(
+        "conformance".to_owned(),
+        SecretKey::from_slice(&[0x35; 32])?,
+        SpendKey::Secret(spend_sk),
+        None,
+        Network::Bitcoin,
+    )?;
+
+    let recipients: Vec<String> = test
+        .given
+        .recipients
+        .iter()
+        .map(|r| match r {
+            VectorRecipient::Address(address) => address.clone(),
+            VectorRecipient::Detailed { address } => address.clone(),
+        })
+        .collect();
+
+    let mut utxos: HashMap<OutPoint, OwnedOutput> = HashMap::new();
+    for (i, vin) in test.given.vin.iter().enumerate() {
+        let (txin, prevout) = parse_input(vin)?;
+        if !prevout.script_pubkey.is_p2tr() || !is_eligible(&txin, &prevout)? {
+            return Ok(false);
+        }
+
+        let input_sk = SecretKey::from_str(
+            vin.private_key
+                .as_ref()
+                .ok_or_else(|| Error::msg("Missing input private key"))?,
+        )?;
+        // spend_sk + tweak = input_sk
+        let tweak = input_sk.add_tweak(&spend_sk.negate().into())?;
+
+        // all the value on the first input so that there's no change output
+        let amount = if i == 0 {
+            AMOUNT_PER_RECIPIENT * recipients.len() as u64
+        } else {
+            Amount::ZERO
+        };
+
+        utxos.insert(
+            txin.previous_output,
+            OwnedOutput {
+                blockheight: 0,
+                tweak: tweak.secret_bytes().to_lower_hex_string(),
+                amount,
+                script: prevout.script_pubkey.to_hex_string(),
+                label: None,
+                spend_status: OutputSpendStatus::Unspent,
+                quarantined: false,
+                blockhash: None,
+                block_time: None,
+            },
+        );
+    }
+
+    if recipients.is_empty() {
+        return Ok(false);
+    }
+
+    let recipients = recipients
+        .into_iter()
+        .map(|address| Recipient {
+            address,
+            amount: AMOUNT_PER_RECIPIENT,
+            nb_outputs: 1,
+        })
+        .collect();
+
+    let mut psbt = client.create_new_psbt(utxos, recipients, None)?;
+    let partial_secret = client.get_partial_secret_from_psbt(&psbt)?;
+    client.fill_sp_outputs(&mut psbt, partial_secret)?;
+
+    let outputs: Vec<String> = psbt
+        .unsigned_tx
+        .output
+        .iter()
+        .map(|o| o.script_pubkey.as_bytes()[2..].to_lower_hex_string())
+        .collect();
+    let output_set: HashSet<&String> = outputs.iter().collect();
+
+    if test.expected.outputs.iter().any(|candidates| {
+        candidates.len() == outputs.len() && candidates.iter().collect::<HashSet<_>>() == output_set
+    }) {
+        Ok(true)
+    } else {
+        Err(Error::msg(format!("Unexpected outputs {:?}", outputs)))
+    }
+}
+
+/// Build a transaction from the vector and scan it as a wallet would
+fn run_receiving_test(test: &ReceivingTest) -> Result<bool> {
+    let scan_sk = SecretKey::from_str(&test.given.key_material.scan_priv_key)?;
+    let spend_sk = SecretKey::from_str(&test.given.key_material.spend_priv_key)?;
+
+    let mut client = SpClient::new(
+        "conformance".to_owned(),
+        scan_sk,
+        SpendKey::Secret(spend_sk),
+        None,
+        Network::Bitcoin,
+    )?;
+
+    for m in test.given.labels.iter() {
+        client.sp_receiver.add_label(Label::new(scan_sk, *m))?;
+    }
+
+    let mut addresses: HashSet<String> = HashSet::new();
+    addresses.insert(client.sp_receiver.get_receiving_address());
+    for label in client.sp_receiver.list_labels() {
+        addresses.insert(client.sp_receiver.get_receiving_address_for_label(&label)?);
+    }
+    if !test.given.labels.contains(&0) {
+        addresses.remove(&client.sp_receiver.get_change_address());
+    }
+    let expected_addresses: HashSet<String> = test.expected.addresses.iter().cloned().collect();
+    if addresses != expected_addresses {
+        return Err(Error::msg(format!("Unexpected addresses {:?}", addresses)));
+    }
+
+    let mut input = vec![];
+    let mut prevouts = vec![];
+    for vin in test.given.vin.iter() {
+        let (txin, prevout) = parse_input(vin)?;
+        input.push(txin);
+        prevouts.push(prevout);
+    }
+
+    let mut output = vec![];
+    for key in test.given.outputs.iter() {
+        let xonly = XOnlyPublicKey::from_str(key)?;
+        output.push(TxOut {
+            value: AMOUNT_PER_RECIPIENT,
+            script_pubkey: ScriptBuf::new_p2tr_tweaked(xonly.dangerous_assume_tweaked()),
+        });
+    }
+
+    let tx = Transaction {
+        version: bitcoin::transaction::Version(2),
+        lock_time: bitcoin::absolute::LockTime::ZERO,
+        input,
+        output,
+    };
+
+    let tweak_data: Option<PublicKey> = get_tweak_data(&tx, &prevouts)?;
+
+    let mut found: HashSet<(String, String)> = HashSet::new();
+    if let Some(tweak_data) = tweak_data {
+        let mut wallet = SpWallet::new(client, None)?;
+        let outputs = wallet.update_wallet_with_transaction(&tx, 1, tweak_data)?;
+        for output in outputs.values() {
+            found.insert((output.script[4..].to_owned(), output.tweak.clone()));
+        }
+    }
+
+    let expected: HashSet<(String, String)> = test
+        .expected
+        .outputs
+        .iter()
+        .map(|o| (o.pub_key.clone(), o.priv_key_tweak.clone()))
+        .collect();
+
+    if found == expected {
+        Ok(true)
+    } else {
+        Err(Error::msg(format!("Found {:?}", found)))
+    }
+}
+
+/// Run every vector of `vectors`, the content of the official test vectors file
+pub fn run_conformance_tests(vectors: &str) -> Result<ConformanceReport> {
+    let cases: Vec<TestCase> = serde_json::from_str(vectors)?;
+
+    let mut report = ConformanceReport::default();
+    for case in cases.iter() {
+        for test in case.sending.iter() {
+            report.record(&case.comment, "sending", run_sending_test(test));
+        }
+        for test in case.receiving.iter() {
+            report.record(&case.comment, "receiving", run_receiving_test(test));
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Copy of `bip-0352/send_and_receive_test_vectors.json` from the bips repository
+    const VECTORS_PATH: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/send_and_receive_test_vectors.json"
+    );
+
+    #[test]
+    #[ignore = "needs the official vectors in tests/data"]
+    fn official_vectors() {
+        let vectors = std::fs::read_to_string(VECTORS_PATH).unwrap();
+        let report = run_conformance_tests(&vectors).unwrap();
+        assert!(report.is_success(), "{:#?}", report.failures);
+        assert!(report.passed > 0);
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+use bitcoin::{Amount, OutPoint};
+use serde::{Deserialize, Serialize};
+
+use anyhow::Result;
+
+use crate::spclient::{OwnedOutput, Psbt, Recipient, SpClient, SpWallet};
+
+/// vsize of a taproot key path input, rounded up
+const TAPROOT_INPUT_VSIZE: u64 = 58;
+
+/// When the wallet should merge its small outputs, checked by the sync loop after each scan
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ConsolidationPolicy {
+    /// Only consolidate when fees are at most this, in sat/vB
+    pub max_fee_rate: Amount,
+    /// Only consolidate when there are more small outputs than this
+    pub min_outputs: usize,
+    /// Outputs below this amount are the ones we merge
+    pub max_output_amount: Amount,
+    /// Broadcast without asking for confirmation, the sync loop decides what to do with it
+    pub auto_broadcast: bool,
+}
+
+impl SpWallet {
+    /// The outputs a consolidation at `fee_rate` would merge, empty if the policy doesn't apply
+    /// Outputs that cost more to spend than they're worth are left alone
+    pub fn get_consolidation_candidates(
+        &self,
+        policy: &ConsolidationPolicy,
+        fee_rate: Amount,
+    ) -> HashMap<OutPoint, OwnedOutput> {
+        if fee_rate > policy.max_fee_rate {
+            return HashMap::new();
+        }
+
+        let spend_cost = fee_rate * TAPROOT_INPUT_VSIZE;
+        let candidates: HashMap<OutPoint, OwnedOutput> = self
+            .get_outputs()
+            .to_spendable_list()
+            .into_iter()
+            .filter(|(_, o)| o.amount < policy.max_output_amount && o.amount > spend_cost)
+            .collect();
+
+        if candidates.len() > policy.min_outputs {
+            candidates
+        } else {
+            HashMap::new()
+        }
+    }
+
+    /// Build the consolidation psbt if the policy applies, everything goes to our change address
+    /// The psbt still has to be signed
+    pub fn build_consolidation(
+        &self,
+        policy: &ConsolidationPolicy,
+        fee_rate: Amount,
+    ) -> Result<Option<Psbt>> {
+        let candidates = self.get_consolidation_candidates(policy, fee_rate);
+        if candidates.is_empty() {
+            return Ok(None);
+        }
+
+        let client = self.get_client();
+        let change_address = client.sp_receiver.get_change_address();
+        let total: Amount = candidates.values().map(|o| o.amount).sum();
+
+        let mut psbt = client.create_new_psbt(
+            candidates,
+            vec![Recipient {
+                address: change_address.clone(),
+                amount: total,
+                nb_outputs: 1,
+            }],
+            None,
+        )?;
+        SpClient::set_fees_with_policy(
+            &mut psbt,
+            fee_rate,
+            change_address,
+            &client.get_change_policy(),
+        )?;
+        let partial_secret = client.get_partial_secret_from_psbt(&psbt)?;
+        client.fill_and_order(&mut psbt, partial_secret)?;
+
+        Ok(Some(psbt))
+    }
+}
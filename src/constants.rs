@@ -2,12 +2,22 @@ pub const PSBT_SP_PREFIX: &str = "sp";
 pub const PSBT_SP_SUBTYPE: u8 = 0;
 pub const PSBT_SP_TWEAK_KEY: &str = "tweak";
 pub const PSBT_SP_ADDRESS_KEY: &str = "address";
+/// Empty value on the outputs paying our own change address
+pub const PSBT_SP_CHANGE_KEY: &str = "change";
+
+/// Silent payment address version we create
+pub const SP_ADDRESS_VERSION: u8 = 0;
+/// Addresses of this version can't be paid as version 0, every version below can (BIP352)
+pub const SP_ADDRESS_RESERVED_VERSION: u8 = 31;
 
 pub const NUMS: &str = "50929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0";
 
-// This threshold is used during change address creation.
+// Default threshold used during change address creation, see `ChangePolicy`.
 // If the change amount is below this number, we don't bother making a change address.
 // Instead, the funds will be added to the transaction fee.
 pub const DUST_THRESHOLD: bitcoin::Amount = bitcoin::Amount::from_sat(546);
 
+// Unsolicited outputs below this are quarantined as potential dust attacks, see `SpClient::set_dust_attack_threshold`
+pub const DUST_ATTACK_THRESHOLD: bitcoin::Amount = bitcoin::Amount::from_sat(1000);
+
 pub const DATA_CARRIER_SIZE: usize = 205;
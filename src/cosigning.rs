@@ -0,0 +1,250 @@
+//! Client for a remote co-signing service, the second half of a MuSig2 spend key
+//!
+//! The service only contributes its partial signatures once the spend was approved with a second factor.
+//! In case the service disappears, it co-signs in advance a recovery transaction that's only valid
+//! after a timelock, and that we can broadcast on our own.
+//! The wallet is set up with `SpendKey::Public(get_aggregate_pubkey())`, see `musig` for the limitations.
+
+use std::sync::Arc;
+
+use bitcoin::{
+    absolute::LockTime,
+    secp256k1::{Message, PublicKey, Secp256k1, SecretKey},
+    Amount, Sequence,
+};
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Error, Result};
+
+use crate::musig::{Musig2Cosigner, Musig2KeyAgg, Musig2Signer};
+use crate::sealed::SealedClient;
+use crate::spclient::{try_parse_sp_address, Psbt, Recipient, SpClient, SpWallet};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum SecondFactor {
+    Totp,
+    /// Notification to an app already paired with the service
+    Push,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Enrollment {
+    pub wallet_id: String,
+    pub cosigner_pubkey: PublicKey,
+    pub second_factor: SecondFactor,
+    /// `otpauth://` uri to add to an authenticator app, only for `SecondFactor::Totp`
+    pub totp_uri: Option<String>,
+    /// Blocks a recovery transaction must wait before the service co-signs it without approval
+    pub recovery_delay: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum ApprovalStatus {
+    Pending,
+    /// `token` authorizes the signing of the approved psbt only
+    Approved {
+        token: String,
+    },
+    Denied,
+}
+
+/// The api of the service, the transport is up to the implementation
+pub trait CosigningService {
+    fn enroll(&self, our_pubkey: &PublicKey, second_factor: SecondFactor) -> Result<Enrollment>;
+
+    /// `psbt` is base64 encoded, returns the id of the request
+    /// With TOTP the code is given right away, with push the user approves on the paired app
+    fn request_approval(
+        &self,
+        wallet_id: &str,
+        psbt: &str,
+        totp_code: Option<&str>,
+    ) -> Result<String>;
+
+    fn get_approval_status(&self, wallet_id: &str, request_id: &str) -> Result<ApprovalStatus>;
+
+    fn get_public_nonce(
+        &self,
+        token: &str,
+        session_id: &[u8; 32],
+        msg: &Message,
+        tweak: &SecretKey,
+    ) -> Result<(PublicKey, PublicKey)>;
+
+    fn get_partial_signature(
+        &self,
+        token: &str,
+        session_id: &[u8; 32],
+        agg_nonce: &(PublicKey, PublicKey),
+    ) -> Result<SecretKey>;
+
+    /// The service keeps a copy of our sealed key share, it should be sealed with a key
+    fn store_backup(&self, wallet_id: &str, backup: &SealedClient) -> Result<()>;
+
+    /// Needs a token from an approved request, like spending
+    fn get_backup(&self, wallet_id: &str, token: &str) -> Result<SealedClient>;
+}
+
+/// The service as a `Musig2Cosigner`, for the approved psbt only
+struct ApprovedCosigner {
+    service: Arc<dyn CosigningService + Send + Sync>,
+    pubkey: PublicKey,
+    token: String,
+}
+
+impl Musig2Cosigner for ApprovedCosigner {
+    fn get_pubkey(&self) -> Result<PublicKey> {
+        Ok(self.pubkey)
+    }
+
+    fn get_public_nonce(
+        &self,
+        session_id: &[u8; 32],
+        msg: &Message,
+        tweak: &SecretKey,
+    ) -> Result<(PublicKey, PublicKey)> {
+        self.service
+            .get_public_nonce(&self.token, session_id, msg, tweak)
+    }
+
+    fn get_partial_signature(
+        &self,
+        session_id: &[u8; 32],
+        agg_nonce: &(PublicKey, PublicKey),
+    ) -> Result<SecretKey> {
+        self.service
+            .get_partial_signature(&self.token, session_id, agg_nonce)
+    }
+}
+
+pub struct CosigningClient {
+    service: Arc<dyn CosigningService + Send + Sync>,
+    enrollment: Enrollment,
+}
+
+impl CosigningClient {
+    /// For a wallet that's already enrolled
+    pub fn new(service: Arc<dyn CosigningService + Send + Sync>, enrollment: Enrollment) -> Self {
+        Self {
+            service,
+            enrollment,
+        }
+    }
+
+    /// `enrollment` must be persisted, and the TOTP uri shown to the user
+    pub fn enroll(
+        service: Arc<dyn CosigningService + Send + Sync>,
+        our_pubkey: &PublicKey,
+        second_factor: SecondFactor,
+    ) -> Result<Self> {
+        let enrollment = service.enroll(our_pubkey, second_factor)?;
+        if enrollment.second_factor != second_factor {
+            return Err(Error::msg("Service enrolled another second factor"));
+        }
+        Ok(Self::new(service, enrollment))
+    }
+
+    pub fn get_enrollment(&self) -> &Enrollment {
+        &self.enrollment
+    }
+
+    /// Spend key of the wallet
+    pub fn get_aggregate_pubkey(&self, our_pubkey: PublicKey) -> Result<PublicKey> {
+        Ok(
+            Musig2KeyAgg::new(vec![our_pubkey, self.enrollment.cosigner_pubkey])?
+                .get_aggregate_pubkey(),
+        )
+    }
+
+    pub fn request_approval(&self, psbt: &Psbt, totp_code: Option<&str>) -> Result<String> {
+        if self.enrollment.second_factor == SecondFactor::Totp && totp_code.is_none() {
+            return Err(Error::msg("Missing TOTP code"));
+        }
+        self.service
+            .request_approval(&self.enrollment.wallet_id, &psbt.to_string(), totp_code)
+    }
+
+    pub fn get_approval_status(&self, request_id: &str) -> Result<ApprovalStatus> {
+        self.service
+            .get_approval_status(&self.enrollment.wallet_id, request_id)
+    }
+
+    /// Signer for the psbt approved with `token`, give it to `SpClient::sign_psbt_with_signer`
+    pub fn get_signer(&self, sk: SecretKey, token: String) -> Result<Musig2Signer> {
+        let cosigner = ApprovedCosigner {
+            service: self.service.clone(),
+            pubkey: self.enrollment.cosigner_pubkey,
+            token,
+        };
+        Musig2Signer::new(sk, Box::new(cosigner))
+    }
+
+    pub fn backup_key(&self, sealed: &SealedClient) -> Result<()> {
+        if !sealed.is_encrypted() {
+            return Err(Error::msg("Backup must be sealed with a key"));
+        }
+        self.service
+            .store_backup(&self.enrollment.wallet_id, sealed)
+    }
+
+    pub fn restore_key(&self, token: &str) -> Result<SealedClient> {
+        self.service.get_backup(&self.enrollment.wallet_id, token)
+    }
+
+    /// Spend all the outputs of `wallet` to `address` once the chain reaches `unlock_height`
+    /// `address` can't be a silent payment address, see `musig`
+    /// The psbt goes through `request_approval` like any other, the service approves it by itself
+    /// if `unlock_height` is at least `recovery_delay` blocks away.
+    /// Once signed, keep the transaction somewhere safe, it must be built again after each new output.
+    pub fn build_recovery_psbt(
+        &self,
+        wallet: &SpWallet,
+        address: String,
+        unlock_height: u32,
+        fee_rate: Amount,
+    ) -> Result<Psbt> {
+        if try_parse_sp_address(&address)?.is_some() {
+            return Err(Error::msg("Can't recover to a silent payment address"));
+        }
+
+        let utxos = wallet.get_outputs().to_spendable_list();
+        if utxos.is_empty() {
+            return Err(Error::msg("Nothing to recover"));
+        }
+        let total: Amount = utxos.values().map(|o| o.amount).sum();
+
+        let client = wallet.get_client();
+        let mut psbt = client.create_new_psbt(
+            utxos,
+            vec![Recipient {
+                address: address.clone(),
+                amount: total,
+                nb_outputs: 1,
+            }],
+            None,
+        )?;
+
+        psbt.unsigned_tx.lock_time = LockTime::from_height(unlock_height)?;
+        for input in psbt.unsigned_tx.input.iter_mut() {
+            input.sequence = Sequence::ENABLE_LOCKTIME_NO_RBF;
+        }
+
+        SpClient::set_fees_with_policy(&mut psbt, fee_rate, address, &client.get_change_policy())?;
+
+        Ok(psbt)
+    }
+}
+
+/// Our share of the spend key, to check it matches the wallet before signing anything
+pub fn check_key_share(
+    client: &SpClient,
+    sk: &SecretKey,
+    cosigner: &CosigningClient,
+) -> Result<()> {
+    let our_pubkey = sk.public_key(&Secp256k1::signing_only());
+    let spend_pubkey: PublicKey = client.get_spend_key().into();
+    if cosigner.get_aggregate_pubkey(our_pubkey)? != spend_pubkey {
+        return Err(Error::msg("Key share doesn't match the wallet"));
+    }
+    Ok(())
+}
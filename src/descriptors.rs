@@ -0,0 +1,142 @@
+use std::str::FromStr;
+
+use bitcoin::{
+    hex::DisplayHex,
+    secp256k1::{PublicKey, Scalar, Secp256k1, SecretKey},
+    OutPoint, PrivateKey, ScriptBuf,
+};
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Error, Result};
+
+use crate::spclient::{OutputSpendStatus, OwnedOutput, SpWallet, SpendKey};
+
+const INPUT_CHARSET: &str =
+    "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct OutputDescriptor {
+    pub outpoint: OutPoint,
+    pub blockheight: u32,
+    /// `rawtr()` descriptor with checksum, holds the private key if it was requested
+    pub descriptor: String,
+}
+
+impl SpWallet {
+    /// Export our unspent outputs as descriptors that can be imported in Bitcoin Core (`importdescriptors`)
+    /// With `include_private`, the tweaked private key of each output is exported as well, which requires the spend key
+    pub fn export_descriptors(&self, include_private: bool) -> Result<Vec<OutputDescriptor>> {
+        let secp = Secp256k1::new();
+        let network = self.get_client().get_network();
+
+        let spend_sk = match (self.get_client().get_spend_key(), include_private) {
+            (SpendKey::Secret(sk), true) => Some(sk),
+            (SpendKey::Public(_), true) => {
+                return Err(Error::msg("Watch-only wallet, can't export private keys"))
+            }
+            (_, false) => None,
+        };
+        let spend_pk: PublicKey = self.get_client().get_spend_key().into();
+
+        let mut res = vec![];
+        for (outpoint, output) in self.get_outputs().to_outpoints_list() {
+            if output.spend_status != OutputSpendStatus::Unspent {
+                continue;
+            }
+
+            let tweak: Scalar = SecretKey::from_str(&output.tweak)?.into();
+
+            let output_key = spend_pk.add_exp_tweak(&secp, &tweak)?;
+            check_output_key(&output, &output_key)?;
+
+            let key = match spend_sk {
+                Some(sk) => {
+                    let mut tweaked = sk.add_tweak(&tweak)?;
+                    let wif = PrivateKey::new(tweaked, network).to_wif();
+                    tweaked.non_secure_erase();
+                    wif
+                }
+                None => output_key
+                    .x_only_public_key()
+                    .0
+                    .serialize()
+                    .to_lower_hex_string(),
+            };
+
+            res.push(OutputDescriptor {
+                outpoint,
+                blockheight: output.blockheight,
+                descriptor: add_checksum(&format!("rawtr({})", key))?,
+            });
+        }
+
+        res.sort_by_key(|d| d.blockheight);
+
+        Ok(res)
+    }
+}
+
+// The key we derive must be the one in the script we stored
+pub(crate) fn check_output_key(output: &OwnedOutput, output_key: &PublicKey) -> Result<()> {
+    let script = ScriptBuf::from_hex(&output.script)?;
+    if !script.is_p2tr() || script.as_bytes()[2..] != output_key.x_only_public_key().0.serialize() {
+        return Err(Error::msg(format!(
+            "Output with script {} doesn't match its tweak",
+            output.script
+        )));
+    }
+    Ok(())
+}
+
+/// Append the descriptor checksum as defined in Bitcoin Core `descriptor.cpp`
+pub fn add_checksum(descriptor: &str) -> Result<String> {
+    const GENERATOR: [u64; 5] = [
+        0xf5dee51989,
+        0xa9fdca3312,
+        0x1bab10e32d,
+        0x3706b1677a,
+        0x644d626ffd,
+    ];
+
+    fn polymod(chk: u64, value: u64) -> u64 {
+        let top = chk >> 35;
+        let mut chk = ((chk & 0x7ffffffff) << 5) ^ value;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= generator;
+            }
+        }
+        chk
+    }
+
+    let mut chk = 1u64;
+    let mut groups: Vec<u64> = Vec::with_capacity(3);
+    for c in descriptor.chars() {
+        let pos = INPUT_CHARSET
+            .find(c)
+            .ok_or_else(|| Error::msg(format!("Invalid character {} in descriptor", c)))?
+            as u64;
+        chk = polymod(chk, pos & 31);
+        groups.push(pos >> 5);
+        if groups.len() == 3 {
+            chk = polymod(chk, groups[0] * 9 + groups[1] * 3 + groups[2]);
+            groups.clear();
+        }
+    }
+    match groups.len() {
+        1 => chk = polymod(chk, groups[0]),
+        2 => chk = polymod(chk, groups[0] * 3 + groups[1]),
+        _ => (),
+    }
+    for _ in 0..8 {
+        chk = polymod(chk, 0);
+    }
+    chk ^= 1;
+
+    let checksum: String = (0..8)
+        .map(|i| CHECKSUM_CHARSET[((chk >> (5 * (7 - i))) & 31) as usize] as char)
+        .collect();
+
+    Ok(format!("{}#{}", descriptor, checksum))
+}
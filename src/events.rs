@@ -0,0 +1,87 @@
+//! Publish/subscribe bus between the wallet logic and whatever shows or forwards what happens,
+//! e.g. the app streams, webhooks, or the logs of the daemon
+
+use std::sync::{
+    mpsc::{channel, Receiver},
+    Arc, Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::spclient::Balance;
+use crate::sync_status::SyncStatus;
+use crate::webhook::{WalletEvent, WebhookRequest, Webhooks};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum Event {
+    Wallet(WalletEvent),
+    BalanceChanged(Balance),
+    SyncStatusChanged(SyncStatus),
+}
+
+/// Returns false to be unsubscribed
+type Subscriber = Box<dyn FnMut(&Event) -> bool + Send>;
+
+/// Cloned wherever events are published or subscribed to
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl std::fmt::Debug for EventBus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let count = self
+            .subscribers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .len();
+        f.debug_struct("EventBus")
+            .field("subscribers", &count)
+            .finish()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subscribers are called in the publishing thread, in the order they subscribed
+    /// They must not publish themselves
+    pub fn publish(&self, event: Event) {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain_mut(|subscriber| subscriber(&event));
+    }
+
+    pub fn subscribe_with(&self, subscriber: impl FnMut(&Event) -> bool + Send + 'static) {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(Box::new(subscriber));
+    }
+
+    /// Dropping the receiver unsubscribes at the next event
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = channel();
+        self.subscribe_with(move |event| sender.send(event.clone()).is_ok());
+        receiver
+    }
+
+    /// Requests for `webhooks` on each wallet event, `send` returns false to stop
+    pub fn subscribe_webhooks(
+        &self,
+        webhooks: Webhooks,
+        mut send: impl FnMut(WebhookRequest) -> bool + Send + 'static,
+    ) {
+        self.subscribe_with(move |event| match event {
+            // only fails if the event can't be serialized, which would fail for every event
+            Event::Wallet(wallet_event) => match webhooks.get_requests(wallet_event) {
+                Ok(requests) => requests.into_iter().all(&mut send),
+                Err(_) => true,
+            },
+            _ => true,
+        });
+    }
+}
@@ -0,0 +1,395 @@
+//! History of the transactions of the wallet, what was sent and received and the fees paid
+
+use std::{collections::HashMap, ops::Range};
+
+use bitcoin::{Amount, Denomination, OutPoint, Transaction, Txid};
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Error, Result};
+
+use crate::chain::{broadcast_idempotent, Broadcaster};
+use crate::rates::ExchangeRate;
+use crate::spclient::{SpWallet, UNCONFIRMED_HEIGHT};
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct LabelTotals {
+    pub label: Option<String>,
+    pub received: Amount,
+    pub sent: Amount,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TxRecord {
+    pub txid: Txid,
+    pub blockheight: u32,
+    pub timestamp: u64,
+    /// From others, our change doesn't count
+    pub received: Amount,
+    /// To others, fee excluded
+    pub sent: Amount,
+    /// Only known when all the inputs are ours
+    pub fee: Option<Amount>,
+    pub vsize: u64,
+    /// Received by each label, and sent from the outputs of each label
+    pub labels: Vec<LabelTotals>,
+    #[serde(default)]
+    pub memo: Option<String>,
+    /// When the memo was last set, the latest wins when merging with another device
+    #[serde(default)]
+    pub memo_updated_at: u64,
+}
+
+fn add_to_label(
+    labels: &mut Vec<LabelTotals>,
+    label: &Option<String>,
+    received: Amount,
+    sent: Amount,
+) {
+    match labels.iter_mut().find(|l| l.label == *label) {
+        Some(totals) => {
+            totals.received += received;
+            totals.sent += sent;
+        }
+        None => labels.push(LabelTotals {
+            label: label.clone(),
+            received,
+            sent,
+        }),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SpendingReport {
+    pub tx_count: usize,
+    pub received: Amount,
+    pub sent: Amount,
+    pub fees: Amount,
+    /// sat/vB over the transactions we paid the fee of, None if there's none
+    pub average_fee_rate: Option<f64>,
+    pub labels: Vec<LabelTotals>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    /// One line per transaction, amounts in BTC
+    Csv,
+    /// `ExportRow`s, amounts in sats
+    Json,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+    /// Between our own outputs, only the fee left the wallet
+    ToSelf,
+}
+
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Incoming => write!(f, "incoming"),
+            Self::Outgoing => write!(f, "outgoing"),
+            Self::ToSelf => write!(f, "self"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ExportRow {
+    /// ISO 8601, UTC
+    pub date: String,
+    pub txid: Txid,
+    pub direction: Direction,
+    pub amount: Amount,
+    pub fee: Option<Amount>,
+    /// Value of `amount` at the last rate known before the transaction
+    pub fiat_value: Option<f64>,
+    pub fiat_currency: Option<String>,
+    pub memo: Option<String>,
+}
+
+impl ExportRow {
+    fn new(record: &TxRecord, rates: &[ExchangeRate]) -> Self {
+        let (direction, amount) = if record.received > Amount::ZERO {
+            (Direction::Incoming, record.received)
+        } else if record.sent > Amount::ZERO {
+            (Direction::Outgoing, record.sent)
+        } else {
+            (Direction::ToSelf, Amount::ZERO)
+        };
+        let rate = rates
+            .iter()
+            .filter(|r| r.timestamp <= record.timestamp)
+            .max_by_key(|r| r.timestamp);
+        Self {
+            date: format_utc(record.timestamp),
+            txid: record.txid,
+            direction,
+            amount,
+            fee: record.fee,
+            fiat_value: rate.map(|r| amount.to_btc() * r.rate),
+            fiat_currency: rate.map(|r| r.currency.clone()),
+            memo: record.memo.clone(),
+        }
+    }
+
+    fn to_csv_line(&self) -> String {
+        let fields = [
+            self.date.clone(),
+            self.txid.to_string(),
+            self.direction.to_string(),
+            self.amount.to_string_in(Denomination::Bitcoin),
+            self.fee
+                .map(|fee| fee.to_string_in(Denomination::Bitcoin))
+                .unwrap_or_default(),
+            self.fiat_value
+                .map(|value| format!("{:.2}", value))
+                .unwrap_or_default(),
+            self.fiat_currency.clone().unwrap_or_default(),
+            self.memo.clone().unwrap_or_default(),
+        ];
+        fields
+            .iter()
+            .map(|field| escape_csv(field))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+const CSV_HEADER: &str = "date,txid,direction,amount_btc,fee_btc,fiat_value,fiat_currency,memo";
+
+fn escape_csv(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// `timestamp` in seconds since the epoch, as `YYYY-MM-DDTHH:MM:SSZ`
+fn format_utc(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let secs = timestamp % 86400;
+
+    // days to civil date, from Howard Hinnant's algorithm
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TxHistory {
+    records: HashMap<Txid, TxRecord>,
+}
+
+impl TxHistory {
+    /// Call after `SpWallet::update_wallet_with_transaction`, the wallet must know the outputs of `tx`
+    /// Recording a transaction again replaces it, e.g. once it's mined
+    pub fn record(
+        &mut self,
+        wallet: &SpWallet,
+        tx: &Transaction,
+        blockheight: u32,
+        timestamp: u64,
+    ) -> Result<()> {
+        let txid = tx.txid();
+        let outputs = wallet.get_outputs();
+        let mut labels = vec![];
+
+        let mut our_inputs = Amount::ZERO;
+        let mut all_inputs_ours = true;
+        for txin in tx.input.iter() {
+            match outputs.get_outpoint(txin.previous_output) {
+                Ok((_, output)) => {
+                    our_inputs += output.amount;
+                    add_to_label(&mut labels, &output.label, Amount::ZERO, output.amount);
+                }
+                Err(_) => all_inputs_ours = false,
+            }
+        }
+
+        let mut our_outputs = Amount::ZERO;
+        for vout in 0..tx.output.len() {
+            if let Ok((_, output)) = outputs.get_outpoint(OutPoint::new(txid, vout as u32)) {
+                our_outputs += output.amount;
+                if our_inputs == Amount::ZERO {
+                    add_to_label(&mut labels, &output.label, output.amount, Amount::ZERO);
+                }
+            }
+        }
+        let total_outputs: Amount = tx.output.iter().map(|o| o.value).sum();
+
+        let (received, sent, fee) = if our_inputs == Amount::ZERO {
+            (our_outputs, Amount::ZERO, None)
+        } else if all_inputs_ours {
+            let fee = our_inputs
+                .checked_sub(total_outputs)
+                .unwrap_or(Amount::ZERO);
+            (Amount::ZERO, total_outputs - our_outputs, Some(fee))
+        } else {
+            // collaborative transaction, we can't tell our share of the fee
+            (
+                Amount::ZERO,
+                our_inputs.checked_sub(our_outputs).unwrap_or(Amount::ZERO),
+                None,
+            )
+        };
+
+        // the memo survives the transaction being recorded again once mined
+        let (memo, memo_updated_at) = self
+            .records
+            .get(&txid)
+            .map(|r| (r.memo.clone(), r.memo_updated_at))
+            .unwrap_or_default();
+        self.records.insert(
+            txid,
+            TxRecord {
+                txid,
+                blockheight,
+                timestamp,
+                received,
+                sent,
+                fee,
+                vsize: tx.vsize() as u64,
+                labels,
+                memo,
+                memo_updated_at,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Broadcast with `broadcast_idempotent` and record `tx` as unconfirmed, unless it's already recorded
+    /// Call after `SpWallet::update_wallet_with_transaction`, like `record`
+    pub fn broadcast(
+        &mut self,
+        broadcaster: &(impl Broadcaster + ?Sized),
+        wallet: &SpWallet,
+        tx: &Transaction,
+        timestamp: u64,
+    ) -> Result<Txid> {
+        let txid = broadcast_idempotent(broadcaster, tx)?;
+        if !self.records.contains_key(&txid) {
+            self.record(wallet, tx, UNCONFIRMED_HEIGHT, timestamp)?;
+        }
+        Ok(txid)
+    }
+
+    /// Forget what was mined above `height`, after a reorg
+    pub fn reset_to_height(&mut self, height: u32) {
+        self.records.retain(|_, r| r.blockheight <= height);
+    }
+
+    pub fn get_record(&self, txid: &Txid) -> Option<&TxRecord> {
+        self.records.get(txid)
+    }
+
+    /// `timestamp` is when the user set it
+    pub fn set_memo(&mut self, txid: &Txid, memo: Option<String>, timestamp: u64) -> Result<()> {
+        let record = self
+            .records
+            .get_mut(txid)
+            .ok_or_else(|| Error::msg(format!("Unknown transaction {}", txid)))?;
+        record.memo = memo;
+        record.memo_updated_at = timestamp;
+        Ok(())
+    }
+
+    /// Most recent first
+    pub fn list_records(&self) -> Vec<&TxRecord> {
+        let mut records: Vec<&TxRecord> = self.records.values().collect();
+        records.sort_by_key(|r| std::cmp::Reverse(r.timestamp));
+        records
+    }
+
+    /// Totals of the transactions with a timestamp in `period`
+    pub fn get_spending_report(&self, period: Range<u64>) -> SpendingReport {
+        let mut report = SpendingReport {
+            tx_count: 0,
+            received: Amount::ZERO,
+            sent: Amount::ZERO,
+            fees: Amount::ZERO,
+            average_fee_rate: None,
+            labels: vec![],
+        };
+        let mut fee_vsize = 0;
+
+        for record in self
+            .records
+            .values()
+            .filter(|r| period.contains(&r.timestamp))
+        {
+            report.tx_count += 1;
+            report.received += record.received;
+            report.sent += record.sent;
+            if let Some(fee) = record.fee {
+                report.fees += fee;
+                fee_vsize += record.vsize;
+            }
+            for totals in record.labels.iter() {
+                add_to_label(
+                    &mut report.labels,
+                    &totals.label,
+                    totals.received,
+                    totals.sent,
+                );
+            }
+        }
+
+        if fee_vsize > 0 {
+            report.average_fee_rate = Some(report.fees.to_sat() as f64 / fee_vsize as f64);
+        }
+
+        report
+    }
+    /// The transactions with a timestamp in `period`, oldest first
+    /// `rates` are the exchange rates the app kept over time, the fiat value is left empty without one
+    pub fn export_history(
+        &self,
+        format: ExportFormat,
+        period: Range<u64>,
+        rates: &[ExchangeRate],
+    ) -> Result<String> {
+        let mut records: Vec<&TxRecord> = self
+            .records
+            .values()
+            .filter(|r| period.contains(&r.timestamp))
+            .collect();
+        records.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then(a.txid.cmp(&b.txid)));
+        let rows: Vec<ExportRow> = records
+            .into_iter()
+            .map(|r| ExportRow::new(r, rates))
+            .collect();
+
+        match format {
+            ExportFormat::Csv => {
+                let mut res = CSV_HEADER.to_owned();
+                for row in rows.iter() {
+                    res.push('\n');
+                    res.push_str(&row.to_csv_line());
+                }
+                res.push('\n');
+                Ok(res)
+            }
+            ExportFormat::Json => Ok(serde_json::to_string_pretty(&rows)?),
+        }
+    }
+}
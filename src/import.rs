@@ -0,0 +1,270 @@
+//! Import of the state recorded by another device or wallet file with the same keys, without a rescan
+//!
+//! Everything is merged into what we already have, nothing we know is dropped.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::Write,
+    path::Path,
+    str::FromStr,
+};
+
+use bitcoin::{
+    key::TweakedPublicKey,
+    secp256k1::{PublicKey, Secp256k1, SecretKey},
+    Amount, OutPoint, ScriptBuf, Txid,
+};
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Error, Result};
+
+use crate::descriptors::check_output_key;
+use crate::history::{ExportRow, TxHistory};
+use crate::merge::merge_output;
+use crate::spclient::{OutputList, OutputSpendStatus, OwnedOutput, SpWallet, UNCONFIRMED_HEIGHT};
+
+/// The minimum to import an output, e.g. from a list kept by hand
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ImportedOutput {
+    pub outpoint: OutPoint,
+    /// Hex encoded, as in `OwnedOutput`
+    pub tweak: String,
+    pub amount: Amount,
+    /// None if unknown, the output is then considered unconfirmed until the wallet sees it
+    pub blockheight: Option<u32>,
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ImportReport {
+    pub added: Vec<OutPoint>,
+    /// Known outputs the other device had more recent state for, see `merge::merge_output`
+    pub updated: Vec<OutPoint>,
+    /// Outputs whose tweak doesn't give us their script, with why
+    pub rejected: Vec<(OutPoint, String)>,
+    /// Blocks neither side scanned, between the ranges of both, scanning starts again from there
+    #[serde(default)]
+    pub rescan_from: Option<u32>,
+}
+
+/// Adds the outputs we don't have, and merges those we have with `merge_output`
+fn merge_outputs(
+    dest: &mut OutputList,
+    incoming: impl IntoIterator<Item = (OutPoint, OwnedOutput)>,
+    report: &mut ImportReport,
+) {
+    let known = dest.to_outpoints_list();
+    let mut merged = HashMap::new();
+    for (outpoint, output) in incoming {
+        match known.get(&outpoint) {
+            None => {
+                report.added.push(outpoint);
+                merged.insert(outpoint, output);
+            }
+            Some(ours) => {
+                let updated = merge_output(ours, &output);
+                if updated != *ours {
+                    report.updated.push(outpoint);
+                    merged.insert(outpoint, updated);
+                }
+            }
+        }
+    }
+    dest.extend_from(merged);
+}
+
+/// For the same seed restored twice, `source` must have the same keys as `dest`
+/// `dest` gets the outputs of both and the earliest birthday. It gets the latest scan height only
+/// if the scanned ranges of both overlap, otherwise it keeps the end of the earliest range, and
+/// the report tells where the blocks nobody scanned start
+pub fn merge_output_lists(source: &OutputList, dest: &mut OutputList) -> Result<ImportReport> {
+    if source.wallet_fingerprint != dest.wallet_fingerprint {
+        return Err(Error::msg("Wallets don't have the same keys"));
+    }
+    let mut report = ImportReport::default();
+    merge_outputs(dest, source.to_outpoints_list(), &mut report);
+    merge_scanned_range(
+        dest,
+        source.get_birthday(),
+        source.get_last_scan(),
+        &mut report,
+    );
+    Ok(report)
+}
+
+/// The scan range rule of `merge_output_lists`, for a range from `birthday` to `last_scan` scanned elsewhere
+pub(crate) fn merge_scanned_range(
+    dest: &mut OutputList,
+    birthday: u32,
+    last_scan: u32,
+    report: &mut ImportReport,
+) {
+    let mut ranges = [
+        (dest.get_birthday(), dest.get_last_scan()),
+        (birthday, last_scan),
+    ];
+    ranges.sort();
+    let [(birthday, first_end), (second_start, second_end)] = ranges;
+    let last_scan = if second_start <= first_end.saturating_add(1) {
+        first_end.max(second_end)
+    } else {
+        report.rescan_from = Some(first_end + 1);
+        first_end
+    };
+    dest.set_birthday(birthday);
+    dest.update_last_scan(last_scan);
+}
+
+/// `merge_output_lists` on the `OutputList`s saved as json at `source` and `dest`
+/// `dest` is replaced atomically, then `source` is deleted
+pub fn merge_wallets(source: &Path, dest: &Path) -> Result<ImportReport> {
+    if source == dest {
+        return Err(Error::msg("Can't merge a wallet into itself"));
+    }
+    let source_outputs: OutputList = serde_json::from_str(&fs::read_to_string(source)?)?;
+    let mut dest_outputs: OutputList = serde_json::from_str(&fs::read_to_string(dest)?)?;
+    let report = merge_output_lists(&source_outputs, &mut dest_outputs)?;
+
+    let mut tmp_path = dest.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(serde_json::to_string(&dest_outputs)?.as_bytes())?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, dest)?;
+
+    fs::remove_file(source)?;
+    Ok(report)
+}
+
+impl SpWallet {
+    /// Outputs from the other device's `OutputList`, their scripts are checked against our keys
+    /// Outputs we already have are merged with `merge_output`
+    pub fn import_owned_outputs(
+        &mut self,
+        outputs: HashMap<OutPoint, OwnedOutput>,
+    ) -> Result<ImportReport> {
+        let spend_pk: PublicKey = self.get_client().get_spend_key().into();
+        let secp = Secp256k1::verification_only();
+        let check = |output: &OwnedOutput| -> Result<()> {
+            let tweak = SecretKey::from_str(&output.tweak)?;
+            let output_key = spend_pk.add_exp_tweak(&secp, &tweak.into())?;
+            check_output_key(output, &output_key)
+        };
+
+        let mut report = ImportReport::default();
+        let mut valid = vec![];
+        for (outpoint, output) in outputs {
+            match check(&output) {
+                Ok(()) => valid.push((outpoint, output)),
+                Err(e) => report.rejected.push((outpoint, e.to_string())),
+            }
+        }
+        merge_outputs(self.get_mut_outputs(), valid, &mut report);
+        Ok(report)
+    }
+
+    /// Same as `import_owned_outputs` for a plain list of outputs, assumed unspent
+    pub fn import_outputs(&mut self, outputs: &[ImportedOutput]) -> Result<ImportReport> {
+        let spend_pk: PublicKey = self.get_client().get_spend_key().into();
+        let secp = Secp256k1::verification_only();
+        let derive_script = |tweak: &str| -> Result<ScriptBuf> {
+            let tweak = SecretKey::from_str(tweak)?;
+            let output_key = spend_pk.add_exp_tweak(&secp, &tweak.into())?;
+            Ok(ScriptBuf::new_p2tr_tweaked(
+                TweakedPublicKey::dangerous_assume_tweaked(output_key.x_only_public_key().0),
+            ))
+        };
+
+        let mut owned = HashMap::new();
+        let mut rejected = vec![];
+        for imported in outputs {
+            match derive_script(&imported.tweak) {
+                Ok(script) => {
+                    owned.insert(
+                        imported.outpoint,
+                        OwnedOutput {
+                            blockheight: imported.blockheight.unwrap_or(UNCONFIRMED_HEIGHT),
+                            tweak: imported.tweak.clone(),
+                            amount: imported.amount,
+                            script: script.to_hex_string(),
+                            label: imported.label.clone(),
+                            spend_status: OutputSpendStatus::Unspent,
+                            quarantined: false,
+                            blockhash: None,
+                            block_time: None,
+                        },
+                    );
+                }
+                Err(e) => rejected.push((imported.outpoint, e.to_string())),
+            }
+        }
+
+        let mut report = self.import_owned_outputs(owned)?;
+        report.rejected.extend(rejected);
+        Ok(report)
+    }
+}
+
+impl TxHistory {
+    /// Memos from the JSON of `export_history`, for the transactions we have
+    /// It doesn't tell when they were set, so a memo we set ourselves is kept
+    /// Returns the transactions we don't have yet, they're recorded once the wallet sees them
+    pub fn import_history(&mut self, json: &str) -> Result<Vec<Txid>> {
+        let rows: Vec<ExportRow> = serde_json::from_str(json)?;
+        let mut unknown = vec![];
+        for row in rows {
+            let Some(record) = self.get_record(&row.txid) else {
+                unknown.push(row.txid);
+                continue;
+            };
+            if record.memo.is_none() {
+                self.merge_memo(&row.txid, row.memo, 0)?;
+            }
+        }
+        Ok(unknown)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output_list(birthday: u32, last_scan: u32) -> OutputList {
+        let secp = Secp256k1::signing_only();
+        let mut list = OutputList::new(
+            SecretKey::from_slice(&[0x11; 32])
+                .unwrap()
+                .public_key(&secp),
+            SecretKey::from_slice(&[0x22; 32])
+                .unwrap()
+                .public_key(&secp),
+            birthday,
+        );
+        list.update_last_scan(last_scan);
+        list
+    }
+
+    #[test]
+    fn last_scan_only_moves_over_overlapping_ranges() {
+        // overlapping, or right after one another
+        for (source, expected) in [((150, 300), 300), ((201, 300), 300), ((120, 180), 200)] {
+            let mut dest = output_list(100, 200);
+            let report = merge_output_lists(&output_list(source.0, source.1), &mut dest).unwrap();
+            assert_eq!(dest.get_birthday(), 100);
+            assert_eq!(dest.get_last_scan(), expected);
+            assert_eq!(report.rescan_from, None);
+        }
+
+        // nobody scanned 201 to 249, in either direction
+        let mut dest = output_list(100, 200);
+        let report = merge_output_lists(&output_list(250, 300), &mut dest).unwrap();
+        assert_eq!((dest.get_birthday(), dest.get_last_scan()), (100, 200));
+        assert_eq!(report.rescan_from, Some(201));
+
+        let mut dest = output_list(250, 300);
+        let report = merge_output_lists(&output_list(100, 200), &mut dest).unwrap();
+        assert_eq!((dest.get_birthday(), dest.get_last_scan()), (100, 200));
+        assert_eq!(report.rescan_from, Some(201));
+    }
+}
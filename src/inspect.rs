@@ -0,0 +1,224 @@
+//! Inspection of transactions and psbts that may not come from our own builder
+
+use std::str::FromStr;
+
+use bitcoin::{
+    consensus::deserialize, hex::DisplayHex, hex::FromHex, psbt::raw, Address, Amount, Network,
+    OutPoint, Transaction, TxOut, Txid,
+};
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Error, Result};
+
+use crate::constants::{PSBT_SP_ADDRESS_KEY, PSBT_SP_PREFIX, PSBT_SP_SUBTYPE};
+use crate::spclient::Psbt;
+use crate::weight::predict_psbt_vsize;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct PsbtFee {
+    pub fee: Amount,
+    pub vsize: u64,
+    /// sat/vB
+    pub fee_rate: f64,
+}
+
+/// Amounts in a psbt we didn't build can be anything, their sum must not overflow
+pub(crate) fn checked_sum(amounts: impl IntoIterator<Item = Amount>) -> Result<Amount> {
+    amounts
+        .into_iter()
+        .try_fold(Amount::ZERO, Amount::checked_add)
+        .ok_or_else(|| Error::msg("Amounts overflow"))
+}
+
+/// Input and output totals, fails if an input has no prevout
+pub(crate) fn get_psbt_totals(psbt: &Psbt) -> Result<(Amount, Amount)> {
+    let input_amts = psbt
+        .iter_funding_utxos()
+        .map(|utxo| utxo.map(|utxo| utxo.value))
+        .collect::<Result<Vec<Amount>, _>>()?;
+    let total_input_amt = checked_sum(input_amts)?;
+    let total_output_amt = checked_sum(psbt.unsigned_tx.output.iter().map(|o| o.value))?;
+    Ok((total_input_amt, total_output_amt))
+}
+
+/// Fee of any psbt, the size of inputs that aren't finalized yet is estimated from their script type
+pub fn get_psbt_fee(psbt: &Psbt) -> Result<PsbtFee> {
+    let (total_input_amt, total_output_amt) = get_psbt_totals(psbt)?;
+    let fee = total_input_amt
+        .checked_sub(total_output_amt)
+        .ok_or_else(|| Error::msg("Outputs exceed inputs"))?;
+
+    let vsize = predict_psbt_vsize(psbt)?;
+
+    Ok(PsbtFee {
+        fee,
+        vsize,
+        fee_rate: fee.to_sat() as f64 / vsize as f64,
+    })
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DecodedOutput {
+    pub value: Amount,
+    pub script_pubkey: String,
+    /// None for scripts without an address, e.g. op_return
+    pub address: Option<String>,
+    /// Silent payment address the output pays, only known from our psbt proprietary fields
+    pub sp_address: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DecodedInput {
+    pub outpoint: OutPoint,
+    pub sequence: u32,
+    pub script_sig: String,
+    pub witness: Vec<String>,
+    /// Only known for psbts
+    pub prevout: Option<DecodedOutput>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DecodedTx {
+    pub txid: Txid,
+    pub version: i32,
+    pub locktime: u32,
+    pub weight: u64,
+    pub inputs: Vec<DecodedInput>,
+    pub outputs: Vec<DecodedOutput>,
+    /// Only known if we have all the prevouts
+    pub fee: Option<Amount>,
+}
+
+fn decode_output(txout: &TxOut, network: Network, sp_address: Option<String>) -> DecodedOutput {
+    DecodedOutput {
+        value: txout.value,
+        script_pubkey: txout.script_pubkey.to_hex_string(),
+        address: Address::from_script(&txout.script_pubkey, network)
+            .ok()
+            .map(|a| a.to_string()),
+        sp_address,
+    }
+}
+
+fn decode(
+    tx: &Transaction,
+    prevouts: Vec<Option<DecodedOutput>>,
+    outputs: Vec<DecodedOutput>,
+) -> Result<DecodedTx> {
+    let output_amt = checked_sum(outputs.iter().map(|o| o.value))?;
+    let fee = match prevouts
+        .iter()
+        .map(|p| p.as_ref().map(|p| p.value))
+        .collect::<Option<Vec<Amount>>>()
+    {
+        Some(input_amts) => checked_sum(input_amts)?.checked_sub(output_amt),
+        None => None,
+    };
+
+    Ok(DecodedTx {
+        txid: tx.txid(),
+        version: tx.version.0,
+        locktime: tx.lock_time.to_consensus_u32(),
+        weight: tx.weight().to_wu(),
+        inputs: tx
+            .input
+            .iter()
+            .zip(prevouts)
+            .map(|(txin, prevout)| DecodedInput {
+                outpoint: txin.previous_output,
+                sequence: txin.sequence.0,
+                script_sig: txin.script_sig.to_hex_string(),
+                witness: txin
+                    .witness
+                    .iter()
+                    .map(|w| w.to_lower_hex_string())
+                    .collect(),
+                prevout,
+            })
+            .collect(),
+        outputs,
+        fee,
+    })
+}
+
+pub fn decode_tx(hex: &str, network: Network) -> Result<DecodedTx> {
+    let tx: Transaction = deserialize(&Vec::<u8>::from_hex(hex)?)?;
+    let outputs = tx
+        .output
+        .iter()
+        .map(|o| decode_output(o, network, None))
+        .collect();
+    decode(&tx, vec![None; tx.input.len()], outputs)
+}
+
+/// `psbt` is base64 encoded, silent payments outputs that aren't filled yet show their placeholder script
+pub fn decode_psbt(psbt: &str, network: Network) -> Result<DecodedTx> {
+    let psbt = Psbt::from_str(psbt)?;
+
+    let prevouts = psbt
+        .iter_funding_utxos()
+        .map(|utxo| utxo.ok().map(|utxo| decode_output(utxo, network, None)))
+        .collect();
+
+    let sp_key = raw::ProprietaryKey {
+        prefix: PSBT_SP_PREFIX.as_bytes().to_vec(),
+        subtype: PSBT_SP_SUBTYPE,
+        key: PSBT_SP_ADDRESS_KEY.as_bytes().to_vec(),
+    };
+    let outputs = psbt
+        .unsigned_tx
+        .output
+        .iter()
+        .zip(psbt.outputs.iter())
+        .map(|(txout, output)| {
+            let sp_address = output
+                .proprietary
+                .get(&sp_key)
+                .map(|value| deserialize::<String>(value))
+                .transpose()?;
+            Ok(decode_output(txout, network, sp_address))
+        })
+        .collect::<Result<Vec<DecodedOutput>>>()?;
+
+    decode(&psbt.unsigned_tx, prevouts, outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::{
+        absolute::LockTime, hashes::Hash, transaction::Version, ScriptBuf, Sequence, TxIn, Witness,
+    };
+
+    #[test]
+    fn overflowing_amounts_are_errors() {
+        let txin = |vout| TxIn {
+            previous_output: OutPoint::new(Txid::from_byte_array([1; 32]), vout),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        };
+        let tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![txin(0), txin(1)],
+            output: vec![TxOut {
+                value: Amount::from_sat(1_000),
+                script_pubkey: ScriptBuf::new_op_return([]),
+            }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        for input in psbt.inputs.iter_mut() {
+            input.witness_utxo = Some(TxOut {
+                value: Amount::from_sat(u64::MAX / 2 + 1),
+                script_pubkey: ScriptBuf::new_op_return([]),
+            });
+        }
+
+        // also what `SpendIntent::check` uses for the fee
+        assert!(get_psbt_totals(&psbt).is_err());
+        assert!(get_psbt_fee(&psbt).is_err());
+        assert!(decode_psbt(&psbt.to_string(), Network::Regtest).is_err());
+    }
+}
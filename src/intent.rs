@@ -0,0 +1,205 @@
+use std::str::FromStr;
+
+use bitcoin::{consensus::deserialize, psbt::raw, Address, Amount};
+use serde::{Deserialize, Serialize};
+
+use silentpayments::utils::SilentPaymentAddress;
+
+use anyhow::{Error, Result};
+
+use crate::constants::{PSBT_SP_ADDRESS_KEY, PSBT_SP_PREFIX, PSBT_SP_SUBTYPE};
+use crate::inspect::{checked_sum, get_psbt_totals};
+use crate::spclient::{get_address_key, try_parse_sp_address, Psbt, Recipient, SpClient};
+
+/// What the user asked for when the psbt was created,
+/// checked again right before signing in case the psbt was tampered with in between
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SpendIntent {
+    pub recipients: Vec<Recipient>,
+    /// Fees may be deducted from a recipient output, so it's also the most a recipient can lose
+    pub max_fee: Amount,
+    /// Recipients that agreed to have the fee deducted from their outputs, all others get their full amount
+    #[serde(default)]
+    pub fee_payers: Vec<String>,
+}
+
+/// Returned wrapped in an `anyhow::Error`, use `downcast_ref` to show which recipient would be shortchanged
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecipientShortfall {
+    /// Address, or output script in hex when we don't know the address
+    pub recipient: String,
+    pub requested: Amount,
+    pub actual: Amount,
+}
+
+impl std::fmt::Display for RecipientShortfall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} would receive {} instead of {}, short of {}",
+            self.recipient,
+            self.actual,
+            self.requested,
+            self.requested - self.actual
+        )
+    }
+}
+
+impl std::error::Error for RecipientShortfall {}
+
+/// Every recipient not in `fee_payers` must receive at least its amount, summed over all its outputs
+/// Meant to be called before broadcasting
+pub fn check_recipient_amounts(
+    psbt: &Psbt,
+    recipients: &[Recipient],
+    fee_payers: &[String],
+) -> Result<()> {
+    for recipient in recipients {
+        if fee_payers
+            .iter()
+            .any(|payer| get_address_key(payer) == get_address_key(&recipient.address))
+        {
+            continue;
+        }
+        let mut paid = vec![];
+        for (vout, txout) in psbt.unsigned_tx.output.iter().enumerate() {
+            if pays_address(psbt, vout, &recipient.address)? {
+                paid.push(txout.value);
+            }
+        }
+        let actual = checked_sum(paid)?;
+        // a recipient listed twice must get both amounts, merged as `normalize_recipients` does
+        let key = get_address_key(&recipient.address);
+        let requested = checked_sum(
+            recipients
+                .iter()
+                .filter(|r| get_address_key(&r.address) == key)
+                .map(|r| r.amount),
+        )?;
+        if actual < requested {
+            return Err(RecipientShortfall {
+                recipient: recipient.address.clone(),
+                requested,
+                actual,
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+impl SpendIntent {
+    pub fn new(recipients: &[Recipient], max_fee: Amount) -> Self {
+        Self {
+            recipients: recipients.to_vec(),
+            max_fee,
+            fee_payers: vec![],
+        }
+    }
+
+    /// The payer given to `set_fees`, if it's one of the recipients
+    pub fn with_fee_payer(mut self, address: &str) -> Self {
+        self.fee_payers.push(address.to_owned());
+        self
+    }
+
+    /// Every recipient must be paid, the only other outputs allowed are our change and an op_return,
+    /// and the fee can't exceed `max_fee`
+    /// Only the `fee_payers` may receive less than what they were asked
+    /// Needs the spend key, to derive silent payments outputs again
+    pub fn check(&self, client: &SpClient, psbt: &Psbt) -> Result<()> {
+        check_recipient_amounts(psbt, &self.recipients, &self.fee_payers)?;
+
+        let change_address = client.sp_receiver.get_change_address();
+
+        let mut unmatched: Vec<usize> = (0..psbt.unsigned_tx.output.len()).collect();
+
+        // a recipient may be listed more than once, and its amount split between several outputs
+        let mut requested: Vec<(String, &str, Amount)> = vec![];
+        for recipient in self.recipients.iter() {
+            let key = get_address_key(&recipient.address);
+            match requested.iter_mut().find(|(k, _, _)| *k == key) {
+                Some((_, _, amount)) => {
+                    *amount = checked_sum([*amount, recipient.amount])?;
+                }
+                None => requested.push((key, &recipient.address, recipient.amount)),
+            }
+        }
+
+        for (_, address, amount) in requested {
+            let min_amount = amount.checked_sub(self.max_fee).unwrap_or(Amount::ZERO);
+            let mut paid = vec![];
+            for vout in unmatched.iter().copied() {
+                if pays_address(psbt, vout, address)? {
+                    paid.push(vout);
+                }
+            }
+            let actual = checked_sum(paid.iter().map(|vout| psbt.unsigned_tx.output[*vout].value))?;
+            if paid.is_empty() || actual > amount || actual < min_amount {
+                return Err(Error::msg(format!(
+                    "No output pays {} to {}",
+                    amount, address
+                )));
+            }
+            unmatched.retain(|vout| !paid.contains(vout));
+        }
+
+        for vout in unmatched {
+            let txout = &psbt.unsigned_tx.output[vout];
+            let is_op_return = txout.script_pubkey.is_op_return() && txout.value == Amount::ZERO;
+            if !is_op_return && !pays_address(psbt, vout, &change_address)? {
+                return Err(Error::msg(format!("Unexpected output {}", vout)));
+            }
+        }
+
+        // the scripts of silent payments outputs must be the ones we derive for their address
+        // outputs to the same address may get their keys in another order once the psbt was reordered
+        let mut expected = psbt.clone();
+        let partial_secret = client.get_partial_secret_from_psbt(psbt)?;
+        client.fill_sp_outputs(&mut expected, partial_secret)?;
+        let mut expected_outputs = expected.unsigned_tx.output;
+        let mut outputs = psbt.unsigned_tx.output.clone();
+        expected_outputs.sort();
+        outputs.sort();
+        if expected_outputs != outputs {
+            return Err(Error::msg(
+                "Silent payments outputs don't match their address",
+            ));
+        }
+
+        let (total_input_amt, total_output_amt) = get_psbt_totals(psbt)?;
+        let fee = total_input_amt
+            .checked_sub(total_output_amt)
+            .ok_or_else(|| Error::msg("Outputs exceed inputs"))?;
+        if fee > self.max_fee {
+            return Err(Error::msg(format!(
+                "Fee of {} is above the maximum of {}",
+                fee, self.max_fee
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Silent payments outputs are matched by the address stored in the psbt, others by script
+fn pays_address(psbt: &Psbt, vout: usize, address: &str) -> Result<bool> {
+    match try_parse_sp_address(address)? {
+        Some(sp_address) => {
+            match psbt.outputs[vout].proprietary.get(&raw::ProprietaryKey {
+                prefix: PSBT_SP_PREFIX.as_bytes().to_vec(),
+                subtype: PSBT_SP_SUBTYPE,
+                key: PSBT_SP_ADDRESS_KEY.as_bytes().to_vec(),
+            }) {
+                Some(value) => Ok(
+                    SilentPaymentAddress::try_from(deserialize::<String>(value)?)? == sp_address,
+                ),
+                None => Ok(false),
+            }
+        }
+        None => {
+            let spk = Address::from_str(address)?.assume_checked().script_pubkey();
+            Ok(psbt.unsigned_tx.output[vout].script_pubkey == spk)
+        }
+    }
+}
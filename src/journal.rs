@@ -0,0 +1,261 @@
+//! Append-only persistence for the outputs of a wallet
+//!
+//! Instead of rewriting the whole `OutputList` on every change, we append the changes to a journal
+//! and only write a full snapshot when compacting. Loading is the snapshot with the journal replayed on top.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use bitcoin::OutPoint;
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Error, Result};
+
+use crate::spclient::{OutputList, OwnedOutput};
+
+const SNAPSHOT_FILE: &str = "outputs.json";
+const JOURNAL_FILE: &str = "outputs.journal";
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum JournalEntry {
+    /// New outputs and outputs whose status changed, e.g. what `update_wallet_with_transaction` returns
+    Upsert(HashMap<OutPoint, OwnedOutput>),
+    LastScan(u32),
+    /// Outputs found or updated in the block at `height`, and `height` as the last scan,
+    /// in a single line so that one can't be persisted without the other
+    ScannedBlock {
+        height: u32,
+        outputs: HashMap<OutPoint, OwnedOutput>,
+    },
+    ResetToHeight(u32),
+    Birthday(u32),
+}
+
+impl JournalEntry {
+    pub fn apply(&self, outputs: &mut OutputList) {
+        match self {
+            Self::Upsert(new) => outputs.extend_from(new.clone()),
+            Self::LastScan(height) => outputs.update_last_scan(*height),
+            Self::ScannedBlock {
+                height,
+                outputs: new,
+            } => {
+                outputs.extend_from(new.clone());
+                outputs.update_last_scan(*height);
+            }
+            Self::ResetToHeight(height) => outputs.reset_to_height(*height),
+            Self::Birthday(height) => outputs.set_birthday(*height),
+        }
+    }
+}
+
+pub struct Journal {
+    dir: PathBuf,
+    file: File,
+    entries_since_compaction: usize,
+    /// Compact once the journal has that many entries
+    compact_every: usize,
+}
+
+impl Journal {
+    /// Load the outputs stored in `dir`, `new_outputs` is used if there's no snapshot yet
+    pub fn open(
+        dir: &Path,
+        compact_every: usize,
+        new_outputs: OutputList,
+    ) -> Result<(Self, OutputList)> {
+        fs::create_dir_all(dir)?;
+
+        let snapshot_path = dir.join(SNAPSHOT_FILE);
+        let mut outputs = if snapshot_path.exists() {
+            serde_json::from_str(&fs::read_to_string(&snapshot_path)?)?
+        } else {
+            new_outputs
+        };
+
+        let journal_path = dir.join(JOURNAL_FILE);
+        let mut entries = 0;
+        if journal_path.exists() {
+            let lines: Vec<String> = BufReader::new(File::open(&journal_path)?)
+                .lines()
+                .collect::<Result<_, _>>()?;
+            for (i, line) in lines.iter().enumerate() {
+                match serde_json::from_str::<JournalEntry>(line) {
+                    Ok(entry) => {
+                        entry.apply(&mut outputs);
+                        entries += 1;
+                    }
+                    // the last write may have been interrupted, anything before it is fine
+                    Err(_) if i == lines.len() - 1 => break,
+                    Err(e) => {
+                        return Err(Error::msg(format!(
+                            "Corrupted journal at line {}: {}",
+                            i, e
+                        )))
+                    }
+                }
+            }
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&journal_path)?;
+
+        let mut journal = Self {
+            dir: dir.to_owned(),
+            file,
+            entries_since_compaction: entries,
+            compact_every,
+        };
+
+        // also gets rid of an interrupted last line
+        if entries > 0 {
+            journal.compact(&outputs)?;
+        }
+
+        Ok((journal, outputs))
+    }
+
+    /// Persist `entry`, it's durable when this returns
+    pub fn append(&mut self, entry: &JournalEntry) -> Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()?;
+        self.entries_since_compaction += 1;
+        Ok(())
+    }
+
+    /// Append all `entries` with a single sync
+    pub fn append_all(&mut self, entries: &[JournalEntry]) -> Result<()> {
+        let mut lines = String::new();
+        for entry in entries {
+            lines.push_str(&serde_json::to_string(entry)?);
+            lines.push('\n');
+        }
+        self.file.write_all(lines.as_bytes())?;
+        self.file.sync_data()?;
+        self.entries_since_compaction += entries.len();
+        Ok(())
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        self.entries_since_compaction >= self.compact_every
+    }
+
+    /// Write `outputs` as the new snapshot and empty the journal
+    /// `outputs` must be the current state, i.e. every appended entry applied
+    pub fn compact(&mut self, outputs: &OutputList) -> Result<()> {
+        let snapshot_path = self.dir.join(SNAPSHOT_FILE);
+        let tmp_path = self.dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(serde_json::to_string(outputs)?.as_bytes())?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &snapshot_path)?;
+
+        // if we crash before this, the journal is replayed on a snapshot that already has it,
+        // which gives the same state since entries are idempotent
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.entries_since_compaction = 0;
+
+        Ok(())
+    }
+
+    pub fn compact_if_needed(&mut self, outputs: &OutputList) -> Result<()> {
+        if self.needs_compaction() {
+            self.compact(outputs)?;
+        }
+        Ok(())
+    }
+}
+
+/// Buffers the changes of a scan and writes them in batches, every `flush_every_blocks` blocks
+/// or `flush_interval`, whichever comes first
+/// Entries are written in the order they were recorded, so a `LastScan` is never persisted
+/// before the outputs found up to that height
+pub struct BatchedJournal {
+    journal: Journal,
+    pending: Vec<JournalEntry>,
+    blocks_since_flush: u32,
+    last_flush: Instant,
+    flush_every_blocks: u32,
+    flush_interval: Duration,
+}
+
+impl BatchedJournal {
+    pub fn new(journal: Journal, flush_every_blocks: u32, flush_interval: Duration) -> Self {
+        Self {
+            journal,
+            pending: vec![],
+            blocks_since_flush: 0,
+            last_flush: Instant::now(),
+            flush_every_blocks,
+            flush_interval,
+        }
+    }
+
+    pub fn record(&mut self, entry: JournalEntry) {
+        if let JournalEntry::Upsert(ref outputs) = entry {
+            if outputs.is_empty() {
+                return;
+            }
+        }
+        self.pending.push(entry);
+    }
+
+    /// Call once all the outputs of the block at `height` were recorded
+    pub fn record_block(&mut self, height: u32) -> Result<()> {
+        self.pending.push(JournalEntry::LastScan(height));
+        self.end_block()
+    }
+
+    /// Instead of `record` and `record_block`, commits the block as a whole:
+    /// after a crash the wallet is back at the last block that was flushed, never half way through one
+    pub fn record_scanned_block(
+        &mut self,
+        height: u32,
+        outputs: HashMap<OutPoint, OwnedOutput>,
+    ) -> Result<()> {
+        self.pending
+            .push(JournalEntry::ScannedBlock { height, outputs });
+        self.end_block()
+    }
+
+    fn end_block(&mut self) -> Result<()> {
+        self.blocks_since_flush += 1;
+
+        if self.blocks_since_flush >= self.flush_every_blocks
+            || self.last_flush.elapsed() >= self.flush_interval
+        {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Must also be called when the scan is cancelled, or the pending changes are lost
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.pending.is_empty() {
+            self.journal.append_all(&self.pending)?;
+            self.pending.clear();
+        }
+        self.blocks_since_flush = 0;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+
+    /// End of the scan, `outputs` is the state with everything recorded applied
+    pub fn finish(mut self, outputs: &OutputList) -> Result<Journal> {
+        self.flush()?;
+        self.journal.compact_if_needed(outputs)?;
+        Ok(self.journal)
+    }
+}
@@ -0,0 +1,290 @@
+use bitcoin::{
+    secp256k1::{PublicKey, Secp256k1, SecretKey},
+    Network,
+};
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Error, Result};
+use zeroize::Zeroizing;
+
+use crate::spclient::{SpClient, SpendKey};
+
+/// Callback interface to a platform key store (Android Keystore, iOS Keychain, secure element...)
+/// Secrets are addressed by an opaque reference, the reference is the only thing that ends up on disk
+pub trait KeyStore {
+    fn store_secret(&self, key_ref: &str, secret: &[u8]) -> Result<()>;
+    fn load_secret(&self, key_ref: &str) -> Result<Vec<u8>>;
+    fn delete_secret(&self, key_ref: &str) -> Result<()>;
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum StoredScanKey {
+    Plain(SecretKey),
+    Reference(String),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum StoredSpendKey {
+    // We keep the public key next to the reference so that we can scan without unlocking the store
+    Reference {
+        key_ref: String,
+        public_key: PublicKey,
+    },
+    Public(PublicKey),
+}
+
+/// What gets persisted instead of a `SpClient` when secrets are held by a `KeyStore`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct KeyStoreClient {
+    pub label: String,
+    scan_key: StoredScanKey,
+    spend_key: StoredSpendKey,
+    mnemonic_ref: Option<String>,
+    network: Network,
+    /// Incremented by `rekey`, so that new secrets never overwrite the ones still in use
+    #[serde(default)]
+    generation: u32,
+}
+
+fn get_key_ref(label: &str, name: &str, generation: u32) -> String {
+    match generation {
+        0 => format!("sp_client/{}/{}", label, name),
+        n => format!("sp_client/{}/{}/{}", label, name, n),
+    }
+}
+
+impl KeyStoreClient {
+    /// Move the client secrets to `store`
+    /// The scan key is only moved if `protect_scan_key` is set, since it's needed for every scan
+    pub fn from_client(
+        client: &SpClient,
+        store: &dyn KeyStore,
+        protect_scan_key: bool,
+    ) -> Result<Self> {
+        let scan_key = if protect_scan_key {
+            let key_ref = get_key_ref(&client.label, "scan", 0);
+            store.store_secret(&key_ref, &client.get_scan_key().secret_bytes())?;
+            StoredScanKey::Reference(key_ref)
+        } else {
+            StoredScanKey::Plain(client.get_scan_key())
+        };
+
+        let spend_key = match client.get_spend_key() {
+            SpendKey::Secret(sk) => {
+                let key_ref = get_key_ref(&client.label, "spend", 0);
+                store.store_secret(&key_ref, &sk.secret_bytes())?;
+                StoredSpendKey::Reference {
+                    key_ref,
+                    public_key: sk.public_key(&Secp256k1::signing_only()),
+                }
+            }
+            SpendKey::Public(pk) => StoredSpendKey::Public(pk),
+        };
+
+        let mnemonic_ref = match client.get_mnemonic().map(Zeroizing::new) {
+            Some(mnemonic) => {
+                let key_ref = get_key_ref(&client.label, "mnemonic", 0);
+                store.store_secret(&key_ref, mnemonic.as_bytes())?;
+                Some(key_ref)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            label: client.label.clone(),
+            scan_key,
+            spend_key,
+            mnemonic_ref,
+            network: client.get_network(),
+            generation: 0,
+        })
+    }
+
+    /// Rebuild the client from the secrets in `store`
+    /// If `with_spend_key` is false the spend secret stays in the store and we get a watch-only client
+    pub fn load(&self, store: &dyn KeyStore, with_spend_key: bool) -> Result<SpClient> {
+        let scan_sk = match &self.scan_key {
+            StoredScanKey::Plain(sk) => *sk,
+            StoredScanKey::Reference(key_ref) => {
+                let secret = Zeroizing::new(store.load_secret(key_ref)?);
+                SecretKey::from_slice(&secret)?
+            }
+        };
+
+        let spend_key = match &self.spend_key {
+            StoredSpendKey::Reference {
+                key_ref,
+                public_key,
+            } => {
+                if with_spend_key {
+                    let secret = Zeroizing::new(store.load_secret(key_ref)?);
+                    SpendKey::Secret(SecretKey::from_slice(&secret)?)
+                } else {
+                    SpendKey::Public(*public_key)
+                }
+            }
+            StoredSpendKey::Public(pk) => SpendKey::Public(*pk),
+        };
+
+        let mnemonic = match (&self.mnemonic_ref, with_spend_key) {
+            (Some(key_ref), true) => {
+                let secret = Zeroizing::new(store.load_secret(key_ref)?);
+                Some(std::str::from_utf8(&secret)?.to_owned())
+            }
+            _ => None,
+        };
+
+        SpClient::new(
+            self.label.clone(),
+            scan_sk,
+            spend_key,
+            mnemonic,
+            self.network,
+        )
+    }
+
+    /// Copy all the secrets from `old_store` to `new_store`, e.g. when the password protecting the store changes
+    /// They're written under new references, even if both are the same store, and read back to
+    /// check them. If anything fails what was written is removed and nothing else changes
+    /// Persist the returned client, then `delete_secrets` of this one from `old_store`
+    pub fn rekey(&self, old_store: &dyn KeyStore, new_store: &dyn KeyStore) -> Result<Self> {
+        let mut rekeyed = self.clone();
+        rekeyed.generation = self.generation + 1;
+        let generation = rekeyed.generation;
+        let new_ref = |name: &str| get_key_ref(&self.label, name, generation);
+        if let StoredScanKey::Reference(key_ref) = &mut rekeyed.scan_key {
+            *key_ref = new_ref("scan");
+        }
+        if let StoredSpendKey::Reference { key_ref, .. } = &mut rekeyed.spend_key {
+            *key_ref = new_ref("spend");
+        }
+        if let Some(key_ref) = &mut rekeyed.mnemonic_ref {
+            *key_ref = new_ref("mnemonic");
+        }
+
+        let mut written = vec![];
+        for (old_ref, new_ref) in self.get_key_refs().into_iter().zip(rekeyed.get_key_refs()) {
+            let res = old_store
+                .load_secret(old_ref)
+                .map(Zeroizing::new)
+                .and_then(|secret| {
+                    new_store.store_secret(new_ref, &secret)?;
+                    if *Zeroizing::new(new_store.load_secret(new_ref)?) != *secret {
+                        return Err(Error::msg(format!(
+                            "Secret {} not stored correctly",
+                            new_ref
+                        )));
+                    }
+                    Ok(())
+                });
+            written.push(new_ref);
+            if let Err(e) = res {
+                for key_ref in written {
+                    let _ = new_store.delete_secret(key_ref);
+                }
+                return Err(e);
+            }
+        }
+
+        Ok(rekeyed)
+    }
+
+    fn get_key_refs(&self) -> Vec<&String> {
+        let mut refs = vec![];
+        if let StoredScanKey::Reference(key_ref) = &self.scan_key {
+            refs.push(key_ref);
+        }
+        if let StoredSpendKey::Reference { key_ref, .. } = &self.spend_key {
+            refs.push(key_ref);
+        }
+        if let Some(key_ref) = &self.mnemonic_ref {
+            refs.push(key_ref);
+        }
+        refs
+    }
+
+    /// Remove every secret this client references from `store`
+    pub fn delete_secrets(&self, store: &dyn KeyStore) -> Result<()> {
+        for key_ref in self.get_key_refs() {
+            store.delete_secret(key_ref)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{cell::RefCell, collections::HashMap};
+
+    #[derive(Default)]
+    struct MemoryStore {
+        secrets: RefCell<HashMap<String, Vec<u8>>>,
+        read_only: bool,
+    }
+
+    impl KeyStore for MemoryStore {
+        fn store_secret(&self, key_ref: &str, secret: &[u8]) -> Result<()> {
+            if self.read_only {
+                return Err(Error::msg("Read only"));
+            }
+            self.secrets
+                .borrow_mut()
+                .insert(key_ref.to_owned(), secret.to_vec());
+            Ok(())
+        }
+
+        fn load_secret(&self, key_ref: &str) -> Result<Vec<u8>> {
+            self.secrets
+                .borrow()
+                .get(key_ref)
+                .cloned()
+                .ok_or_else(|| Error::msg("Unknown secret"))
+        }
+
+        fn delete_secret(&self, key_ref: &str) -> Result<()> {
+            self.secrets.borrow_mut().remove(key_ref);
+            Ok(())
+        }
+    }
+
+    use crate::test_utils::client_from_seeds;
+
+    fn test_client() -> SpClient {
+        client_from_seeds(0x11, 0x22, Some("abandon"), Network::Regtest)
+    }
+
+    #[test]
+    fn rekey_in_the_same_store() {
+        let client = test_client();
+        let store = MemoryStore::default();
+        let stored = KeyStoreClient::from_client(&client, &store, true).unwrap();
+
+        let rekeyed = stored.rekey(&store, &store).unwrap();
+        // both are usable until the old secrets are deleted
+        assert_eq!(stored.load(&store, true).unwrap(), client);
+        assert_eq!(rekeyed.load(&store, true).unwrap(), client);
+
+        stored.delete_secrets(&store).unwrap();
+        assert_eq!(rekeyed.load(&store, true).unwrap(), client);
+        assert_eq!(store.secrets.borrow().len(), 3);
+    }
+
+    #[test]
+    fn failed_rekey_changes_nothing() {
+        let client = test_client();
+        let store = MemoryStore::default();
+        let stored = KeyStoreClient::from_client(&client, &store, true).unwrap();
+        let before = store.secrets.borrow().clone();
+
+        let broken = MemoryStore {
+            read_only: true,
+            ..Default::default()
+        };
+        assert!(stored.rekey(&store, &broken).is_err());
+        assert!(broken.secrets.borrow().is_empty());
+        assert_eq!(*store.secrets.borrow(), before);
+        assert_eq!(stored.load(&store, true).unwrap(), client);
+    }
+}
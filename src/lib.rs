@@ -1,5 +1,69 @@
+pub mod amounts;
+pub mod anti_exfil;
+pub mod audit;
+pub mod auth;
+pub mod block_times;
+pub mod bump;
+pub mod chain;
+pub mod coin_selection;
+pub mod coinjoin;
+pub mod config;
+pub mod confirmations;
+#[cfg(feature = "conformance")]
+pub mod conformance;
+pub mod consolidation;
 pub mod constants;
+pub mod cosigning;
+pub mod descriptors;
+pub mod events;
+pub mod history;
+pub mod import;
+pub mod inspect;
+pub mod intent;
+pub mod journal;
+pub mod keystore;
+#[cfg(feature = "mempool-space")]
+pub mod mempool_space;
+pub mod merge;
+pub mod metrics;
+pub mod mnemonic;
+#[cfg(any(test, feature = "mock-chain"))]
+pub mod mock_chain;
+pub mod multiparty;
+pub mod musig;
+pub mod outbox;
+pub mod ownership;
+pub mod payment_proof;
+pub mod payment_request;
+pub mod policy;
+pub mod privacy;
+pub mod psbt_data;
+pub mod push;
+pub mod qr;
+pub mod rate_limit;
+pub mod rates;
+#[cfg(feature = "regtest")]
+pub mod regtest;
+pub mod rng;
+pub mod rotation;
+pub mod sealed;
+pub mod settings;
+pub mod shutdown;
+pub mod signer;
+pub mod slip39;
 pub mod spclient;
+pub mod standardness;
+pub mod state_sync;
+pub mod sync_status;
+#[cfg(test)]
+mod test_utils;
+pub mod wallet_lock;
+pub mod watch_list;
+pub mod watch_only;
+pub mod webhook;
+pub mod weight;
+pub mod workers;
+pub mod zero_conf;
 
 pub use bitcoin;
 pub use silentpayments;
@@ -0,0 +1,241 @@
+//! Client for the mempool.space api, or a self hosted instance of it
+//!
+//! Only available with the `mempool-space` feature.
+
+use bitcoin::{
+    consensus::serialize, hex::DisplayHex, Amount, BlockHash, OutPoint, ScriptBuf, Transaction,
+    TxOut, Txid,
+};
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Error, Result};
+
+use crate::chain::{
+    BroadcastError, Broadcaster, ChainBackend, ChainOutput, ConfirmationSource, ConfirmationStatus,
+    FeeEstimator,
+};
+
+pub const MEMPOOL_SPACE_URL: &str = "https://mempool.space/api";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RecommendedFees {
+    pub fastest_fee: u64,
+    pub half_hour_fee: u64,
+    pub hour_fee: u64,
+    pub economy_fee: u64,
+    pub minimum_fee: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct TxStatus {
+    confirmed: bool,
+    block_height: Option<u32>,
+    block_hash: Option<BlockHash>,
+    block_time: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TxVout {
+    scriptpubkey: String,
+    value: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Tx {
+    weight: u64,
+    fee: u64,
+    vout: Vec<TxVout>,
+    status: TxStatus,
+}
+
+#[derive(Debug, Deserialize)]
+struct Outspend {
+    spent: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MempoolBlock {
+    block_v_size: f64,
+    /// Lowest and highest fee rates in the block
+    fee_range: Vec<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum BroadcastStatus {
+    Confirmed {
+        blockheight: u32,
+        blockhash: BlockHash,
+    },
+    InMempool {
+        /// sat/vB
+        fee_rate: f64,
+        /// vbytes of transactions that should be mined before ours
+        vsize_ahead: u64,
+        /// Number of blocks until confirmation, None if we're below the projected blocks
+        eta_blocks: Option<u32>,
+    },
+    /// Never seen or evicted
+    Unknown,
+}
+
+pub struct MempoolSpaceClient {
+    base_url: String,
+}
+
+impl Default for MempoolSpaceClient {
+    fn default() -> Self {
+        Self::new(MEMPOOL_SPACE_URL)
+    }
+}
+
+impl MempoolSpaceClient {
+    /// `base_url` is the api root, e.g. `https://mempool.space/api` or `https://mempool.space/signet/api`
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+        }
+    }
+
+    fn get(&self, path: &str) -> Result<ureq::Response> {
+        Ok(ureq::get(&format!("{}{}", self.base_url, path)).call()?)
+    }
+
+    /// None on a 404, the api doesn't know about it
+    fn get_opt(&self, path: &str) -> Result<Option<ureq::Response>> {
+        match ureq::get(&format!("{}{}", self.base_url, path)).call() {
+            Ok(response) => Ok(Some(response)),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn get_recommended_fees(&self) -> Result<RecommendedFees> {
+        Ok(self.get("/v1/fees/recommended")?.into_json()?)
+    }
+
+    /// Where our transaction stands, from its fee rate and the projected next blocks
+    pub fn get_broadcast_status(&self, txid: &Txid) -> Result<BroadcastStatus> {
+        let tx: Tx = match self.get_opt(&format!("/tx/{}", txid))? {
+            Some(response) => response.into_json()?,
+            None => return Ok(BroadcastStatus::Unknown),
+        };
+
+        if tx.status.confirmed {
+            return Ok(BroadcastStatus::Confirmed {
+                blockheight: tx
+                    .status
+                    .block_height
+                    .ok_or_else(|| Error::msg("Missing block height"))?,
+                blockhash: tx
+                    .status
+                    .block_hash
+                    .ok_or_else(|| Error::msg("Missing block hash"))?,
+            });
+        }
+
+        let fee_rate = tx.fee as f64 / (tx.weight as f64 / 4.0);
+        let blocks: Vec<MempoolBlock> = self.get("/v1/fees/mempool-blocks")?.into_json()?;
+
+        let mut vsize_ahead = 0f64;
+        let mut eta_blocks = None;
+        for (i, block) in blocks.iter().enumerate() {
+            let min_fee_rate = block.fee_range.first().copied().unwrap_or(0.0);
+            if fee_rate >= min_fee_rate {
+                eta_blocks = Some(i as u32 + 1);
+                break;
+            }
+            vsize_ahead += block.block_v_size;
+        }
+
+        Ok(BroadcastStatus::InMempool {
+            fee_rate,
+            vsize_ahead: vsize_ahead as u64,
+            eta_blocks,
+        })
+    }
+}
+
+impl FeeEstimator for MempoolSpaceClient {
+    fn get_fee_rate(&self, target_blocks: u32) -> Result<Amount> {
+        let fees = self.get_recommended_fees()?;
+        let rate = match target_blocks {
+            0 | 1 => fees.fastest_fee,
+            2 | 3 => fees.half_hour_fee,
+            4..=6 => fees.hour_fee,
+            _ => fees.economy_fee,
+        };
+        Ok(Amount::from_sat(rate.max(fees.minimum_fee)))
+    }
+}
+
+impl ChainBackend for MempoolSpaceClient {
+    fn get_tip_height(&self) -> Result<u32> {
+        Ok(self
+            .get("/blocks/tip/height")?
+            .into_string()?
+            .trim()
+            .parse()?)
+    }
+
+    fn get_output(&self, outpoint: &OutPoint) -> Result<Option<ChainOutput>> {
+        let tx: Tx = match self.get_opt(&format!("/tx/{}", outpoint.txid))? {
+            Some(response) => response.into_json()?,
+            None => return Ok(None),
+        };
+        let vout = match tx.vout.get(outpoint.vout as usize) {
+            Some(vout) => vout,
+            None => return Ok(None),
+        };
+        let outspend: Outspend = self
+            .get(&format!("/tx/{}/outspend/{}", outpoint.txid, outpoint.vout))?
+            .into_json()?;
+
+        Ok(Some(ChainOutput {
+            txout: TxOut {
+                value: Amount::from_sat(vout.value),
+                script_pubkey: ScriptBuf::from_hex(&vout.scriptpubkey)?,
+            },
+            blockheight: tx.status.block_height.filter(|_| tx.status.confirmed),
+            spent: outspend.spent,
+        }))
+    }
+}
+
+impl ConfirmationSource for MempoolSpaceClient {
+    fn get_confirmation_status(&self, txid: &Txid) -> Result<ConfirmationStatus> {
+        let status: TxStatus = match self.get_opt(&format!("/tx/{}/status", txid))? {
+            Some(response) => response.into_json()?,
+            None => return Ok(ConfirmationStatus::Unknown),
+        };
+        if !status.confirmed {
+            return Ok(ConfirmationStatus::InMempool);
+        }
+        match (status.block_height, status.block_hash, status.block_time) {
+            (Some(blockheight), Some(blockhash), Some(block_time)) => {
+                Ok(ConfirmationStatus::Confirmed {
+                    blockheight,
+                    blockhash,
+                    block_time,
+                })
+            }
+            _ => Err(Error::msg("Missing block of confirmed transaction")),
+        }
+    }
+}
+
+impl Broadcaster for MempoolSpaceClient {
+    fn broadcast_tx(&self, tx: &Transaction) -> Result<Txid> {
+        match ureq::post(&format!("{}/tx", self.base_url))
+            .send_string(&serialize(tx).to_lower_hex_string())
+        {
+            Ok(response) => Ok(response.into_string()?.trim().parse()?),
+            // the body is the error of the node, e.g. `sendrawtransaction RPC error: {"code":-26,"message":"..."}`
+            Err(ureq::Error::Status(_, response)) => {
+                Err(BroadcastError::from_message(&response.into_string()?).into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
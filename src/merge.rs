@@ -0,0 +1,213 @@
+//! Merge rules for the state edited on several devices at once, e.g. two phones with the same seed
+//!
+//! Merging gives the same result in whatever order and however many times the devices exchange
+//! their state, so that they all end up in agreement:
+//! - outputs are the union of what both sides know
+//! - the spend status only moves forward, unspent < spent < mined
+//! - a confirmation wins over no confirmation
+//! - memos are last writer wins, on the time they were set
+//!
+//! Ties are broken on the values themselves, never on which side is ours.
+//! `quarantined` is left out, each device keeps its own decision.
+
+use bitcoin::{BlockHash, Txid};
+
+use anyhow::{Error, Result};
+
+use crate::history::TxHistory;
+use crate::spclient::{OutputSpendStatus, OwnedOutput, UNCONFIRMED_HEIGHT};
+
+fn status_key(status: &OutputSpendStatus) -> (u8, &str) {
+    match status {
+        OutputSpendStatus::Unspent => (0, ""),
+        OutputSpendStatus::Spent(txid) => (1, txid),
+        OutputSpendStatus::Mined(blockhash) => (2, blockhash),
+    }
+}
+
+fn confirmation_key(output: &OwnedOutput) -> (bool, u32, Option<BlockHash>) {
+    (
+        output.blockheight != UNCONFIRMED_HEIGHT,
+        output.blockheight,
+        output.blockhash,
+    )
+}
+
+/// Whether `status` is further along than `other`, e.g. mined is further than spent
+pub fn is_more_advanced(status: &OutputSpendStatus, other: &OutputSpendStatus) -> bool {
+    status_key(status) > status_key(other)
+}
+
+/// The same output as known by two devices, `ours` only matters for `quarantined`
+pub fn merge_output(ours: &OwnedOutput, theirs: &OwnedOutput) -> OwnedOutput {
+    let mut merged = ours.clone();
+    if is_more_advanced(&theirs.spend_status, &ours.spend_status) {
+        merged.spend_status = theirs.spend_status.clone();
+    }
+    if confirmation_key(theirs) > confirmation_key(ours) {
+        merged.blockheight = theirs.blockheight;
+        merged.blockhash = theirs.blockhash;
+        merged.block_time = theirs.block_time;
+    }
+    merged.label = ours.label.clone().max(theirs.label.clone());
+    merged
+}
+
+impl TxHistory {
+    /// Takes `memo` if it was set after ours, returns whether it was
+    pub fn merge_memo(
+        &mut self,
+        txid: &Txid,
+        memo: Option<String>,
+        updated_at: u64,
+    ) -> Result<bool> {
+        let record = self
+            .get_record(txid)
+            .ok_or_else(|| Error::msg(format!("Unknown transaction {}", txid)))?;
+        if (updated_at, &memo) > (record.memo_updated_at, &record.memo) {
+            self.set_memo(txid, memo, updated_at)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::{absolute::LockTime, hashes::Hash, transaction::Version, Amount, Transaction};
+
+    use crate::spclient::SpWallet;
+    use crate::test_utils::test_client;
+
+    fn output(
+        spend_status: OutputSpendStatus,
+        blockheight: u32,
+        label: Option<&str>,
+    ) -> OwnedOutput {
+        OwnedOutput {
+            blockheight,
+            tweak: String::new(),
+            amount: Amount::from_sat(1000),
+            script: String::new(),
+            label: label.map(str::to_owned),
+            spend_status,
+            quarantined: false,
+            blockhash: (blockheight != UNCONFIRMED_HEIGHT)
+                .then(|| BlockHash::from_byte_array([blockheight as u8; 32])),
+            block_time: None,
+        }
+    }
+
+    fn variants() -> Vec<OwnedOutput> {
+        let statuses = [
+            OutputSpendStatus::Unspent,
+            OutputSpendStatus::Spent("aa".repeat(32)),
+            OutputSpendStatus::Spent("bb".repeat(32)),
+            OutputSpendStatus::Mined("cc".repeat(32)),
+            OutputSpendStatus::Mined("dd".repeat(32)),
+        ];
+        let mut res = vec![];
+        for status in statuses {
+            for height in [UNCONFIRMED_HEIGHT, 100, 101] {
+                for label in [None, Some("a"), Some("b")] {
+                    res.push(output(status.clone(), height, label));
+                }
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn merge_output_is_commutative() {
+        for a in variants() {
+            for b in variants() {
+                assert_eq!(merge_output(&a, &b), merge_output(&b, &a));
+            }
+        }
+    }
+
+    #[test]
+    fn merge_output_is_idempotent() {
+        for a in variants() {
+            assert_eq!(merge_output(&a, &a), a);
+            for b in variants() {
+                let merged = merge_output(&a, &b);
+                assert_eq!(merge_output(&merged, &b), merged);
+                assert_eq!(merge_output(&merged, &a), merged);
+            }
+        }
+    }
+
+    #[test]
+    fn merge_output_only_moves_forward() {
+        for a in variants() {
+            for b in variants() {
+                let merged = merge_output(&a, &b);
+                assert!(!is_more_advanced(&a.spend_status, &merged.spend_status));
+                assert!(!is_more_advanced(&b.spend_status, &merged.spend_status));
+                assert!(confirmation_key(&merged) >= confirmation_key(&a));
+                assert!(confirmation_key(&merged) >= confirmation_key(&b));
+            }
+        }
+    }
+
+    fn history_with(tx: &Transaction) -> TxHistory {
+        let wallet = SpWallet::new(test_client(), None).unwrap();
+        let mut history = TxHistory::default();
+        history.record(&wallet, tx, 100, 0).unwrap();
+        history
+    }
+
+    fn memo_of(history: &TxHistory, txid: &Txid) -> (Option<String>, u64) {
+        let record = history.get_record(txid).unwrap();
+        (record.memo.clone(), record.memo_updated_at)
+    }
+
+    #[test]
+    fn merge_memo_converges() {
+        let tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![],
+        };
+        let txid = tx.txid();
+        let memos = [
+            (None, 0),
+            (Some("a".to_owned()), 1),
+            (Some("b".to_owned()), 1),
+            (None, 2),
+            (Some("a".to_owned()), 3),
+        ];
+
+        for a in memos.iter() {
+            for b in memos.iter() {
+                let mut ab = history_with(&tx);
+                ab.merge_memo(&txid, a.0.clone(), a.1).unwrap();
+                ab.merge_memo(&txid, b.0.clone(), b.1).unwrap();
+                let mut ba = history_with(&tx);
+                ba.merge_memo(&txid, b.0.clone(), b.1).unwrap();
+                ba.merge_memo(&txid, a.0.clone(), a.1).unwrap();
+                assert_eq!(memo_of(&ab, &txid), memo_of(&ba, &txid));
+
+                // merging the same memo again changes nothing
+                assert!(!ab.merge_memo(&txid, a.0.clone(), a.1).unwrap());
+                assert!(!ab.merge_memo(&txid, b.0.clone(), b.1).unwrap());
+                assert!(memo_of(&ab, &txid).1 >= a.1.max(b.1));
+            }
+        }
+
+        let mut history = history_with(&tx);
+        history
+            .merge_memo(&txid, Some("new".to_owned()), 5)
+            .unwrap();
+        assert!(!history
+            .merge_memo(&txid, Some("old".to_owned()), 4)
+            .unwrap());
+        assert_eq!(memo_of(&history, &txid), (Some("new".to_owned()), 5));
+        assert!(history.merge_memo(&Txid::all_zeros(), None, 0).is_err());
+    }
+}
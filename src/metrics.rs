@@ -0,0 +1,126 @@
+//! Counters for monitoring a hosted instance, rendered in the Prometheus text format
+//!
+//! Serving `render_prometheus` over http is up to the daemon.
+
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// Upper bounds of the scan duration histogram, in seconds
+const SCAN_DURATION_BUCKETS: [f64; 8] = [0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// One count per bucket, not cumulative
+    counts: [u64; SCAN_DURATION_BUCKETS.len()],
+    /// Above the last bucket
+    overflow: u64,
+    sum: f64,
+}
+
+/// Shared by everything that reports, e.g. in an `Arc`
+#[derive(Debug, Default)]
+pub struct Metrics {
+    blocks_scanned: AtomicU64,
+    outputs_found: AtomicU64,
+    broadcast_attempts: AtomicU64,
+    broadcast_failures: AtomicU64,
+    peer_count: AtomicU64,
+    scan_duration: Mutex<Histogram>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_blocks_scanned(&self, count: u64) {
+        self.blocks_scanned.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn add_outputs_found(&self, count: u64) {
+        self.outputs_found.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn record_broadcast(&self, success: bool) {
+        self.broadcast_attempts.fetch_add(1, Ordering::Relaxed);
+        if !success {
+            self.broadcast_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn set_peer_count(&self, count: u64) {
+        self.peer_count.store(count, Ordering::Relaxed);
+    }
+
+    pub fn record_scan_duration(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let mut histogram = self.scan_duration.lock().unwrap_or_else(|e| e.into_inner());
+        match SCAN_DURATION_BUCKETS.iter().position(|le| secs <= *le) {
+            Some(i) => histogram.counts[i] += 1,
+            None => histogram.overflow += 1,
+        }
+        histogram.sum += secs;
+    }
+
+    pub fn render_prometheus(&self) -> String {
+        let mut res = String::new();
+
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(res, "# HELP {} {}", name, help);
+            let _ = writeln!(res, "# TYPE {} {}", name, kind);
+            let _ = writeln!(res, "{} {}", name, value);
+        };
+        metric(
+            "sp_blocks_scanned_total",
+            "counter",
+            "Blocks scanned",
+            self.blocks_scanned.load(Ordering::Relaxed),
+        );
+        metric(
+            "sp_outputs_found_total",
+            "counter",
+            "Outputs found while scanning",
+            self.outputs_found.load(Ordering::Relaxed),
+        );
+        metric(
+            "sp_broadcast_attempts_total",
+            "counter",
+            "Transactions we tried to broadcast",
+            self.broadcast_attempts.load(Ordering::Relaxed),
+        );
+        metric(
+            "sp_broadcast_failures_total",
+            "counter",
+            "Transactions the network refused",
+            self.broadcast_failures.load(Ordering::Relaxed),
+        );
+        metric(
+            "sp_peer_count",
+            "gauge",
+            "Connected peers",
+            self.peer_count.load(Ordering::Relaxed),
+        );
+
+        let histogram = self.scan_duration.lock().unwrap_or_else(|e| e.into_inner());
+        let name = "sp_scan_duration_seconds";
+        let _ = writeln!(res, "# HELP {} Duration of scans", name);
+        let _ = writeln!(res, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (le, count) in SCAN_DURATION_BUCKETS.iter().zip(histogram.counts.iter()) {
+            cumulative += count;
+            let _ = writeln!(res, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative);
+        }
+        cumulative += histogram.overflow;
+        let _ = writeln!(res, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative);
+        let _ = writeln!(res, "{}_sum {}", name, histogram.sum);
+        let _ = writeln!(res, "{}_count {}", name, cumulative);
+
+        res
+    }
+}
@@ -0,0 +1,163 @@
+//! Detailed feedback on a mnemonic being typed, to help the user find their typos
+
+use bip39::{Language, Mnemonic};
+use serde::{Deserialize, Serialize};
+
+use crate::spclient::MnemonicLanguage;
+
+pub const VALID_WORD_COUNTS: [usize; 5] = [12, 15, 18, 21, 24];
+
+/// Further than that it's not a typo anymore
+const MAX_SUGGESTION_DISTANCE: usize = 2;
+const MAX_SUGGESTIONS: usize = 3;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum MnemonicIssue {
+    /// `position` is 0 based, `suggestions` are the closest words of the wordlist
+    UnknownWord {
+        position: usize,
+        word: String,
+        suggestions: Vec<String>,
+    },
+    WrongWordCount(usize),
+    /// All words are known but the checksum doesn't match
+    /// `fixes` are the single word replacements, close to the typed word, that give a valid checksum
+    InvalidChecksum {
+        fixes: Vec<(usize, String)>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MnemonicValidation {
+    /// The wordlist with the most words of the phrase, None if no word is known
+    pub language: Option<MnemonicLanguage>,
+    pub issues: Vec<MnemonicIssue>,
+}
+
+impl MnemonicValidation {
+    pub fn is_valid(&self) -> bool {
+        self.language.is_some() && self.issues.is_empty()
+    }
+}
+
+/// Unlike `Mnemonic::parse`, reports every problem at once instead of the first one
+pub fn validate_mnemonic(phrase: &str) -> MnemonicValidation {
+    let words: Vec<String> = phrase
+        .split_whitespace()
+        .map(|word| word.to_lowercase())
+        .collect();
+
+    let language = detect_language(&words);
+    let mut issues = vec![];
+
+    if !VALID_WORD_COUNTS.contains(&words.len()) {
+        issues.push(MnemonicIssue::WrongWordCount(words.len()));
+    }
+
+    let Some(language) = language else {
+        return MnemonicValidation { language, issues };
+    };
+    let bip39_language: Language = language.into();
+
+    for (position, word) in words.iter().enumerate() {
+        if bip39_language.find_word(word).is_none() {
+            issues.push(MnemonicIssue::UnknownWord {
+                position,
+                word: word.clone(),
+                suggestions: get_suggestions(bip39_language, word),
+            });
+        }
+    }
+
+    if issues.is_empty() && !is_valid_in(bip39_language, &words) {
+        issues.push(MnemonicIssue::InvalidChecksum {
+            fixes: get_checksum_fixes(bip39_language, &words),
+        });
+    }
+
+    MnemonicValidation {
+        language: Some(language),
+        issues,
+    }
+}
+
+/// English wins ties, it's by far the most used
+fn detect_language(words: &[String]) -> Option<MnemonicLanguage> {
+    let mut best = None;
+    let mut best_count = 0;
+    for language in MnemonicLanguage::ALL {
+        let bip39_language: Language = language.into();
+        let count = words
+            .iter()
+            .filter(|word| bip39_language.find_word(word).is_some())
+            .count();
+        if count > best_count {
+            best = Some(language);
+            best_count = count;
+        }
+    }
+    best
+}
+
+fn is_valid_in(language: Language, words: &[String]) -> bool {
+    Mnemonic::parse_in(language, words.join(" ")).is_ok()
+}
+
+/// Closest first, words sharing the first 4 letters count as the closest since that's
+/// all that's needed to tell english words apart
+fn get_suggestions(language: Language, word: &str) -> Vec<String> {
+    let prefix: String = word.chars().take(4).collect();
+    let mut candidates: Vec<(usize, &str)> = language
+        .word_list()
+        .iter()
+        .filter_map(|candidate| {
+            if prefix.chars().count() == 4 && candidate.starts_with(&prefix) {
+                return Some((0, *candidate));
+            }
+            let distance = edit_distance(word, candidate);
+            (distance <= MAX_SUGGESTION_DISTANCE).then_some((distance, *candidate))
+        })
+        .collect();
+    candidates.sort();
+    candidates
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, candidate)| candidate.to_owned())
+        .collect()
+}
+
+/// Replacing any word with a random one gives a valid checksum once in 16 tries or more,
+/// so only replacements that look like a typo are worth showing
+fn get_checksum_fixes(language: Language, words: &[String]) -> Vec<(usize, String)> {
+    let mut fixes = vec![];
+    let mut candidate_words = words.to_vec();
+    for (position, word) in words.iter().enumerate() {
+        for candidate in language.word_list() {
+            if *candidate == word.as_str() || edit_distance(word, candidate) > 1 {
+                continue;
+            }
+            candidate_words[position] = (*candidate).to_owned();
+            if is_valid_in(language, &candidate_words) {
+                fixes.push((position, (*candidate).to_owned()));
+            }
+        }
+        candidate_words[position] = word.clone();
+    }
+    fixes
+}
+
+/// Levenshtein distance, on chars so that it works for all wordlists
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, ca) in a.chars().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
@@ -0,0 +1,386 @@
+//! In-memory chain for deterministic tests of wallet behavior
+//!
+//! Blocks, reorgs and mempool evictions are all scripted by the caller.
+
+use std::collections::HashMap;
+
+use bitcoin::{OutPoint, Transaction, TxOut, Txid};
+
+use anyhow::{Error, Result};
+
+use crate::chain::{ChainBackend, ChainOutput};
+use crate::spclient::{OwnedOutput, SpWallet};
+
+#[derive(Debug, Default, Clone)]
+pub struct MockChain {
+    /// Transactions of each block, the first block is at height 0
+    blocks: Vec<Vec<Transaction>>,
+    mempool: Vec<Transaction>,
+}
+
+impl MockChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mine a block with `txs`, they're removed from the mempool if there
+    /// Returns the height of the new block
+    pub fn mine_block(&mut self, txs: Vec<Transaction>) -> u32 {
+        let txids: Vec<Txid> = txs.iter().map(|tx| tx.txid()).collect();
+        self.mempool.retain(|tx| !txids.contains(&tx.txid()));
+        self.blocks.push(txs);
+        self.blocks.len() as u32 - 1
+    }
+
+    /// Mine everything in the mempool
+    pub fn mine_mempool(&mut self) -> u32 {
+        let txs = std::mem::take(&mut self.mempool);
+        self.mine_block(txs)
+    }
+
+    pub fn add_to_mempool(&mut self, tx: Transaction) {
+        self.mempool.push(tx);
+    }
+
+    /// Drop a transaction from the mempool, as a node would after expiry or replacement
+    pub fn evict_from_mempool(&mut self, txid: &Txid) -> Result<Transaction> {
+        let pos = self
+            .mempool
+            .iter()
+            .position(|tx| tx.txid() == *txid)
+            .ok_or_else(|| Error::msg(format!("{} not in mempool", txid)))?;
+        Ok(self.mempool.remove(pos))
+    }
+
+    /// Remove the last `depth` blocks and mine `new_blocks` instead, and roll back `wallet`
+    /// Transactions of the removed blocks that aren't mined again go back to the mempool
+    pub fn reorg(
+        &mut self,
+        depth: u32,
+        new_blocks: Vec<Vec<Transaction>>,
+        wallet: &mut SpWallet,
+    ) -> Result<u32> {
+        if depth as usize >= self.blocks.len() {
+            return Err(Error::msg("Reorg deeper than the chain"));
+        }
+
+        let fork = self.blocks.len() - depth as usize;
+        let removed: Vec<Transaction> = self.blocks.drain(fork..).flatten().collect();
+        wallet.rollback_to(fork as u32 - 1);
+
+        for txs in new_blocks {
+            self.mine_block(txs);
+        }
+
+        // coinbases of the removed blocks are gone for good, they can't go back to the mempool
+        for tx in removed.into_iter().filter(|tx| !tx.is_coinbase()) {
+            if self.find_tx(&tx.txid()).is_none() {
+                self.mempool.push(tx);
+            }
+        }
+
+        Ok(self.get_height())
+    }
+
+    pub fn get_height(&self) -> u32 {
+        self.blocks.len().saturating_sub(1) as u32
+    }
+
+    pub fn get_block(&self, height: u32) -> Option<&Vec<Transaction>> {
+        self.blocks.get(height as usize)
+    }
+
+    pub fn get_mempool(&self) -> &Vec<Transaction> {
+        &self.mempool
+    }
+
+    /// Returns the transaction and its height, None for the mempool
+    pub fn find_tx(&self, txid: &Txid) -> Option<(&Transaction, Option<u32>)> {
+        for (height, block) in self.blocks.iter().enumerate() {
+            if let Some(tx) = block.iter().find(|tx| tx.txid() == *txid) {
+                return Some((tx, Some(height as u32)));
+            }
+        }
+        self.mempool
+            .iter()
+            .find(|tx| tx.txid() == *txid)
+            .map(|tx| (tx, None))
+    }
+
+    /// The outputs spent by `tx`, needed to compute its tweak data
+    pub fn get_prevouts(&self, tx: &Transaction) -> Result<Vec<TxOut>> {
+        tx.input
+            .iter()
+            .map(|txin| {
+                self.find_tx(&txin.previous_output.txid)
+                    .and_then(|(prev_tx, _)| {
+                        prev_tx
+                            .output
+                            .get(txin.previous_output.vout as usize)
+                            .cloned()
+                    })
+                    .ok_or_else(|| Error::msg(format!("Unknown prevout {}", txin.previous_output)))
+            })
+            .collect()
+    }
+
+    /// Scan blocks from the last scan height of `wallet` to the tip,
+    /// returns the outputs that were found or updated
+    pub fn scan_to_tip(&self, wallet: &mut SpWallet) -> Result<HashMap<OutPoint, OwnedOutput>> {
+        let mut found = HashMap::new();
+        for height in wallet.get_outputs().get_last_scan() + 1..=self.get_height() {
+            found.extend(wallet.scan_block_transactions(
+                &self.blocks[height as usize],
+                height,
+                |tx| self.get_prevouts(tx),
+            )?);
+        }
+        Ok(found)
+    }
+
+    fn is_spent(&self, outpoint: &OutPoint) -> bool {
+        self.blocks
+            .iter()
+            .flatten()
+            .chain(self.mempool.iter())
+            .any(|tx| tx.input.iter().any(|i| i.previous_output == *outpoint))
+    }
+
+    /// Index of everything created on chain or in the mempool, handy for assertions
+    pub fn get_all_outputs(&self) -> HashMap<OutPoint, ChainOutput> {
+        let mut res = HashMap::new();
+        for (height, block) in self.blocks.iter().enumerate() {
+            for tx in block {
+                self.insert_outputs(&mut res, tx, Some(height as u32));
+            }
+        }
+        for tx in self.mempool.iter() {
+            self.insert_outputs(&mut res, tx, None);
+        }
+        res
+    }
+
+    fn insert_outputs(
+        &self,
+        res: &mut HashMap<OutPoint, ChainOutput>,
+        tx: &Transaction,
+        blockheight: Option<u32>,
+    ) {
+        let txid = tx.txid();
+        for (vout, txout) in tx.output.iter().enumerate() {
+            let outpoint = OutPoint::new(txid, vout as u32);
+            res.insert(
+                outpoint,
+                ChainOutput {
+                    txout: txout.clone(),
+                    blockheight,
+                    spent: self.is_spent(&outpoint),
+                },
+            );
+        }
+    }
+}
+
+impl ChainBackend for MockChain {
+    fn get_tip_height(&self) -> Result<u32> {
+        if self.blocks.is_empty() {
+            return Err(Error::msg("Empty chain"));
+        }
+        Ok(self.get_height())
+    }
+
+    fn get_output(&self, outpoint: &OutPoint) -> Result<Option<ChainOutput>> {
+        Ok(self.find_tx(&outpoint.txid).and_then(|(tx, blockheight)| {
+            tx.output
+                .get(outpoint.vout as usize)
+                .map(|txout| ChainOutput {
+                    txout: txout.clone(),
+                    blockheight,
+                    spent: self.is_spent(outpoint),
+                })
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::{
+        absolute::LockTime, script::PushBytesBuf, transaction::Version, Amount, ScriptBuf,
+        Sequence, TxIn, Witness,
+    };
+
+    use crate::spclient::{Recipient, SpClient};
+    use crate::test_utils::{other_client, owned_output, test_client};
+
+    fn coinbase(tag: u8, output: TxOut) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new_op_return(PushBytesBuf::from([tag; 4])),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![output],
+        }
+    }
+
+    /// A coinbase paying `sender`, and a transaction spending it to `receiver`
+    fn payment(sender: &SpClient, receiver: &SpClient) -> (Transaction, Transaction) {
+        let (_, output) = owned_output(sender, 0x55, Amount::from_sat(50_000));
+        let funding = coinbase(
+            0,
+            TxOut {
+                value: output.amount,
+                script_pubkey: ScriptBuf::from_hex(&output.script).unwrap(),
+            },
+        );
+
+        let utxos = HashMap::from([(OutPoint::new(funding.txid(), 0), output)]);
+        let recipients = vec![Recipient {
+            address: receiver.get_receiving_address(),
+            amount: Amount::from_sat(40_000),
+            nb_outputs: 1,
+        }];
+        let mut psbt = sender.create_new_psbt(utxos, recipients, None).unwrap();
+        SpClient::set_fees(
+            &mut psbt,
+            Amount::from_sat(2),
+            sender.sp_receiver.get_change_address(),
+        )
+        .unwrap();
+        let partial_secret = sender.get_partial_secret_from_psbt(&psbt).unwrap();
+        sender.fill_sp_outputs(&mut psbt, partial_secret).unwrap();
+        let mut psbt = sender.sign_psbt(psbt, &[0u8; 32], None).unwrap();
+        SpClient::finalize_psbt(&mut psbt).unwrap();
+        (funding, psbt.extract_tx().unwrap())
+    }
+
+    fn empty_coinbase(tag: u8) -> Transaction {
+        coinbase(
+            tag,
+            TxOut {
+                value: Amount::from_sat(50_000),
+                script_pubkey: ScriptBuf::new(),
+            },
+        )
+    }
+
+    #[test]
+    fn reorg_skips_coinbases() {
+        let (funding, tx) = payment(&test_client(), &other_client());
+        let mut wallet = SpWallet::new(other_client(), None).unwrap();
+
+        let mut chain = MockChain::new();
+        chain.mine_block(vec![funding]);
+        chain.mine_block(vec![empty_coinbase(1), tx.clone()]);
+
+        assert_eq!(chain.reorg(1, vec![vec![]], &mut wallet).unwrap(), 1);
+        assert_eq!(chain.get_mempool(), &vec![tx.clone()]);
+        assert_eq!(chain.find_tx(&tx.txid()).unwrap().1, None);
+
+        assert!(chain.reorg(2, vec![], &mut wallet).is_err());
+    }
+
+    #[test]
+    fn mempool_eviction() {
+        let (funding, tx) = payment(&test_client(), &other_client());
+
+        let mut chain = MockChain::new();
+        chain.mine_block(vec![funding]);
+        chain.add_to_mempool(tx.clone());
+        assert!(
+            chain
+                .get_output(&tx.input[0].previous_output)
+                .unwrap()
+                .unwrap()
+                .spent
+        );
+
+        chain.evict_from_mempool(&tx.txid()).unwrap();
+        assert!(chain.get_mempool().is_empty());
+        assert!(chain.evict_from_mempool(&tx.txid()).is_err());
+        assert!(
+            !chain
+                .get_output(&tx.input[0].previous_output)
+                .unwrap()
+                .unwrap()
+                .spent
+        );
+    }
+
+    #[test]
+    fn scan_tolerates_already_scanned() {
+        let (funding, tx) = payment(&test_client(), &other_client());
+        let mut wallet = SpWallet::new(other_client(), None).unwrap();
+
+        let mut chain = MockChain::new();
+        chain.mine_block(vec![funding]);
+        chain.add_to_mempool(tx.clone());
+        assert_eq!(chain.mine_mempool(), 1);
+        assert_eq!(chain.get_tip_height().unwrap(), 1);
+
+        let found = chain.scan_to_tip(&mut wallet).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(
+            found.values().next().unwrap().amount,
+            Amount::from_sat(40_000)
+        );
+        assert_eq!(wallet.get_outputs().get_last_scan(), 1);
+
+        // scanning the same blocks again finds nothing new, and isn't an error
+        wallet.get_mut_outputs().update_last_scan(0);
+        assert!(chain.scan_to_tip(&mut wallet).unwrap().is_empty());
+        assert_eq!(wallet.get_outputs().to_outpoints_list().len(), 1);
+    }
+
+    #[test]
+    fn reorg_rolls_back_the_wallet() {
+        let (funding, tx) = payment(&test_client(), &other_client());
+        let mut receiver = SpWallet::new(other_client(), None).unwrap();
+
+        // the sender spends an output we give it at height 0
+        let mut sender = SpWallet::new(test_client(), None).unwrap();
+        let (_, mut funding_output) =
+            owned_output(sender.get_client(), 0x55, Amount::from_sat(50_000));
+        funding_output.blockheight = 0;
+        sender.get_mut_outputs().extend_from(HashMap::from([(
+            OutPoint::new(funding.txid(), 0),
+            funding_output,
+        )]));
+
+        let mut chain = MockChain::new();
+        chain.mine_block(vec![funding]);
+        chain.mine_block(vec![tx.clone()]);
+        chain.scan_to_tip(&mut receiver).unwrap();
+        chain.scan_to_tip(&mut sender).unwrap();
+        assert_eq!(
+            receiver.get_outputs().get_balance(),
+            Amount::from_sat(40_000)
+        );
+        let change = sender.get_outputs().get_balance();
+        assert!(change > Amount::ZERO);
+
+        // the payment is reorged out, then mined again one block later
+        chain
+            .reorg(1, vec![vec![empty_coinbase(1)]], &mut receiver)
+            .unwrap();
+        sender.rollback_to(0);
+        assert_eq!(receiver.get_outputs().get_balance(), Amount::ZERO);
+        assert_eq!(receiver.get_outputs().get_last_scan(), 0);
+        assert_eq!(sender.get_outputs().get_balance(), Amount::from_sat(50_000));
+
+        chain.mine_mempool();
+        let found = chain.scan_to_tip(&mut receiver).unwrap();
+        assert_eq!(found.values().next().unwrap().blockheight, 2);
+        assert_eq!(
+            receiver.get_outputs().get_balance(),
+            Amount::from_sat(40_000)
+        );
+        chain.scan_to_tip(&mut sender).unwrap();
+        assert_eq!(sender.get_outputs().get_balance(), change);
+    }
+}
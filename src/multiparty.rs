@@ -0,0 +1,492 @@
+//! Silent payment outputs in transactions with inputs of other parties, e.g. payjoin or coinjoin
+//!
+//! The shared secret of BIP352 needs the keys of all the eligible inputs. Each party computes a
+//! contribution from the inputs it owns: the sum of their public keys and the ECDH shares with the scan
+//! key of each recipient, so that no secret has to leave its wallet. Whoever fills the outputs
+//! combines all the contributions.
+//!
+//! Each share comes with a DLEQ proof that it was computed with the secret of the key sum, and the
+//! key sum is checked against the inputs, so that a party can't steer the outputs elsewhere.
+
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use bitcoin::{
+    consensus::{deserialize, serialize},
+    hex::{DisplayHex, FromHex},
+    key::{Parity, TweakedPublicKey},
+    psbt::raw,
+    secp256k1::{PublicKey, Secp256k1, SecretKey},
+    OutPoint, ScriptBuf, XOnlyPublicKey,
+};
+use serde::{Deserialize, Serialize};
+use silentpayments::utils as sp_utils;
+
+use anyhow::{Error, Result};
+
+use crate::constants::{PSBT_SP_ADDRESS_KEY, PSBT_SP_PREFIX, PSBT_SP_SUBTYPE, PSBT_SP_TWEAK_KEY};
+use crate::payment_proof::{dleq_prove, dleq_verify};
+use crate::signer::{hash_to_scalar, tagged_hash};
+use crate::spclient::{
+    get_psbt_input, parse_sp_address, OutputSpendStatus, Psbt, SpClient, SpWallet, SpendKey,
+};
+
+/// The secret of a key sum times a recipient scan key
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EcdhShare {
+    pub scan_pubkey: PublicKey,
+    pub share: PublicKey,
+    /// Hex encoded DLEQ proof that `share` and the key sum have the same secret
+    pub dleq: String,
+}
+
+/// One party's share of the shared secret
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SpContribution {
+    /// All the inputs of the party, eligible or not, so that we can check every input is accounted for
+    pub outpoints: Vec<OutPoint>,
+    /// Sum of the public keys of the eligible inputs, None if there's none
+    pub pubkey_sum: Option<PublicKey>,
+    /// One for each recipient scan key
+    pub ecdh_shares: Vec<EcdhShare>,
+}
+
+impl SpContribution {
+    /// For a counterparty that hands out its summed input secret instead of computing the shares itself
+    /// `secret` must already be negated for taproot keys with an odd y, as in BIP352
+    pub fn from_partial_secret(
+        outpoints: Vec<OutPoint>,
+        secret: &SecretKey,
+        scan_pubkeys: &[PublicKey],
+        aux_rand: &[u8; 32],
+    ) -> Result<Self> {
+        let secp = Secp256k1::new();
+        let ecdh_shares = scan_pubkeys
+            .iter()
+            .map(|scan| {
+                Ok(EcdhShare {
+                    scan_pubkey: *scan,
+                    share: scan.mul_tweak(&secp, &(*secret).into())?,
+                    dleq: dleq_prove(secret, scan, aux_rand)?.to_lower_hex_string(),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            outpoints,
+            pubkey_sum: Some(secret.public_key(&secp)),
+            ecdh_shares,
+        })
+    }
+
+    /// Inputs without a public key we could use, e.g. only non-eligible scripts
+    pub fn empty(outpoints: Vec<OutPoint>) -> Self {
+        Self {
+            outpoints,
+            pubkey_sum: None,
+            ecdh_shares: vec![],
+        }
+    }
+}
+
+/// Scan keys of the silent payment outputs of `psbt`, to give to the other parties
+pub fn get_recipient_scan_keys(psbt: &Psbt) -> Result<Vec<PublicKey>> {
+    let mut res = vec![];
+    for address in get_sp_addresses(psbt)?.into_iter().flatten() {
+        let scan = parse_sp_address(&address)?.scan_pubkey;
+        if !res.contains(&scan) {
+            res.push(scan);
+        }
+    }
+    Ok(res)
+}
+
+fn get_sp_addresses(psbt: &Psbt) -> Result<Vec<Option<String>>> {
+    psbt.outputs
+        .iter()
+        .map(|output| {
+            output
+                .proprietary
+                .get(&raw::ProprietaryKey {
+                    prefix: PSBT_SP_PREFIX.as_bytes().to_vec(),
+                    subtype: PSBT_SP_SUBTYPE,
+                    key: PSBT_SP_ADDRESS_KEY.as_bytes().to_vec(),
+                })
+                .map(|value| deserialize::<String>(value))
+                .transpose()
+                .map_err(Error::from)
+        })
+        .collect()
+}
+
+impl SpClient {
+    /// Our contribution for the inputs of `psbt` that have our tweak, the others are left to their owners
+    pub fn get_sp_contribution(&self, psbt: &Psbt, aux_rand: &[u8; 32]) -> Result<SpContribution> {
+        let mut b_spend = match self.get_spend_key() {
+            SpendKey::Secret(key) => key,
+            SpendKey::Public(_) => return Err(Error::msg("Watch-only wallet, can't spend")),
+        };
+        let secp = Secp256k1::new();
+
+        let mut outpoints = vec![];
+        let mut sum: Option<SecretKey> = None;
+        for (txin, input) in psbt.unsigned_tx.input.iter().zip(psbt.inputs.iter()) {
+            let Some(tweak) = input.proprietary.get(&raw::ProprietaryKey {
+                prefix: PSBT_SP_PREFIX.as_bytes().to_vec(),
+                subtype: PSBT_SP_SUBTYPE,
+                key: PSBT_SP_TWEAK_KEY.as_bytes().to_vec(),
+            }) else {
+                continue;
+            };
+            let tweak = SecretKey::from_slice(tweak)?;
+            let mut input_key = b_spend.add_tweak(&tweak.into())?;
+            // our outputs are taproot, BIP352 uses the key with an even y
+            if input_key.x_only_public_key(&secp).1 == Parity::Odd {
+                input_key = input_key.negate();
+            }
+            sum = Some(match sum {
+                Some(sum) => sum.add_tweak(&input_key.into())?,
+                None => input_key,
+            });
+            input_key.non_secure_erase();
+            outpoints.push(txin.previous_output);
+        }
+        b_spend.non_secure_erase();
+
+        let res = match sum {
+            Some(mut sum) => {
+                let res = SpContribution::from_partial_secret(
+                    outpoints,
+                    &sum,
+                    &get_recipient_scan_keys(psbt)?,
+                    aux_rand,
+                );
+                sum.non_secure_erase();
+                res?
+            }
+            None => SpContribution::empty(outpoints),
+        };
+        Ok(res)
+    }
+
+    /// Same as `fill_sp_outputs`, but with the contributions of every party, ours included
+    /// Together they must cover all the inputs of `psbt`, each exactly once
+    pub fn fill_sp_outputs_multiparty(
+        &self,
+        psbt: &mut Psbt,
+        contributions: &[SpContribution],
+    ) -> Result<()> {
+        let secp = Secp256k1::new();
+
+        if psbt.unsigned_tx.input.is_empty() {
+            return Err(Error::msg("No inputs"));
+        }
+        let all_inputs: HashSet<OutPoint> = psbt
+            .unsigned_tx
+            .input
+            .iter()
+            .map(|i| i.previous_output)
+            .collect();
+        let mut covered = HashSet::new();
+        for outpoint in contributions.iter().flat_map(|c| c.outpoints.iter()) {
+            if !all_inputs.contains(outpoint) || !covered.insert(*outpoint) {
+                return Err(Error::msg(format!(
+                    "Input {} is unknown or contributed twice",
+                    outpoint
+                )));
+            }
+        }
+        if covered.len() != all_inputs.len() {
+            return Err(Error::msg("Some inputs have no contribution"));
+        }
+
+        let input_keys = get_input_pubkeys(psbt)?;
+        for contribution in contributions {
+            let keys: Vec<&PublicKey> = contribution
+                .outpoints
+                .iter()
+                .filter_map(|outpoint| input_keys.get(outpoint).and_then(Option::as_ref))
+                .collect();
+            let expected = match keys.is_empty() {
+                true => None,
+                false => Some(PublicKey::combine_keys(&keys)?),
+            };
+            if contribution.pubkey_sum != expected {
+                return Err(Error::msg(
+                    "Key sum doesn't match the inputs of the contribution",
+                ));
+            }
+        }
+
+        let pubkeys: Vec<&PublicKey> = contributions
+            .iter()
+            .filter_map(|c| c.pubkey_sum.as_ref())
+            .collect();
+        if pubkeys.is_empty() {
+            return Err(Error::msg("No eligible input for silent payments"));
+        }
+        let a_sum = PublicKey::combine_keys(&pubkeys)?;
+
+        let smallest_outpoint = all_inputs
+            .iter()
+            .map(serialize)
+            .min()
+            .ok_or_else(|| Error::msg("No inputs"))?;
+        let input_hash = hash_to_scalar(tagged_hash(
+            "BIP0352/Inputs",
+            &[&smallest_outpoint, &a_sum.serialize()],
+        ))?;
+
+        let addresses = get_sp_addresses(psbt)?;
+        let mut counters: HashMap<PublicKey, u32> = HashMap::new();
+        for (i, address) in addresses.iter().enumerate() {
+            let Some(address) = address else {
+                continue;
+            };
+            let info = parse_sp_address(address)?;
+
+            let shares: Vec<&PublicKey> = contributions
+                .iter()
+                .filter_map(|c| c.pubkey_sum.as_ref().map(|sum| (c, sum)))
+                .map(|(c, pubkey_sum)| {
+                    let share = c
+                        .ecdh_shares
+                        .iter()
+                        .find(|s| s.scan_pubkey == info.scan_pubkey)
+                        .ok_or_else(|| Error::msg(format!("Missing ECDH share for {}", address)))?;
+                    let dleq: [u8; 64] = Vec::<u8>::from_hex(&share.dleq)?
+                        .try_into()
+                        .map_err(|_| Error::msg("Invalid DLEQ proof length"))?;
+                    dleq_verify(pubkey_sum, &info.scan_pubkey, &share.share, &dleq)?;
+                    Ok(&share.share)
+                })
+                .collect::<Result<_>>()?;
+            let shared_secret =
+                PublicKey::combine_keys(&shares)?.mul_tweak(&secp, &input_hash.into())?;
+
+            let k = counters.entry(info.scan_pubkey).or_insert(0);
+            let t_k = hash_to_scalar(tagged_hash(
+                "BIP0352/SharedSecret",
+                &[&shared_secret.serialize(), &k.to_be_bytes()],
+            ))?;
+            *k += 1;
+
+            let (output_key, _) = info
+                .spend_pubkey
+                .add_exp_tweak(&secp, &t_k.into())?
+                .x_only_public_key();
+            psbt.unsigned_tx.output[i].script_pubkey =
+                ScriptBuf::new_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(output_key));
+        }
+
+        Ok(())
+    }
+}
+
+/// The key BIP352 takes from each input, None for inputs that aren't eligible
+/// Before an input is signed its key is only known from the psbt: the output key for taproot,
+/// otherwise one of its `bip32_derivation` keys matching the script
+fn get_input_pubkeys(psbt: &Psbt) -> Result<HashMap<OutPoint, Option<PublicKey>>> {
+    let mut res = HashMap::new();
+    for ((txin, input), prevout) in psbt
+        .unsigned_tx
+        .input
+        .iter()
+        .zip(psbt.inputs.iter())
+        .zip(psbt.iter_funding_utxos())
+    {
+        let spk = &prevout?.script_pubkey;
+        let key = if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+            let script_sig = input.final_script_sig.clone().unwrap_or_default();
+            let witness = input
+                .final_script_witness
+                .as_ref()
+                .map(|w| w.to_vec())
+                .unwrap_or_default();
+            sp_utils::receiving::get_pubkey_from_input(
+                script_sig.as_bytes(),
+                &witness,
+                spk.as_bytes(),
+            )?
+        } else if spk.is_p2tr() {
+            let output_key = XOnlyPublicKey::from_slice(&spk.as_bytes()[2..])?;
+            Some(PublicKey::from_x_only_public_key(output_key, Parity::Even))
+        } else if spk.is_p2wpkh()
+            || spk.is_p2pkh()
+            || input.redeem_script.as_ref().is_some_and(|s| s.is_p2wpkh())
+        {
+            let key = input
+                .bip32_derivation
+                .keys()
+                .find(|key| {
+                    let key = bitcoin::PublicKey::new(**key);
+                    let wpkh = key.wpubkey_hash().map(|hash| ScriptBuf::new_p2wpkh(&hash));
+                    *spk == ScriptBuf::new_p2pkh(&key.pubkey_hash())
+                        || wpkh.as_ref().is_some_and(|wpkh| {
+                            spk == wpkh || *spk == ScriptBuf::new_p2sh(&wpkh.script_hash())
+                        })
+                })
+                .ok_or_else(|| {
+                    Error::msg(format!("Unknown key for input {}", txin.previous_output))
+                })?;
+            Some(*key)
+        } else if spk.is_p2sh() && input.redeem_script.is_none() {
+            return Err(Error::msg(format!(
+                "Unknown redeem script for input {}",
+                txin.previous_output
+            )));
+        } else {
+            None
+        };
+        res.insert(txin.previous_output, key);
+    }
+    Ok(res)
+}
+
+impl SpWallet {
+    /// For a psbt built by someone else, e.g. a coordinator: add what's needed to sign our inputs
+    /// Returns the indices of our inputs, the others are left untouched
+    pub fn attach_input_data(&self, psbt: &mut Psbt) -> Result<Vec<usize>> {
+        let spendable = self.get_outputs().to_outpoints_list();
+        let mut ours = vec![];
+        for (i, txin) in psbt.unsigned_tx.input.iter().enumerate() {
+            let Some(output) = spendable.get(&txin.previous_output) else {
+                continue;
+            };
+            if output.spend_status != OutputSpendStatus::Unspent {
+                return Err(Error::msg(format!(
+                    "Input {} is already spent",
+                    txin.previous_output
+                )));
+            }
+            let input = get_psbt_input(
+                &ScriptBuf::from_hex(&output.script)?,
+                output.amount,
+                &SecretKey::from_str(&output.tweak)?.into(),
+            )?;
+            let psbt_input = &mut psbt.inputs[i];
+            psbt_input.witness_utxo = input.witness_utxo;
+            psbt_input.proprietary.extend(input.proprietary);
+            ours.push(i);
+        }
+        Ok(ours)
+    }
+
+    /// `attach_input_data` then our contribution to the silent payment outputs, to send back to
+    /// whoever fills them with `SpClient::fill_sp_outputs_multiparty`
+    pub fn contribute_to_psbt(
+        &self,
+        psbt: &mut Psbt,
+        aux_rand: &[u8; 32],
+    ) -> Result<SpContribution> {
+        if self.attach_input_data(psbt)?.is_empty() {
+            return Err(Error::msg("None of the inputs are ours"));
+        }
+        self.get_client().get_sp_contribution(psbt, aux_rand)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::Amount;
+
+    use crate::spclient::Recipient;
+    use crate::test_utils::{other_client, owned_output, test_client};
+
+    /// Two of our inputs paying another wallet, with the secrets of the inputs
+    fn two_inputs(client: &SpClient) -> (Psbt, Vec<(OutPoint, SecretKey)>) {
+        let spend_sk = SecretKey::from_slice(&[0x22; 32]).unwrap();
+        let outputs = [
+            owned_output(client, 1, Amount::from_sat(50_000)),
+            owned_output(client, 2, Amount::from_sat(30_000)),
+        ];
+        let secrets = outputs
+            .iter()
+            .map(|(outpoint, output)| {
+                let tweak = SecretKey::from_str(&output.tweak).unwrap();
+                let mut secret = spend_sk.add_tweak(&tweak.into()).unwrap();
+                if secret.x_only_public_key(&Secp256k1::new()).1 == Parity::Odd {
+                    secret = secret.negate();
+                }
+                (*outpoint, secret)
+            })
+            .collect();
+        let recipients = vec![Recipient {
+            address: other_client().get_receiving_address(),
+            amount: Amount::from_sat(60_000),
+            nb_outputs: 2,
+        }];
+        let psbt = client
+            .create_new_psbt(HashMap::from(outputs), recipients, None)
+            .unwrap();
+        (psbt, secrets)
+    }
+
+    /// Each input as if it belonged to another party
+    fn contributions(psbt: &Psbt, secrets: &[(OutPoint, SecretKey)]) -> Vec<SpContribution> {
+        let scan_keys = get_recipient_scan_keys(psbt).unwrap();
+        secrets
+            .iter()
+            .map(|(outpoint, secret)| {
+                SpContribution::from_partial_secret(vec![*outpoint], secret, &scan_keys, &[0; 32])
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn two_parties_match_a_single_one() {
+        let client = test_client();
+        let (psbt, secrets) = two_inputs(&client);
+
+        let mut single = psbt.clone();
+        let partial_secret = client.get_partial_secret_from_psbt(&single).unwrap();
+        client.fill_sp_outputs(&mut single, partial_secret).unwrap();
+
+        let contributions = contributions(&psbt, &secrets);
+        let mut multi = psbt;
+        client
+            .fill_sp_outputs_multiparty(&mut multi, &contributions)
+            .unwrap();
+        assert_eq!(multi.unsigned_tx.output, single.unsigned_tx.output);
+    }
+
+    #[test]
+    fn forged_contributions_are_refused() {
+        let client = test_client();
+        let (psbt, secrets) = two_inputs(&client);
+        let honest = contributions(&psbt, &secrets);
+        let other = SecretKey::from_slice(&[0x55; 32]).unwrap();
+
+        // a share computed with another secret
+        let mut forged = honest.clone();
+        let scan = forged[1].ecdh_shares[0].scan_pubkey;
+        forged[1].ecdh_shares[0].share = scan.mul_tweak(&Secp256k1::new(), &other.into()).unwrap();
+        assert!(client
+            .fill_sp_outputs_multiparty(&mut psbt.clone(), &forged)
+            .is_err());
+
+        // a key sum that isn't the one of the inputs, with a valid proof for it
+        let mut forged = honest;
+        forged[1] =
+            SpContribution::from_partial_secret(vec![secrets[1].0], &other, &[scan], &[0; 32])
+                .unwrap();
+        assert!(client
+            .fill_sp_outputs_multiparty(&mut psbt.clone(), &forged)
+            .is_err());
+    }
+
+    #[test]
+    fn no_inputs_is_an_error() {
+        let client = test_client();
+        let (mut psbt, secrets) = two_inputs(&client);
+        psbt.unsigned_tx.input.clear();
+        psbt.inputs.clear();
+        let mut contribution = contributions(&psbt, &secrets).remove(0);
+        contribution.outpoints.clear();
+        assert!(client
+            .fill_sp_outputs_multiparty(&mut psbt, &[contribution])
+            .is_err());
+    }
+}
@@ -0,0 +1,608 @@
+//! MuSig2 (BIP327) for a spend key split between several devices
+//!
+//! Receiving only needs the aggregate public key, so the wallet is set up with `SpendKey::Public(aggregate)`.
+//! Spending goes through `Musig2Signer`, that runs the two rounds of the protocol with the other participant
+//! for each input. Note that sending to silent payment addresses (including our own change)
+//! still requires the sum of the input keys, which this doesn't provide: set `WalletSettings::musig`
+//! so that `SpClient::create_new_psbt` refuses them, and spend the whole amount of the inputs.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use bitcoin::secp256k1::{schnorr, Message, PublicKey, Secp256k1, SecretKey};
+
+use anyhow::{Error, Result};
+
+use crate::policy::SpendingPolicy;
+use crate::rng::{SharedRng, SpRng};
+use crate::signer::{has_even_y, hash_to_scalar, tagged_hash, xbytes, Signer};
+use crate::spclient::{Psbt, SpClient};
+
+/// Key aggregation context, participants keys are sorted so that the order doesn't matter
+#[derive(Debug, Clone, PartialEq)]
+pub struct Musig2KeyAgg {
+    pubkeys: Vec<PublicKey>,
+    coefficients: Vec<Option<SecretKey>>,
+    aggregate: PublicKey,
+}
+
+impl Musig2KeyAgg {
+    pub fn new(mut pubkeys: Vec<PublicKey>) -> Result<Self> {
+        if pubkeys.len() < 2 {
+            return Err(Error::msg("Need at least 2 participants"));
+        }
+        pubkeys.sort_by_key(|pk| pk.serialize());
+
+        let secp = Secp256k1::verification_only();
+
+        let serialized: Vec<[u8; 33]> = pubkeys.iter().map(|pk| pk.serialize()).collect();
+        let all_keys: Vec<&[u8]> = serialized.iter().map(|pk| pk.as_slice()).collect();
+        let list_hash = tagged_hash("KeyAgg list", &all_keys);
+
+        let second_key = pubkeys.iter().find(|pk| **pk != pubkeys[0]).copied();
+
+        let mut coefficients = vec![];
+        let mut terms = vec![];
+        for (pk, ser) in pubkeys.iter().zip(serialized.iter()) {
+            // The second distinct key gets a coefficient of 1, see BIP327
+            if Some(*pk) == second_key {
+                coefficients.push(None);
+                terms.push(*pk);
+            } else {
+                let coef = hash_to_scalar(tagged_hash(
+                    "KeyAgg coefficient",
+                    &[&list_hash[..], &ser[..]],
+                ))?;
+                terms.push(pk.mul_tweak(&secp, &coef.into())?);
+                coefficients.push(Some(coef));
+            }
+        }
+
+        let terms_ref: Vec<&PublicKey> = terms.iter().collect();
+        let aggregate = PublicKey::combine_keys(&terms_ref)?;
+
+        Ok(Self {
+            pubkeys,
+            coefficients,
+            aggregate,
+        })
+    }
+
+    /// The key to use as `SpendKey::Public` for the wallet
+    pub fn get_aggregate_pubkey(&self) -> PublicKey {
+        self.aggregate
+    }
+
+    fn get_coefficient(&self, pk: &PublicKey) -> Result<Option<SecretKey>> {
+        let i = self
+            .pubkeys
+            .iter()
+            .position(|p| p == pk)
+            .ok_or_else(|| Error::msg("Unknown participant"))?;
+        Ok(self.coefficients[i])
+    }
+
+    // Multiply by the key aggregation coefficient of `pk`
+    fn apply_coefficient(&self, pk: &PublicKey, sk: SecretKey) -> Result<SecretKey> {
+        match self.get_coefficient(pk)? {
+            Some(coef) => Ok(sk.mul_tweak(&coef.into())?),
+            None => Ok(sk),
+        }
+    }
+}
+
+/// Secret nonces of one signing session, must never be reused
+pub struct Musig2SecretNonce {
+    k1: SecretKey,
+    k2: SecretKey,
+}
+
+impl Drop for Musig2SecretNonce {
+    fn drop(&mut self) {
+        self.k1.non_secure_erase();
+        self.k2.non_secure_erase();
+    }
+}
+
+impl Musig2SecretNonce {
+    pub fn generate(
+        sk: &SecretKey,
+        msg: &Message,
+        aux_rand: &[u8; 32],
+        rng: &SharedRng,
+    ) -> Result<Self> {
+        let rand: [u8; 32] = rng.gen_bytes();
+
+        let msg_bytes = &msg[..];
+        let k1 = hash_to_scalar(tagged_hash(
+            "MuSig/nonce",
+            &[
+                &rand[..],
+                &aux_rand[..],
+                &sk.secret_bytes()[..],
+                msg_bytes,
+                &[0u8][..],
+            ],
+        ))?;
+        let k2 = hash_to_scalar(tagged_hash(
+            "MuSig/nonce",
+            &[
+                &rand[..],
+                &aux_rand[..],
+                &sk.secret_bytes()[..],
+                msg_bytes,
+                &[1u8][..],
+            ],
+        ))?;
+
+        Ok(Self { k1, k2 })
+    }
+
+    pub fn get_public_nonce(&self) -> (PublicKey, PublicKey) {
+        let secp = Secp256k1::signing_only();
+        (self.k1.public_key(&secp), self.k2.public_key(&secp))
+    }
+}
+
+/// Everything the participants need to agree on to sign one input
+struct Musig2Session<'a> {
+    key_agg: &'a Musig2KeyAgg,
+    // aggregate key tweaked by the silent payment tweak of the input
+    output_key: PublicKey,
+    tweak: SecretKey,
+    msg: Message,
+    // b in BIP327
+    nonce_coef: SecretKey,
+    final_nonce: PublicKey,
+    // e in BIP327
+    challenge: SecretKey,
+}
+
+impl<'a> Musig2Session<'a> {
+    fn new(
+        key_agg: &'a Musig2KeyAgg,
+        tweak: &SecretKey,
+        msg: &Message,
+        agg_nonce: &(PublicKey, PublicKey),
+    ) -> Result<Self> {
+        let secp = Secp256k1::verification_only();
+
+        let output_key = key_agg
+            .get_aggregate_pubkey()
+            .add_exp_tweak(&secp, &(*tweak).into())?;
+
+        let msg_bytes = &msg[..];
+        let nonce_coef = hash_to_scalar(tagged_hash(
+            "MuSig/noncecoef",
+            &[
+                &agg_nonce.0.serialize()[..],
+                &agg_nonce.1.serialize()[..],
+                &xbytes(&output_key)[..],
+                msg_bytes,
+            ],
+        ))?;
+
+        let final_nonce = agg_nonce
+            .0
+            .combine(&agg_nonce.1.mul_tweak(&secp, &nonce_coef.into())?)?;
+
+        let challenge = hash_to_scalar(tagged_hash(
+            "BIP0340/challenge",
+            &[
+                &xbytes(&final_nonce)[..],
+                &xbytes(&output_key)[..],
+                msg_bytes,
+            ],
+        ))?;
+
+        Ok(Self {
+            key_agg,
+            output_key,
+            tweak: *tweak,
+            msg: *msg,
+            nonce_coef,
+            final_nonce,
+            challenge,
+        })
+    }
+
+    fn partial_sign(&self, sk: &SecretKey, nonce: &Musig2SecretNonce) -> Result<SecretKey> {
+        let secp = Secp256k1::signing_only();
+
+        let (mut k1, mut k2) = (nonce.k1, nonce.k2);
+        if !has_even_y(&self.final_nonce) {
+            k1 = k1.negate();
+            k2 = k2.negate();
+        }
+
+        let mut d = *sk;
+        if !has_even_y(&self.output_key) {
+            d = d.negate();
+        }
+
+        let pk = sk.public_key(&secp);
+        let ead = self
+            .key_agg
+            .apply_coefficient(&pk, d)?
+            .mul_tweak(&self.challenge.into())?;
+
+        let s = k1
+            .add_tweak(&k2.mul_tweak(&self.nonce_coef.into())?.into())?
+            .add_tweak(&ead.into())?;
+
+        d.non_secure_erase();
+
+        Ok(s)
+    }
+
+    fn partial_verify(
+        &self,
+        partial_sig: &SecretKey,
+        pk: &PublicKey,
+        public_nonce: &(PublicKey, PublicKey),
+    ) -> Result<()> {
+        let secp = Secp256k1::new();
+
+        let mut nonce = public_nonce
+            .0
+            .combine(&public_nonce.1.mul_tweak(&secp, &self.nonce_coef.into())?)?;
+        if !has_even_y(&self.final_nonce) {
+            nonce = nonce.negate(&secp);
+        }
+
+        let mut key_term = match self.key_agg.get_coefficient(pk)? {
+            Some(coef) => pk.mul_tweak(&secp, &coef.into())?,
+            None => *pk,
+        }
+        .mul_tweak(&secp, &self.challenge.into())?;
+        if !has_even_y(&self.output_key) {
+            key_term = key_term.negate(&secp);
+        }
+
+        if partial_sig.public_key(&secp) != nonce.combine(&key_term)? {
+            return Err(Error::msg("Invalid partial signature"));
+        }
+
+        Ok(())
+    }
+
+    fn aggregate(&self, partial_sigs: &[SecretKey]) -> Result<schnorr::Signature> {
+        let mut s = self.tweak.mul_tweak(&self.challenge.into())?;
+        if !has_even_y(&self.output_key) {
+            s = s.negate();
+        }
+        for partial_sig in partial_sigs {
+            s = s.add_tweak(&(*partial_sig).into())?;
+        }
+
+        let mut sig = [0u8; 64];
+        sig[..32].copy_from_slice(&xbytes(&self.final_nonce));
+        sig[32..].copy_from_slice(&s.secret_bytes());
+
+        let sig = schnorr::Signature::from_slice(&sig)?;
+
+        let secp = Secp256k1::verification_only();
+        secp.verify_schnorr(&sig, &self.msg, &self.output_key.x_only_public_key().0)?;
+
+        Ok(sig)
+    }
+}
+
+/// The other participant of the protocol, typically a second device or a co-signing service
+/// Calls for the same `session_id` happen in order, and a session is never signed twice
+pub trait Musig2Cosigner {
+    fn get_pubkey(&self) -> Result<PublicKey>;
+
+    /// First round, the cosigner commits to its nonces for this message
+    fn get_public_nonce(
+        &self,
+        session_id: &[u8; 32],
+        msg: &Message,
+        tweak: &SecretKey,
+    ) -> Result<(PublicKey, PublicKey)>;
+
+    /// Second round, the cosigner gets the aggregate nonce and returns its partial signature
+    fn get_partial_signature(
+        &self,
+        session_id: &[u8; 32],
+        agg_nonce: &(PublicKey, PublicKey),
+    ) -> Result<SecretKey>;
+}
+
+/// Our side of a 2-of-2 spend key
+pub struct Musig2Signer {
+    sk: SecretKey,
+    key_agg: Musig2KeyAgg,
+    cosigner: Box<dyn Musig2Cosigner>,
+    rng: SharedRng,
+}
+
+impl Musig2Signer {
+    pub fn new(sk: SecretKey, cosigner: Box<dyn Musig2Cosigner>) -> Result<Self> {
+        let secp = Secp256k1::signing_only();
+        let key_agg = Musig2KeyAgg::new(vec![sk.public_key(&secp), cosigner.get_pubkey()?])?;
+        Ok(Self {
+            sk,
+            key_agg,
+            cosigner,
+            rng: SharedRng::default(),
+        })
+    }
+
+    pub fn with_rng(mut self, rng: impl SpRng + 'static) -> Self {
+        self.rng = SharedRng::new(rng);
+        self
+    }
+}
+
+impl Drop for Musig2Signer {
+    fn drop(&mut self) {
+        self.sk.non_secure_erase();
+    }
+}
+
+impl Signer for Musig2Signer {
+    fn get_spend_pubkey(&self) -> Result<PublicKey> {
+        Ok(self.key_agg.get_aggregate_pubkey())
+    }
+
+    fn sign_tweaked(
+        &self,
+        msg: &Message,
+        tweak: &SecretKey,
+        aux_rand: &[u8; 32],
+    ) -> Result<schnorr::Signature> {
+        let session_id: [u8; 32] = self.rng.gen_bytes();
+
+        let nonce = Musig2SecretNonce::generate(&self.sk, msg, aux_rand, &self.rng)?;
+        let our_nonce = nonce.get_public_nonce();
+
+        let their_nonce = self.cosigner.get_public_nonce(&session_id, msg, tweak)?;
+
+        let agg_nonce = (
+            our_nonce.0.combine(&their_nonce.0)?,
+            our_nonce.1.combine(&their_nonce.1)?,
+        );
+
+        let session = Musig2Session::new(&self.key_agg, tweak, msg, &agg_nonce)?;
+
+        let our_sig = session.partial_sign(&self.sk, &nonce)?;
+        drop(nonce);
+
+        let their_sig = self
+            .cosigner
+            .get_partial_signature(&session_id, &agg_nonce)?;
+        session.partial_verify(&their_sig, &self.cosigner.get_pubkey()?, &their_nonce)?;
+
+        session.aggregate(&[our_sig, their_sig])
+    }
+}
+
+struct PendingSession {
+    msg: Message,
+    tweak: SecretKey,
+    nonce: Musig2SecretNonce,
+}
+
+/// A cosigner holding its key in memory, this is what the second device runs
+/// It only signs the inputs of psbts given to `approve_psbt` first
+pub struct LocalCosigner {
+    sk: SecretKey,
+    key_agg: Musig2KeyAgg,
+    sessions: Mutex<HashMap<[u8; 32], PendingSession>>,
+    /// Message and tweak of each input we agreed to sign
+    approved: Mutex<HashSet<([u8; 32], [u8; 32])>>,
+    rng: SharedRng,
+}
+
+impl LocalCosigner {
+    pub fn new(sk: SecretKey, other_pubkey: PublicKey) -> Result<Self> {
+        let secp = Secp256k1::signing_only();
+        let key_agg = Musig2KeyAgg::new(vec![sk.public_key(&secp), other_pubkey])?;
+        Ok(Self {
+            sk,
+            key_agg,
+            sessions: Mutex::new(HashMap::new()),
+            approved: Mutex::new(HashSet::new()),
+            rng: SharedRng::default(),
+        })
+    }
+
+    pub fn with_rng(mut self, rng: impl SpRng + 'static) -> Self {
+        self.rng = SharedRng::new(rng);
+        self
+    }
+
+    /// Check `psbt` against `policy`, then its inputs can be signed once
+    /// `client` is the wallet of the aggregate key, e.g. a watch-only copy on this device
+    pub fn approve_psbt(
+        &self,
+        client: &SpClient,
+        policy: &SpendingPolicy,
+        psbt: &Psbt,
+    ) -> Result<()> {
+        let aggregate = self.key_agg.get_aggregate_pubkey();
+        if PublicKey::from(client.get_spend_key()) != aggregate {
+            return Err(Error::msg("Client isn't the wallet of our aggregate key"));
+        }
+        policy.check_psbt(client, psbt)?;
+
+        let requests = SpClient::get_signing_requests(psbt, aggregate)?;
+        self.approved
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .extend(
+                requests
+                    .into_iter()
+                    .map(|(msg, tweak)| (*msg.as_ref(), tweak.secret_bytes())),
+            );
+        Ok(())
+    }
+}
+
+impl Drop for LocalCosigner {
+    fn drop(&mut self) {
+        self.sk.non_secure_erase();
+    }
+}
+
+impl Musig2Cosigner for LocalCosigner {
+    fn get_pubkey(&self) -> Result<PublicKey> {
+        Ok(self.sk.public_key(&Secp256k1::signing_only()))
+    }
+
+    fn get_public_nonce(
+        &self,
+        session_id: &[u8; 32],
+        msg: &Message,
+        tweak: &SecretKey,
+    ) -> Result<(PublicKey, PublicKey)> {
+        // approvals are used up here, a new session for the same input needs a new approval
+        if !self
+            .approved
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&(*msg.as_ref(), tweak.secret_bytes()))
+        {
+            return Err(Error::msg("Input not approved, see approve_psbt"));
+        }
+
+        let aux_rand: [u8; 32] = self.rng.gen_bytes();
+
+        let nonce = Musig2SecretNonce::generate(&self.sk, msg, &aux_rand, &self.rng)?;
+        let public_nonce = nonce.get_public_nonce();
+
+        let mut sessions = self
+            .sessions
+            .lock()
+            .map_err(|_| Error::msg("Failed to lock sessions"))?;
+        if sessions.contains_key(session_id) {
+            return Err(Error::msg("Session already exists"));
+        }
+        sessions.insert(
+            *session_id,
+            PendingSession {
+                msg: *msg,
+                tweak: *tweak,
+                nonce,
+            },
+        );
+
+        Ok(public_nonce)
+    }
+
+    fn get_partial_signature(
+        &self,
+        session_id: &[u8; 32],
+        agg_nonce: &(PublicKey, PublicKey),
+    ) -> Result<SecretKey> {
+        // Removing the session makes sure we never sign twice with the same nonce
+        let pending = self
+            .sessions
+            .lock()
+            .map_err(|_| Error::msg("Failed to lock sessions"))?
+            .remove(session_id)
+            .ok_or_else(|| Error::msg("Unknown session"))?;
+
+        let session = Musig2Session::new(&self.key_agg, &pending.tweak, &pending.msg, agg_nonce)?;
+
+        session.partial_sign(&self.sk, &pending.nonce)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::{hashes::Hash, Address, Amount, Network, OutPoint, ScriptBuf, WPubkeyHash};
+
+    use crate::policy::PolicyViolation;
+    use crate::spclient::{OwnedOutput, Recipient, SpendKey};
+    use crate::test_utils::output_for_key;
+
+    fn musig_client(aggregate: PublicKey) -> SpClient {
+        let mut client = SpClient::new(
+            "musig".to_owned(),
+            SecretKey::from_slice(&[0x11; 32]).unwrap(),
+            SpendKey::Public(aggregate),
+            None,
+            Network::Regtest,
+        )
+        .unwrap();
+        let mut settings = client.get_settings().clone();
+        settings.musig = true;
+        client.set_settings(settings);
+        client
+    }
+
+    fn utxos(aggregate: PublicKey) -> HashMap<OutPoint, OwnedOutput> {
+        HashMap::from([output_for_key(&aggregate, 1, Amount::from_sat(100_000))])
+    }
+
+    fn regular_address(seed: u8) -> String {
+        let script = ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([seed; 20]));
+        Address::from_script(&script, Network::Regtest)
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn cosigner_only_signs_approved_psbts() {
+        let secp = Secp256k1::signing_only();
+        let our_sk = SecretKey::from_slice(&[0x01; 32]).unwrap();
+        let their_sk = SecretKey::from_slice(&[0x02; 32]).unwrap();
+        let cosigner = LocalCosigner::new(their_sk, our_sk.public_key(&secp)).unwrap();
+        let aggregate =
+            Musig2KeyAgg::new(vec![our_sk.public_key(&secp), their_sk.public_key(&secp)])
+                .unwrap()
+                .get_aggregate_pubkey();
+        let client = musig_client(aggregate);
+
+        let recipients = |address: String, amount: u64| {
+            vec![Recipient {
+                address,
+                amount: Amount::from_sat(amount),
+                nb_outputs: 1,
+            }]
+        };
+        // no silent payment, not even our change
+        assert!(client
+            .create_new_psbt(
+                utxos(aggregate),
+                recipients(client.get_receiving_address(), 100_000),
+                None
+            )
+            .is_err());
+        assert!(client
+            .create_new_psbt(
+                utxos(aggregate),
+                recipients(regular_address(1), 60_000),
+                None
+            )
+            .is_err());
+
+        let address = regular_address(1);
+        let mut psbt = client
+            .create_new_psbt(utxos(aggregate), recipients(address.clone(), 100_000), None)
+            .unwrap();
+        SpClient::set_fees(&mut psbt, Amount::from_sat(2), address.clone()).unwrap();
+
+        let policy = SpendingPolicy {
+            whitelist: Some(vec![regular_address(2)]),
+            ..Default::default()
+        };
+        let err = cosigner.approve_psbt(&client, &policy, &psbt).unwrap_err();
+        assert!(err.downcast_ref::<PolicyViolation>().is_some());
+        cosigner
+            .approve_psbt(&client, &SpendingPolicy::default(), &psbt)
+            .unwrap();
+
+        let signer = Musig2Signer::new(our_sk, Box::new(cosigner)).unwrap();
+        let signed = SpClient::sign_psbt_with_signer(&signer, psbt.clone(), &[0u8; 32]).unwrap();
+        assert!(signed.inputs[0].tap_key_sig.is_some());
+        // the approval is used up
+        assert!(SpClient::sign_psbt_with_signer(&signer, psbt, &[0u8; 32]).is_err());
+    }
+}
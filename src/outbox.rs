@@ -0,0 +1,144 @@
+//! Payments queued to be sent later in a single transaction, which costs less than one each
+
+use std::str::FromStr;
+
+use bitcoin::{Address, Amount};
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Error, Result};
+
+use crate::chain::FeeEstimator;
+use crate::settings::FeeLevel;
+use crate::spclient::{try_parse_sp_address, Psbt, Recipient, SpClient, SpWallet};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct QueuedPayment {
+    pub id: u64,
+    pub address: String,
+    pub amount: Amount,
+    pub memo: Option<String>,
+}
+
+/// Persisted by the app next to the wallet
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Outbox {
+    payments: Vec<QueuedPayment>,
+    next_id: u64,
+}
+
+fn check_payment(address: &str, amount: Amount) -> Result<()> {
+    if amount == Amount::ZERO {
+        return Err(Error::msg("Can't queue a payment of 0"));
+    }
+    if try_parse_sp_address(address)?.is_none() {
+        Address::from_str(address)?;
+    }
+    Ok(())
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the id of the payment, to edit or remove it later
+    pub fn queue(&mut self, address: String, amount: Amount, memo: Option<String>) -> Result<u64> {
+        check_payment(&address, amount)?;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.payments.push(QueuedPayment {
+            id,
+            address,
+            amount,
+            memo,
+        });
+        Ok(id)
+    }
+
+    /// In the order they were queued
+    pub fn list_payments(&self) -> &[QueuedPayment] {
+        &self.payments
+    }
+
+    pub fn get_payment(&self, id: u64) -> Option<&QueuedPayment> {
+        self.payments.iter().find(|p| p.id == id)
+    }
+
+    pub fn edit(
+        &mut self,
+        id: u64,
+        address: String,
+        amount: Amount,
+        memo: Option<String>,
+    ) -> Result<()> {
+        check_payment(&address, amount)?;
+        let payment = self
+            .payments
+            .iter_mut()
+            .find(|p| p.id == id)
+            .ok_or_else(|| Error::msg(format!("Unknown queued payment {}", id)))?;
+        payment.address = address;
+        payment.amount = amount;
+        payment.memo = memo;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<QueuedPayment> {
+        let i = self.payments.iter().position(|p| p.id == id)?;
+        Some(self.payments.remove(i))
+    }
+
+    pub fn get_total(&self) -> Amount {
+        self.payments.iter().map(|p| p.amount).sum()
+    }
+
+    /// One transaction paying everything queued, the psbt still has to be signed
+    /// Also returns the ids it pays, to give to `remove_sent` once it's broadcast,
+    /// payments queued in the meantime stay in the outbox
+    pub fn build_batch(
+        &self,
+        wallet: &SpWallet,
+        fee_estimator: &impl FeeEstimator,
+        fee_level: FeeLevel,
+    ) -> Result<(Psbt, Vec<u64>)> {
+        if self.payments.is_empty() {
+            return Err(Error::msg("No queued payment"));
+        }
+
+        let fee_rate = fee_estimator.get_fee_rate(fee_level.get_target_blocks())?;
+        let client = wallet.get_client();
+        let selection = wallet.select_coins(
+            self.get_total(),
+            // the change
+            self.payments.len() + 1,
+            fee_rate,
+            client.get_settings().privacy_mode,
+        )?;
+
+        let recipients = self
+            .payments
+            .iter()
+            .map(|p| Recipient {
+                address: p.address.clone(),
+                amount: p.amount,
+                nb_outputs: 1,
+            })
+            .collect();
+        let mut psbt = client.create_new_psbt(selection.utxos, recipients, None)?;
+
+        SpClient::set_fees_with_policy(
+            &mut psbt,
+            fee_rate,
+            client.sp_receiver.get_change_address(),
+            &client.get_change_policy(),
+        )?;
+        let partial_secret = client.get_partial_secret_from_psbt(&psbt)?;
+        client.fill_and_order(&mut psbt, partial_secret)?;
+
+        Ok((psbt, self.payments.iter().map(|p| p.id).collect()))
+    }
+
+    pub fn remove_sent(&mut self, ids: &[u64]) {
+        self.payments.retain(|p| !ids.contains(&p.id));
+    }
+}
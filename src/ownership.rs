@@ -0,0 +1,211 @@
+//! Proofs of ownership for our inputs (SLIP-19, as used with BIP127), for collaborative transactions
+//!
+//! We only handle taproot keypath proofs, which is all we need for silent payments outputs.
+
+use std::str::FromStr;
+
+use bitcoin::{
+    hashes::{hmac, sha256, Hash, HashEngine},
+    secp256k1::{schnorr, Message, Secp256k1, SecretKey, XOnlyPublicKey},
+    ScriptBuf,
+};
+
+use anyhow::{Error, Result};
+
+use crate::signer::{tagged_hash, LocalSigner, Signer};
+use crate::spclient::{OwnedOutput, SpClient, SpendKey};
+
+const VERSION_MAGIC: [u8; 4] = [0x53, 0x4c, 0x00, 0x19];
+const SCHNORR_SIG_LEN: usize = 64;
+
+fn write_compact_size(buf: &mut Vec<u8>, n: usize) {
+    match n {
+        0..=0xfc => buf.push(n as u8),
+        0xfd..=0xffff => {
+            buf.push(0xfd);
+            buf.extend_from_slice(&(n as u16).to_le_bytes());
+        }
+        _ => {
+            buf.push(0xfe);
+            buf.extend_from_slice(&(n as u32).to_le_bytes());
+        }
+    }
+}
+
+fn read_compact_size(data: &[u8], pos: &mut usize) -> Result<usize> {
+    let first = *data
+        .get(*pos)
+        .ok_or_else(|| Error::msg("Proof too short"))?;
+    *pos += 1;
+    let len = match first {
+        0xfd => 2,
+        0xfe => 4,
+        0xff => return Err(Error::msg("Compact size too big")),
+        n => return Ok(n as usize),
+    };
+    let bytes = data
+        .get(*pos..*pos + len)
+        .ok_or_else(|| Error::msg("Proof too short"))?;
+    *pos += len;
+    let mut le = [0u8; 4];
+    le[..len].copy_from_slice(bytes);
+    Ok(u32::from_le_bytes(le) as usize)
+}
+
+fn proof_body(ownership_ids: &[[u8; 32]]) -> Vec<u8> {
+    let mut body = VERSION_MAGIC.to_vec();
+    // flags, we don't require user confirmation
+    body.push(0);
+    write_compact_size(&mut body, ownership_ids.len());
+    for id in ownership_ids {
+        body.extend_from_slice(id);
+    }
+    body
+}
+
+fn proof_sighash(body: &[u8], script_pubkey: &ScriptBuf, commitment_data: &[u8]) -> Message {
+    let mut engine = sha256::Hash::engine();
+    engine.input(body);
+    let mut footer = vec![];
+    write_compact_size(&mut footer, script_pubkey.len());
+    footer.extend_from_slice(script_pubkey.as_bytes());
+    write_compact_size(&mut footer, commitment_data.len());
+    footer.extend_from_slice(commitment_data);
+    engine.input(&footer);
+    Message::from_digest(sha256::Hash::from_engine(engine).to_byte_array())
+}
+
+/// A parsed and verified proof
+#[derive(Debug, Clone, PartialEq)]
+pub struct OwnershipProof {
+    pub ownership_ids: Vec<[u8; 32]>,
+}
+
+impl OwnershipProof {
+    /// Check a counterparty proof for the output with `script_pubkey`
+    pub fn verify(proof: &[u8], script_pubkey: &ScriptBuf, commitment_data: &[u8]) -> Result<Self> {
+        if !script_pubkey.is_p2tr() {
+            return Err(Error::msg("Only taproot proofs are supported"));
+        }
+        if proof.get(..4) != Some(&VERSION_MAGIC[..]) {
+            return Err(Error::msg("Not a proof of ownership"));
+        }
+
+        let mut pos = 5;
+        let n = read_compact_size(proof, &mut pos)?;
+        // the count comes from the counterparty, don't allocate for more ids than the proof holds
+        if n > proof.len().saturating_sub(pos) / 32 {
+            return Err(Error::msg("Proof too short"));
+        }
+        let mut ownership_ids = Vec::with_capacity(n);
+        for _ in 0..n {
+            let id: [u8; 32] = proof
+                .get(pos..pos + 32)
+                .ok_or_else(|| Error::msg("Proof too short"))?
+                .try_into()?;
+            ownership_ids.push(id);
+            pos += 32;
+        }
+        let body = &proof[..pos];
+
+        // empty script sig, then a witness with a single signature
+        if read_compact_size(proof, &mut pos)? != 0 || read_compact_size(proof, &mut pos)? != 1 {
+            return Err(Error::msg("Unexpected proof signature"));
+        }
+        let sig_len = read_compact_size(proof, &mut pos)?;
+        if sig_len != SCHNORR_SIG_LEN || proof.len() != pos + sig_len {
+            return Err(Error::msg("Unexpected proof signature"));
+        }
+        let sig = schnorr::Signature::from_slice(&proof[pos..])?;
+
+        let msg = proof_sighash(body, script_pubkey, commitment_data);
+        let output_key = XOnlyPublicKey::from_slice(&script_pubkey.as_bytes()[2..])?;
+        Secp256k1::verification_only()
+            .verify_schnorr(&sig, &msg, &output_key)
+            .map_err(|_| Error::msg("Invalid proof signature"))?;
+
+        Ok(Self { ownership_ids })
+    }
+}
+
+impl SpClient {
+    /// Identifies our outputs without revealing anything about them, only we can compute it
+    pub fn get_ownership_id(&self, script_pubkey: &ScriptBuf) -> [u8; 32] {
+        let key = tagged_hash(
+            "sp_client/ownership_id_key",
+            &[&self.get_scan_key().secret_bytes()[..]],
+        );
+        let mut engine = hmac::HmacEngine::<sha256::Hash>::new(&key);
+        engine.input(script_pubkey.as_bytes());
+        hmac::Hmac::<sha256::Hash>::from_engine(engine).to_byte_array()
+    }
+
+    /// Prove that we own `output`, `commitment_data` binds the proof to a specific transaction or round
+    pub fn prove_ownership(
+        &self,
+        output: &OwnedOutput,
+        commitment_data: &[u8],
+        aux_rand: &[u8; 32],
+    ) -> Result<Vec<u8>> {
+        let spend_sk = match self.get_spend_key() {
+            SpendKey::Secret(sk) => sk,
+            SpendKey::Public(_) => return Err(Error::msg("Watch-only wallet, can't spend")),
+        };
+        let signer = LocalSigner::new(spend_sk);
+
+        let script_pubkey = ScriptBuf::from_hex(&output.script)?;
+        let tweak = SecretKey::from_str(&output.tweak)?;
+
+        let mut proof = proof_body(&[self.get_ownership_id(&script_pubkey)]);
+
+        let msg = proof_sighash(&proof, &script_pubkey, commitment_data);
+        let sig = signer.sign_tweaked(&msg, &tweak, aux_rand)?;
+
+        // empty script sig
+        write_compact_size(&mut proof, 0);
+        // witness
+        write_compact_size(&mut proof, 1);
+        write_compact_size(&mut proof, SCHNORR_SIG_LEN);
+        proof.extend_from_slice(&sig[..]);
+
+        Ok(proof)
+    }
+
+    /// Verify a counterparty proof and make sure it's not for one of our own outputs,
+    /// which would mean someone is trying to get us to sign for an input we don't know is ours
+    pub fn verify_foreign_ownership(
+        &self,
+        proof: &[u8],
+        script_pubkey: &ScriptBuf,
+        commitment_data: &[u8],
+    ) -> Result<OwnershipProof> {
+        let proof = OwnershipProof::verify(proof, script_pubkey, commitment_data)?;
+        if proof
+            .ownership_ids
+            .contains(&self.get_ownership_id(script_pubkey))
+        {
+            return Err(Error::msg("Proof is for one of our own outputs"));
+        }
+        Ok(proof)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn huge_id_count_is_an_error() {
+        let (output_key, _) = SecretKey::from_slice(&[0x01; 32])
+            .unwrap()
+            .x_only_public_key(&Secp256k1::signing_only());
+        let script_pubkey = ScriptBuf::new_p2tr_tweaked(
+            bitcoin::key::TweakedPublicKey::dangerous_assume_tweaked(output_key),
+        );
+        let mut proof = VERSION_MAGIC.to_vec();
+        proof.push(0);
+        proof.extend_from_slice(&[0xfe, 0xff, 0xff, 0xff, 0xff]);
+        proof.extend_from_slice(&[0u8; 64]);
+        assert!(OwnershipProof::verify(&proof, &script_pubkey, &[]).is_err());
+    }
+}
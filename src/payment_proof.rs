@@ -0,0 +1,350 @@
+//! Proofs that an output was sent to a silent payment address
+//!
+//! The sender reveals the ECDH shared secret between its inputs and the recipient scan key,
+//! along with a DLEQ proof that it was computed with the same secret as the inputs of the transaction.
+//! Anyone can then derive the output key from the shared secret and the recipient address.
+
+use bitcoin::{
+    consensus::deserialize,
+    hashes::Hash,
+    hex::{DisplayHex, FromHex},
+    psbt::raw,
+    secp256k1::{PublicKey, Secp256k1, SecretKey},
+    Transaction, TxOut, Txid,
+};
+
+use silentpayments::utils::{self as sp_utils, SilentPaymentAddress};
+
+use anyhow::{Error, Result};
+
+use crate::constants::{PSBT_SP_ADDRESS_KEY, PSBT_SP_PREFIX, PSBT_SP_SUBTYPE};
+use crate::signer::{hash_to_scalar, tagged_hash};
+use crate::spclient::{get_tweak_data, Psbt, SpClient};
+
+const PAYMENT_PROOF_PREFIX: &str = "spproof:";
+const PAYMENT_PROOF_VERSION: u8 = 0;
+// version + txid + vout + k + shared secret + dleq proof
+const PAYMENT_PROOF_LEN: usize = 1 + 32 + 4 + 4 + 33 + 64;
+
+const DLEQ_NONCE_TAG: &str = "sp_client/payment_proof/nonce";
+const DLEQ_CHALLENGE_TAG: &str = "sp_client/payment_proof/challenge";
+
+/// Shared as the string of `encode`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaymentProof {
+    pub txid: Txid,
+    pub vout: u32,
+    /// Index of the output among the ones for the same scan key
+    pub k: u32,
+    pub shared_secret: PublicKey,
+    /// Proves that `shared_secret` is the tweak data of the transaction times the scan key
+    pub dleq: [u8; 64],
+}
+
+fn dleq_challenge(
+    tweak_data: &PublicKey,
+    scan_pk: &PublicKey,
+    shared_secret: &PublicKey,
+    r1: &PublicKey,
+    r2: &PublicKey,
+) -> Result<SecretKey> {
+    hash_to_scalar(tagged_hash(
+        DLEQ_CHALLENGE_TAG,
+        &[
+            &tweak_data.serialize()[..],
+            &scan_pk.serialize()[..],
+            &shared_secret.serialize()[..],
+            &r1.serialize()[..],
+            &r2.serialize()[..],
+        ],
+    ))
+}
+
+/// Prove that `partial_secret * G` and `partial_secret * scan_pk` share the same discrete log
+pub(crate) fn dleq_prove(
+    partial_secret: &SecretKey,
+    scan_pk: &PublicKey,
+    aux_rand: &[u8; 32],
+) -> Result<[u8; 64]> {
+    let secp = Secp256k1::new();
+
+    let tweak_data = partial_secret.public_key(&secp);
+    let shared_secret = scan_pk.mul_tweak(&secp, &(*partial_secret).into())?;
+
+    let mut nonce = hash_to_scalar(tagged_hash(
+        DLEQ_NONCE_TAG,
+        &[
+            &partial_secret.secret_bytes()[..],
+            &aux_rand[..],
+            &shared_secret.serialize()[..],
+        ],
+    ))?;
+    let r1 = nonce.public_key(&secp);
+    let r2 = scan_pk.mul_tweak(&secp, &nonce.into())?;
+
+    let e = dleq_challenge(&tweak_data, scan_pk, &shared_secret, &r1, &r2)?;
+    let s = nonce.add_tweak(&partial_secret.mul_tweak(&e.into())?.into())?;
+
+    nonce.non_secure_erase();
+
+    let mut proof = [0u8; 64];
+    proof[..32].copy_from_slice(&e.secret_bytes());
+    proof[32..].copy_from_slice(&s.secret_bytes());
+    Ok(proof)
+}
+
+pub(crate) fn dleq_verify(
+    tweak_data: &PublicKey,
+    scan_pk: &PublicKey,
+    shared_secret: &PublicKey,
+    proof: &[u8; 64],
+) -> Result<()> {
+    let secp = Secp256k1::new();
+
+    let e = SecretKey::from_slice(&proof[..32])?;
+    let s = SecretKey::from_slice(&proof[32..])?;
+
+    // R1 = s*G - e*A, R2 = s*B - e*C
+    let r1 = s
+        .public_key(&secp)
+        .combine(&tweak_data.mul_tweak(&secp, &e.into())?.negate(&secp))?;
+    let r2 = scan_pk
+        .mul_tweak(&secp, &s.into())?
+        .combine(&shared_secret.mul_tweak(&secp, &e.into())?.negate(&secp))?;
+
+    if dleq_challenge(tweak_data, scan_pk, shared_secret, &r1, &r2)? != e {
+        return Err(Error::msg("Invalid DLEQ proof"));
+    }
+    Ok(())
+}
+
+fn output_key(shared_secret: &PublicKey, spend_pk: &PublicKey, k: u32) -> Result<PublicKey> {
+    let t_k = hash_to_scalar(tagged_hash(
+        "BIP0352/SharedSecret",
+        &[&shared_secret.serialize()[..], &k.to_be_bytes()[..]],
+    ))?;
+    Ok(spend_pk.add_exp_tweak(&Secp256k1::verification_only(), &t_k.into())?)
+}
+
+impl PaymentProof {
+    pub fn encode(&self) -> String {
+        let mut data = Vec::with_capacity(PAYMENT_PROOF_LEN);
+        data.push(PAYMENT_PROOF_VERSION);
+        data.extend_from_slice(&self.txid.to_byte_array());
+        data.extend_from_slice(&self.vout.to_be_bytes());
+        data.extend_from_slice(&self.k.to_be_bytes());
+        data.extend_from_slice(&self.shared_secret.serialize());
+        data.extend_from_slice(&self.dleq);
+
+        format!("{}{}", PAYMENT_PROOF_PREFIX, data.to_lower_hex_string())
+    }
+
+    pub fn decode(encoded: &str) -> Result<Self> {
+        let hex = encoded
+            .strip_prefix(PAYMENT_PROOF_PREFIX)
+            .ok_or_else(|| Error::msg("Not a payment proof"))?;
+        let data = Vec::<u8>::from_hex(hex)?;
+
+        if data.len() != PAYMENT_PROOF_LEN {
+            return Err(Error::msg("Invalid payment proof length"));
+        }
+        if data[0] != PAYMENT_PROOF_VERSION {
+            return Err(Error::msg(format!(
+                "Unknown payment proof version {}",
+                data[0]
+            )));
+        }
+
+        Ok(Self {
+            txid: Txid::from_byte_array(data[1..33].try_into()?),
+            vout: u32::from_be_bytes(data[33..37].try_into()?),
+            k: u32::from_be_bytes(data[37..41].try_into()?),
+            shared_secret: PublicKey::from_slice(&data[41..74])?,
+            dleq: data[74..].try_into()?,
+        })
+    }
+}
+
+impl SpClient {
+    /// Prove that output `vout` of the transaction in `psbt` pays the silent payment address it was created for
+    /// The psbt must still have the tweaks of our inputs and the address of the output
+    pub fn create_payment_proof(
+        &self,
+        psbt: &Psbt,
+        vout: u32,
+        aux_rand: &[u8; 32],
+    ) -> Result<PaymentProof> {
+        let output = psbt
+            .outputs
+            .get(vout as usize)
+            .ok_or_else(|| Error::msg(format!("No output {}", vout)))?;
+        let address = output
+            .proprietary
+            .get(&raw::ProprietaryKey {
+                prefix: PSBT_SP_PREFIX.as_bytes().to_vec(),
+                subtype: PSBT_SP_SUBTYPE,
+                key: PSBT_SP_ADDRESS_KEY.as_bytes().to_vec(),
+            })
+            .ok_or_else(|| Error::msg(format!("Output {} is not a silent payment", vout)))?;
+        let address = SilentPaymentAddress::try_from(deserialize::<String>(address)?)?;
+        let script_pubkey = &psbt.unsigned_tx.output[vout as usize].script_pubkey;
+
+        let mut partial_secret = self.get_partial_secret_from_psbt(psbt)?;
+        let scan_pk = address.get_scan_key();
+        let shared_secret =
+            sp_utils::sending::calculate_ecdh_shared_secret(&scan_pk, &partial_secret);
+
+        // outputs for the same scan key are numbered in order, there can't be more than there are outputs
+        let proof = (0..psbt.outputs.len() as u32)
+            .find(|k| {
+                output_key(&shared_secret, &address.get_spend_key(), *k)
+                    .map(|key| {
+                        script_pubkey.is_p2tr()
+                            && script_pubkey.as_bytes()[2..]
+                                == key.x_only_public_key().0.serialize()
+                    })
+                    .unwrap_or(false)
+            })
+            .ok_or_else(|| Error::msg(format!("Output {} doesn't pay {}", vout, address)))
+            .and_then(|k| Ok((k, dleq_prove(&partial_secret, &scan_pk, aux_rand)?)));
+
+        // don't leave the secret lying around, whatever the result
+        partial_secret.non_secure_erase();
+
+        let (k, dleq) = proof?;
+
+        Ok(PaymentProof {
+            txid: psbt.unsigned_tx.txid(),
+            vout,
+            k,
+            shared_secret,
+            dleq,
+        })
+    }
+}
+
+/// Check a payment proof against the transaction it claims to be in, no wallet keys needed
+/// `prevouts` are the outputs spent by each input of `tx`, in order
+pub fn verify_payment_proof(
+    proof: &str,
+    tx: &Transaction,
+    prevouts: &[TxOut],
+    sp_address: &str,
+) -> Result<PaymentProof> {
+    let proof = PaymentProof::decode(proof)?;
+    let address = SilentPaymentAddress::try_from(sp_address)?;
+
+    if tx.txid() != proof.txid {
+        return Err(Error::msg("Proof is for another transaction"));
+    }
+    let output = tx
+        .output
+        .get(proof.vout as usize)
+        .ok_or_else(|| Error::msg(format!("No output {}", proof.vout)))?;
+
+    let tweak_data = get_tweak_data(tx, prevouts)?
+        .ok_or_else(|| Error::msg("No input eligible for silent payments"))?;
+
+    dleq_verify(
+        &tweak_data,
+        &address.get_scan_key(),
+        &proof.shared_secret,
+        &proof.dleq,
+    )?;
+
+    let expected = output_key(&proof.shared_secret, &address.get_spend_key(), proof.k)?;
+    if !output.script_pubkey.is_p2tr()
+        || output.script_pubkey.as_bytes()[2..] != expected.x_only_public_key().0.serialize()
+    {
+        return Err(Error::msg(format!(
+            "Output {} doesn't pay {}",
+            proof.vout, sp_address
+        )));
+    }
+
+    Ok(proof)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use bitcoin::Amount;
+
+    use crate::spclient::Recipient;
+    use crate::test_utils::{other_client, owned_output, test_client};
+
+    #[test]
+    fn dleq_proves_the_same_secret() {
+        let secp = Secp256k1::new();
+        let secret = SecretKey::from_slice(&[0x05; 32]).unwrap();
+        let other = SecretKey::from_slice(&[0x06; 32]).unwrap();
+        let scan_pk = SecretKey::from_slice(&[0x11; 32])
+            .unwrap()
+            .public_key(&secp);
+        let tweak_data = secret.public_key(&secp);
+        let shared_secret = scan_pk.mul_tweak(&secp, &secret.into()).unwrap();
+
+        let proof = dleq_prove(&secret, &scan_pk, &[0; 32]).unwrap();
+        dleq_verify(&tweak_data, &scan_pk, &shared_secret, &proof).unwrap();
+
+        // a shared secret or tweak data from another secret
+        let wrong = scan_pk.mul_tweak(&secp, &other.into()).unwrap();
+        assert!(dleq_verify(&tweak_data, &scan_pk, &wrong, &proof).is_err());
+        assert!(dleq_verify(&other.public_key(&secp), &scan_pk, &shared_secret, &proof).is_err());
+
+        let mut tampered = proof;
+        tampered[63] ^= 1;
+        assert!(dleq_verify(&tweak_data, &scan_pk, &shared_secret, &tampered).is_err());
+    }
+
+    #[test]
+    fn proof_verifies_against_the_transaction() {
+        let client = test_client();
+        let recipient = other_client().get_receiving_address();
+        let utxos = HashMap::from([owned_output(&client, 1, Amount::from_sat(50_000))]);
+        let recipients = vec![Recipient {
+            address: recipient.clone(),
+            amount: Amount::from_sat(40_000),
+            nb_outputs: 1,
+        }];
+        let mut psbt = client.create_new_psbt(utxos, recipients, None).unwrap();
+        SpClient::set_fees(
+            &mut psbt,
+            Amount::from_sat(2),
+            client.sp_receiver.get_change_address(),
+        )
+        .unwrap();
+        let partial_secret = client.get_partial_secret_from_psbt(&psbt).unwrap();
+        client.fill_sp_outputs(&mut psbt, partial_secret).unwrap();
+        let vout = psbt
+            .unsigned_tx
+            .output
+            .iter()
+            .position(|o| o.value == Amount::from_sat(40_000))
+            .unwrap() as u32;
+
+        let proof = client.create_payment_proof(&psbt, vout, &[0; 32]).unwrap();
+        assert_eq!(PaymentProof::decode(&proof.encode()).unwrap(), proof);
+
+        let prevouts: Vec<TxOut> = psbt
+            .iter_funding_utxos()
+            .map(|utxo| utxo.unwrap().clone())
+            .collect();
+        let mut signed = client.sign_psbt(psbt, &[0; 32], None).unwrap();
+        SpClient::finalize_psbt(&mut signed).unwrap();
+        let tx = signed.extract_tx().unwrap();
+
+        verify_payment_proof(&proof.encode(), &tx, &prevouts, &recipient).unwrap();
+        // not the address it pays
+        assert!(verify_payment_proof(
+            &proof.encode(),
+            &tx,
+            &prevouts,
+            &client.get_receiving_address()
+        )
+        .is_err());
+    }
+}
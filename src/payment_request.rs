@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+
+use bitcoin::{Amount, OutPoint};
+use serde::{Deserialize, Serialize};
+
+use silentpayments::receiving::Label;
+
+use anyhow::{Error, Result};
+
+use crate::spclient::{OwnedOutput, SpClient};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum PaymentRequestStatus {
+    Pending,
+    Paid,
+    /// We received something, but less than requested
+    Underpaid,
+    Expired,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PaymentRequest {
+    pub id: u32,
+    /// Label index the address is derived from, 0 is the change label so it's never used
+    pub label_index: u32,
+    pub address: String,
+    pub amount: Amount,
+    pub memo: Option<String>,
+    /// Unix timestamp
+    pub expiry: Option<u64>,
+    pub received: Amount,
+    pub payments: Vec<OutPoint>,
+    /// Label string as stored in `OwnedOutput`, to match incoming payments
+    label: String,
+}
+
+impl PaymentRequest {
+    pub fn get_status(&self, now: u64) -> PaymentRequestStatus {
+        if self.received >= self.amount {
+            PaymentRequestStatus::Paid
+        } else if self.received > Amount::ZERO {
+            PaymentRequestStatus::Underpaid
+        } else if self.is_expired(now) {
+            PaymentRequestStatus::Expired
+        } else {
+            PaymentRequestStatus::Pending
+        }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expiry.is_some_and(|expiry| now >= expiry)
+    }
+}
+
+/// All the payment requests of a wallet, to be persisted next to its `OutputList`
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PaymentRequests {
+    next_id: u32,
+    requests: Vec<PaymentRequest>,
+}
+
+impl PaymentRequests {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a request paid to a labeled address
+    /// If `label_index` is None a new label is allocated, otherwise its address is reused,
+    /// as long as no other open request uses it since we couldn't tell the payments apart
+    pub fn create_payment_request(
+        &mut self,
+        client: &mut SpClient,
+        amount: Amount,
+        memo: Option<String>,
+        expiry: Option<u64>,
+        label_index: Option<u32>,
+        now: u64,
+    ) -> Result<PaymentRequest> {
+        let label_index = match label_index {
+            Some(0) => return Err(Error::msg("Label 0 is reserved for change")),
+            Some(m) => {
+                if self.requests.iter().any(|r| {
+                    r.label_index == m && r.get_status(now) == PaymentRequestStatus::Pending
+                }) {
+                    return Err(Error::msg(format!(
+                        "Label {} is used by an open request",
+                        m
+                    )));
+                }
+                m
+            }
+            None => {
+                self.requests
+                    .iter()
+                    .map(|r| r.label_index)
+                    .max()
+                    .unwrap_or(0)
+                    + 1
+            }
+        };
+
+        let label = Label::new(client.get_scan_key(), label_index);
+        let label_str = label.as_string();
+        client.sp_receiver.add_label(label.clone())?;
+        let address = client.sp_receiver.get_receiving_address_for_label(&label)?;
+
+        let request = PaymentRequest {
+            id: self.next_id,
+            label_index,
+            address,
+            amount,
+            memo,
+            expiry,
+            received: Amount::ZERO,
+            payments: vec![],
+            label: label_str,
+        };
+        self.next_id += 1;
+        self.requests.push(request.clone());
+
+        Ok(request)
+    }
+
+    /// Labels live in the client, a client restored from its keys must get them back to find the payments
+    pub fn restore_labels(&self, client: &mut SpClient) -> Result<()> {
+        let scan_sk = client.get_scan_key();
+        for request in self.requests.iter() {
+            client
+                .sp_receiver
+                .add_label(Label::new(scan_sk, request.label_index))?;
+        }
+        Ok(())
+    }
+
+    /// Attribute new outputs, e.g. the result of `update_wallet_with_transaction`, to the requests
+    /// An output paying a reused label goes to the latest request for that label
+    /// Returns the ids of the requests that were updated
+    pub fn match_outputs(&mut self, outputs: &HashMap<OutPoint, OwnedOutput>) -> Vec<u32> {
+        let mut updated = vec![];
+        for (outpoint, output) in outputs.iter() {
+            let label = match output.label {
+                Some(ref label) => label,
+                None => continue,
+            };
+            if let Some(request) = self.requests.iter_mut().rev().find(|r| r.label == *label) {
+                if request.payments.contains(outpoint) {
+                    continue;
+                }
+                request.payments.push(*outpoint);
+                request.received += output.amount;
+                if !updated.contains(&request.id) {
+                    updated.push(request.id);
+                }
+            }
+        }
+        updated
+    }
+
+    pub fn get_request(&self, id: u32) -> Option<&PaymentRequest> {
+        self.requests.iter().find(|r| r.id == id)
+    }
+
+    pub fn list_requests(&self) -> &Vec<PaymentRequest> {
+        &self.requests
+    }
+
+    pub fn list_with_status(&self, status: PaymentRequestStatus, now: u64) -> Vec<&PaymentRequest> {
+        self.requests
+            .iter()
+            .filter(|r| r.get_status(now) == status)
+            .collect()
+    }
+}
@@ -0,0 +1,249 @@
+//! Limits on what the wallet is allowed to spend
+//!
+//! Rules that only depend on the transaction are checked by `SpClient` when building and signing,
+//! the ones that depend on past spends (daily limit, time delay) need the history of `SpWallet::sign_psbt`,
+//! `SpClient::sign_psbt` refuses to sign when there are some.
+
+use std::collections::HashMap;
+
+use bitcoin::{consensus::deserialize, psbt::raw, Address, Amount, Txid};
+use serde::{Deserialize, Serialize};
+
+use anyhow::Result;
+
+use crate::constants::{PSBT_SP_ADDRESS_KEY, PSBT_SP_PREFIX, PSBT_SP_SUBTYPE};
+use crate::psbt_data::is_psbt_change_output;
+use crate::spclient::{Psbt, Recipient, SpClient};
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// Spends above `above` must be requested and wait `delay` seconds before they can be signed
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct TimeDelay {
+    pub above: Amount,
+    pub delay: u64,
+}
+
+/// No limit at all by default
+/// Our own change never counts as spent
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SpendingPolicy {
+    /// Over any 24 hours window
+    pub daily_limit: Option<Amount>,
+    pub max_per_tx: Option<Amount>,
+    /// Only these addresses can be paid when set
+    pub whitelist: Option<Vec<String>>,
+    pub time_delay: Option<TimeDelay>,
+}
+
+/// Returned wrapped in an `anyhow::Error`, use `downcast_ref` to tell the user what happened
+#[derive(Debug, Clone, PartialEq)]
+pub enum PolicyViolation {
+    MaxPerTx {
+        amount: Amount,
+        max: Amount,
+    },
+    DailyLimit {
+        amount: Amount,
+        /// Already spent in the last 24 hours
+        spent: Amount,
+        limit: Amount,
+    },
+    NotWhitelisted(String),
+    /// `ready_at` is None if the spend wasn't requested yet, see `SpWallet::request_delayed_spend`
+    DelayRequired {
+        amount: Amount,
+        ready_at: Option<u64>,
+    },
+    /// Signing without the spend history while the policy has a daily limit or a time delay
+    HistoryRequired,
+}
+
+impl std::fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MaxPerTx { amount, max } => {
+                write!(
+                    f,
+                    "Spending {} is above the maximum of {} per transaction",
+                    amount, max
+                )
+            }
+            Self::DailyLimit {
+                amount,
+                spent,
+                limit,
+            } => write!(
+                f,
+                "Spending {} on top of the {} spent today is above the daily limit of {}",
+                amount, spent, limit
+            ),
+            Self::NotWhitelisted(address) => write!(f, "{} is not whitelisted", address),
+            Self::DelayRequired {
+                amount,
+                ready_at: None,
+            } => write!(f, "Spending {} must be requested in advance", amount),
+            Self::DelayRequired {
+                amount,
+                ready_at: Some(ready_at),
+            } => write!(f, "Spending {} is only possible after {}", amount, ready_at),
+            Self::HistoryRequired => {
+                write!(
+                    f,
+                    "The spending policy depends on past spends, sign with the wallet"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for PolicyViolation {}
+
+impl SpendingPolicy {
+    /// The daily limit and the time delay can only be checked with the `SpendHistory`
+    pub fn depends_on_history(&self) -> bool {
+        self.daily_limit.is_some() || self.time_delay.is_some()
+    }
+
+    fn check_whitelist(&self, address: &str) -> Result<()> {
+        match self.whitelist {
+            Some(ref whitelist) if !whitelist.iter().any(|a| a == address) => {
+                Err(PolicyViolation::NotWhitelisted(address.to_owned()).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_max_per_tx(&self, amount: Amount) -> Result<()> {
+        match self.max_per_tx {
+            Some(max) if amount > max => Err(PolicyViolation::MaxPerTx { amount, max }.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Rules that only depend on what we pay, `recipients` must not include our change
+    pub fn check_recipients(&self, recipients: &[Recipient]) -> Result<()> {
+        for recipient in recipients {
+            self.check_whitelist(&recipient.address)?;
+        }
+        self.check_max_per_tx(recipients.iter().map(|r| r.amount).sum())
+    }
+
+    /// Same as `check_recipients` for a psbt built by anyone, returns the amount it spends
+    /// An output is only our change if its script is the one we derive for our change address
+    pub fn check_psbt(&self, client: &SpClient, psbt: &Psbt) -> Result<Amount> {
+        let change_address = client.sp_receiver.get_change_address();
+        let change_scripts = client.get_change_scripts(psbt);
+        let sp_key = raw::ProprietaryKey {
+            prefix: PSBT_SP_PREFIX.as_bytes().to_vec(),
+            subtype: PSBT_SP_SUBTYPE,
+            key: PSBT_SP_ADDRESS_KEY.as_bytes().to_vec(),
+        };
+
+        let mut spent = Amount::ZERO;
+        for (vout, (txout, output)) in psbt
+            .unsigned_tx
+            .output
+            .iter()
+            .zip(psbt.outputs.iter())
+            .enumerate()
+        {
+            if txout.script_pubkey.is_op_return() && txout.value == Amount::ZERO {
+                continue;
+            }
+            let address = match output.proprietary.get(&sp_key) {
+                Some(value) => deserialize::<String>(value)?,
+                None => Address::from_script(&txout.script_pubkey, client.get_network())
+                    .map(|a| a.to_string())
+                    .unwrap_or_else(|_| txout.script_pubkey.to_string()),
+            };
+            if address == change_address
+                && is_psbt_change_output(psbt, vout)
+                && change_scripts.contains(&txout.script_pubkey)
+            {
+                continue;
+            }
+            self.check_whitelist(&address)?;
+            spent += txout.value;
+        }
+
+        self.check_max_per_tx(spent)?;
+
+        Ok(spent)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct SpendRecord {
+    pub txid: Txid,
+    pub amount: Amount,
+    pub timestamp: u64,
+}
+
+/// Past spends and delayed spends waiting for their time, persisted with the wallet
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SpendHistory {
+    spends: Vec<SpendRecord>,
+    /// When each delayed spend was requested
+    requested: HashMap<Txid, u64>,
+}
+
+impl SpendHistory {
+    pub fn get_spent_since(&self, since: u64) -> Amount {
+        self.spends
+            .iter()
+            .filter(|s| s.timestamp > since)
+            .map(|s| s.amount)
+            .sum()
+    }
+
+    /// Rules that depend on past spends, for spending `amount` in the transaction `txid`
+    pub fn check(
+        &self,
+        policy: &SpendingPolicy,
+        txid: Txid,
+        amount: Amount,
+        now: u64,
+    ) -> Result<()> {
+        if let Some(limit) = policy.daily_limit {
+            let spent = self.get_spent_since(now.saturating_sub(DAY));
+            if spent + amount > limit {
+                return Err(PolicyViolation::DailyLimit {
+                    amount,
+                    spent,
+                    limit,
+                }
+                .into());
+            }
+        }
+
+        if let Some(time_delay) = policy.time_delay {
+            if amount > time_delay.above {
+                let ready_at = self.requested.get(&txid).map(|t| t + time_delay.delay);
+                match ready_at {
+                    Some(ready_at) if ready_at <= now => (),
+                    _ => return Err(PolicyViolation::DelayRequired { amount, ready_at }.into()),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Start the delay for `txid`, requesting it again doesn't restart it
+    pub fn request(&mut self, txid: Txid, now: u64) -> u64 {
+        *self.requested.entry(txid).or_insert(now)
+    }
+
+    pub fn record(&mut self, txid: Txid, amount: Amount, now: u64) {
+        self.requested.remove(&txid);
+        // older spends don't count anymore
+        self.spends
+            .retain(|s| s.timestamp > now.saturating_sub(DAY));
+        self.spends.push(SpendRecord {
+            txid,
+            amount,
+            timestamp: now,
+        });
+    }
+}
@@ -0,0 +1,118 @@
+//! Heuristic privacy report of a psbt, to warn the user before signing
+
+use std::collections::HashSet;
+
+use bitcoin::{consensus::deserialize, psbt::raw, Amount, OutPoint};
+use serde::{Deserialize, Serialize};
+
+use anyhow::Result;
+
+use crate::constants::{PSBT_SP_ADDRESS_KEY, PSBT_SP_PREFIX, PSBT_SP_SUBTYPE};
+use crate::spclient::{Psbt, SpWallet};
+
+/// Amounts that are a multiple of this look like payments
+const ROUND_AMOUNT: u64 = 10_000;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum PrivacyWarning {
+    /// Inputs from different labels, whoever paid each label learns about the others
+    MixedLabels(Vec<Option<String>>),
+    /// The change is the only output that isn't round
+    DetectableChangeAmount,
+    /// The change is the only taproot output, like all the inputs
+    DetectableChangeType,
+    /// Inputs that were quarantined or are below the dust attack threshold
+    DustInputs(Vec<OutPoint>),
+}
+
+impl PrivacyWarning {
+    fn get_penalty(&self) -> u8 {
+        match self {
+            Self::MixedLabels(_) => 30,
+            Self::DetectableChangeAmount => 20,
+            Self::DetectableChangeType => 20,
+            Self::DustInputs(_) => 30,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PrivacyReport {
+    /// 100 when nothing was found, lower is worse
+    pub score: u8,
+    pub warnings: Vec<PrivacyWarning>,
+}
+
+fn is_round(amount: Amount) -> bool {
+    amount > Amount::ZERO && amount.to_sat().is_multiple_of(ROUND_AMOUNT)
+}
+
+impl SpWallet {
+    /// `psbt` must spend our outputs only
+    pub fn get_privacy_report(&self, psbt: &Psbt) -> Result<PrivacyReport> {
+        let outputs = self.get_outputs();
+        let dust_threshold = self.get_client().get_dust_attack_threshold();
+
+        let mut labels: Vec<Option<String>> = vec![];
+        let mut dust_inputs = vec![];
+        for txin in psbt.unsigned_tx.input.iter() {
+            let (outpoint, output) = outputs.get_outpoint(txin.previous_output)?;
+            if !labels.contains(&output.label) {
+                labels.push(output.label.clone());
+            }
+            if output.quarantined || output.amount < dust_threshold {
+                dust_inputs.push(outpoint);
+            }
+        }
+
+        let change_address = self.get_client().sp_receiver.get_change_address();
+        let sp_key = raw::ProprietaryKey {
+            prefix: PSBT_SP_PREFIX.as_bytes().to_vec(),
+            subtype: PSBT_SP_SUBTYPE,
+            key: PSBT_SP_ADDRESS_KEY.as_bytes().to_vec(),
+        };
+        let mut change_vouts = HashSet::new();
+        for (vout, output) in psbt.outputs.iter().enumerate() {
+            if let Some(value) = output.proprietary.get(&sp_key) {
+                if deserialize::<String>(value)? == change_address {
+                    change_vouts.insert(vout);
+                }
+            }
+        }
+
+        let mut warnings = vec![];
+        if labels.len() > 1 {
+            warnings.push(PrivacyWarning::MixedLabels(labels));
+        }
+
+        let (change, payments): (Vec<_>, Vec<_>) = psbt
+            .unsigned_tx
+            .output
+            .iter()
+            .enumerate()
+            .filter(|(_, o)| !o.script_pubkey.is_op_return())
+            .partition(|(vout, _)| change_vouts.contains(vout));
+        if !change.is_empty() && !payments.is_empty() {
+            if payments.iter().all(|(_, o)| is_round(o.value))
+                && change.iter().all(|(_, o)| !is_round(o.value))
+            {
+                warnings.push(PrivacyWarning::DetectableChangeAmount);
+            }
+            // all our inputs are taproot
+            if payments.iter().all(|(_, o)| !o.script_pubkey.is_p2tr()) {
+                warnings.push(PrivacyWarning::DetectableChangeType);
+            }
+        }
+
+        if !dust_inputs.is_empty() {
+            warnings.push(PrivacyWarning::DustInputs(dust_inputs));
+        }
+
+        let penalty: u8 = warnings.iter().map(|w| w.get_penalty()).sum();
+
+        Ok(PrivacyReport {
+            score: 100u8.saturating_sub(penalty),
+            warnings,
+        })
+    }
+}
@@ -0,0 +1,108 @@
+//! Read and write the proprietary silent payment fields of a psbt, see `constants`
+//!
+//! For tools that inspect or build psbts for us without knowing the encoding.
+
+use bitcoin::{
+    consensus::{deserialize, serialize},
+    hex::DisplayHex,
+    psbt::raw,
+    secp256k1::SecretKey,
+};
+use serde::{Deserialize, Serialize};
+use silentpayments::utils::SilentPaymentAddress;
+
+use anyhow::{Error, Result};
+
+use crate::constants::{
+    PSBT_SP_ADDRESS_KEY, PSBT_SP_CHANGE_KEY, PSBT_SP_PREFIX, PSBT_SP_SUBTYPE, PSBT_SP_TWEAK_KEY,
+};
+use crate::spclient::Psbt;
+
+fn sp_key(key: &str) -> raw::ProprietaryKey {
+    raw::ProprietaryKey {
+        prefix: PSBT_SP_PREFIX.as_bytes().to_vec(),
+        subtype: PSBT_SP_SUBTYPE,
+        key: key.as_bytes().to_vec(),
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SpPsbtData {
+    /// Hex encoded tweak of each input, None for inputs that aren't ours
+    pub input_tweaks: Vec<Option<String>>,
+    /// Silent payment address of each output, None for other outputs
+    pub output_addresses: Vec<Option<String>>,
+}
+
+pub fn get_psbt_sp_data(psbt: &Psbt) -> Result<SpPsbtData> {
+    let input_tweaks = psbt
+        .inputs
+        .iter()
+        .map(|input| {
+            input
+                .proprietary
+                .get(&sp_key(PSBT_SP_TWEAK_KEY))
+                .map(|tweak| tweak.to_lower_hex_string())
+        })
+        .collect();
+    let output_addresses = psbt
+        .outputs
+        .iter()
+        .map(|output| {
+            output
+                .proprietary
+                .get(&sp_key(PSBT_SP_ADDRESS_KEY))
+                .map(|value| deserialize::<String>(value))
+                .transpose()
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(SpPsbtData {
+        input_tweaks,
+        output_addresses,
+    })
+}
+
+/// Whether `create_new_psbt` marked the output as our change
+pub fn is_psbt_change_output(psbt: &Psbt, vout: usize) -> bool {
+    psbt.outputs
+        .get(vout)
+        .is_some_and(|output| output.proprietary.contains_key(&sp_key(PSBT_SP_CHANGE_KEY)))
+}
+
+/// Mark `vout` as our change, its address must be set with `set_psbt_sp_address`
+pub fn set_psbt_change_output(psbt: &mut Psbt, vout: usize) -> Result<()> {
+    let output = psbt
+        .outputs
+        .get_mut(vout)
+        .ok_or_else(|| Error::msg(format!("No output {}", vout)))?;
+    output
+        .proprietary
+        .insert(sp_key(PSBT_SP_CHANGE_KEY), vec![]);
+    Ok(())
+}
+
+/// The output script is left as is, it's set by `fill_sp_outputs`
+pub fn set_psbt_sp_address(psbt: &mut Psbt, vout: usize, sp_address: &str) -> Result<()> {
+    let sp_address = SilentPaymentAddress::try_from(sp_address)?;
+    let output = psbt
+        .outputs
+        .get_mut(vout)
+        .ok_or_else(|| Error::msg(format!("No output {}", vout)))?;
+    output.proprietary.insert(
+        sp_key(PSBT_SP_ADDRESS_KEY),
+        serialize(&sp_address.to_string()),
+    );
+    Ok(())
+}
+
+pub fn set_psbt_input_tweak(psbt: &mut Psbt, input_index: usize, tweak: &SecretKey) -> Result<()> {
+    let input = psbt
+        .inputs
+        .get_mut(input_index)
+        .ok_or_else(|| Error::msg(format!("No input {}", input_index)))?;
+    input
+        .proprietary
+        .insert(sp_key(PSBT_SP_TWEAK_KEY), tweak.secret_bytes().to_vec());
+    Ok(())
+}
@@ -0,0 +1,105 @@
+//! Registration with a push notification server, so that the phone is woken up to scan a block
+//!
+//! We don't do any networking here, the app sends the registration to its server.
+//! By default the server only gets the outputs we own, to report when they confirm or get spent,
+//! and wakes us for every new block. It only gets the scan key, to detect payments itself,
+//! when delegated scanning was explicitly enabled.
+
+use bitcoin::{BlockHash, Network, OutPoint};
+use serde::{Deserialize, Serialize};
+
+use anyhow::Result;
+
+use crate::spclient::SpWallet;
+use crate::watch_list::WatchEvent;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PushRegistration {
+    /// Opaque token of the platform push service, we never look into it
+    pub token: String,
+    pub network: Network,
+    /// Encoded `WatchList`
+    pub watch_list: String,
+    /// Encoded `WatchOnlyPackage`, only with delegated scanning
+    pub delegated_scan: Option<String>,
+}
+
+/// Payload of the notifications the server sends
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum PushNotification {
+    NewBlock {
+        height: u32,
+        blockhash: BlockHash,
+    },
+    /// Only with delegated scanning, the server found an output that may be ours
+    PotentialPayment {
+        height: u32,
+        blockhash: BlockHash,
+    },
+    Watched {
+        events: Vec<WatchEvent>,
+    },
+}
+
+impl PushNotification {
+    pub fn from_payload(payload: &str) -> Result<Self> {
+        Ok(serde_json::from_str(payload)?)
+    }
+}
+
+/// What the app should do once woken up
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum PushAction {
+    /// Scan from `from` to `to` included, blocks we missed come before the one we were woken for
+    ScanBlocks {
+        from: u32,
+        to: u32,
+    },
+    /// Persist these outputs, they were updated from the watch events
+    SaveOutputs(Vec<OutPoint>),
+    Nothing,
+}
+
+impl SpWallet {
+    /// `delegate_scan` gives the server our scan key, it can then see all our incoming payments
+    /// Register again after each scan or spend, so that the watch list stays current
+    pub fn create_push_registration(
+        &self,
+        token: String,
+        delegate_scan: bool,
+    ) -> Result<PushRegistration> {
+        Ok(PushRegistration {
+            token,
+            network: self.get_client().get_network(),
+            watch_list: self.export_watch_list()?.encode()?,
+            delegated_scan: delegate_scan.then(|| self.export_watch_only().encode()),
+        })
+    }
+
+    pub fn handle_push(&mut self, notification: &PushNotification) -> Result<PushAction> {
+        match notification {
+            // blocks we already scanned were pushed again, e.g. after a reconnection of the server
+            PushNotification::NewBlock { height, .. }
+            | PushNotification::PotentialPayment { height, .. } => {
+                let last_scan = self.get_outputs().get_last_scan();
+                if *height > last_scan {
+                    Ok(PushAction::ScanBlocks {
+                        from: last_scan + 1,
+                        to: *height,
+                    })
+                } else {
+                    Ok(PushAction::Nothing)
+                }
+            }
+            PushNotification::Watched { events } => {
+                let changed = self.apply_watch_events(events)?;
+                if changed.is_empty() {
+                    Ok(PushAction::Nothing)
+                } else {
+                    Ok(PushAction::SaveOutputs(changed))
+                }
+            }
+        }
+    }
+}
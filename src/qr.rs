@@ -0,0 +1,335 @@
+//! Animated QR codes for psbts, to talk to air-gapped signers
+//!
+//! Two formats are supported: UR (`crypto-psbt`, fountain encoded) and BBQr.
+
+use std::{collections::HashSet, str::FromStr};
+
+use bitcoin::{
+    base64::{engine::general_purpose::STANDARD, Engine},
+    hex::FromHex,
+};
+
+use anyhow::{Error, Result};
+
+use crate::spclient::Psbt;
+
+const UR_PSBT_TYPE: &str = "crypto-psbt";
+const UR_BYTES_TYPE: &str = "bytes";
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+/// "B$", encoding, file type, part count and part index
+const BBQR_HEADER_LEN: usize = 8;
+const BBQR_MAX_PARTS: usize = 1295;
+
+/// `crypto-psbt` is the psbt as a CBOR byte string
+fn cbor_bytes(data: &[u8]) -> Vec<u8> {
+    let len = data.len();
+    let mut res = if len < 24 {
+        vec![0x40 | len as u8]
+    } else if len < 0x100 {
+        vec![0x58, len as u8]
+    } else if len < 0x10000 {
+        let mut header = vec![0x59];
+        header.extend_from_slice(&(len as u16).to_be_bytes());
+        header
+    } else {
+        let mut header = vec![0x5a];
+        header.extend_from_slice(&(len as u32).to_be_bytes());
+        header
+    };
+    res.extend_from_slice(data);
+    res
+}
+
+fn cbor_bytes_decode(data: &[u8]) -> Result<Vec<u8>> {
+    let (&first, rest) = data.split_first().ok_or_else(|| Error::msg("Empty CBOR"))?;
+    if first & 0xe0 != 0x40 {
+        return Err(Error::msg("Expected a CBOR byte string"));
+    }
+    let (len, rest) = match first & 0x1f {
+        n @ 0..=23 => (n as usize, rest),
+        24 if !rest.is_empty() => (rest[0] as usize, &rest[1..]),
+        25 if rest.len() >= 2 => (u16::from_be_bytes([rest[0], rest[1]]) as usize, &rest[2..]),
+        26 if rest.len() >= 4 => (
+            u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize,
+            &rest[4..],
+        ),
+        _ => return Err(Error::msg("Invalid CBOR byte string length")),
+    };
+    if rest.len() != len {
+        return Err(Error::msg("CBOR byte string length mismatch"));
+    }
+    Ok(rest.to_vec())
+}
+
+/// Produces the UR parts of a psbt, first the fragments themselves, then fountain encoded parts
+/// so that the receiver can miss some frames. Display the parts in a loop until the signer is done.
+pub struct UrPsbtEncoder {
+    encoder: ur::Encoder<'static>,
+}
+
+impl UrPsbtEncoder {
+    /// `psbt` is base64 encoded, `max_fragment_len` is in bytes of psbt per part
+    pub fn new(psbt: &str, max_fragment_len: usize) -> Result<Self> {
+        let psbt = Psbt::from_str(psbt)?;
+        let encoder = ur::Encoder::new(
+            &cbor_bytes(&psbt.serialize()),
+            max_fragment_len,
+            UR_PSBT_TYPE,
+        )
+        .map_err(|e| Error::msg(format!("Failed to create UR encoder: {:?}", e)))?;
+        Ok(Self { encoder })
+    }
+
+    pub fn get_fragment_count(&self) -> usize {
+        self.encoder.fragment_count()
+    }
+
+    pub fn next_part(&mut self) -> Result<String> {
+        self.encoder
+            .next_part()
+            .map_err(|e| Error::msg(format!("Failed to encode UR part: {:?}", e)))
+    }
+}
+
+fn base32_encode(data: &[u8]) -> String {
+    let mut res = String::new();
+    for chunk in data.chunks(5) {
+        let mut buf = [0u8; 5];
+        buf[..chunk.len()].copy_from_slice(chunk);
+        let bits = u64::from_be_bytes([0, 0, 0, buf[0], buf[1], buf[2], buf[3], buf[4]]);
+        // no padding, only the characters that carry data
+        let chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..chars {
+            let index = (bits >> (35 - i * 5)) & 0x1f;
+            res.push(BASE32_ALPHABET[index as usize] as char);
+        }
+    }
+    res
+}
+
+fn base32_decode(data: &str) -> Result<Vec<u8>> {
+    let mut res = vec![];
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in data.bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a == c)
+            .ok_or_else(|| Error::msg(format!("Invalid base32 character {}", c as char)))?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            res.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Ok(res)
+}
+
+fn base36_encode(n: usize) -> String {
+    const DIGITS: &[u8; 36] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    format!("{}{}", DIGITS[n / 36] as char, DIGITS[n % 36] as char)
+}
+
+fn base36_decode(s: &str) -> Result<usize> {
+    Ok(usize::from_str_radix(s, 36)?)
+}
+
+/// Split a psbt in BBQr parts of at most `max_part_len` characters, base32 encoded
+pub fn psbt_to_bbqr_parts(psbt: &str, max_part_len: usize) -> Result<Vec<String>> {
+    let psbt = Psbt::from_str(psbt)?;
+    let data = base32_encode(&psbt.serialize());
+
+    // each part but the last must hold a whole number of 5 bytes groups, i.e. of 8 characters
+    let chunk_len = max_part_len.saturating_sub(BBQR_HEADER_LEN) / 8 * 8;
+    if chunk_len == 0 {
+        return Err(Error::msg("Parts are too small"));
+    }
+    let chunks: Vec<&str> = data
+        .as_bytes()
+        .chunks(chunk_len)
+        .map(|c| std::str::from_utf8(c).expect("base32 is ascii"))
+        .collect();
+    if chunks.len() > BBQR_MAX_PARTS {
+        return Err(Error::msg("Too many parts, use bigger ones"));
+    }
+
+    Ok(chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            format!(
+                "B$2P{}{}{}",
+                base36_encode(chunks.len()),
+                base36_encode(i),
+                chunk
+            )
+        })
+        .collect())
+}
+
+/// What a complete sequence of parts contains
+#[derive(Debug, Clone, PartialEq)]
+pub enum QrPayload {
+    /// Base64 encoded psbt
+    Psbt(String),
+    /// Anything else, e.g. an account export
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QrProgress {
+    /// Estimated share of the data we have, between 0 and 1
+    InProgress(f64),
+    Complete(QrPayload),
+}
+
+fn to_base64_psbt(data: &[u8]) -> Result<String> {
+    // make sure it's a psbt before handing it over
+    Psbt::deserialize(data)?;
+    Ok(STANDARD.encode(data))
+}
+
+struct BbqrParts {
+    encoding: char,
+    file_type: char,
+    parts: Vec<Option<String>>,
+}
+
+/// Reassembles the parts scanned by the camera, UR or BBQr, whichever comes first
+/// A decoder is meant for a single payload, start a new one for the next
+#[derive(Default)]
+pub struct QrDecoder {
+    ur: ur::Decoder,
+    ur_type: Option<String>,
+    /// Sequence numbers we got, and how many fragments there are
+    ur_seen: HashSet<u32>,
+    ur_fragment_count: u32,
+    bbqr: Option<BbqrParts>,
+}
+
+impl QrDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn receive_part(&mut self, part: &str) -> Result<QrProgress> {
+        let part = part.trim();
+        if part.to_lowercase().starts_with("ur:") {
+            self.receive_ur_part(part)
+        } else if part.starts_with("B$") {
+            self.receive_bbqr_part(part)
+        } else {
+            Err(Error::msg("Unknown QR format"))
+        }
+    }
+
+    fn receive_ur_part(&mut self, part: &str) -> Result<QrProgress> {
+        if self.bbqr.is_some() {
+            return Err(Error::msg("Expected a BBQr part"));
+        }
+        let lower = part.to_lowercase();
+        let mut path = lower["ur:".len()..].split('/');
+        let ur_type = path.next().unwrap_or_default().to_owned();
+        match self.ur_type {
+            Some(ref t) if *t != ur_type => {
+                return Err(Error::msg(format!("Expected a {} part", t)))
+            }
+            _ => self.ur_type = Some(ur_type.clone()),
+        }
+
+        // single part URs have no sequence
+        if let Some((seq, count)) = path
+            .next()
+            .and_then(|s| s.split_once('-'))
+            .and_then(|(seq, count)| Some((seq.parse::<u32>().ok()?, count.parse::<u32>().ok()?)))
+        {
+            self.ur_seen.insert(seq);
+            self.ur_fragment_count = count;
+        }
+
+        self.ur
+            .receive(&lower)
+            .map_err(|e| Error::msg(format!("Invalid UR part: {:?}", e)))?;
+
+        if !self.ur.complete() {
+            // fountain parts mix fragments, so this is only an estimate
+            let progress = self.ur_seen.len() as f64 / self.ur_fragment_count.max(1) as f64;
+            return Ok(QrProgress::InProgress(progress.min(0.99)));
+        }
+
+        let message = self
+            .ur
+            .message()
+            .map_err(|e| Error::msg(format!("Invalid UR message: {:?}", e)))?
+            .ok_or_else(|| Error::msg("Missing UR message"))?;
+        let data = cbor_bytes_decode(&message)?;
+
+        let payload = match ur_type.as_str() {
+            UR_PSBT_TYPE => QrPayload::Psbt(to_base64_psbt(&data)?),
+            UR_BYTES_TYPE => QrPayload::Text(String::from_utf8(data)?),
+            _ => return Err(Error::msg(format!("Unsupported UR type {}", ur_type))),
+        };
+
+        Ok(QrProgress::Complete(payload))
+    }
+
+    fn receive_bbqr_part(&mut self, part: &str) -> Result<QrProgress> {
+        if self.ur_type.is_some() {
+            return Err(Error::msg("Expected a UR part"));
+        }
+        if part.len() < BBQR_HEADER_LEN || !part.is_ascii() {
+            return Err(Error::msg("Invalid BBQr part"));
+        }
+
+        let encoding = part[2..3].chars().next().unwrap_or_default();
+        let file_type = part[3..4].chars().next().unwrap_or_default();
+        let count = base36_decode(&part[4..6])?;
+        let index = base36_decode(&part[6..8])?;
+        if count == 0 || index >= count {
+            return Err(Error::msg("Invalid BBQr part index"));
+        }
+
+        let bbqr = self.bbqr.get_or_insert_with(|| BbqrParts {
+            encoding,
+            file_type,
+            parts: vec![None; count],
+        });
+        if bbqr.encoding != encoding || bbqr.file_type != file_type || bbqr.parts.len() != count {
+            return Err(Error::msg("BBQr part from another sequence"));
+        }
+        bbqr.parts[index] = Some(part[BBQR_HEADER_LEN..].to_owned());
+
+        let received = bbqr.parts.iter().filter(|p| p.is_some()).count();
+        if received < count {
+            return Ok(QrProgress::InProgress(received as f64 / count as f64));
+        }
+
+        let data: String = bbqr.parts.iter().flatten().map(|p| p.as_str()).collect();
+        let data = match encoding {
+            '2' => base32_decode(&data)?,
+            'H' => Vec::<u8>::from_hex(&data)?,
+            _ => {
+                return Err(Error::msg(format!(
+                    "Unsupported BBQr encoding {}",
+                    encoding
+                )))
+            }
+        };
+
+        let payload = match file_type {
+            'P' => QrPayload::Psbt(to_base64_psbt(&data)?),
+            'U' | 'J' => QrPayload::Text(String::from_utf8(data)?),
+            _ => {
+                return Err(Error::msg(format!(
+                    "Unsupported BBQr file type {}",
+                    file_type
+                )))
+            }
+        };
+
+        Ok(QrProgress::Complete(payload))
+    }
+}
@@ -0,0 +1,224 @@
+//! Rate limits for an API in front of the wallet, e.g. the daemon's RPC
+//!
+//! Token buckets per method and per connection, and a cap on the requests running at once,
+//! so that a misbehaving client can't wedge the scanner or hammer broadcasts.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use anyhow::Result;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Limit {
+    /// Requests allowed at once after a quiet period
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RateLimitConfig {
+    /// Methods without a limit here are only limited per connection
+    pub per_method: HashMap<String, Limit>,
+    pub per_connection: Option<Limit>,
+    pub max_concurrent: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            per_method: HashMap::from([
+                (
+                    "broadcast".to_owned(),
+                    Limit {
+                        burst: 5,
+                        per_second: 0.5,
+                    },
+                ),
+                (
+                    "scan".to_owned(),
+                    Limit {
+                        burst: 1,
+                        per_second: 0.1,
+                    },
+                ),
+            ]),
+            per_connection: Some(Limit {
+                burst: 50,
+                per_second: 10.0,
+            }),
+            max_concurrent: 16,
+        }
+    }
+}
+
+/// Returned wrapped in an `anyhow::Error`, use `downcast_ref` to map it to the API's "too many requests"
+#[derive(Debug, Clone, PartialEq)]
+pub enum RateLimited {
+    Method {
+        method: String,
+        retry_after: Duration,
+    },
+    Connection {
+        retry_after: Duration,
+    },
+    TooManyConcurrent(usize),
+}
+
+impl std::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Method {
+                method,
+                retry_after,
+            } => write!(
+                f,
+                "Too many {} requests, retry in {}ms",
+                method,
+                retry_after.as_millis()
+            ),
+            Self::Connection { retry_after } => write!(
+                f,
+                "Too many requests, retry in {}ms",
+                retry_after.as_millis()
+            ),
+            Self::TooManyConcurrent(max) => {
+                write!(f, "Already {} requests running", max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(limit: &Limit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &Limit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        self.updated = now;
+    }
+
+    /// None if a token is available, or how long until there's one
+    fn wait_time(&self, limit: &Limit) -> Option<Duration> {
+        if self.tokens >= 1.0 {
+            None
+        } else if limit.per_second > 0.0 {
+            Some(
+                Duration::try_from_secs_f64((1.0 - self.tokens) / limit.per_second)
+                    .unwrap_or(Duration::MAX),
+            )
+        } else {
+            Some(Duration::MAX)
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    methods: HashMap<String, Bucket>,
+    connections: HashMap<String, Bucket>,
+    running: usize,
+}
+
+/// Shared by all the connections
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    config: Arc<RateLimitConfig>,
+    state: Arc<Mutex<State>>,
+}
+
+/// The request counts as running until dropped
+#[derive(Debug)]
+pub struct RequestPermit {
+    state: Arc<Mutex<State>>,
+}
+
+impl Drop for RequestPermit {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.running -= 1;
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            state: Arc::default(),
+        }
+    }
+
+    /// Call before handling each request, `connection` identifies the client, e.g. its address
+    /// A refused request doesn't use up any token
+    pub fn check(&self, connection: &str, method: &str) -> Result<RequestPermit> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        if state.running >= self.config.max_concurrent {
+            return Err(RateLimited::TooManyConcurrent(self.config.max_concurrent).into());
+        }
+
+        let method_limit = self.config.per_method.get(method);
+        if let Some(limit) = method_limit {
+            let bucket = state
+                .methods
+                .entry(method.to_owned())
+                .or_insert_with(|| Bucket::new(limit, now));
+            bucket.refill(limit, now);
+            if let Some(retry_after) = bucket.wait_time(limit) {
+                return Err(RateLimited::Method {
+                    method: method.to_owned(),
+                    retry_after,
+                }
+                .into());
+            }
+        }
+        if let Some(limit) = self.config.per_connection.as_ref() {
+            let bucket = state
+                .connections
+                .entry(connection.to_owned())
+                .or_insert_with(|| Bucket::new(limit, now));
+            bucket.refill(limit, now);
+            if let Some(retry_after) = bucket.wait_time(limit) {
+                return Err(RateLimited::Connection { retry_after }.into());
+            }
+            bucket.tokens -= 1.0;
+        }
+        if method_limit.is_some() {
+            if let Some(bucket) = state.methods.get_mut(method) {
+                bucket.tokens -= 1.0;
+            }
+        }
+
+        state.running += 1;
+        Ok(RequestPermit {
+            state: self.state.clone(),
+        })
+    }
+
+    /// Forget a connection once it's closed
+    pub fn remove_connection(&self, connection: &str) {
+        self.state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .connections
+            .remove(connection);
+    }
+}
@@ -0,0 +1,138 @@
+//! BTC/fiat exchange rates
+//!
+//! The app provides the rate sources, we poll them in order and fall back to the next one on failure.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Error, Result};
+
+/// Granularity at which the polling thread checks if it must stop
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
+pub trait RateProvider {
+    fn get_name(&self) -> String;
+
+    /// Price of 1 BTC in `currency`, an ISO 4217 code
+    fn get_rate(&self, currency: &str) -> Result<f64>;
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ExchangeRate {
+    pub currency: String,
+    pub rate: f64,
+    /// Unix timestamp of when we got it
+    pub timestamp: u64,
+    pub provider: String,
+}
+
+/// Ask each provider in turn until one answers
+pub fn fetch_rate(
+    providers: &[Box<dyn RateProvider + Send>],
+    currency: &str,
+) -> Result<ExchangeRate> {
+    let mut errors = vec![];
+    for provider in providers.iter() {
+        match provider.get_rate(currency) {
+            Ok(rate) if rate.is_finite() && rate > 0.0 => {
+                return Ok(ExchangeRate {
+                    currency: currency.to_owned(),
+                    rate,
+                    timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+                    provider: provider.get_name(),
+                })
+            }
+            Ok(rate) => errors.push(format!("{}: invalid rate {}", provider.get_name(), rate)),
+            Err(e) => errors.push(format!("{}: {}", provider.get_name(), e)),
+        }
+    }
+    Err(Error::msg(format!(
+        "No provider could give the {} rate ({})",
+        currency,
+        errors.join(", ")
+    )))
+}
+
+/// Rates polled in a background thread, which stops when the stream is dropped
+pub struct RateStream {
+    receiver: Receiver<ExchangeRate>,
+    last: Arc<Mutex<Option<ExchangeRate>>>,
+    stop: Arc<AtomicBool>,
+}
+
+/// Start polling every `interval`
+/// `cached` is the last rate we know of, e.g. persisted from a previous run, so we have something to show offline
+pub fn create_rate_stream(
+    currency: &str,
+    providers: Vec<Box<dyn RateProvider + Send>>,
+    interval: Duration,
+    cached: Option<ExchangeRate>,
+) -> RateStream {
+    let (sender, receiver) = channel();
+    let last = Arc::new(Mutex::new(cached.filter(|r| r.currency == currency)));
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let currency = currency.to_owned();
+    let thread_last = last.clone();
+    let thread_stop = stop.clone();
+    thread::spawn(move || {
+        while !thread_stop.load(Ordering::Relaxed) {
+            // failures are silent, the last rate stays available
+            if let Ok(rate) = fetch_rate(&providers, &currency) {
+                if let Ok(mut last) = thread_last.lock() {
+                    *last = Some(rate.clone());
+                }
+                if sender.send(rate).is_err() {
+                    break;
+                }
+            }
+
+            let mut waited = Duration::ZERO;
+            while waited < interval && !thread_stop.load(Ordering::Relaxed) {
+                thread::sleep(STOP_CHECK_INTERVAL);
+                waited += STOP_CHECK_INTERVAL;
+            }
+        }
+    });
+
+    RateStream {
+        receiver,
+        last,
+        stop,
+    }
+}
+
+impl RateStream {
+    /// Wait for the next rate, None if it didn't come within `timeout`
+    pub fn next_rate(&self, timeout: Duration) -> Result<Option<ExchangeRate>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(rate) => Ok(Some(rate)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(Error::msg("Rate stream stopped")),
+        }
+    }
+
+    /// The last rate we got, or the cached one if we couldn't get any yet
+    pub fn get_last_rate(&self) -> Option<ExchangeRate> {
+        self.last.lock().ok().and_then(|last| last.clone())
+    }
+
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for RateStream {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -0,0 +1,228 @@
+//! Drive a local regtest bitcoind to test the wallet end to end
+//!
+//! Only available with the `regtest` feature. The node must run with `-txindex`,
+//! since we look up the outputs spent by each transaction we scan.
+
+use std::collections::HashMap;
+
+use bitcoin::{
+    absolute::LockTime,
+    hashes::Hash,
+    key::{TapTweak, TweakedPublicKey},
+    secp256k1::{Keypair, Message, Secp256k1, SecretKey},
+    sighash::{Prevouts, SighashCache},
+    transaction::Version,
+    Address, Amount, BlockHash, OutPoint, ScriptBuf, Sequence, TapSighashType, Transaction, TxIn,
+    TxOut, Witness,
+};
+use bitcoincore_rpc::{Auth, Client, RpcApi};
+
+use anyhow::{Error, Result};
+
+use crate::constants::DUST_THRESHOLD;
+use crate::spclient::{OwnedOutput, SpWallet};
+
+const HARNESS_WALLET: &str = "sp_client_harness";
+
+/// A taproot output we control with a single key, to be used as input of a payment
+#[derive(Debug, Clone)]
+pub struct FundedInput {
+    pub secret_key: SecretKey,
+    pub outpoint: OutPoint,
+    pub txout: TxOut,
+}
+
+pub struct RegtestHarness {
+    rpc: Client,
+    mining_address: Address,
+}
+
+impl RegtestHarness {
+    /// Connect to the node and load (or create) the wallet funding the tests
+    pub fn new(url: &str, user: &str, password: &str) -> Result<Self> {
+        let rpc = Client::new(url, Auth::UserPass(user.to_owned(), password.to_owned()))?;
+
+        if !rpc.list_wallets()?.iter().any(|w| w == HARNESS_WALLET)
+            && rpc.load_wallet(HARNESS_WALLET).is_err()
+        {
+            rpc.create_wallet(HARNESS_WALLET, None, None, None, None)?;
+        }
+
+        let mining_address = rpc.get_new_address(None, None)?.assume_checked();
+
+        let harness = Self {
+            rpc,
+            mining_address,
+        };
+
+        // coinbase outputs need 100 blocks to mature
+        if harness.get_height()? < 101 {
+            harness.mine_blocks(101)?;
+        }
+
+        Ok(harness)
+    }
+
+    pub fn get_rpc(&self) -> &Client {
+        &self.rpc
+    }
+
+    pub fn get_height(&self) -> Result<u32> {
+        Ok(self.rpc.get_block_count()? as u32)
+    }
+
+    pub fn mine_blocks(&self, count: u64) -> Result<Vec<BlockHash>> {
+        Ok(self.rpc.generate_to_address(count, &self.mining_address)?)
+    }
+
+    /// Send `amount` to a new taproot key and mine it
+    pub fn fund_taproot_input(&self, amount: Amount) -> Result<FundedInput> {
+        let secp = Secp256k1::new();
+        let (secret_key, _) = secp.generate_keypair(&mut bitcoin::secp256k1::rand::thread_rng());
+
+        // no script path, we use the key as output key like silent payments do
+        let output_key =
+            TweakedPublicKey::dangerous_assume_tweaked(secret_key.x_only_public_key(&secp).0);
+        let address = Address::p2tr_tweaked(output_key, bitcoin::Network::Regtest);
+
+        let txid = self
+            .rpc
+            .send_to_address(&address, amount, None, None, None, None, None, None)?;
+        self.mine_blocks(1)?;
+
+        let tx = self.rpc.get_raw_transaction(&txid, None)?;
+        let vout = tx
+            .output
+            .iter()
+            .position(|o| o.script_pubkey == address.script_pubkey())
+            .ok_or_else(|| Error::msg("Funding output not found"))?;
+
+        Ok(FundedInput {
+            secret_key,
+            outpoint: OutPoint::new(txid, vout as u32),
+            txout: tx.output[vout].clone(),
+        })
+    }
+
+    /// Spend `input` to `sp_address`, the change goes back to the node wallet
+    /// The transaction is broadcast but not mined
+    pub fn send_sp_payment(
+        &self,
+        input: &FundedInput,
+        sp_address: &str,
+        amount: Amount,
+        fee: Amount,
+    ) -> Result<Transaction> {
+        let outpoints = vec![(input.outpoint.txid.to_string(), input.outpoint.vout)];
+        let partial_secret = silentpayments::utils::sending::calculate_partial_secret(
+            &[(input.secret_key, true)],
+            &outpoints,
+        )?;
+        let output_key = silentpayments::sending::generate_recipient_pubkeys(
+            vec![sp_address.to_owned()],
+            partial_secret,
+        )?
+        .remove(sp_address)
+        .and_then(|mut keys| keys.pop())
+        .ok_or_else(|| Error::msg("No key generated for address"))?;
+
+        let mut output = vec![TxOut {
+            value: amount,
+            script_pubkey: ScriptBuf::new_p2tr_tweaked(output_key.dangerous_assume_tweaked()),
+        }];
+
+        let change = input
+            .txout
+            .value
+            .checked_sub(amount + fee)
+            .ok_or_else(|| Error::msg("Input doesn't cover amount and fee"))?;
+        if change > DUST_THRESHOLD {
+            output.push(TxOut {
+                value: change,
+                script_pubkey: self.mining_address.script_pubkey(),
+            });
+        }
+
+        let mut tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: input.outpoint,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            }],
+            output,
+        };
+
+        let prevouts = [&input.txout];
+        let sighash = SighashCache::new(&tx).taproot_key_spend_signature_hash(
+            0,
+            &Prevouts::All(&prevouts[..]),
+            TapSighashType::Default,
+        )?;
+        let secp = Secp256k1::new();
+        let keypair = Keypair::from_secret_key(&secp, &input.secret_key);
+        let sig =
+            secp.sign_schnorr_no_aux_rand(&Message::from_digest(sighash.to_byte_array()), &keypair);
+        tx.input[0].witness.push(&sig[..]);
+
+        self.rpc.send_raw_transaction(&tx)?;
+
+        Ok(tx)
+    }
+
+    /// Scan blocks from the last scan height of `wallet` to the tip,
+    /// returns the outputs that were found or updated
+    pub fn scan_to_tip(&self, wallet: &mut SpWallet) -> Result<HashMap<OutPoint, OwnedOutput>> {
+        let tip = self.get_height()?;
+        let mut found = HashMap::new();
+
+        for height in wallet.get_outputs().get_last_scan() + 1..=tip {
+            let hash = self.rpc.get_block_hash(height as u64)?;
+            let block = self.rpc.get_block(&hash)?;
+
+            found.extend(wallet.scan_block_transactions(&block.txdata, height, |tx| {
+                tx.input
+                    .iter()
+                    .map(|txin| {
+                        let prev_tx = self
+                            .rpc
+                            .get_raw_transaction(&txin.previous_output.txid, None)?;
+                        Ok(prev_tx.output[txin.previous_output.vout as usize].clone())
+                    })
+                    .collect()
+            })?);
+        }
+
+        Ok(found)
+    }
+
+    /// Replace the last `depth` blocks with `depth + 1` new ones, and roll back `wallet`
+    /// Transactions of the invalidated blocks go back to the mempool and are mined again
+    pub fn reorg(&self, depth: u32, wallet: &mut SpWallet) -> Result<Vec<BlockHash>> {
+        let tip = self.get_height()?;
+        if depth == 0 || depth > tip {
+            return Err(Error::msg("Invalid reorg depth"));
+        }
+
+        let fork_height = tip - depth;
+        let first_invalid = self.rpc.get_block_hash(fork_height as u64 + 1)?;
+        self.rpc.invalidate_block(&first_invalid)?;
+
+        wallet.rollback_to(fork_height);
+
+        self.mine_blocks(depth as u64 + 1)
+    }
+
+    pub fn assert_balance(&self, wallet: &SpWallet, expected: Amount) -> Result<()> {
+        let balance = wallet.get_outputs().get_balance();
+        if balance != expected {
+            return Err(Error::msg(format!(
+                "Expected a balance of {}, got {}",
+                expected, balance
+            )));
+        }
+        Ok(())
+    }
+}
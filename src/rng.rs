@@ -0,0 +1,77 @@
+use std::sync::{Arc, Mutex};
+
+use bitcoin::secp256k1::rand::{self, thread_rng, CryptoRng, RngCore};
+
+/// Any cryptographically secure rng can be injected, e.g. a seeded one for reproducible tests,
+/// or one that mixes in entropy from a hardware source
+pub trait SpRng: RngCore + CryptoRng + Send {}
+
+impl<T: RngCore + CryptoRng + Send> SpRng for T {}
+
+/// Randomness source shared by a client and its clones, `thread_rng` if none was injected
+#[derive(Clone, Default)]
+pub struct SharedRng(Option<Arc<Mutex<Box<dyn SpRng>>>>);
+
+impl SharedRng {
+    pub fn new(rng: impl SpRng + 'static) -> Self {
+        Self(Some(Arc::new(Mutex::new(Box::new(rng)))))
+    }
+
+    pub fn is_injected(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub fn gen_bytes<const N: usize>(&self) -> [u8; N] {
+        let mut res = [0u8; N];
+        self.clone().fill_bytes(&mut res);
+        res
+    }
+}
+
+impl std::fmt::Debug for SharedRng {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(_) => f.write_str("SharedRng(injected)"),
+            None => f.write_str("SharedRng(thread_rng)"),
+        }
+    }
+}
+
+/// The rng isn't part of what makes two clients equal
+impl PartialEq for SharedRng {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl RngCore for SharedRng {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0u8; 4];
+        self.fill_bytes(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0u8; 8];
+        self.fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        match &self.0 {
+            // a poisoned lock only means another thread panicked while drawing bytes, the rng is still usable
+            Some(rng) => rng
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .fill_bytes(dest),
+            None => thread_rng().fill_bytes(dest),
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for SharedRng {}
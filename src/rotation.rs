@@ -0,0 +1,164 @@
+//! Key rotation: everything is swept to a freshly derived wallet, and the old one is retired
+//!
+//! The sweeps are returned unsigned, the app signs them with the old wallet and broadcasts them,
+//! then calls `Rotation::update` until they're all confirmed and `SpWallet::retire` to finish.
+
+use std::collections::HashMap;
+
+use bitcoin::{Amount, OutPoint, Txid};
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Error, Result};
+
+use crate::chain::ChainBackend;
+use crate::spclient::{
+    OwnedOutput, Psbt, Recipient, SpClient, SpWallet, SubDustChange, WalletType,
+};
+use crate::standardness::MAX_STANDARD_TX_WEIGHT;
+
+/// Weight of a taproot key path input with the default sighash
+const TAPROOT_INPUT_WEIGHT: u64 = 230;
+/// Same, in vbytes rounded up
+const TAPROOT_INPUT_VSIZE: u64 = 58;
+/// Room for the rest of the transaction, with plenty of margin
+const SWEEP_OVERHEAD_WEIGHT: u64 = 1_000;
+/// So that each sweep stays under the standard size
+const MAX_SWEEP_INPUTS: usize =
+    ((MAX_STANDARD_TX_WEIGHT - SWEEP_OVERHEAD_WEIGHT) / TAPROOT_INPUT_WEIGHT) as usize;
+
+/// What to persist while the sweeps confirm
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Rotation {
+    pub new_address: String,
+    /// Txids of the sweeps, known before signing since all the inputs are segwit
+    pub pending: Vec<Txid>,
+    pub confirmed: Vec<Txid>,
+    /// Quarantined outputs and outputs worth less than the fee to spend them, left in the old wallet
+    pub left_behind: Vec<OutPoint>,
+}
+
+impl Rotation {
+    pub fn is_complete(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Moves the sweeps with a confirmed output to `confirmed`, returns true once they all are
+    pub fn update(&mut self, backend: &dyn ChainBackend) -> Result<bool> {
+        let mut pending = vec![];
+        for txid in self.pending.drain(..) {
+            match backend.get_output(&OutPoint::new(txid, 0))? {
+                Some(output) if output.blockheight.is_some() => self.confirmed.push(txid),
+                _ => pending.push(txid),
+            }
+        }
+        self.pending = pending;
+        Ok(self.is_complete())
+    }
+}
+
+pub struct RotationPlan {
+    /// Starts scanning at the height given to `rotate_wallet`
+    pub new_wallet: SpWallet,
+    /// Unsigned, in the order of `rotation.pending`
+    pub sweeps: Vec<Psbt>,
+    pub rotation: Rotation,
+}
+
+impl SpWallet {
+    /// Create the new wallet from `new_keys` and the sweeps of all our spendable outputs to it at `fee_rate`
+    /// `tip_height` is the birthday of the new wallet, nothing can have been sent to it before
+    pub fn rotate_wallet(
+        &self,
+        new_keys: WalletType,
+        label: String,
+        account: u32,
+        tip_height: u32,
+        fee_rate: Amount,
+    ) -> Result<RotationPlan> {
+        let client = self.get_client();
+        if client.get_settings().retired_to.is_some() {
+            return Err(Error::msg("Wallet is already retired"));
+        }
+        let network = client.get_network();
+
+        let new_client = SpClient::from_wallet_type(label, new_keys, account, network)?;
+        if new_client.get_receiving_address() == client.get_receiving_address() {
+            return Err(Error::msg("The new keys are the ones of this wallet"));
+        }
+        let new_address = new_client.get_receiving_address();
+        let mut new_wallet = SpWallet::new(new_client, None)?;
+        new_wallet
+            .get_mut_outputs()
+            .change_birthday(tip_height, false);
+        new_wallet.get_mut_outputs().update_last_scan(tip_height);
+
+        let spend_cost = fee_rate * TAPROOT_INPUT_VSIZE;
+        let mut left_behind: Vec<OutPoint> =
+            self.get_outputs().get_quarantined().into_keys().collect();
+        let mut to_sweep: Vec<(OutPoint, OwnedOutput)> = vec![];
+        for (outpoint, output) in self.get_outputs().to_spendable_list() {
+            if output.amount > spend_cost {
+                to_sweep.push((outpoint, output));
+            } else {
+                left_behind.push(outpoint);
+            }
+        }
+        if to_sweep.is_empty() {
+            return Err(Error::msg("Nothing worth sweeping"));
+        }
+        to_sweep.sort_by_key(|(outpoint, _)| *outpoint);
+        left_behind.sort();
+
+        let mut policy = client.get_change_policy();
+        policy.sub_dust_change = SubDustChange::AddToFees;
+
+        let mut sweeps = vec![];
+        for chunk in to_sweep.chunks(MAX_SWEEP_INPUTS) {
+            let utxos: HashMap<OutPoint, OwnedOutput> = chunk.iter().cloned().collect();
+            let total: Amount = utxos.values().map(|o| o.amount).sum();
+            let (mut psbt, _) = client.create_new_psbt_with_policy(
+                utxos,
+                vec![Recipient {
+                    address: new_address.clone(),
+                    amount: total,
+                    nb_outputs: 1,
+                }],
+                None,
+                &policy,
+            )?;
+            SpClient::set_fees_with_policy(&mut psbt, fee_rate, new_address.clone(), &policy)?;
+            let partial_secret = client.get_partial_secret_from_psbt(&psbt)?;
+            client.fill_and_order(&mut psbt, partial_secret)?;
+            sweeps.push(psbt);
+        }
+
+        let rotation = Rotation {
+            new_address,
+            pending: sweeps.iter().map(|psbt| psbt.unsigned_tx.txid()).collect(),
+            confirmed: vec![],
+            left_behind,
+        };
+
+        Ok(RotationPlan {
+            new_wallet,
+            sweeps,
+            rotation,
+        })
+    }
+
+    /// Mark the wallet retired once all the sweeps of `rotation` are confirmed
+    /// Persist the client afterwards, the mark is part of its settings
+    pub fn retire(&mut self, rotation: &Rotation) -> Result<()> {
+        if !rotation.is_complete() {
+            return Err(Error::msg(format!(
+                "{} sweeps are still unconfirmed",
+                rotation.pending.len()
+            )));
+        }
+        let client = self.get_mut_client();
+        let mut settings = client.get_settings().clone();
+        settings.retired_to = Some(rotation.new_address.clone());
+        client.set_settings(settings);
+        Ok(())
+    }
+}
@@ -0,0 +1,554 @@
+use bitcoin::{
+    hex::{DisplayHex, FromHex},
+    secp256k1::{rand::RngCore, PublicKey, SecretKey},
+    Network,
+};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use silentpayments::receiving::{Label, Receiver};
+use silentpayments::utils::Network as SpNetwork;
+
+use anyhow::{Error, Result};
+use zeroize::Zeroizing;
+
+use crate::policy::SpendingPolicy;
+use crate::rng::SharedRng;
+use crate::settings::WalletSettings;
+use crate::spclient::{OutputList, SpClient, SpWallet, SpendKey};
+
+const SEALED_VERSION: u8 = 1;
+const SPEND_KEY_SECRET: u8 = 0;
+const SPEND_KEY_PUBLIC: u8 = 1;
+/// What's sealed is padded to a multiple of this, so that the size doesn't tell what's inside
+const SEALED_PADDING: usize = 4096;
+// poly1305 tag
+const SEALED_TAG_LEN: usize = 16;
+
+/// Hex encoded, and the nonce if it's encrypted with `key`
+/// `plain` must end with JSON, it's padded with whitespace
+fn encrypt(
+    plain: &[u8],
+    key: Option<&[u8; 32]>,
+    rng: &SharedRng,
+) -> Result<(Option<[u8; 12]>, String)> {
+    let mut plain = Zeroizing::new(plain.to_vec());
+    let padded_len = plain.len().div_ceil(SEALED_PADDING) * SEALED_PADDING;
+    plain.resize(padded_len, b' ');
+    match key {
+        Some(key) => {
+            let nonce: [u8; 12] = rng.gen_bytes();
+            let encrypted = ChaCha20Poly1305::new(Key::from_slice(key))
+                .encrypt(Nonce::from_slice(&nonce), plain.as_ref())
+                .map_err(|_| Error::msg("Failed to encrypt secrets"))?;
+            Ok((Some(nonce), encrypted.to_lower_hex_string()))
+        }
+        None => Ok((None, plain.to_lower_hex_string())),
+    }
+}
+
+fn decrypt(
+    nonce: Option<[u8; 12]>,
+    data: &str,
+    key: Option<&[u8; 32]>,
+) -> Result<Zeroizing<Vec<u8>>> {
+    let data = Vec::<u8>::from_hex(data)?;
+    Ok(Zeroizing::new(match nonce {
+        Some(nonce) => {
+            let key = key.ok_or_else(|| Error::msg("Secrets are encrypted, missing key"))?;
+            ChaCha20Poly1305::new(Key::from_slice(key))
+                .decrypt(Nonce::from_slice(&nonce), data.as_ref())
+                .map_err(|_| Error::msg("Failed to decrypt secrets, wrong key?"))?
+        }
+        None => data,
+    }))
+}
+
+/// `SpClient` as older versions serialized it, secrets in the clear
+#[derive(Deserialize)]
+struct LegacyClient {
+    label: String,
+    scan_sk: SecretKey,
+    spend_key: LegacySpendKey,
+    mnemonic: Option<String>,
+    sp_receiver: Receiver,
+}
+
+#[derive(Deserialize)]
+enum LegacySpendKey {
+    Secret(SecretKey),
+    Public(PublicKey),
+}
+
+#[derive(Deserialize)]
+struct LegacyWallet {
+    client: LegacyClient,
+    outputs: OutputList,
+}
+
+impl LegacyClient {
+    /// Signet wallets come back as testnet, the old format didn't tell them apart
+    fn into_client(self) -> Result<SpClient> {
+        let network = match self.sp_receiver.network {
+            SpNetwork::Mainnet => Network::Bitcoin,
+            SpNetwork::Testnet => Network::Testnet,
+            SpNetwork::Regtest => Network::Regtest,
+        };
+        let spend_key = match self.spend_key {
+            LegacySpendKey::Secret(sk) => SpendKey::Secret(sk),
+            LegacySpendKey::Public(pk) => SpendKey::Public(pk),
+        };
+        let mut client =
+            SpClient::new(self.label, self.scan_sk, spend_key, self.mnemonic, network)?;
+        for label in self.sp_receiver.list_labels() {
+            client.sp_receiver.add_label(label)?;
+        }
+        Ok(client)
+    }
+}
+
+/// Sealed along with the secrets of a `SpClient`
+#[derive(Serialize, Deserialize)]
+struct SealedMetadata {
+    label: String,
+    network: Network,
+    spending_policy: SpendingPolicy,
+    settings: WalletSettings,
+    backup_verified_at: Option<u64>,
+    /// Hex of our labels, as in `Receiver::list_labels`
+    labels: Vec<String>,
+}
+
+/// What gets persisted instead of a `SpClient`
+/// Secrets only leave the client through `SpClient::seal`, encrypted if a key is given, along with
+/// everything else about the wallet: encrypted sealed clients can't be told apart without their key,
+/// see `unseal_slot` for a hidden wallet
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SealedClient {
+    /// None if the secrets aren't encrypted
+    nonce: Option<[u8; 12]>,
+    data: String,
+}
+
+impl SealedClient {
+    /// Migrate a `SpClient` in the JSON of older versions, sealed with `key`
+    /// Persist the result in place of the old JSON, that still has the secrets in the clear
+    pub fn from_legacy_json(json: &str, key: Option<&[u8; 32]>) -> Result<Self> {
+        serde_json::from_str::<LegacyClient>(json)?
+            .into_client()?
+            .seal(key)
+    }
+
+    /// Same as `from_legacy_json` for a `SpWallet`, its outputs are returned to be persisted apart
+    pub fn from_legacy_wallet_json(
+        json: &str,
+        key: Option<&[u8; 32]>,
+    ) -> Result<(Self, OutputList)> {
+        let legacy: LegacyWallet = serde_json::from_str(json)?;
+        let wallet = SpWallet::new(legacy.client.into_client()?, Some(legacy.outputs))?;
+        Ok((wallet.get_client().seal(key)?, wallet.get_outputs().clone()))
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.nonce.is_some()
+    }
+
+    /// Unseals with no key, to fill the slots that have no wallet
+    /// Keeping as many slots whether there's a hidden wallet or not, its existence isn't apparent
+    pub fn filler(rng: &SharedRng) -> Self {
+        let mut data = vec![0u8; SEALED_PADDING + SEALED_TAG_LEN];
+        rng.clone().fill_bytes(&mut data);
+        Self {
+            nonce: Some(rng.gen_bytes()),
+            data: data.to_lower_hex_string(),
+        }
+    }
+
+    /// Index and client of the slot that `key` unseals, e.g. with the key derived from a PIN:
+    /// a duress PIN opens the hidden wallet instead of the main one
+    /// Every slot is tried, so that the time it takes doesn't tell which one opened
+    pub fn unseal_slot(slots: &[SealedClient], key: &[u8; 32]) -> Result<(usize, SpClient)> {
+        let mut res = None;
+        for (i, slot) in slots.iter().enumerate() {
+            if !slot.is_encrypted() {
+                return Err(Error::msg("Wallet slots must be encrypted"));
+            }
+            if let Ok(client) = slot.unseal(Some(key)) {
+                res.get_or_insert((i, client));
+            }
+        }
+        res.ok_or_else(|| Error::msg("No wallet for this key"))
+    }
+
+    /// `key` must be the one given to `SpClient::seal`, and is ignored if the secrets aren't encrypted
+    pub fn unseal(&self, key: Option<&[u8; 32]>) -> Result<SpClient> {
+        let plain = decrypt(self.nonce, &self.data, key)?;
+
+        let (version, rest) = plain
+            .split_first()
+            .ok_or_else(|| Error::msg("Empty sealed secrets"))?;
+        if *version != SEALED_VERSION {
+            return Err(Error::msg(format!("Unknown sealed version {}", version)));
+        }
+        if rest.len() < 33 {
+            return Err(Error::msg("Sealed secrets too short"));
+        }
+
+        let scan_sk = SecretKey::from_slice(&rest[..32])?;
+        let (spend_key, rest) = match rest[32] {
+            SPEND_KEY_SECRET if rest.len() >= 65 => (
+                SpendKey::Secret(SecretKey::from_slice(&rest[33..65])?),
+                &rest[65..],
+            ),
+            SPEND_KEY_PUBLIC if rest.len() >= 66 => (
+                SpendKey::Public(PublicKey::from_slice(&rest[33..66])?),
+                &rest[66..],
+            ),
+            _ => return Err(Error::msg("Invalid sealed spend key")),
+        };
+        if rest.len() < 2 {
+            return Err(Error::msg("Sealed secrets too short"));
+        }
+        let (mnemonic_len, rest) = rest.split_at(2);
+        let mnemonic_len = u16::from_be_bytes([mnemonic_len[0], mnemonic_len[1]]) as usize;
+        if rest.len() < mnemonic_len {
+            return Err(Error::msg("Sealed secrets too short"));
+        }
+        let (mnemonic, rest) = rest.split_at(mnemonic_len);
+        let mnemonic = if mnemonic.is_empty() {
+            None
+        } else {
+            Some(std::str::from_utf8(mnemonic)?.to_owned())
+        };
+        let metadata: SealedMetadata = serde_json::from_slice(rest)?;
+
+        let mut client = SpClient::new(
+            metadata.label,
+            scan_sk,
+            spend_key,
+            mnemonic,
+            metadata.network,
+        )?;
+        client.set_spending_policy(metadata.spending_policy);
+        client.set_settings(metadata.settings);
+        client.set_backup_verified_at(metadata.backup_verified_at);
+        for label in metadata.labels.iter() {
+            client
+                .sp_receiver
+                .add_label(Label::try_from(label.as_str())?)?;
+        }
+
+        Ok(client)
+    }
+
+    /// Same secrets sealed with `new_key` instead of `old_key`, checked to unseal to the same client
+    pub fn change_password(
+        &self,
+        old_key: Option<&[u8; 32]>,
+        new_key: Option<&[u8; 32]>,
+    ) -> Result<Self> {
+        let client = self.unseal(old_key)?;
+        let resealed = client.seal(new_key)?;
+        if resealed.unseal(new_key)? != client {
+            return Err(Error::msg("Resealed secrets don't match"));
+        }
+        Ok(resealed)
+    }
+}
+
+/// Anything else persisted with the wallet key, e.g. the `TxHistory`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SealedData {
+    /// None if the data isn't encrypted
+    nonce: Option<[u8; 12]>,
+    data: String,
+}
+
+impl SealedData {
+    pub fn seal<T: Serialize>(value: &T, key: Option<&[u8; 32]>, rng: &SharedRng) -> Result<Self> {
+        let plain = Zeroizing::new(serde_json::to_vec(value)?);
+        let (nonce, data) = encrypt(&plain, key, rng)?;
+        Ok(Self { nonce, data })
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.nonce.is_some()
+    }
+
+    pub fn unseal<T: DeserializeOwned>(&self, key: Option<&[u8; 32]>) -> Result<T> {
+        Ok(serde_json::from_slice(&decrypt(
+            self.nonce, &self.data, key,
+        )?)?)
+    }
+
+    /// Same as `SealedClient::change_password`
+    pub fn change_password(
+        &self,
+        old_key: Option<&[u8; 32]>,
+        new_key: Option<&[u8; 32]>,
+        rng: &SharedRng,
+    ) -> Result<Self> {
+        let plain = decrypt(self.nonce, &self.data, old_key)?;
+        let (nonce, data) = encrypt(&plain, new_key, rng)?;
+        let resealed = Self { nonce, data };
+        if decrypt(resealed.nonce, &resealed.data, new_key)? != plain {
+            return Err(Error::msg("Resealed data doesn't match"));
+        }
+        Ok(resealed)
+    }
+}
+
+/// Everything the app persists sealed with the wallet key, to re-encrypt together
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SealedArtifacts {
+    /// The wallet file
+    pub clients: Vec<SealedClient>,
+    /// e.g. the history
+    pub data: Vec<SealedData>,
+    /// e.g. what was given to `CosigningClient::backup_key`
+    pub backups: Vec<SealedClient>,
+}
+
+impl SealedArtifacts {
+    /// Everything re-encrypted with `new_key`, or an error and nothing to persist if any of them
+    /// doesn't unseal with `old_key`
+    /// Write all of the result at once, keeping the old artifacts until it's done
+    pub fn change_password(
+        &self,
+        old_key: Option<&[u8; 32]>,
+        new_key: Option<&[u8; 32]>,
+        rng: &SharedRng,
+    ) -> Result<Self> {
+        Ok(Self {
+            clients: self
+                .clients
+                .iter()
+                .map(|c| c.change_password(old_key, new_key))
+                .collect::<Result<_>>()?,
+            data: self
+                .data
+                .iter()
+                .map(|d| d.change_password(old_key, new_key, rng))
+                .collect::<Result<_>>()?,
+            backups: self
+                .backups
+                .iter()
+                .map(|b| b.change_password(old_key, new_key))
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+impl SpClient {
+    /// The only way to persist the client secrets
+    /// Without `key` they're only hex encoded, so that's meant for storage that's already protected
+    pub fn seal(&self, key: Option<&[u8; 32]>) -> Result<SealedClient> {
+        let mut plain = Zeroizing::new(vec![SEALED_VERSION]);
+        plain.extend_from_slice(&self.get_scan_key().secret_bytes());
+        match self.get_spend_key() {
+            SpendKey::Secret(sk) => {
+                plain.push(SPEND_KEY_SECRET);
+                plain.extend_from_slice(&sk.secret_bytes());
+            }
+            SpendKey::Public(pk) => {
+                plain.push(SPEND_KEY_PUBLIC);
+                plain.extend_from_slice(&pk.serialize());
+            }
+        }
+        let mnemonic = Zeroizing::new(self.get_mnemonic().unwrap_or_default());
+        let mnemonic_len =
+            u16::try_from(mnemonic.len()).map_err(|_| Error::msg("Mnemonic too long"))?;
+        plain.extend_from_slice(&mnemonic_len.to_be_bytes());
+        plain.extend_from_slice(mnemonic.as_bytes());
+
+        let mut labels: Vec<String> = self
+            .sp_receiver
+            .list_labels()
+            .iter()
+            .map(Label::as_string)
+            .collect();
+        labels.sort();
+        let metadata = SealedMetadata {
+            label: self.label.clone(),
+            network: self.get_network(),
+            spending_policy: self.get_spending_policy().clone(),
+            settings: self.get_settings().clone(),
+            backup_verified_at: self.get_backup_verified_at(),
+            labels,
+        };
+        plain.extend_from_slice(&serde_json::to_vec(&metadata)?);
+
+        let (nonce, data) = encrypt(&plain, key, &self.get_rng())?;
+
+        Ok(SealedClient { nonce, data })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::Amount;
+
+    use crate::spclient::{ChangePolicy, FeeSplit, SubDustChange, TxOrdering, WalletType};
+    use crate::test_utils::{client_from_seeds, test_client};
+
+    fn legacy_json(client: &SpClient, spend_key: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "label": client.label,
+            "scan_sk": client.get_scan_key().secret_bytes().to_lower_hex_string(),
+            "spend_key": spend_key,
+            "mnemonic": client.get_mnemonic(),
+            "sp_receiver": client.sp_receiver,
+        })
+    }
+
+    #[test]
+    fn legacy_json_is_sealed() {
+        let scan_sk = SecretKey::from_slice(&[0x11; 32]).unwrap();
+        let spend_sk = SecretKey::from_slice(&[0x22; 32]).unwrap();
+        let mut client = client_from_seeds(0x11, 0x22, None, Network::Testnet);
+        client
+            .sp_receiver
+            .add_label(Label::new(scan_sk, 7))
+            .unwrap();
+
+        let json = legacy_json(
+            &client,
+            serde_json::json!({ "Secret": spend_sk.secret_bytes().to_lower_hex_string() }),
+        );
+        let key = [0x42; 32];
+        let sealed = SealedClient::from_legacy_json(&json.to_string(), Some(&key)).unwrap();
+        assert!(sealed.is_encrypted());
+        let unsealed = sealed.unseal(Some(&key)).unwrap();
+        assert_eq!(unsealed.get_scan_key(), scan_sk);
+        assert_eq!(unsealed.get_spend_key(), SpendKey::Secret(spend_sk));
+        assert_eq!(unsealed.get_network(), Network::Testnet);
+        assert_eq!(
+            unsealed.sp_receiver.list_labels(),
+            client.sp_receiver.list_labels()
+        );
+        assert_eq!(
+            SealedClient::from_legacy_json(&json.to_string(), None)
+                .unwrap()
+                .unseal(None)
+                .unwrap()
+                .sp_receiver,
+            client.sp_receiver
+        );
+
+        let outputs = OutputList::new(
+            scan_sk.public_key(&bitcoin::secp256k1::Secp256k1::signing_only()),
+            spend_sk.public_key(&bitcoin::secp256k1::Secp256k1::signing_only()),
+            100,
+        );
+        let wallet_json = serde_json::json!({ "client": json, "outputs": outputs });
+        let (sealed, migrated) =
+            SealedClient::from_legacy_wallet_json(&wallet_json.to_string(), None).unwrap();
+        assert_eq!(migrated, outputs);
+        assert_eq!(sealed.unseal(None).unwrap().get_scan_key(), scan_sk);
+
+        // outputs of another wallet
+        let other = OutputList::new(
+            spend_sk.public_key(&bitcoin::secp256k1::Secp256k1::signing_only()),
+            spend_sk.public_key(&bitcoin::secp256k1::Secp256k1::signing_only()),
+            100,
+        );
+        let wallet_json = serde_json::json!({ "client": json, "outputs": other });
+        assert!(SealedClient::from_legacy_wallet_json(&wallet_json.to_string(), None).is_err());
+    }
+
+    #[test]
+    fn settings_survive_sealing() {
+        let mut client = test_client();
+        client.set_tx_ordering(TxOrdering::Bip69);
+        client.set_dust_attack_threshold(Amount::from_sat(5_000));
+        let policy = ChangePolicy {
+            sub_dust_change: SubDustChange::AddToRecipient(0),
+            fee_split: FeeSplit::Proportional,
+            ..client.get_change_policy()
+        };
+        client.set_change_policy(policy);
+        let unsealed = client.seal(None).unwrap().unseal(None).unwrap();
+        assert_eq!(unsealed.get_change_policy(), policy);
+        assert_eq!(unsealed.get_tx_ordering(), TxOrdering::Bip69);
+        assert_eq!(
+            unsealed.get_dust_attack_threshold(),
+            Amount::from_sat(5_000)
+        );
+    }
+
+    #[test]
+    fn pin_selects_the_wallet() {
+        let mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        let main = SpClient::from_wallet_type(
+            "main".to_owned(),
+            WalletType::Mnemonic(mnemonic.to_owned()),
+            0,
+            Network::Regtest,
+        )
+        .unwrap();
+        let hidden = SpClient::from_wallet_type(
+            "hidden".to_owned(),
+            WalletType::MnemonicWithPassphrase(mnemonic.to_owned(), "duress".to_owned()),
+            0,
+            Network::Regtest,
+        )
+        .unwrap();
+        let (pin_key, duress_key) = ([0x01; 32], [0x02; 32]);
+        let rng = SharedRng::default();
+
+        let slots = vec![
+            main.seal(Some(&pin_key)).unwrap(),
+            SealedClient::filler(&rng),
+            hidden.seal(Some(&duress_key)).unwrap(),
+            test_client().seal(Some(&pin_key)).unwrap(),
+        ];
+        // nothing tells the slots apart without the key
+        for slot in slots.iter() {
+            let json = serde_json::to_value(slot).unwrap();
+            assert_eq!(json.as_object().unwrap().len(), 2);
+            assert_eq!(slot.data.len(), slots[1].data.len());
+        }
+
+        let (index, client) = SealedClient::unseal_slot(&slots, &pin_key).unwrap();
+        assert_eq!((index, &client), (0, &main));
+        let (index, client) = SealedClient::unseal_slot(&slots, &duress_key).unwrap();
+        assert_eq!((index, &client), (2, &hidden));
+        assert_ne!(main.get_receiving_address(), hidden.get_receiving_address());
+        assert!(SealedClient::unseal_slot(&slots, &[0x03; 32]).is_err());
+        assert!(SealedClient::unseal_slot(&[main.seal(None).unwrap()], &pin_key).is_err());
+    }
+
+    #[test]
+    fn change_password_reseals_everything() {
+        let client = test_client();
+        let rng = SharedRng::default();
+        let (old_key, new_key) = ([0x01; 32], [0x02; 32]);
+        let artifacts = SealedArtifacts {
+            clients: vec![client.seal(Some(&old_key)).unwrap()],
+            data: vec![SealedData::seal(&vec![1u32, 2, 3], Some(&old_key), &rng).unwrap()],
+            backups: vec![client.seal(Some(&old_key)).unwrap()],
+        };
+
+        let changed = artifacts
+            .change_password(Some(&old_key), Some(&new_key), &rng)
+            .unwrap();
+        assert_eq!(changed.clients[0].unseal(Some(&new_key)).unwrap(), client);
+        assert_eq!(changed.backups[0].unseal(Some(&new_key)).unwrap(), client);
+        assert_eq!(
+            changed.data[0].unseal::<Vec<u32>>(Some(&new_key)).unwrap(),
+            vec![1, 2, 3]
+        );
+        assert!(changed.clients[0].unseal(Some(&old_key)).is_err());
+
+        // one artifact sealed with another key, nothing comes out
+        let mut mixed = artifacts.clone();
+        mixed.backups.push(client.seal(Some(&[0x03; 32])).unwrap());
+        assert!(mixed
+            .change_password(Some(&old_key), Some(&new_key), &rng)
+            .is_err());
+    }
+}
@@ -0,0 +1,78 @@
+//! Preferences of a wallet, persisted in its `SealedClient` so that they come back with the backup
+
+use bitcoin::Amount;
+use serde::{Deserialize, Serialize};
+
+use crate::chain::BroadcastMode;
+use crate::coin_selection::SelectionPreference;
+use crate::constants::DUST_ATTACK_THRESHOLD;
+use crate::spclient::{ChangePolicy, TxOrdering};
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum FeeLevel {
+    Fastest,
+    #[default]
+    HalfHour,
+    Hour,
+    Economy,
+}
+
+impl FeeLevel {
+    /// To give to `FeeEstimator::get_fee_rate`
+    pub fn get_target_blocks(&self) -> u32 {
+        match self {
+            Self::Fastest => 1,
+            Self::HalfHour => 3,
+            Self::Hour => 6,
+            Self::Economy => 144,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct WalletSettings {
+    pub fee_level: FeeLevel,
+    /// ISO 4217 code, as given to `RateProvider::get_rate`
+    pub fiat_currency: String,
+    /// What to do with our change, see `SpClient::get_change_policy`
+    pub change_policy: ChangePolicy,
+    /// Signal replaceability in the transactions we create
+    pub rbf: bool,
+    pub privacy_mode: SelectionPreference,
+    /// To give to `BroadcastRouter`
+    #[serde(default)]
+    pub broadcast_mode: BroadcastMode,
+    /// Address the funds were swept to by a key rotation, the wallet shouldn't receive anymore
+    #[serde(default)]
+    pub retired_to: Option<String>,
+    /// Spend key shared with `musig`, we can't pay silent payment addresses, our change included
+    #[serde(default)]
+    pub musig: bool,
+    /// Used by `SpClient::order_tx`
+    #[serde(default)]
+    pub tx_ordering: TxOrdering,
+    /// See `SpClient::get_dust_attack_threshold`
+    #[serde(default = "default_dust_attack_threshold")]
+    pub dust_attack_threshold: Amount,
+}
+
+fn default_dust_attack_threshold() -> Amount {
+    DUST_ATTACK_THRESHOLD
+}
+
+impl Default for WalletSettings {
+    fn default() -> Self {
+        Self {
+            fee_level: FeeLevel::default(),
+            fiat_currency: "USD".to_owned(),
+            change_policy: ChangePolicy::default(),
+            rbf: false,
+            privacy_mode: SelectionPreference::default(),
+            broadcast_mode: BroadcastMode::default(),
+            retired_to: None,
+            musig: false,
+            tx_ordering: TxOrdering::default(),
+            dust_attack_threshold: DUST_ATTACK_THRESHOLD,
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Coordinated shutdown, so that the app can be killed right after `shutdown` returns without losing state
+//!
+//! Long running work (scans, the node) registers a task and polls `is_requested` to stop early,
+//! pending saves (wallet files, `BatchedJournal::flush`...) register a flush that runs once all tasks stopped.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Error, Result};
+
+type Flush = Box<dyn FnOnce() -> Result<()> + Send>;
+
+#[derive(Default)]
+struct State {
+    running_tasks: Vec<(u64, String)>,
+    next_task_id: u64,
+    flushes: Vec<(String, Flush)>,
+}
+
+#[derive(Default)]
+struct Inner {
+    requested: AtomicBool,
+    state: Mutex<State>,
+    task_stopped: Condvar,
+}
+
+/// Cloned in everything that must stop or save on shutdown
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+impl std::fmt::Debug for Shutdown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.lock_state();
+        f.debug_struct("Shutdown")
+            .field("requested", &self.is_requested())
+            .field("running_tasks", &state.running_tasks)
+            .field("flushes", &state.flushes.len())
+            .finish()
+    }
+}
+
+/// Keeps the shutdown waiting until dropped
+#[derive(Debug)]
+pub struct TaskGuard {
+    id: u64,
+    shutdown: Shutdown,
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        let mut state = self.shutdown.lock_state();
+        state.running_tasks.retain(|(id, _)| *id != self.id);
+        self.shutdown.inner.task_stopped.notify_all();
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.inner.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Checked by tasks between units of work, e.g. blocks of a scan
+    pub fn is_requested(&self) -> bool {
+        self.inner.requested.load(Ordering::Relaxed)
+    }
+
+    /// Fails once shutdown was requested, the task must not start
+    pub fn register_task(&self, name: &str) -> Result<TaskGuard> {
+        let mut state = self.lock_state();
+        if self.is_requested() {
+            return Err(Error::msg("Shutting down"));
+        }
+        let id = state.next_task_id;
+        state.next_task_id += 1;
+        state.running_tasks.push((id, name.to_owned()));
+        Ok(TaskGuard {
+            id,
+            shutdown: self.clone(),
+        })
+    }
+
+    /// `flush` runs once during `shutdown`, after the tasks stopped, in the order they were registered
+    pub fn register_flush(
+        &self,
+        name: &str,
+        flush: impl FnOnce() -> Result<()> + Send + 'static,
+    ) -> Result<()> {
+        let mut state = self.lock_state();
+        if self.is_requested() {
+            return Err(Error::msg("Shutting down"));
+        }
+        state.flushes.push((name.to_owned(), Box::new(flush)));
+        Ok(())
+    }
+
+    /// Stop the tasks, wait up to `timeout` for them, then run all the flushes
+    /// Returns only when everything is persisted, or with the tasks or flushes that didn't make it
+    pub fn shutdown(&self, timeout: Duration) -> Result<()> {
+        self.inner.requested.store(true, Ordering::Relaxed);
+
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock_state();
+        while !state.running_tasks.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = self
+                .inner
+                .task_stopped
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+        let stuck: Vec<String> = state
+            .running_tasks
+            .iter()
+            .map(|(_, name)| name.clone())
+            .collect();
+        let flushes = std::mem::take(&mut state.flushes);
+        drop(state);
+
+        // flushing even if some tasks are stuck saves what can be saved
+        let failed: Vec<String> = flushes
+            .into_iter()
+            .filter_map(|(name, flush)| flush().err().map(|e| format!("{}: {}", name, e)))
+            .collect();
+
+        if !stuck.is_empty() || !failed.is_empty() {
+            return Err(Error::msg(format!(
+                "Unclean shutdown, tasks still running: [{}], failed flushes: [{}]",
+                stuck.join(", "),
+                failed.join(", ")
+            )));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use bitcoin::{
+    hashes::{sha256, Hash, HashEngine},
+    key::Parity,
+    secp256k1::{schnorr, Keypair, Message, PublicKey, Secp256k1, SecretKey},
+};
+
+use anyhow::{Error, Result};
+
+/// Anything that can produce signatures for the spend key of the wallet,
+/// e.g. a hardware wallet or a remote signer
+/// The signer never hands out the spend key, it gets the per-input tweak and the message to sign
+pub trait Signer {
+    fn get_spend_pubkey(&self) -> Result<PublicKey>;
+
+    /// Produce a schnorr signature of `msg` with the spend key tweaked by `tweak`
+    fn sign_tweaked(
+        &self,
+        msg: &Message,
+        tweak: &SecretKey,
+        aux_rand: &[u8; 32],
+    ) -> Result<schnorr::Signature>;
+}
+
+/// The default signer, holding the spend secret key in memory
+pub struct LocalSigner {
+    spend_sk: SecretKey,
+}
+
+impl LocalSigner {
+    pub fn new(spend_sk: SecretKey) -> Self {
+        Self { spend_sk }
+    }
+}
+
+impl Drop for LocalSigner {
+    fn drop(&mut self) {
+        self.spend_sk.non_secure_erase();
+    }
+}
+
+impl Signer for LocalSigner {
+    fn get_spend_pubkey(&self) -> Result<PublicKey> {
+        Ok(self.spend_sk.public_key(&Secp256k1::signing_only()))
+    }
+
+    fn sign_tweaked(
+        &self,
+        msg: &Message,
+        tweak: &SecretKey,
+        aux_rand: &[u8; 32],
+    ) -> Result<schnorr::Signature> {
+        let secp = Secp256k1::signing_only();
+
+        let mut sk = self.spend_sk.add_tweak(&(*tweak).into())?;
+
+        let mut keypair = Keypair::from_secret_key(&secp, &sk);
+
+        let sig = secp.sign_schnorr_with_aux_rand(msg, &keypair, aux_rand);
+
+        sk.non_secure_erase();
+        keypair.non_secure_erase();
+
+        Ok(sig)
+    }
+}
+
+pub(crate) fn tagged_hash(tag: &str, data: &[&[u8]]) -> [u8; 32] {
+    let tag_hash = sha256::Hash::hash(tag.as_bytes()).to_byte_array();
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag_hash);
+    engine.input(&tag_hash);
+    for d in data {
+        engine.input(d);
+    }
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+// The odds of a hash not being a valid scalar are negligible, we just fail if it happens
+pub(crate) fn hash_to_scalar(hash: [u8; 32]) -> Result<SecretKey> {
+    SecretKey::from_slice(&hash).map_err(|_| Error::msg("Hash is not a valid scalar"))
+}
+
+pub(crate) fn has_even_y(pk: &PublicKey) -> bool {
+    pk.x_only_public_key().1 == Parity::Even
+}
+
+pub(crate) fn xbytes(pk: &PublicKey) -> [u8; 32] {
+    pk.x_only_public_key().0.serialize()
+}
@@ -0,0 +1,197 @@
+use bitcoin::{bip32::Xpriv, secp256k1::SecretKey, Network};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use anyhow::{Error, Result};
+
+use crate::spclient::derive_keys_from_xprv;
+
+// SLIP-39 allows 128 or 256 bits master secrets
+const ALLOWED_STRENGTH_BITS: [u16; 2] = [128, 256];
+
+// We use the default iteration exponent from the spec
+const ITERATION_EXPONENT: u8 = 0;
+
+/// What the UI needs to know about a share during the restore flow
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Slip39ShareInfo {
+    /// Random identifier common to all the shares of a set
+    pub identifier: u16,
+    pub member_index: u8,
+    /// Number of shares needed to restore the secret
+    pub member_threshold: u8,
+}
+
+/// Generate a new random master secret and split it in `share_count` shares,
+/// any `threshold` of them being enough to restore the wallet
+pub fn generate_slip39_shares(
+    threshold: u8,
+    share_count: u8,
+    strength_bits: u16,
+    passphrase: &str,
+) -> Result<Vec<String>> {
+    if !ALLOWED_STRENGTH_BITS.contains(&strength_bits) {
+        return Err(Error::msg(format!(
+            "Invalid strength {}, must be one of {:?}",
+            strength_bits, ALLOWED_STRENGTH_BITS
+        )));
+    }
+    check_threshold(threshold, share_count)?;
+
+    let groups = sssmc39::generate_mnemonics_random(
+        1,
+        &[(threshold, share_count)],
+        strength_bits,
+        passphrase,
+        ITERATION_EXPONENT,
+    )
+    .map_err(|e| Error::msg(e.to_string()))?;
+
+    flatten_shares(groups)
+}
+
+/// Split an existing master secret in SLIP-39 shares
+pub fn split_master_secret(
+    master_secret: &[u8],
+    threshold: u8,
+    share_count: u8,
+    passphrase: &str,
+) -> Result<Vec<String>> {
+    check_threshold(threshold, share_count)?;
+
+    let groups = sssmc39::generate_mnemonics(
+        1,
+        &[(threshold, share_count)],
+        master_secret,
+        passphrase,
+        ITERATION_EXPONENT,
+    )
+    .map_err(|e| Error::msg(e.to_string()))?;
+
+    flatten_shares(groups)
+}
+
+/// Parse a single share and check its checksum
+pub fn validate_slip39_share(share: &str) -> Result<Slip39ShareInfo> {
+    let words: Vec<String> = share.split_whitespace().map(|w| w.to_lowercase()).collect();
+    let share = sssmc39::Share::from_mnemonic(&words).map_err(|e| Error::msg(e.to_string()))?;
+
+    Ok(Slip39ShareInfo {
+        identifier: share.identifier,
+        member_index: share.member_index,
+        member_threshold: share.member_threshold,
+    })
+}
+
+/// Restore the master secret from a set of shares
+pub fn combine_slip39_shares(shares: &[String], passphrase: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let mnemonics: Vec<Vec<String>> = shares
+        .iter()
+        .map(|s| s.split_whitespace().map(|w| w.to_lowercase()).collect())
+        .collect();
+
+    let master_secret = sssmc39::combine_mnemonics(&mnemonics, passphrase)
+        .map_err(|e| Error::msg(e.to_string()))?;
+
+    Ok(Zeroizing::new(master_secret))
+}
+
+/// The SLIP-39 master secret is used as the BIP32 seed, from there derivation is the same as with BIP39
+pub fn derive_keys_from_slip39(
+    shares: &[String],
+    passphrase: &str,
+    account: u32,
+    network: Network,
+) -> Result<(SecretKey, SecretKey)> {
+    let master_secret = combine_slip39_shares(shares, passphrase)?;
+
+    let mut xprv = Xpriv::new_master(network, &master_secret)?;
+
+    let keys = derive_keys_from_xprv(xprv, account);
+
+    xprv.private_key.non_secure_erase();
+
+    keys
+}
+
+fn check_threshold(threshold: u8, share_count: u8) -> Result<()> {
+    if threshold == 0 || threshold > share_count {
+        return Err(Error::msg(format!(
+            "Invalid threshold {} for {} shares",
+            threshold, share_count
+        )));
+    }
+    if threshold == 1 && share_count > 1 {
+        // the spec forbids it, all the shares would just be copies of the secret
+        return Err(Error::msg(
+            "Threshold of 1 only allowed with a single share",
+        ));
+    }
+    Ok(())
+}
+
+fn flatten_shares(groups: Vec<sssmc39::GroupShare>) -> Result<Vec<String>> {
+    let mut res = vec![];
+    for group in groups {
+        let mnemonics = group
+            .mnemonic_list()
+            .map_err(|e| Error::msg(e.to_string()))?;
+        res.extend(mnemonics.into_iter().map(|words| words.join(" ")));
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::spclient::{SpClient, WalletType};
+
+    #[test]
+    fn any_threshold_of_shares_restores_the_secret() {
+        let secret = [0x42u8; 16];
+        let shares = split_master_secret(&secret, 2, 3, "").unwrap();
+        assert_eq!(shares.len(), 3);
+
+        for pair in [[0, 1], [0, 2], [1, 2]] {
+            let subset: Vec<String> = pair.iter().map(|i| shares[*i].clone()).collect();
+            assert_eq!(*combine_slip39_shares(&subset, "").unwrap(), secret);
+        }
+        assert!(combine_slip39_shares(&shares[..1], "").is_err());
+
+        let info = validate_slip39_share(&shares[1]).unwrap();
+        assert_eq!(info.member_threshold, 2);
+        assert_eq!(
+            info.identifier,
+            validate_slip39_share(&shares[2]).unwrap().identifier
+        );
+    }
+
+    #[test]
+    fn invalid_thresholds_are_refused() {
+        for (threshold, count) in [(0, 3), (4, 3), (1, 2)] {
+            assert!(split_master_secret(&[0x42; 16], threshold, count, "").is_err());
+        }
+        assert!(generate_slip39_shares(2, 3, 192, "").is_err());
+    }
+
+    #[test]
+    fn wallet_from_shares() {
+        let shares = generate_slip39_shares(2, 3, 128, "").unwrap();
+        let wallet = |shares: &[String]| {
+            SpClient::from_wallet_type(
+                "slip39".to_owned(),
+                WalletType::Slip39(shares.to_vec()),
+                0,
+                Network::Regtest,
+            )
+        };
+        let first = wallet(&shares[..2]).unwrap();
+        let second = wallet(&shares[1..]).unwrap();
+        assert_eq!(
+            first.get_receiving_address(),
+            second.get_receiving_address()
+        );
+        assert!(wallet(&shares[..1]).is_err());
+    }
+}
@@ -4,21 +4,23 @@ use std::{
     str::FromStr,
 };
 
+use bech32::FromBase32;
 use bitcoin::{
-    bip32::{DerivationPath, Xpriv},
+    bip32::{DerivationPath, Xpriv, Xpub},
     consensus::{deserialize, serialize},
     hex::DisplayHex,
     key::{constants::ONE, TapTweak},
     psbt::PsbtSighashType,
-    script::PushBytesBuf,
-    secp256k1::{Keypair, Message, PublicKey, Scalar, Secp256k1, SecretKey, ThirtyTwoByteHash},
+    script::{Instruction, PushBytesBuf},
+    secp256k1::rand::seq::SliceRandom,
+    secp256k1::{Message, PublicKey, Scalar, Secp256k1, SecretKey, ThirtyTwoByteHash},
     sighash::{Prevouts, SighashCache},
     taproot::Signature,
     Address, Amount, BlockHash, Network, OutPoint, ScriptBuf, TapLeafHash, Transaction, TxIn,
     TxOut, Txid, Witness, XOnlyPublicKey,
 };
 use bitcoin::{
-    hashes::Hash,
+    hashes::{sha256, Hash, HashEngine},
     psbt::{raw, Input, Output},
 };
 use serde::{Deserialize, Serialize};
@@ -28,14 +30,261 @@ use silentpayments::utils as sp_utils;
 use silentpayments::utils::{Network as SpNetwork, SilentPaymentAddress};
 
 use anyhow::{Error, Result};
+use bip39::{Language, Mnemonic};
+use zeroize::Zeroize;
 
 use crate::constants::{
-    DATA_CARRIER_SIZE, DUST_THRESHOLD, NUMS, PSBT_SP_ADDRESS_KEY, PSBT_SP_PREFIX, PSBT_SP_SUBTYPE,
-    PSBT_SP_TWEAK_KEY,
+    DATA_CARRIER_SIZE, DUST_THRESHOLD, NUMS, PSBT_SP_ADDRESS_KEY, PSBT_SP_CHANGE_KEY,
+    PSBT_SP_PREFIX, PSBT_SP_SUBTYPE, PSBT_SP_TWEAK_KEY, SP_ADDRESS_RESERVED_VERSION,
 };
+use crate::inspect::get_psbt_totals;
+use crate::intent::{RecipientShortfall, SpendIntent};
+use crate::policy::{PolicyViolation, SpendHistory, SpendingPolicy};
+use crate::psbt_data::{get_psbt_sp_data, is_psbt_change_output};
+use crate::rng::{SharedRng, SpRng};
+use crate::settings::WalletSettings;
+use crate::signer::{LocalSigner, Signer};
+use crate::slip39::derive_keys_from_slip39;
+use crate::watch_only::WatchOnlyPackage;
+use crate::weight::predict_psbt_vsize;
+use crate::workers;
 
 pub use bitcoin::psbt::Psbt;
 
+/// `set_fees` should converge in 2 iterations, this only guards against an endless loop
+const MAX_FEE_ITERATIONS: usize = 10;
+
+/// Payers that `SpClient::set_fees_from` resolves to our change output
+pub const CHANGE_PAYERS: [&str; 2] = ["change", "self"];
+
+const BECH32_CHARSET: &str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+/// Version of anything that looks like a silent payment address, even if we can't parse it
+fn get_sp_address_version(address: &str) -> Option<u8> {
+    let address = address.to_lowercase();
+    let (hrp, data) = address.rsplit_once('1')?;
+    if !matches!(hrp, "sp" | "tsp" | "sprt") {
+        return None;
+    }
+    let version = data.chars().next()?;
+    BECH32_CHARSET.find(version).map(|v| v as u8)
+}
+
+/// Returned wrapped in an `anyhow::Error`, use `downcast_ref` to tell the user to update the app
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnsupportedAddressVersion(pub u8);
+
+impl std::fmt::Display for UnsupportedAddressVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Silent payment address version {} not supported, please update",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedAddressVersion {}
+
+/// Returned wrapped in an `anyhow::Error`, use `downcast_ref` to skip transactions seen twice,
+/// e.g. found in the mempool and then in a block
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlreadyScanned(pub Txid);
+
+impl std::fmt::Display for AlreadyScanned {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Transaction already scanned")
+    }
+}
+
+impl std::error::Error for AlreadyScanned {}
+
+/// Addresses of a later version start with the same keys as version 0, followed by data we ignore
+fn decode_sp_address(address: &str, version: u8) -> Result<SilentPaymentAddress> {
+    let (hrp, data, variant) = bech32::decode(address)?;
+    if variant != bech32::Variant::Bech32m {
+        return Err(Error::msg("Not a bech32m string"));
+    }
+    let network = match hrp.as_str() {
+        "sp" => SpNetwork::Mainnet,
+        "tsp" => SpNetwork::Testnet,
+        _ => SpNetwork::Regtest,
+    };
+    if data.is_empty() {
+        return Err(Error::msg("Missing address version"));
+    }
+    let payload = Vec::<u8>::from_base32(&data[1..])?;
+    if payload.len() < 66 || (version == 0 && payload.len() != 66) {
+        return Err(Error::msg("Wrong address length"));
+    }
+    Ok(SilentPaymentAddress::new(
+        PublicKey::from_slice(&payload[..33])?,
+        PublicKey::from_slice(&payload[33..66])?,
+        network,
+        0,
+    )?)
+}
+
+/// None for anything without a silent payment prefix, e.g. a regular address
+/// An address with the prefix is never tried as a regular address, so that the reserved version
+/// gets `UnsupportedAddressVersion` instead of an error about an invalid address
+/// Any other version is returned as the version 0 address we pay
+/// Only paying is concerned, scanning doesn't involve our own address version
+pub fn try_parse_sp_address(address: &str) -> Result<Option<SilentPaymentAddress>> {
+    match get_sp_address_version(address) {
+        None => Ok(None),
+        Some(version) if version >= SP_ADDRESS_RESERVED_VERSION => {
+            Err(UnsupportedAddressVersion(version).into())
+        }
+        Some(version) => decode_sp_address(address, version)
+            .map(Some)
+            .map_err(|e| Error::msg(format!("Invalid silent payment address {}: {}", address, e))),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SpAddressInfo {
+    pub scan_pubkey: PublicKey,
+    /// With the label tweak if it's a labeled address
+    pub spend_pubkey: PublicKey,
+    pub network: SpNetwork,
+    /// As written in the address, it's paid as version 0 anyway
+    pub version: u8,
+}
+
+/// What's in a silent payment address, to show the recipient before paying it
+pub fn parse_sp_address(address: &str) -> Result<SpAddressInfo> {
+    let sp_address =
+        try_parse_sp_address(address)?.ok_or(Error::msg("Not a silent payment address"))?;
+
+    Ok(SpAddressInfo {
+        scan_pubkey: sp_address.get_scan_key(),
+        spend_pubkey: sp_address.get_spend_key(),
+        network: sp_address.get_network(),
+        version: get_sp_address_version(address).unwrap_or_default(),
+    })
+}
+
+/// Same key for every spelling of an address
+pub(crate) fn get_address_key(address: &str) -> String {
+    if let Ok(Some(sp_address)) = try_parse_sp_address(address) {
+        sp_address.to_string()
+    } else if let Ok(address) = Address::from_str(address) {
+        address.assume_checked().script_pubkey().to_hex_string()
+    } else {
+        address.to_owned()
+    }
+}
+
+/// Index of the first entry of a recipient in the normalized list, and its number of outputs
+type RecipientPosition = (usize, u32);
+
+/// Recipients listed more than once are merged, then each gets one entry per output,
+/// the amount being split between outputs
+/// Also returns where each of the given recipients ended up in the list
+fn normalize_recipients(
+    recipients: &[Recipient],
+) -> Result<(Vec<Recipient>, Vec<RecipientPosition>)> {
+    let mut merged: Vec<(String, Recipient)> = vec![];
+    let mut merged_index = vec![];
+    for recipient in recipients {
+        if recipient.amount == Amount::ZERO {
+            return Err(Error::msg(format!(
+                "Amount to {} can't be zero",
+                recipient.address
+            )));
+        }
+        if recipient.nb_outputs == 0 {
+            return Err(Error::msg(format!(
+                "Recipient {} needs at least one output",
+                recipient.address
+            )));
+        }
+
+        let key = get_address_key(&recipient.address);
+        match merged.iter().position(|(k, _)| *k == key) {
+            Some(i) => {
+                let existing = &mut merged[i].1;
+                existing.amount = existing
+                    .amount
+                    .checked_add(recipient.amount)
+                    .ok_or(Error::msg("Overflow on output amount"))?;
+                existing.nb_outputs = existing.nb_outputs.max(recipient.nb_outputs);
+                merged_index.push(i);
+            }
+            None => {
+                merged_index.push(merged.len());
+                merged.push((key, recipient.clone()));
+            }
+        }
+    }
+
+    let mut res = vec![];
+    let mut positions = vec![];
+    for (_, recipient) in merged {
+        if recipient.nb_outputs > 1 && try_parse_sp_address(&recipient.address)?.is_none() {
+            return Err(Error::msg(format!(
+                "Only silent payment addresses can have more than one output, not {}",
+                recipient.address
+            )));
+        }
+        positions.push((res.len(), recipient.nb_outputs));
+        let nb_outputs = recipient.nb_outputs as u64;
+        let share = recipient.amount.to_sat() / nb_outputs;
+        let remainder = recipient.amount.to_sat() % nb_outputs;
+        for i in 0..nb_outputs {
+            res.push(Recipient {
+                address: recipient.address.clone(),
+                amount: Amount::from_sat(share + if i == 0 { remainder } else { 0 }),
+                nb_outputs: 1,
+            });
+        }
+    }
+
+    Ok((
+        res,
+        merged_index.into_iter().map(|i| positions[i]).collect(),
+    ))
+}
+
+struct InputToSign {
+    index: usize,
+    msg: Message,
+    hash_ty: bitcoin::TapSighashType,
+    tweak: SecretKey,
+    /// The key the signature must verify against, the output key or the key in the leaf
+    output_key: XOnlyPublicKey,
+    /// Only for script path spends
+    leaf_hash: Option<TapLeafHash>,
+}
+
+impl InputToSign {
+    fn sign(&self, signer: &dyn Signer, aux_rand: &[u8; 32]) -> Result<Signature> {
+        let sig = signer.sign_tweaked(&self.msg, &self.tweak, aux_rand)?;
+
+        // Don't trust the signer blindly
+        Secp256k1::verification_only()
+            .verify_schnorr(&sig, &self.msg, &self.output_key)
+            .map_err(|_| {
+                Error::msg(format!(
+                    "Signer returned an invalid signature for input {}",
+                    self.index
+                ))
+            })?;
+
+        Ok(Signature {
+            sig,
+            hash_ty: self.hash_ty,
+        })
+    }
+}
+
+impl Drop for InputToSign {
+    fn drop(&mut self) {
+        self.tweak.non_secure_erase();
+    }
+}
+
 type SpendingTxId = String;
 type MinedInBlock = String;
 
@@ -48,6 +297,26 @@ pub enum OutputSpendStatus {
 
 type WalletFingerprint = [u8; 8];
 
+/// Outputs found in a mempool transaction are stored at this height until they're mined
+pub const UNCONFIRMED_HEIGHT: u32 = 0;
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Balance {
+    /// Unspent outputs that are mined
+    pub confirmed: Amount,
+    /// Unspent outputs we only saw in the mempool, including the change of our own transactions
+    pub pending_incoming: Amount,
+    /// What our transactions that are not mined yet spend, their change subtracted
+    pub pending_outgoing: Amount,
+}
+
+impl Balance {
+    /// What we'll have once everything pending is mined
+    pub fn get_expected(&self) -> Amount {
+        self.confirmed + self.pending_incoming
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct OwnedOutput {
     pub blockheight: u32,
@@ -56,6 +325,15 @@ pub struct OwnedOutput {
     pub script: String,
     pub label: Option<String>,
     pub spend_status: OutputSpendStatus,
+    /// Potential dust attack, left out of `to_spendable_list` until the user releases it
+    #[serde(default)]
+    pub quarantined: bool,
+    /// Block the output was found in, None for outputs found in the mempool
+    #[serde(default)]
+    pub blockhash: Option<BlockHash>,
+    /// Timestamp of that block, as in its header
+    #[serde(default)]
+    pub block_time: Option<u32>,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
@@ -106,6 +384,14 @@ impl OutputList {
         self.birthday = new_birthday;
     }
 
+    /// With `and_reset`, also drop the outputs from `new_birthday` on and scan again from there
+    pub fn change_birthday(&mut self, new_birthday: u32, and_reset: bool) {
+        self.set_birthday(new_birthday);
+        if and_reset {
+            self.reset_to_birthday();
+        }
+    }
+
     pub fn update_last_scan(&mut self, scan_height: u32) {
         self.last_scan = scan_height;
     }
@@ -139,13 +425,34 @@ impl OutputList {
             .fold(Amount::from_sat(0), |acc, x| acc + x.1.amount)
     }
 
+    /// Quarantined outputs are left out, spending them along with our other outputs would link them
     pub fn to_spendable_list(&self) -> HashMap<OutPoint, OwnedOutput> {
         self.to_outpoints_list()
             .into_iter()
-            .filter(|(_, o)| o.spend_status == OutputSpendStatus::Unspent)
+            .filter(|(_, o)| o.spend_status == OutputSpendStatus::Unspent && !o.quarantined)
+            .collect()
+    }
+
+    /// Unspent outputs the user should review
+    pub fn get_quarantined(&self) -> HashMap<OutPoint, OwnedOutput> {
+        self.to_outpoints_list()
+            .into_iter()
+            .filter(|(_, o)| o.spend_status == OutputSpendStatus::Unspent && o.quarantined)
             .collect()
     }
 
+    /// Returns the updated output, to persist it
+    pub fn set_quarantined(
+        &mut self,
+        outpoint: OutPoint,
+        quarantined: bool,
+    ) -> Result<(OutPoint, OwnedOutput)> {
+        let (outpoint, mut output) = self.get_outpoint(outpoint)?;
+        output.quarantined = quarantined;
+        self.outputs.insert(outpoint, output.clone());
+        Ok((outpoint, output))
+    }
+
     pub fn get_outpoint(&self, outpoint: OutPoint) -> Result<(OutPoint, OwnedOutput)> {
         let output = self
             .to_outpoints_list()
@@ -225,12 +532,121 @@ pub struct Recipient {
     pub nb_outputs: u32, // if address is not SP, only 1 is valid
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+/// How the fee is taken from the payer when it has several outputs in the transaction
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum FeeSplit {
+    /// All from the first output of the payer
+    #[default]
+    FirstOutput,
+    /// From every output of the payer, in proportion to its amount
+    Proportional,
+}
+
+/// What to do with change that's too small to get its own output
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum SubDustChange {
+    #[default]
+    AddToFees,
+    /// Index of the recipient in the list given to `create_new_psbt`
+    AddToRecipient(usize),
+}
+
+/// Outputs below the threshold of their type are never created
+/// Defaults are the standard relay dust limits
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct DustPolicy {
+    pub p2pkh: Amount,
+    pub p2sh: Amount,
+    pub p2wpkh: Amount,
+    pub p2wsh: Amount,
+    /// Also what our change and silent payments outputs use
+    pub p2tr: Amount,
+    pub other: Amount,
+    /// Replaces all the values above when set, e.g. to deliberately create or refuse small outputs
+    pub user_override: Option<Amount>,
+}
+
+impl Default for DustPolicy {
+    fn default() -> Self {
+        Self {
+            p2pkh: DUST_THRESHOLD,
+            p2sh: Amount::from_sat(540),
+            p2wpkh: Amount::from_sat(294),
+            p2wsh: Amount::from_sat(330),
+            p2tr: Amount::from_sat(330),
+            other: DUST_THRESHOLD,
+            user_override: None,
+        }
+    }
+}
+
+impl DustPolicy {
+    pub fn get_threshold(&self, script_pubkey: &bitcoin::Script) -> Amount {
+        if let Some(threshold) = self.user_override {
+            return threshold;
+        }
+        if script_pubkey.is_p2tr() {
+            self.p2tr
+        } else if script_pubkey.is_p2wpkh() {
+            self.p2wpkh
+        } else if script_pubkey.is_p2wsh() {
+            self.p2wsh
+        } else if script_pubkey.is_p2pkh() {
+            self.p2pkh
+        } else if script_pubkey.is_p2sh() {
+            self.p2sh
+        } else if script_pubkey.is_witness_program() {
+            // future witness versions, same relay limit as any witness output of that size
+            Amount::from_sat(3 * (script_pubkey.len() as u64 + 76))
+        } else {
+            self.other
+        }
+    }
+}
+
+/// Order of the inputs and outputs of our transactions, so that the change isn't always the last output
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum TxOrdering {
+    /// With the client rng
+    #[default]
+    Shuffle,
+    /// Sorted as in BIP 69, what many other wallets do
+    Bip69,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct ChangePolicy {
+    /// Change below the threshold doesn't get an output
+    pub dust: DustPolicy,
+    pub sub_dust_change: SubDustChange,
+    #[serde(default)]
+    pub fee_split: FeeSplit,
+}
+
+/// Where the change of a new psbt went
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum ChangeOutcome {
+    NoChange,
+    Output(Amount),
+    AbsorbedInFees(Amount),
+    AddedToRecipient { index: usize, amount: Amount },
+}
+
+#[derive(PartialEq, Clone)]
 pub enum SpendKey {
     Secret(SecretKey),
     Public(PublicKey),
 }
 
+impl std::fmt::Debug for SpendKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Secret(_) => f.write_str("Secret(<redacted>)"),
+            Self::Public(pk) => f.debug_tuple("Public").field(pk).finish(),
+        }
+    }
+}
+
 impl TryInto<SecretKey> for SpendKey {
     type Error = anyhow::Error;
     fn try_into(self) -> std::prelude::v1::Result<SecretKey, Error> {
@@ -241,25 +657,135 @@ impl TryInto<SecretKey> for SpendKey {
     }
 }
 
-impl Into<PublicKey> for SpendKey {
-    fn into(self) -> PublicKey {
-        match self {
-            Self::Secret(k) => {
+impl From<SpendKey> for PublicKey {
+    fn from(spend_key: SpendKey) -> Self {
+        match spend_key {
+            SpendKey::Secret(k) => {
                 let secp = Secp256k1::signing_only();
                 k.public_key(&secp)
             }
-            Self::Public(p) => p,
+            SpendKey::Public(p) => p,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy, Default)]
+pub enum MnemonicStrength {
+    #[default]
+    Words12,
+    Words24,
+}
+
+impl MnemonicStrength {
+    fn entropy_len(&self) -> usize {
+        match self {
+            Self::Words12 => 16,
+            Self::Words24 => 32,
         }
     }
 }
 
+/// The standard BIP39 wordlists
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+pub enum MnemonicLanguage {
+    #[default]
+    English,
+    SimplifiedChinese,
+    TraditionalChinese,
+    Czech,
+    French,
+    Italian,
+    Japanese,
+    Korean,
+    Portuguese,
+    Spanish,
+}
+
+impl From<MnemonicLanguage> for Language {
+    fn from(language: MnemonicLanguage) -> Self {
+        match language {
+            MnemonicLanguage::English => Language::English,
+            MnemonicLanguage::SimplifiedChinese => Language::SimplifiedChinese,
+            MnemonicLanguage::TraditionalChinese => Language::TraditionalChinese,
+            MnemonicLanguage::Czech => Language::Czech,
+            MnemonicLanguage::French => Language::French,
+            MnemonicLanguage::Italian => Language::Italian,
+            MnemonicLanguage::Japanese => Language::Japanese,
+            MnemonicLanguage::Korean => Language::Korean,
+            MnemonicLanguage::Portuguese => Language::Portuguese,
+            MnemonicLanguage::Spanish => Language::Spanish,
+        }
+    }
+}
+
+impl MnemonicLanguage {
+    pub const ALL: [MnemonicLanguage; 10] = [
+        Self::English,
+        Self::SimplifiedChinese,
+        Self::TraditionalChinese,
+        Self::Czech,
+        Self::French,
+        Self::Italian,
+        Self::Japanese,
+        Self::Korean,
+        Self::Portuguese,
+        Self::Spanish,
+    ];
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub enum WalletType {
+    /// An english mnemonic
+    New(MnemonicStrength),
+    NewInLanguage(MnemonicStrength, MnemonicLanguage),
+    /// In any of the standard languages, see `detect_mnemonic_language`
+    Mnemonic(String),
+    /// Each passphrase gives a completely independent wallet, e.g. for a hidden wallet
+    MnemonicWithPassphrase(String, String),
+    PrivateKeys(SecretKey, SecretKey),
+    ReadOnly(SecretKey, PublicKey),
+    /// An encoded `WatchOnlyPackage`
+    WatchOnly(String),
+    /// A base58 encoded master extended private key
+    Extended(String),
+    /// Enough SLIP-39 shares of a set to restore it, e.g. from `slip39::generate_slip39_shares`
+    Slip39(Vec<String>),
+}
+
+/// Not serializable on purpose, secrets are persisted with `seal`
+#[derive(PartialEq, Clone)]
 pub struct SpClient {
     pub label: String,
     scan_sk: SecretKey,
     spend_key: SpendKey,
     mnemonic: Option<String>,
     pub sp_receiver: Receiver,
+    /// Signet and testnet share the same silent payment network, this tells them apart
+    network: Network,
+    rng: SharedRng,
+    spending_policy: SpendingPolicy,
+    settings: WalletSettings,
+    backup_verified_at: Option<u64>,
+    /// Positions asked by the last `create_mnemonic_challenge`
+    mnemonic_challenge: Option<Vec<usize>>,
+}
+
+impl std::fmt::Debug for SpClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SpClient")
+            .field("label", &self.label)
+            .field("scan_sk", &"<redacted>")
+            .field("spend_key", &self.spend_key)
+            .field("mnemonic", &self.mnemonic.as_ref().map(|_| "<redacted>"))
+            .field("sp_receiver", &self.sp_receiver)
+            .field("network", &self.network)
+            .field("rng", &self.rng)
+            .field("spending_policy", &self.spending_policy)
+            .field("settings", &self.settings)
+            .field("backup_verified_at", &self.backup_verified_at)
+            .field("mnemonic_challenge", &self.mnemonic_challenge)
+            .finish()
+    }
 }
 
 impl Default for SpClient {
@@ -281,6 +807,24 @@ impl Default for SpClient {
                 SpNetwork::Regtest,
             )
             .unwrap(),
+            network: Network::Regtest,
+            rng: SharedRng::default(),
+            spending_policy: SpendingPolicy::default(),
+            settings: WalletSettings::default(),
+            backup_verified_at: None,
+            mnemonic_challenge: None,
+        }
+    }
+}
+
+impl Drop for SpClient {
+    fn drop(&mut self) {
+        self.scan_sk.non_secure_erase();
+        if let SpendKey::Secret(ref mut sk) = self.spend_key {
+            sk.non_secure_erase();
+        }
+        if let Some(ref mut mnemonic) = self.mnemonic {
+            mnemonic.zeroize();
         }
     }
 }
@@ -295,30 +839,18 @@ impl SpClient {
     ) -> Result<Self> {
         let secp = Secp256k1::signing_only();
         let scan_pubkey = scan_sk.public_key(&secp);
-        let sp_receiver: Receiver;
         let change_label = Label::new(scan_sk, 0);
 
         let sp_network = match network {
             Network::Bitcoin => SpNetwork::Mainnet,
             Network::Regtest => SpNetwork::Regtest,
             Network::Testnet | Network::Signet => SpNetwork::Testnet,
-            _ => unreachable!(),
         };
-        match spend_key {
-            SpendKey::Public(key) => {
-                sp_receiver = Receiver::new(0, scan_pubkey, key, change_label.into(), sp_network)?;
-            }
-            SpendKey::Secret(key) => {
-                let spend_pubkey = key.public_key(&secp);
-                sp_receiver = Receiver::new(
-                    0,
-                    scan_pubkey,
-                    spend_pubkey,
-                    change_label.into(),
-                    sp_network,
-                )?;
-            }
-        }
+        let spend_pubkey = match spend_key {
+            SpendKey::Public(key) => key,
+            SpendKey::Secret(key) => key.public_key(&secp),
+        };
+        let sp_receiver = Receiver::new(0, scan_pubkey, spend_pubkey, change_label, sp_network)?;
 
         Ok(Self {
             label,
@@ -326,13 +858,129 @@ impl SpClient {
             spend_key,
             mnemonic,
             sp_receiver,
+            network,
+            rng: SharedRng::default(),
+            spending_policy: SpendingPolicy::default(),
+            settings: WalletSettings::default(),
+            backup_verified_at: None,
+            mnemonic_challenge: None,
         })
     }
 
+    /// `account` is only used for wallets derived from a mnemonic or an extended key
+    pub fn from_wallet_type(
+        label: String,
+        wallet_type: WalletType,
+        account: u32,
+        network: Network,
+    ) -> Result<Self> {
+        match wallet_type {
+            WalletType::New(strength) => Self::from_wallet_type(
+                label,
+                WalletType::NewInLanguage(strength, MnemonicLanguage::English),
+                account,
+                network,
+            ),
+            WalletType::NewInLanguage(strength, language) => {
+                let mnemonic = generate_mnemonic_in(strength, language, &SharedRng::default())?;
+                let (scan_sk, spend_sk) =
+                    derive_keys_from_mnemonic(&mnemonic, "", account, network)?;
+                Self::new(
+                    label,
+                    scan_sk,
+                    SpendKey::Secret(spend_sk),
+                    Some(mnemonic),
+                    network,
+                )
+            }
+            WalletType::Mnemonic(mnemonic) => {
+                let (scan_sk, spend_sk) =
+                    derive_keys_from_mnemonic(&mnemonic, "", account, network)?;
+                Self::new(
+                    label,
+                    scan_sk,
+                    SpendKey::Secret(spend_sk),
+                    Some(mnemonic),
+                    network,
+                )
+            }
+            WalletType::MnemonicWithPassphrase(mnemonic, passphrase) => {
+                let (scan_sk, spend_sk) =
+                    derive_keys_from_mnemonic(&mnemonic, &passphrase, account, network)?;
+                Self::new(
+                    label,
+                    scan_sk,
+                    SpendKey::Secret(spend_sk),
+                    Some(mnemonic),
+                    network,
+                )
+            }
+            WalletType::PrivateKeys(scan_sk, spend_sk) => {
+                Self::new(label, scan_sk, SpendKey::Secret(spend_sk), None, network)
+            }
+            WalletType::ReadOnly(scan_sk, spend_pk) => {
+                Self::new(label, scan_sk, SpendKey::Public(spend_pk), None, network)
+            }
+            WalletType::WatchOnly(encoded) => {
+                let package = WatchOnlyPackage::decode(&encoded)?;
+                if package.network != network {
+                    return Err(Error::msg("Watch-only package is for another network"));
+                }
+                package.to_client(label)
+            }
+            WalletType::Extended(encoded) => {
+                let mut xprv = match Xpriv::from_str(&encoded) {
+                    Ok(xprv) => xprv,
+                    Err(e) => {
+                        if Xpub::from_str(&encoded).is_ok() {
+                            // the scan key path is hardened, we need private derivation
+                            return Err(Error::msg(
+                                "Can't derive the scan key from an xpub, use the scan key and spend public key instead",
+                            ));
+                        }
+                        return Err(e.into());
+                    }
+                };
+                if (xprv.network == Network::Bitcoin) != (network == Network::Bitcoin) {
+                    return Err(Error::msg("Extended key is for another network"));
+                }
+
+                let keys = derive_keys_from_xprv(xprv, account);
+                xprv.private_key.non_secure_erase();
+                let (scan_sk, spend_sk) = keys?;
+
+                Self::new(label, scan_sk, SpendKey::Secret(spend_sk), None, network)
+            }
+            WalletType::Slip39(shares) => {
+                let (scan_sk, spend_sk) = derive_keys_from_slip39(&shares, "", account, network)?;
+                Self::new(label, scan_sk, SpendKey::Secret(spend_sk), None, network)
+            }
+        }
+    }
+
     pub fn get_receiving_address(&self) -> String {
         self.sp_receiver.get_receiving_address()
     }
 
+    pub fn get_network(&self) -> Network {
+        self.network
+    }
+
+    pub fn get_fingerprint(&self) -> WalletFingerprint {
+        let scan_pk = self.scan_sk.public_key(&Secp256k1::signing_only());
+        OutputList::new(scan_pk, self.get_spend_key().into(), 0).wallet_fingerprint
+    }
+
+    /// Opaque identifier to name the wallet files on disk
+    /// It's derived from the scan secret key, so it can't be linked to the wallet address
+    /// and hidden wallets can't be told apart from regular ones
+    pub fn get_storage_id(&self) -> String {
+        let mut engine = sha256::Hash::engine();
+        engine.input(b"sp_client/storage_id");
+        engine.input(&self.scan_sk.secret_bytes());
+        sha256::Hash::from_engine(engine).to_string()
+    }
+
     pub fn get_scan_key(&self) -> SecretKey {
         self.scan_sk
     }
@@ -352,8 +1000,180 @@ impl SpClient {
         }
     }
 
+    /// Copy of the client without the spend secret nor the mnemonic, to persist instead of this one
+    /// Spending then needs `with_spend_key`
+    pub fn to_cold(&self) -> SpClient {
+        let spend_pk: PublicKey = self.spend_key.clone().into();
+        let mut cold = self.clone();
+        if let SpendKey::Secret(ref mut sk) = cold.spend_key {
+            sk.non_secure_erase();
+        }
+        cold.spend_key = SpendKey::Public(spend_pk);
+        if let Some(ref mut mnemonic) = cold.mnemonic {
+            mnemonic.zeroize();
+        }
+        cold.mnemonic = None;
+        cold
+    }
+
+    /// Run `f`, e.g. `fill_sp_outputs` then `sign_psbt`, with the spend secret supplied only for it,
+    /// typed by the user or read from removable storage
+    /// The secret must match our spend public key, it's wiped when this returns
+    pub fn with_spend_key<R>(
+        &self,
+        mut spend_sk: SecretKey,
+        f: impl FnOnce(&SpClient) -> Result<R>,
+    ) -> Result<R> {
+        let expected: PublicKey = self.spend_key.clone().into();
+        if spend_sk.public_key(&Secp256k1::signing_only()) != expected {
+            spend_sk.non_secure_erase();
+            return Err(Error::msg("Spend key doesn't belong to this wallet"));
+        }
+
+        let mut hot = self.clone();
+        hot.spend_key = SpendKey::Secret(spend_sk);
+        spend_sk.non_secure_erase();
+
+        // dropping `hot` wipes its copy
+        f(&hot)
+    }
+
+    /// Replace `thread_rng` with `rng` for everything the client draws at random
+    pub fn set_rng(&mut self, rng: impl SpRng + 'static) {
+        self.rng = SharedRng::new(rng);
+    }
+
+    pub fn get_rng(&self) -> SharedRng {
+        self.rng.clone()
+    }
+
+    /// Used by `create_new_psbt`, and to give to `set_fees_with_policy`
+    pub fn get_change_policy(&self) -> ChangePolicy {
+        self.settings.change_policy
+    }
+
+    /// Saved in the settings
+    pub fn set_change_policy(&mut self, policy: ChangePolicy) {
+        self.settings.change_policy = policy;
+    }
+
+    /// Whether `address` is one of ours, labeled or not, e.g. to warn about a self-send
+    pub fn is_own_address(&self, address: &str) -> Result<bool> {
+        let info = parse_sp_address(address)?;
+        Ok(info.scan_pubkey == self.get_scan_key().public_key(&Secp256k1::signing_only()))
+    }
+
+    pub fn get_settings(&self) -> &WalletSettings {
+        &self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: WalletSettings) {
+        self.settings = settings;
+    }
+
+    /// Used by `order_tx`, saved in the settings
+    pub fn get_tx_ordering(&self) -> TxOrdering {
+        self.settings.tx_ordering
+    }
+
+    pub fn set_tx_ordering(&mut self, ordering: TxOrdering) {
+        self.settings.tx_ordering = ordering;
+    }
+
+    /// Outputs below this that we didn't expect are quarantined when scanned
+    /// Outputs to a label or from our own transactions are never quarantined, zero disables it
+    pub fn get_dust_attack_threshold(&self) -> Amount {
+        self.settings.dust_attack_threshold
+    }
+
+    pub fn set_dust_attack_threshold(&mut self, threshold: Amount) {
+        self.settings.dust_attack_threshold = threshold;
+    }
+
+    /// Checked by `create_new_psbt` and `sign_psbt`, see `SpWallet::sign_psbt` for the rules
+    /// that depend on past spends
+    pub fn get_spending_policy(&self) -> &SpendingPolicy {
+        &self.spending_policy
+    }
+
+    pub fn set_spending_policy(&mut self, policy: SpendingPolicy) {
+        self.spending_policy = policy;
+    }
+
+    /// When the user last proved they wrote down the mnemonic, see `verify_mnemonic`
+    pub fn get_backup_verified_at(&self) -> Option<u64> {
+        self.backup_verified_at
+    }
+
+    /// Only meant to restore the state from storage, use `verify_mnemonic` otherwise
+    pub fn set_backup_verified_at(&mut self, timestamp: Option<u64>) {
+        self.backup_verified_at = timestamp;
+    }
+
+    /// Draw `count` distinct positions (0 based, sorted) of the mnemonic words the user must give back
+    /// Replaces any previous challenge
+    pub fn create_mnemonic_challenge(&mut self, count: usize) -> Result<Vec<usize>> {
+        let nb_words = match self.mnemonic {
+            Some(ref mnemonic) => mnemonic.split_whitespace().count(),
+            None => return Err(Error::msg("No mnemonic to verify")),
+        };
+        if count == 0 || count > nb_words {
+            return Err(Error::msg(format!(
+                "Can't ask for {} words out of {}",
+                count, nb_words
+            )));
+        }
+
+        let mut positions: Vec<usize> = (0..nb_words).collect();
+        positions.shuffle(&mut self.get_rng());
+        positions.truncate(count);
+        positions.sort_unstable();
+
+        self.mnemonic_challenge = Some(positions.clone());
+        Ok(positions)
+    }
+
+    /// Check the words given for the positions of the last `create_mnemonic_challenge`
+    /// On success the backup is marked verified at `now`, and the challenge is consumed either way
+    /// so that a failed attempt needs a new one
+    pub fn verify_mnemonic(
+        &mut self,
+        words_at_positions: &[(usize, String)],
+        now: u64,
+    ) -> Result<bool> {
+        let challenge = self
+            .mnemonic_challenge
+            .take()
+            .ok_or_else(|| Error::msg("No pending mnemonic challenge"))?;
+        let mnemonic = self
+            .mnemonic
+            .as_ref()
+            .ok_or_else(|| Error::msg("No mnemonic to verify"))?;
+
+        let mut given: Vec<(usize, String)> = words_at_positions
+            .iter()
+            .map(|(i, word)| (*i, word.trim().to_lowercase()))
+            .collect();
+        given.sort_unstable_by_key(|(i, _)| *i);
+        if given.iter().map(|(i, _)| *i).ne(challenge.iter().copied()) {
+            return Err(Error::msg("Positions don't match the challenge"));
+        }
+
+        let words: Vec<&str> = mnemonic.split_whitespace().collect();
+        let ok = given.iter().all(|(i, word)| words[*i] == word);
+        if ok {
+            self.backup_verified_at = Some(now);
+        }
+        Ok(ok)
+    }
+
+    /// Fresh `aux_rand` for `sign_psbt` and the other signing methods
+    pub fn get_aux_rand(&self) -> [u8; 32] {
+        self.rng.gen_bytes()
+    }
+
     pub fn get_partial_secret_from_psbt(&self, psbt: &Psbt) -> Result<SecretKey> {
-        let b_spend = match self.spend_key {
+        let mut b_spend = match self.spend_key {
             SpendKey::Secret(key) => key,
             SpendKey::Public(_) => return Err(Error::msg("Watch-only wallet, can't spend")),
         };
@@ -366,7 +1186,7 @@ impl SpClient {
                 subtype: PSBT_SP_SUBTYPE,
                 key: PSBT_SP_TWEAK_KEY.as_bytes().to_vec(),
             }) {
-                let sk = SecretKey::from_slice(&tweak)?;
+                let sk = SecretKey::from_slice(tweak)?;
                 let input_key = b_spend.add_tweak(&sk.into())?;
                 // we add `true` for every key since we only handle silent payments outputs as input
                 input_privkeys.push((input_key, true));
@@ -389,9 +1209,42 @@ impl SpClient {
             .collect();
 
         let partial_secret =
-            sp_utils::sending::calculate_partial_secret(&input_privkeys, &outpoints)?;
+            sp_utils::sending::calculate_partial_secret(&input_privkeys, &outpoints);
+
+        // don't leave the tweaked keys lying around, whatever the result
+        b_spend.non_secure_erase();
+        for (key, _) in input_privkeys.iter_mut() {
+            key.non_secure_erase();
+        }
+
+        Ok(partial_secret?)
+    }
 
-        Ok(partial_secret)
+    /// Scripts of the outputs of `psbt` that really pay our change address, derived from our inputs
+    /// Empty when we can't derive them, e.g. watch-only or inputs that aren't all ours
+    pub(crate) fn get_change_scripts(&self, psbt: &Psbt) -> Vec<ScriptBuf> {
+        let Ok(partial_secret) = self.get_partial_secret_from_psbt(psbt) else {
+            return vec![];
+        };
+        let tweak_data = partial_secret.public_key(&Secp256k1::signing_only());
+        let shared_secret =
+            sp_utils::receiving::calculate_ecdh_shared_secret(&tweak_data, &self.scan_sk);
+        let pubkeys = psbt
+            .unsigned_tx
+            .output
+            .iter()
+            .filter(|o| o.script_pubkey.is_p2tr())
+            .filter_map(|o| XOnlyPublicKey::from_slice(&o.script_pubkey.as_bytes()[2..]).ok())
+            .collect();
+        let Ok(ours) = self.sp_receiver.scan_transaction(&shared_secret, pubkeys) else {
+            return vec![];
+        };
+        let change_label = Label::new(self.scan_sk, 0);
+        ours.into_iter()
+            .filter(|(label, _)| label.as_ref() == Some(&change_label))
+            .flat_map(|(_, keys)| keys.into_keys())
+            .map(|key| ScriptBuf::new_p2tr_tweaked(key.dangerous_assume_tweaked()))
+            .collect()
     }
 
     pub fn replace_op_return_with(psbt: &mut Psbt, new_data: &[u8]) -> Result<()> {
@@ -409,6 +1262,11 @@ impl SpClient {
 
     pub fn fill_sp_outputs(&self, psbt: &mut Psbt, partial_secret: SecretKey) -> Result<()> {
         // get all the silent addresses
+        if psbt.outputs.len() != psbt.unsigned_tx.output.len() {
+            return Err(Error::msg(
+                "Psbt outputs don't match the transaction outputs",
+            ));
+        }
         let mut sp_addresses: Vec<String> = Vec::with_capacity(psbt.outputs.len());
         for output in psbt.outputs.iter() {
             // get the sp address from psbt
@@ -458,102 +1316,303 @@ impl SpClient {
                 continue;
             }
         }
-        for (_, xonlypubkeys) in sp_address2xonlypubkeys {
-            debug_assert!(xonlypubkeys.is_empty());
+        if sp_address2xonlypubkeys
+            .values()
+            .any(|keys| !keys.is_empty())
+        {
+            return Err(Error::msg("Some output keys weren't assigned to an output"));
         }
         Ok(())
     }
 
-    pub fn set_fees(psbt: &mut Psbt, fee_rate: Amount, payer: String) -> Result<()> {
-        // just take the first output that belong to payer
-        // it would be interesting to randomize the outputs we pick,
-        // or scatter the fee amount on all the outputs of the payer
-        // or maybe divide the fee amongst all the participants of the transaction
-        let payer_vout = match SilentPaymentAddress::try_from(payer.clone()) {
-            Ok(sp_address) => psbt
-                .outputs
-                .iter()
-                .enumerate()
-                .find(|(_, o)| {
-                    if let Some(value) = o.proprietary.get(&raw::ProprietaryKey {
-                        prefix: PSBT_SP_PREFIX.as_bytes().to_vec(),
-                        subtype: PSBT_SP_SUBTYPE,
-                        key: PSBT_SP_ADDRESS_KEY.as_bytes().to_vec(),
-                    }) {
-                        let candidate =
-                            SilentPaymentAddress::try_from(deserialize::<String>(value).unwrap())
-                                .unwrap();
-                        sp_address == candidate
-                    } else {
-                        false
-                    }
-                })
-                .map(|(i, _)| i),
-            Err(_) => {
-                let address = Address::from_str(&payer)?;
-                let spk = address.assume_checked().script_pubkey();
-                psbt.unsigned_tx
-                    .output
-                    .iter()
-                    .enumerate()
-                    .find(|(_, o)| o.script_pubkey == spk)
-                    .map(|(i, _)| i)
+    /// `fill_sp_outputs` then `order_tx`, what a psbt needs once its fees are set to be ready for signing
+    pub fn fill_and_order(&self, psbt: &mut Psbt, partial_secret: SecretKey) -> Result<()> {
+        self.fill_sp_outputs(psbt, partial_secret)?;
+        self.order_tx(psbt);
+        Ok(())
+    }
+
+    /// Reorder the inputs and outputs of `psbt` according to `get_tx_ordering`, along with their psbt data
+    /// Must be called after `fill_sp_outputs` and `set_fees`, and before signing
+    pub fn order_tx(&self, psbt: &mut Psbt) {
+        let mut inputs: Vec<(TxIn, Input)> = psbt
+            .unsigned_tx
+            .input
+            .drain(..)
+            .zip(psbt.inputs.drain(..))
+            .collect();
+        let mut outputs: Vec<(TxOut, Output)> = psbt
+            .unsigned_tx
+            .output
+            .drain(..)
+            .zip(psbt.outputs.drain(..))
+            .collect();
+
+        match self.settings.tx_ordering {
+            TxOrdering::Shuffle => {
+                let mut rng = self.get_rng();
+                inputs.shuffle(&mut rng);
+                outputs.shuffle(&mut rng);
+            }
+            TxOrdering::Bip69 => {
+                // txids are compared as displayed, i.e. with their bytes reversed
+                inputs.sort_by_key(|(txin, _)| {
+                    let mut txid = txin.previous_output.txid.to_byte_array();
+                    txid.reverse();
+                    (txid, txin.previous_output.vout)
+                });
+                outputs.sort_by(|(a, _), (b, _)| {
+                    (a.value, a.script_pubkey.as_bytes())
+                        .cmp(&(b.value, b.script_pubkey.as_bytes()))
+                });
+            }
+        }
+
+        (psbt.unsigned_tx.input, psbt.inputs) = inputs.into_iter().unzip();
+        (psbt.unsigned_tx.output, psbt.outputs) = outputs.into_iter().unzip();
+    }
+
+    /// Same as `set_fees_with_policy` with our change policy, and `payer` can be one of `CHANGE_PAYERS`
+    /// so that the caller doesn't need our change address
+    pub fn set_fees_from(&self, psbt: &mut Psbt, fee_rate: Amount, payer: &str) -> Result<()> {
+        let payer = if CHANGE_PAYERS.contains(&payer.to_lowercase().as_str()) {
+            let change_address = self.sp_receiver.get_change_address();
+            let has_change = psbt.outputs.iter().any(|o| {
+                o.proprietary
+                    .get(&raw::ProprietaryKey {
+                        prefix: PSBT_SP_PREFIX.as_bytes().to_vec(),
+                        subtype: PSBT_SP_SUBTYPE,
+                        key: PSBT_SP_ADDRESS_KEY.as_bytes().to_vec(),
+                    })
+                    .and_then(|value| deserialize::<String>(value).ok())
+                    .is_some_and(|address| address == change_address)
+            });
+            if !has_change {
+                return Err(Error::msg(
+                    "No change output to pay the fee from, pick a recipient to pay it",
+                ));
             }
+            change_address
+        } else {
+            payer.to_owned()
         };
+        Self::set_fees_with_policy(psbt, fee_rate, payer, &self.settings.change_policy)
+    }
 
-        if payer_vout.is_none() {
+    pub fn set_fees(psbt: &mut Psbt, fee_rate: Amount, payer: String) -> Result<()> {
+        Self::set_fees_with_policy(psbt, fee_rate, payer, &ChangePolicy::default())
+    }
+
+    /// `policy` must be the one the psbt was created with
+    pub fn set_fees_with_policy(
+        psbt: &mut Psbt,
+        fee_rate: Amount,
+        payer: String,
+        policy: &ChangePolicy,
+    ) -> Result<()> {
+        // it would be interesting to divide the fee amongst all the participants of the transaction
+        let mut payer_vouts: Vec<usize> = match try_parse_sp_address(&payer)? {
+            Some(sp_address) => {
+                let mut vouts = vec![];
+                for (i, o) in psbt.outputs.iter().enumerate() {
+                    if let Some(value) = o.proprietary.get(&raw::ProprietaryKey {
+                        prefix: PSBT_SP_PREFIX.as_bytes().to_vec(),
+                        subtype: PSBT_SP_SUBTYPE,
+                        key: PSBT_SP_ADDRESS_KEY.as_bytes().to_vec(),
+                    }) {
+                        let candidate = SilentPaymentAddress::try_from(deserialize::<String>(
+                            value,
+                        )?)
+                        .map_err(|e| {
+                            Error::msg(format!(
+                                "Invalid silent payment address at output {}: {}",
+                                i, e
+                            ))
+                        })?;
+                        if sp_address == candidate {
+                            vouts.push(i);
+                        }
+                    }
+                }
+                vouts
+            }
+            None => {
+                let address = Address::from_str(&payer)?;
+                let spk = address.assume_checked().script_pubkey();
+                psbt.unsigned_tx
+                    .output
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, o)| o.script_pubkey == spk)
+                    .map(|(i, _)| i)
+                    .collect()
+            }
+        };
+
+        if payer_vouts.is_empty() {
             return Err(Error::msg("Payer is not part of this transaction"));
         }
 
-        // check against the total amt in inputs
-        let total_input_amt: Amount = psbt
-            .iter_funding_utxos()
-            .try_fold(Amount::from_sat(0), |sum, utxo_result| {
-                utxo_result.map(|utxo| sum + utxo.value)
-            })?;
-
-        let total_output_amt: Amount = psbt
+        // only the payer may lose anything, checked again once the fee is set
+        let sp_addresses = get_psbt_sp_data(psbt)?.output_addresses;
+        let others: Vec<(String, Amount)> = psbt
             .unsigned_tx
             .output
             .iter()
-            .fold(Amount::from_sat(0), |sum, add| sum + add.value);
+            .zip(sp_addresses)
+            .enumerate()
+            .filter(|(i, _)| !payer_vouts.contains(i))
+            .map(|(_, (o, address))| {
+                (
+                    address.unwrap_or_else(|| o.script_pubkey.to_hex_string()),
+                    o.value,
+                )
+            })
+            .collect();
+
+        // check against the total amt in inputs
+        let (total_input_amt, _) = get_psbt_totals(psbt)?;
 
-        let dust = total_input_amt
-            .checked_sub(total_output_amt)
-            .ok_or(Error::msg("Not enough funds"))?;
+        let current_fee = |psbt: &Psbt| -> Result<Amount> {
+            let (_, total_output_amt) = get_psbt_totals(psbt)?;
+            total_input_amt
+                .checked_sub(total_output_amt)
+                .ok_or(Error::msg("Not enough funds"))
+        };
 
-        if dust > DUST_THRESHOLD {
+        // without a change output the only thing left for fees is sub-dust change
+        let change_threshold = policy.dust.user_override.unwrap_or(policy.dust.p2tr);
+        if current_fee(psbt)? > change_threshold {
             return Err(Error::msg("Missing a change output"));
         }
 
-        // now compute the size of the tx
-        let fake = Self::sign_psbt_fake(psbt);
-        let vsize = fake.weight().to_vbytes_ceil();
+        // Changing the payer output can change the size of the tx, so we loop until the fee covers the size
+        // In practice only removing the output changes the size, and that can only lower the fee we need
+        for _ in 0..MAX_FEE_ITERATIONS {
+            let vsize = predict_psbt_vsize(psbt)?;
+
+            // absolut amount of fees
+            let fee_amt = fee_rate
+                .checked_mul(vsize)
+                .ok_or_else(|| Error::msg("Fee rate multiplication overflowed"))?;
+
+            // there may already be some dust left out as fee
+            let paid = current_fee(psbt)?;
+            if paid >= fee_amt {
+                let after = psbt
+                    .unsigned_tx
+                    .output
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| !payer_vouts.contains(i))
+                    .map(|(_, o)| o.value);
+                for ((recipient, requested), actual) in others.iter().zip(after) {
+                    if actual < *requested {
+                        return Err(RecipientShortfall {
+                            recipient: recipient.clone(),
+                            requested: *requested,
+                            actual,
+                        }
+                        .into());
+                    }
+                }
+                return Ok(());
+            }
+            let missing = fee_amt - paid;
 
-        // absolut amount of fees
-        let fee_amt = fee_rate
-            .checked_mul(vsize)
-            .ok_or_else(|| Error::msg("Fee rate multiplication overflowed"))?;
+            let targets = match policy.fee_split {
+                FeeSplit::FirstOutput => &payer_vouts[..payer_vouts.len().min(1)],
+                FeeSplit::Proportional => &payer_vouts[..],
+            };
+            if targets.is_empty() {
+                return Err(Error::msg("No payer output left to pay the fee"));
+            }
+            let payer_value: Amount = targets
+                .iter()
+                .map(|vout| psbt.unsigned_tx.output[*vout].value)
+                .sum();
+            let cant_cover = || {
+                Error::msg(format!(
+                    "Payer outputs of {} can't cover a fee of {}",
+                    payer_value, fee_amt
+                ))
+            };
+            if payer_value < missing {
+                return Err(cant_cover());
+            }
 
-        // now deduce the fees from one of the payer outputs
-        // TODO deduce fee from the change address
-        if fee_amt > dust {
-            let output = &mut psbt.unsigned_tx.output[payer_vout.unwrap()];
-            let old_value = output.value;
-            output.value = old_value
-                .checked_sub(fee_amt - dust)
-                .ok_or(Error::msg("Not enough funds"))?; // account for eventual dust
+            // rounding leftovers are taken from the first output
+            let mut shares: Vec<Amount> = targets
+                .iter()
+                .map(|vout| {
+                    let value = psbt.unsigned_tx.output[*vout].value.to_sat() as u128;
+                    Amount::from_sat(
+                        (missing.to_sat() as u128 * value / payer_value.to_sat() as u128) as u64,
+                    )
+                })
+                .collect();
+            let shared: Amount = shares.iter().copied().sum();
+            shares[0] += missing - shared;
+
+            let below_dust = targets.iter().zip(shares.iter()).find(|(vout, share)| {
+                let output = &psbt.unsigned_tx.output[**vout];
+                output
+                    .value
+                    .checked_sub(**share)
+                    .is_none_or(|rest| rest < policy.dust.get_threshold(&output.script_pubkey))
+            });
+            if let Some((vout, _)) = below_dust {
+                // what would be left of our change isn't worth an output, it all goes to fees,
+                // a recipient never loses its whole payment
+                let vout = *vout;
+                if psbt.unsigned_tx.output.len() == 1 || !is_psbt_change_output(psbt, vout) {
+                    return Err(cant_cover());
+                }
+                psbt.unsigned_tx.output.remove(vout);
+                psbt.outputs.remove(vout);
+                payer_vouts.retain(|v| *v != vout);
+                for v in payer_vouts.iter_mut().filter(|v| **v > vout) {
+                    *v -= 1;
+                }
+            } else {
+                for (vout, share) in targets.iter().zip(shares) {
+                    psbt.unsigned_tx.output[*vout].value -= share;
+                }
+            }
         }
 
-        Ok(())
+        Err(Error::msg("Fee calculation didn't converge"))
     }
 
     pub fn create_new_psbt(
         &self,
         utxos: HashMap<OutPoint, OwnedOutput>,
-        mut recipients: Vec<Recipient>,
+        recipients: Vec<Recipient>,
         payload: Option<&[u8]>,
     ) -> Result<Psbt> {
+        self.create_new_psbt_with_policy(utxos, recipients, payload, &self.settings.change_policy)
+            .map(|(psbt, _)| psbt)
+    }
+
+    /// Same as `create_new_psbt`, but the caller decides what happens to change below the dust threshold
+    /// and learns where the change went
+    pub fn create_new_psbt_with_policy(
+        &self,
+        utxos: HashMap<OutPoint, OwnedOutput>,
+        recipients: Vec<Recipient>,
+        payload: Option<&[u8]>,
+        policy: &ChangePolicy,
+    ) -> Result<(Psbt, ChangeOutcome)> {
+        let (mut normalized, positions) = normalize_recipients(&recipients)?;
+        if self.settings.musig {
+            for recipient in normalized.iter() {
+                if try_parse_sp_address(&recipient.address)?.is_some() {
+                    return Err(Error::msg(
+                        "MuSig wallets can't pay silent payment addresses, the sum of the input keys is needed",
+                    ));
+                }
+            }
+        }
         let mut tx_in: Vec<bitcoin::TxIn> = vec![];
         let mut inputs_data: Vec<(ScriptBuf, Amount, Scalar)> = vec![];
         let mut total_input_amount = Amount::from_sat(0);
@@ -563,7 +1622,11 @@ impl SpClient {
             tx_in.push(TxIn {
                 previous_output: outpoint,
                 script_sig: ScriptBuf::new(),
-                sequence: bitcoin::Sequence::MAX,
+                sequence: if self.settings.rbf {
+                    bitcoin::Sequence::ENABLE_RBF_NO_LOCKTIME
+                } else {
+                    bitcoin::Sequence::MAX
+                },
                 witness: bitcoin::Witness::new(),
             });
 
@@ -584,13 +1647,11 @@ impl SpClient {
             bitcoin::XOnlyPublicKey::from_str(NUMS)?.dangerous_assume_tweaked(),
         );
 
-        let _outputs: Result<Vec<TxOut>> = recipients
+        let _outputs: Result<Vec<TxOut>> = normalized
             .iter()
             .map(|o| {
-                let script_pubkey: ScriptBuf;
-
-                match SilentPaymentAddress::try_from(o.address.as_str()) {
-                    Ok(sp_address) => {
+                let script_pubkey = match try_parse_sp_address(&o.address)? {
+                    Some(sp_address) => {
                         if sp_address.get_network() != self.sp_receiver.network {
                             return Err(Error::msg(format!(
                                 "Wrong network for address {}",
@@ -598,16 +1659,17 @@ impl SpClient {
                             )));
                         }
 
-                        script_pubkey = placeholder_spk.clone();
+                        placeholder_spk.clone()
                     }
-                    Err(_) => {
+                    None => {
+                        // segwit versions we don't know yet are paid as is, their script is only
+                        // the version and the program (BIP 350)
                         let unchecked_address = Address::from_str(&o.address)?; // TODO: handle better garbage string
 
                         let address_sp_network = match *unchecked_address.network() {
                             Network::Bitcoin => SpNetwork::Mainnet,
                             Network::Testnet | Network::Signet => SpNetwork::Testnet,
                             Network::Regtest => SpNetwork::Regtest,
-                            _ => unreachable!(),
                         };
 
                         if self.sp_receiver.network != address_sp_network {
@@ -617,13 +1679,20 @@ impl SpClient {
                             )));
                         }
 
-                        script_pubkey = ScriptBuf::from_bytes(
+                        ScriptBuf::from_bytes(
                             unchecked_address
                                 .assume_checked()
                                 .script_pubkey()
                                 .to_bytes(),
-                        );
+                        )
                     }
+                };
+
+                if o.amount < policy.dust.get_threshold(&script_pubkey) {
+                    return Err(Error::msg(format!(
+                        "Output of {} to {} is below the dust threshold",
+                        o.amount, o.address
+                    )));
                 }
 
                 total_output_amount = total_output_amount
@@ -639,25 +1708,62 @@ impl SpClient {
 
         let mut outputs = _outputs?;
 
+        // consolidations pay our change address
+        let change_address = self.sp_receiver.get_change_address();
+        let paid: Vec<Recipient> = normalized
+            .iter()
+            .filter(|r| r.address != change_address)
+            .cloned()
+            .collect();
+        self.spending_policy.check_recipients(&paid)?;
+
         let change_amt = total_input_amount
             .checked_sub(total_output_amount)
             .ok_or(Error::msg("Not enough funds in inputs"))?;
 
-        if change_amt > DUST_THRESHOLD {
+        let change_outcome = if change_amt == Amount::ZERO {
+            ChangeOutcome::NoChange
+        } else if change_amt > policy.dust.get_threshold(&placeholder_spk) {
+            if self.settings.musig {
+                return Err(Error::msg(
+                    "MuSig wallets can't have silent payment change, spend the whole amount of the inputs",
+                ));
+            }
             // Add change output
-            let change_address = self.sp_receiver.get_change_address();
-
             outputs.push(TxOut {
                 value: change_amt,
                 script_pubkey: placeholder_spk,
             });
 
-            recipients.push(Recipient {
+            normalized.push(Recipient {
                 address: change_address,
                 amount: change_amt,
                 nb_outputs: 1,
             });
-        }
+
+            ChangeOutcome::Output(change_amt)
+        } else {
+            match policy.sub_dust_change {
+                SubDustChange::AddToFees => ChangeOutcome::AbsorbedInFees(change_amt),
+                SubDustChange::AddToRecipient(index) => {
+                    let (position, nb_outputs) = *positions
+                        .get(index)
+                        .ok_or_else(|| Error::msg(format!("No recipient at index {}", index)))?;
+                    let recipient = &mut normalized[position];
+                    if nb_outputs != 1 {
+                        return Err(Error::msg(
+                            "Can't add the change to a recipient with more than one output",
+                        ));
+                    }
+                    recipient.amount += change_amt;
+                    outputs[position].value += change_amt;
+                    ChangeOutcome::AddedToRecipient {
+                        index,
+                        amount: change_amt,
+                    }
+                }
+            }
+        };
 
         if let Some(data) = payload {
             if data.len() > DATA_CARRIER_SIZE {
@@ -684,29 +1790,12 @@ impl SpClient {
         let mut psbt = Psbt::from_unsigned_tx(tx)?;
 
         // Add the witness utxo to the input in psbt
-        for (i, input_data) in inputs_data.iter().enumerate() {
-            let (script_pubkey, value, tweak) = input_data;
-            let witness_txout = TxOut {
-                value: *value,
-                script_pubkey: script_pubkey.clone(),
-            };
-            let mut psbt_input = Input {
-                witness_utxo: Some(witness_txout),
-                ..Default::default()
-            };
-            psbt_input.proprietary.insert(
-                raw::ProprietaryKey {
-                    prefix: PSBT_SP_PREFIX.as_bytes().to_vec(),
-                    subtype: PSBT_SP_SUBTYPE,
-                    key: PSBT_SP_TWEAK_KEY.as_bytes().to_vec(),
-                },
-                tweak.to_be_bytes().to_vec(),
-            );
-            psbt.inputs[i] = psbt_input;
+        for (i, (script_pubkey, value, tweak)) in inputs_data.iter().enumerate() {
+            psbt.inputs[i] = get_psbt_input(script_pubkey, *value, tweak)?;
         }
 
-        for (i, recipient) in recipients.iter().enumerate() {
-            if let Ok(sp_address) = SilentPaymentAddress::try_from(recipient.address.as_str()) {
+        for (i, recipient) in normalized.iter().enumerate() {
+            if let Some(sp_address) = try_parse_sp_address(&recipient.address)? {
                 // Add silentpayment address to the output
                 let mut psbt_output = Output {
                     ..Default::default()
@@ -719,6 +1808,16 @@ impl SpClient {
                     },
                     serialize(&sp_address.to_string()),
                 );
+                if sp_address.to_string() == self.sp_receiver.get_change_address() {
+                    psbt_output.proprietary.insert(
+                        raw::ProprietaryKey {
+                            prefix: PSBT_SP_PREFIX.as_bytes().to_vec(),
+                            subtype: PSBT_SP_SUBTYPE,
+                            key: PSBT_SP_CHANGE_KEY.as_bytes().to_vec(),
+                        },
+                        vec![],
+                    );
+                }
                 psbt.outputs[i] = psbt_output;
             } else {
                 // Regular address, we don't need to add more data
@@ -726,7 +1825,7 @@ impl SpClient {
             }
         }
 
-        Ok(psbt)
+        Ok((psbt, change_outcome))
     }
 
     fn taproot_sighash<
@@ -758,84 +1857,221 @@ impl SpClient {
         Ok((msg, hash_ty.into()))
     }
 
-    // Sign a transaction with garbage, used for easier fee estimation
-    fn sign_psbt_fake(psbt: &Psbt) -> Transaction {
-        let mut fake_psbt = psbt.clone();
-
-        let fake_sig = [1u8; 64];
+    /// If `intent` is provided, the psbt is checked against it before anything is signed
+    /// Refused if the spending policy depends on past spends, see `SpWallet::sign_psbt`
+    pub fn sign_psbt(
+        &self,
+        psbt: Psbt,
+        aux_rand: &[u8; 32],
+        intent: Option<&SpendIntent>,
+    ) -> Result<Psbt> {
+        self.sign_psbt_checked(psbt, aux_rand, intent, None)
+            .map(|(signed, _)| signed)
+    }
 
-        for i in fake_psbt.inputs.iter_mut() {
-            i.tap_key_sig = Some(Signature::from_slice(&fake_sig).unwrap());
+    /// Where every signing with our key goes through, the whole spending policy is checked here
+    /// `history` is the spend history of the wallet with the current unix timestamp,
+    /// returns the signed psbt and the amount it spends
+    pub(crate) fn sign_psbt_checked(
+        &self,
+        psbt: Psbt,
+        aux_rand: &[u8; 32],
+        intent: Option<&SpendIntent>,
+        history: Option<(&SpendHistory, u64)>,
+    ) -> Result<(Psbt, Amount)> {
+        if let Some(intent) = intent {
+            intent.check(self, &psbt)?;
+        }
+        let amount = self.spending_policy.check_psbt(self, &psbt)?;
+        match history {
+            Some((history, now)) => {
+                history.check(&self.spending_policy, psbt.unsigned_tx.txid(), amount, now)?
+            }
+            None if self.spending_policy.depends_on_history() => {
+                return Err(PolicyViolation::HistoryRequired.into())
+            }
+            None => (),
         }
 
-        Self::finalize_psbt(&mut fake_psbt).unwrap();
-
-        fake_psbt.extract_tx().expect("Invalid fake tx")
-    }
-
-    pub fn sign_psbt(&self, psbt: Psbt, aux_rand: &[u8; 32]) -> Result<Psbt> {
         let b_spend = match self.spend_key {
             SpendKey::Secret(key) => key,
             SpendKey::Public(_) => return Err(Error::msg("Watch-only wallet, can't spend")),
         };
 
+        let signer = LocalSigner::new(b_spend);
+
+        Ok((Self::sign_psbt_parallel(&signer, psbt, aux_rand)?, amount))
+    }
+
+    /// Sign with a `Signer` that holds the spend key, we only provide it the tweak for each input
+    /// Signatures are checked against the output key of the input before being added to the psbt
+    pub fn sign_psbt_with_signer(
+        signer: &dyn Signer,
+        psbt: Psbt,
+        aux_rand: &[u8; 32],
+    ) -> Result<Psbt> {
+        let to_sign = Self::prepare_inputs(&psbt, signer.get_spend_pubkey()?)?;
+
+        let sigs = to_sign
+            .iter()
+            .map(|input| input.sign(signer, aux_rand))
+            .collect::<Result<Vec<Signature>>>()?;
+
+        Ok(Self::add_signatures(psbt, &to_sign, sigs))
+    }
+
+    /// Same as `sign_psbt_with_signer`, but inputs are signed in parallel
+    /// Only worth it for signers that can sign concurrently, like one holding the key in memory
+    pub fn sign_psbt_parallel(
+        signer: &(dyn Signer + Sync),
+        psbt: Psbt,
+        aux_rand: &[u8; 32],
+    ) -> Result<Psbt> {
+        use rayon::prelude::*;
+
+        let to_sign = Self::prepare_inputs(&psbt, signer.get_spend_pubkey()?)?;
+
+        let sigs = workers::install(|| {
+            to_sign
+                .par_iter()
+                .map(|input| input.sign(signer, aux_rand))
+                .collect::<Result<Vec<Signature>>>()
+        })??;
+
+        Ok(Self::add_signatures(psbt, &to_sign, sigs))
+    }
+
+    /// Message and tweak of each input to sign with `spend_pubkey`, what a remote signer gets asked
+    pub fn get_signing_requests(
+        psbt: &Psbt,
+        spend_pubkey: PublicKey,
+    ) -> Result<Vec<(Message, SecretKey)>> {
+        Ok(Self::prepare_inputs(psbt, spend_pubkey)?
+            .into_iter()
+            .map(|input| (input.msg, input.tweak))
+            .collect())
+    }
+
+    /// Everything we need to sign each input, sighashes are computed with a single cache
+    /// Inputs with `tap_scripts` are spent through the leaf that has our key, e.g. imported outputs with
+    /// a timelocked branch. The key in the leaf is our spend key with the tweak of the input, like for key path spends
+    fn prepare_inputs(psbt: &Psbt, spend_pubkey: PublicKey) -> Result<Vec<InputToSign>> {
+        let secp = Secp256k1::verification_only();
         let mut cache = SighashCache::new(&psbt.unsigned_tx);
 
         let mut prevouts: Vec<&TxOut> = vec![];
 
-        for input in &psbt.inputs {
-            if let Some(witness_utxo) = &input.witness_utxo {
-                prevouts.push(witness_utxo);
+        for (i, input) in psbt.inputs.iter().enumerate() {
+            match &input.witness_utxo {
+                Some(witness_utxo) if witness_utxo.script_pubkey.is_p2tr() => {
+                    prevouts.push(witness_utxo)
+                }
+                Some(_) => return Err(Error::msg(format!("Input {} is not taproot", i))),
+                None => return Err(Error::msg(format!("Missing witness utxo at input {}", i))),
             }
         }
 
-        let mut signed_psbt = psbt.clone();
-
-        let secp = Secp256k1::signing_only();
-
+        let mut res = vec![];
         for (i, input) in psbt.inputs.iter().enumerate() {
-            let tap_leaf_hash: Option<TapLeafHash> = None;
+            let tweak = input
+                .proprietary
+                .get(&raw::ProprietaryKey {
+                    prefix: PSBT_SP_PREFIX.as_bytes().to_vec(),
+                    subtype: PSBT_SP_SUBTYPE,
+                    key: PSBT_SP_TWEAK_KEY.as_bytes().to_vec(),
+                })
+                .ok_or_else(|| Error::msg(format!("Missing tweak at input {}", i)))?;
 
-            let (msg, sighash_ty) =
-                Self::taproot_sighash(input, &prevouts, i, &mut cache, tap_leaf_hash)?;
+            let tweak = SecretKey::from_slice(tweak.as_slice())
+                .map_err(|_| Error::msg(format!("Invalid tweak at input {}", i)))?;
 
-            // Construct the signing key
-            let tweak = input.proprietary.get(&raw::ProprietaryKey {
-                prefix: PSBT_SP_PREFIX.as_bytes().to_vec(),
-                subtype: PSBT_SP_SUBTYPE,
-                key: PSBT_SP_TWEAK_KEY.as_bytes().to_vec(),
-            });
+            let output_key =
+                XOnlyPublicKey::from_slice(&prevouts[i].script_pubkey.as_bytes()[2..])?;
 
-            if tweak.is_none() {
-                panic!("Missing tweak")
+            let (leaf_hash, signing_key) = if input.tap_scripts.is_empty() {
+                (None, output_key)
+            } else {
+                let (signing_key, _) = spend_pubkey
+                    .add_exp_tweak(&secp, &tweak.into())?
+                    .x_only_public_key();
+                let has_our_key = |script: &ScriptBuf| {
+                    script.instructions().any(|instruction| match instruction {
+                        Ok(Instruction::PushBytes(bytes)) => {
+                            bytes.as_bytes() == signing_key.serialize()
+                        }
+                        _ => false,
+                    })
+                };
+                let leaf = input
+                    .tap_scripts
+                    .values()
+                    .find(|(script, _)| has_our_key(script));
+                match leaf {
+                    Some((script, version)) => (
+                        Some(TapLeafHash::from_script(script, *version)),
+                        signing_key,
+                    ),
+                    None if signing_key == output_key => (None, output_key),
+                    None => return Err(Error::msg(format!("No leaf with our key at input {}", i))),
+                }
             };
 
-            let tweak = SecretKey::from_slice(tweak.unwrap().as_slice()).unwrap();
-
-            let sk = b_spend.add_tweak(&tweak.into())?;
-
-            let keypair = Keypair::from_secret_key(&secp, &sk);
-
-            let sig = secp.sign_schnorr_with_aux_rand(&msg, &keypair, aux_rand);
+            let (msg, sighash_ty) =
+                Self::taproot_sighash(input, &prevouts, i, &mut cache, leaf_hash)?;
 
-            signed_psbt.inputs[i].tap_key_sig = Some(Signature {
-                sig,
+            res.push(InputToSign {
+                index: i,
+                msg,
                 hash_ty: sighash_ty.taproot_hash_ty()?,
+                tweak,
+                output_key: signing_key,
+                leaf_hash,
             });
         }
 
-        Ok(signed_psbt)
+        Ok(res)
+    }
+
+    fn add_signatures(mut psbt: Psbt, signed: &[InputToSign], sigs: Vec<Signature>) -> Psbt {
+        for ((input, to_sign), sig) in psbt.inputs.iter_mut().zip(signed).zip(sigs) {
+            match to_sign.leaf_hash {
+                Some(leaf_hash) => {
+                    input
+                        .tap_script_sigs
+                        .insert((to_sign.output_key, leaf_hash), sig);
+                }
+                None => input.tap_key_sig = Some(sig),
+            }
+        }
+        psbt
     }
 
+    /// Nothing is changed unless every input can be finalized
     pub fn finalize_psbt(psbt: &mut Psbt) -> Result<()> {
-        psbt.inputs.iter_mut().for_each(|i| {
+        let mut witnesses = vec![];
+        for (index, i) in psbt.inputs.iter().enumerate() {
             let mut script_witness = Witness::new();
             if let Some(sig) = i.tap_key_sig {
                 script_witness.push(sig.to_vec());
+            } else if let Some(((_, leaf_hash), sig)) = i.tap_script_sigs.iter().next() {
+                // only leaves with a single signature, our own
+                let (control_block, (script, _)) = i
+                    .tap_scripts
+                    .iter()
+                    .find(|(_, (script, version))| {
+                        TapLeafHash::from_script(script, *version) == *leaf_hash
+                    })
+                    .ok_or_else(|| Error::msg(format!("Missing leaf script at input {}", index)))?;
+                script_witness.push(sig.to_vec());
+                script_witness.push(script.as_bytes());
+                script_witness.push(control_block.serialize());
             } else {
-                panic!("Missing signature");
+                return Err(Error::msg(format!("Missing signature at input {}", index)));
             }
+            witnesses.push(script_witness);
+        }
 
+        for (i, script_witness) in psbt.inputs.iter_mut().zip(witnesses) {
             i.final_script_witness = Some(script_witness);
 
             // Clear all the data fields as per the spec.
@@ -845,7 +2081,12 @@ impl SpClient {
             i.redeem_script = None;
             i.witness_script = None;
             i.bip32_derivation = BTreeMap::new();
-        });
+            i.tap_internal_key = None;
+            i.tap_key_origins = BTreeMap::new();
+            i.tap_scripts = BTreeMap::new();
+            i.tap_script_sigs = BTreeMap::new();
+            i.tap_merkle_root = None;
+        }
         Ok(())
     }
 
@@ -856,19 +2097,21 @@ impl SpClient {
         use rayon::prelude::*;
         let b_scan = &self.get_scan_key();
 
-        let shared_secrets: Vec<PublicKey> = tweak_data_vec
-            .into_par_iter()
-            .map(|tweak| sp_utils::receiving::calculate_ecdh_shared_secret(&tweak, b_scan))
-            .collect();
+        let items: Result<Vec<_>> = workers::install(|| {
+            let shared_secrets: Vec<PublicKey> = tweak_data_vec
+                .into_par_iter()
+                .map(|tweak| sp_utils::receiving::calculate_ecdh_shared_secret(&tweak, b_scan))
+                .collect();
 
-        let items: Result<Vec<_>> = shared_secrets
-            .into_par_iter()
-            .map(|secret| {
-                let spks = self.sp_receiver.get_spks_from_shared_secret(&secret)?;
+            shared_secrets
+                .into_par_iter()
+                .map(|secret| {
+                    let spks = self.sp_receiver.get_spks_from_shared_secret(&secret)?;
 
-                Ok((secret, spks.into_values()))
-            })
-            .collect();
+                    Ok((secret, spks.into_values()))
+                })
+                .collect()
+        })?;
 
         let mut res = HashMap::new();
         for (secret, spks) in items? {
@@ -880,10 +2123,25 @@ impl SpClient {
     }
 }
 
-#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+/// What the ui shows about a wallet without touching its secrets
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct WalletInfo {
+    pub label: String,
+    pub network: Network,
+    pub birthday: u32,
+    pub last_scan: u32,
+    pub is_watch_only: bool,
+    pub has_mnemonic: bool,
+    /// None until `SpClient::verify_mnemonic` succeeded
+    pub backup_verified_at: Option<u64>,
+}
+
+/// Persisted as a `SealedClient` and the `OutputList`, see `SpWallet::new`
+#[derive(Debug, Default, Clone)]
 pub struct SpWallet {
     client: SpClient,
     outputs: OutputList,
+    spend_history: SpendHistory,
 }
 
 impl SpWallet {
@@ -893,6 +2151,7 @@ impl SpWallet {
                 Ok(Self {
                     client,
                     outputs: existing_outputs,
+                    spend_history: SpendHistory::default(),
                 })
             } else {
                 Err(Error::msg("outputs don't match client"))
@@ -904,7 +2163,23 @@ impl SpWallet {
                 client.get_spend_key().into(),
                 0,
             );
-            Ok(Self { client, outputs })
+            Ok(Self {
+                client,
+                outputs,
+                spend_history: SpendHistory::default(),
+            })
+        }
+    }
+
+    pub fn get_wallet_info(&self) -> WalletInfo {
+        WalletInfo {
+            label: self.client.label.clone(),
+            network: self.client.get_network(),
+            birthday: self.outputs.get_birthday(),
+            last_scan: self.outputs.get_last_scan(),
+            is_watch_only: matches!(self.client.spend_key, SpendKey::Public(_)),
+            has_mnemonic: self.client.mnemonic.is_some(),
+            backup_verified_at: self.client.get_backup_verified_at(),
         }
     }
 
@@ -924,6 +2199,44 @@ impl SpWallet {
         &mut self.outputs
     }
 
+    /// To persist along with the outputs, the daily limit and time delay rely on it
+    pub fn get_spend_history(&self) -> &SpendHistory {
+        &self.spend_history
+    }
+
+    pub fn set_spend_history(&mut self, history: SpendHistory) {
+        self.spend_history = history;
+    }
+
+    /// Start the time delay of the spending policy for `psbt`, returns when it can be signed
+    pub fn request_delayed_spend(&mut self, psbt: &Psbt, now: u64) -> Result<u64> {
+        let policy = self.client.get_spending_policy();
+        policy.check_psbt(&self.client, psbt)?;
+        let requested_at = self.spend_history.request(psbt.unsigned_tx.txid(), now);
+        Ok(requested_at + policy.time_delay.map(|d| d.delay).unwrap_or(0))
+    }
+
+    /// `SpClient::sign_psbt` with the whole spending policy enforced, `now` is a unix timestamp
+    /// The spend is recorded once signed
+    pub fn sign_psbt(
+        &mut self,
+        psbt: Psbt,
+        aux_rand: &[u8; 32],
+        intent: Option<&SpendIntent>,
+        now: u64,
+    ) -> Result<Psbt> {
+        let txid = psbt.unsigned_tx.txid();
+        let (signed, amount) = self.client.sign_psbt_checked(
+            psbt,
+            aux_rand,
+            intent,
+            Some((&self.spend_history, now)),
+        )?;
+        self.spend_history.record(txid, amount, now);
+
+        Ok(signed)
+    }
+
     pub fn update_wallet_with_transaction(
         &mut self,
         tx: &Transaction,
@@ -942,7 +2255,7 @@ impl SpWallet {
                 })
                 .is_ok()
             {
-                return Err(Error::msg("Transaction already scanned"));
+                return Err(AlreadyScanned(txid).into());
             }
         }
 
@@ -951,17 +2264,107 @@ impl SpWallet {
                 match output.spend_status {
                     OutputSpendStatus::Spent(tx) => {
                         if tx == txid.to_string() {
-                            return Err(Error::msg("Transaction already scanned"));
+                            return Err(AlreadyScanned(txid).into());
                         }
                     }
-                    OutputSpendStatus::Mined(_) => {
-                        return Err(Error::msg("Transaction already scanned"))
-                    }
+                    OutputSpendStatus::Mined(_) => return Err(AlreadyScanned(txid).into()),
                     _ => continue,
                 }
             }
         }
 
+        let mut new_outputs = self.find_tx_outputs(tx, blockheight, partial_tweak)?;
+
+        // our coinjoin outputs are derived from our inputs alone, see `coinjoin`
+        let our_inputs: HashMap<OutPoint, OwnedOutput> = tx
+            .input
+            .iter()
+            .filter_map(|input| {
+                self.outputs
+                    .outputs
+                    .get(&input.previous_output)
+                    .map(|output| (input.previous_output, output.clone()))
+            })
+            .collect();
+        if !our_inputs.is_empty()
+            && our_inputs.len() < tx.input.len()
+            && matches!(self.client.spend_key, SpendKey::Secret(_))
+        {
+            let tweak_data = self.client.get_coinjoin_tweak_data(&our_inputs)?;
+            new_outputs.extend(self.find_tx_outputs(tx, blockheight, tweak_data)?);
+        }
+
+        let mut res = new_outputs.clone();
+        self.outputs.extend_from(new_outputs);
+
+        let txid = tx.txid().to_string();
+        // update outputs that we own and that are spent
+        for input in tx.input.iter() {
+            if let Some(prevout) = self.outputs.outputs.get_mut(&input.previous_output) {
+                // This is spent by this tx
+                prevout.spend_status = OutputSpendStatus::Spent(txid.clone());
+                res.insert(input.previous_output, prevout.clone());
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Scan the transactions of the block at `height`, coinbases and transactions already scanned are skipped
+    /// `get_prevouts` returns the outputs spent by a transaction, in the order of its inputs
+    /// Returns the outputs that were found or updated
+    pub fn scan_block_transactions(
+        &mut self,
+        txs: &[Transaction],
+        height: u32,
+        get_prevouts: impl Fn(&Transaction) -> Result<Vec<TxOut>>,
+    ) -> Result<HashMap<OutPoint, OwnedOutput>> {
+        let mut found = HashMap::new();
+        for tx in txs.iter().filter(|tx| !tx.is_coinbase()) {
+            if let Some(tweak_data) = get_tweak_data(tx, &get_prevouts(tx)?)? {
+                match self.update_wallet_with_transaction(tx, height, tweak_data) {
+                    Ok(outputs) => found.extend(outputs),
+                    // e.g. our own transaction, added to the wallet when we broadcast it
+                    Err(e) if e.is::<AlreadyScanned>() => (),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        self.outputs.update_last_scan(height);
+        Ok(found)
+    }
+
+    /// Forget the blocks above `fork_height` after a reorg, scanning the new ones finds their outputs again
+    /// Outputs spent by a transaction of those blocks that had an output of ours are unspent again,
+    /// so that the transaction isn't skipped as already scanned
+    pub fn rollback_to(&mut self, fork_height: u32) {
+        let dropped: Vec<String> = self
+            .outputs
+            .outputs
+            .iter()
+            .filter(|(_, o)| o.blockheight > fork_height)
+            .map(|(outpoint, _)| outpoint.txid.to_string())
+            .collect();
+        self.outputs.reset_to_height(fork_height + 1);
+        for output in self.outputs.outputs.values_mut() {
+            if let OutputSpendStatus::Spent(ref txid) = output.spend_status {
+                if dropped.contains(txid) {
+                    output.spend_status = OutputSpendStatus::Unspent;
+                }
+            }
+        }
+        if self.outputs.get_last_scan() > fork_height {
+            self.outputs.update_last_scan(fork_height);
+        }
+    }
+
+    /// Our outputs in `tx`, without adding them to the wallet
+    pub(crate) fn find_tx_outputs(
+        &self,
+        tx: &Transaction,
+        blockheight: u32,
+        partial_tweak: PublicKey,
+    ) -> Result<HashMap<OutPoint, OwnedOutput>> {
         let shared_secret = sp_utils::receiving::calculate_ecdh_shared_secret(
             &partial_tweak,
             &self.client.get_scan_key(),
@@ -977,6 +2380,11 @@ impl SpWallet {
             .client
             .sp_receiver
             .scan_transaction(&shared_secret, pubkeys_to_check.keys().cloned().collect())?;
+        // we're not the one who sent it
+        let unsolicited = !tx
+            .input
+            .iter()
+            .any(|input| self.outputs.outputs.contains_key(&input.previous_output));
         let mut new_outputs: HashMap<OutPoint, OwnedOutput> = HashMap::new();
         for (label, map) in ours {
             for (key, scalar) in map {
@@ -996,48 +2404,631 @@ impl SpWallet {
                     tweak: scalar.to_be_bytes().to_lower_hex_string(),
                     amount: txout.value,
                     script: txout.script_pubkey.as_bytes().to_lower_hex_string(),
+                    quarantined: unsolicited
+                        && label_str.is_none()
+                        && txout.value < self.client.get_dust_attack_threshold(),
                     label: label_str,
                     spend_status: OutputSpendStatus::Unspent,
+                    blockhash: None,
+                    block_time: None,
                 };
                 new_outputs.insert(outpoint, owned);
             }
         }
-        let mut res = new_outputs.clone();
-        self.outputs.extend_from(new_outputs);
+        Ok(new_outputs)
+    }
 
-        let txid = tx.txid().to_string();
-        // update outputs that we own and that are spent
-        for input in tx.input.iter() {
-            if let Some(prevout) = self.outputs.outputs.get_mut(&input.previous_output) {
-                // This is spent by this tx
-                prevout.spend_status = OutputSpendStatus::Spent(txid.clone());
-                res.insert(input.previous_output, prevout.clone());
+    /// Same as `update_wallet_with_transaction` for a transaction in a block,
+    /// the outputs found also get the hash and time of the block
+    pub fn update_wallet_with_block_transaction(
+        &mut self,
+        tx: &Transaction,
+        blockheight: u32,
+        blockhash: BlockHash,
+        block_time: u32,
+        partial_tweak: PublicKey,
+    ) -> Result<HashMap<OutPoint, OwnedOutput>> {
+        let mut res = self.update_wallet_with_transaction(tx, blockheight, partial_tweak)?;
+        let txid = tx.txid();
+        for (outpoint, output) in res.iter_mut().filter(|(o, _)| o.txid == txid) {
+            output.blockhash = Some(blockhash);
+            output.block_time = Some(block_time);
+            if let Some(stored) = self.outputs.outputs.get_mut(outpoint) {
+                stored.blockhash = Some(blockhash);
+                stored.block_time = Some(block_time);
             }
         }
-
         Ok(res)
     }
 }
 
-pub fn derive_keys_from_seed(seed: &[u8; 64], network: Network) -> Result<(SecretKey, SecretKey)> {
-    let xprv = Xpriv::new_master(network, seed)?;
+/// What we need in a psbt to sign for one of our outputs
+pub(crate) fn get_psbt_input(
+    script_pubkey: &ScriptBuf,
+    value: Amount,
+    tweak: &Scalar,
+) -> Result<Input> {
+    let witness_txout = TxOut {
+        value,
+        script_pubkey: script_pubkey.clone(),
+    };
+    // No tap_internal_key: the key in the script isn't derived from an internal key as in BIP341,
+    // a signer would tweak it again and sign for another key. There's no key origin either, output
+    // keys don't come from a bip32 derivation. Signers need our tweak field, see `psbt_data`
+    let mut psbt_input = Input {
+        witness_utxo: Some(witness_txout),
+        ..Default::default()
+    };
+    psbt_input.proprietary.insert(
+        raw::ProprietaryKey {
+            prefix: PSBT_SP_PREFIX.as_bytes().to_vec(),
+            subtype: PSBT_SP_SUBTYPE,
+            key: PSBT_SP_TWEAK_KEY.as_bytes().to_vec(),
+        },
+        tweak.to_be_bytes().to_vec(),
+    );
+    Ok(psbt_input)
+}
 
-    let (scan_privkey, spend_privkey) = derive_keys_from_xprv(xprv)?;
+/// Generate a new BIP39 mnemonic, 12 or 24 words depending on `strength`
+pub fn generate_mnemonic(strength: MnemonicStrength) -> Result<String> {
+    generate_mnemonic_with_rng(strength, &SharedRng::default())
+}
 
-    Ok((scan_privkey, spend_privkey))
+pub fn generate_mnemonic_with_rng(strength: MnemonicStrength, rng: &SharedRng) -> Result<String> {
+    generate_mnemonic_in(strength, MnemonicLanguage::English, rng)
+}
+
+pub fn generate_mnemonic_in(
+    strength: MnemonicStrength,
+    language: MnemonicLanguage,
+    rng: &SharedRng,
+) -> Result<String> {
+    use bitcoin::secp256k1::rand::RngCore;
+
+    let mut entropy = vec![0u8; strength.entropy_len()];
+    rng.clone().fill_bytes(&mut entropy);
+
+    let mnemonic = Mnemonic::from_entropy_in(language.into(), &entropy);
+
+    entropy.zeroize();
+
+    Ok(mnemonic?.to_string())
 }
 
-fn derive_keys_from_xprv(xprv: Xpriv) -> Result<(SecretKey, SecretKey)> {
-    let (scan_path, spend_path) = match xprv.network {
-        bitcoin::Network::Bitcoin => ("m/352h/0h/0h/1h/0", "m/352h/0h/0h/0h/0"),
-        _ => ("m/352h/1h/0h/1h/0", "m/352h/1h/0h/0h/0"),
+/// The language whose wordlist has all the words of `seedphrase` and gives a valid checksum
+/// Some words are in several wordlists, english wins if the phrase is valid in more than one
+pub fn detect_mnemonic_language(seedphrase: &str) -> Result<MnemonicLanguage> {
+    let mut valid = MnemonicLanguage::ALL
+        .into_iter()
+        .filter(|language| Mnemonic::parse_in((*language).into(), seedphrase).is_ok());
+    match valid.next() {
+        Some(language) => Ok(language),
+        // the error of the english parsing is the most likely to help
+        None => Err(Mnemonic::parse_in(Language::English, seedphrase)
+            .err()
+            .map(Error::from)
+            .unwrap_or_else(|| Error::msg("Invalid mnemonic"))),
+    }
+}
+
+pub fn derive_keys_from_mnemonic(
+    seedphrase: &str,
+    passphrase: &str,
+    account: u32,
+    network: Network,
+) -> Result<(SecretKey, SecretKey)> {
+    let language = detect_mnemonic_language(seedphrase)?;
+    let mnemonic = Mnemonic::parse_in(language.into(), seedphrase)?;
+    let mut seed = mnemonic.to_seed(passphrase);
+
+    let keys = derive_keys_from_seed(&seed, account, network);
+
+    seed.zeroize();
+
+    keys
+}
+
+pub fn derive_keys_from_seed(
+    seed: &[u8; 64],
+    account: u32,
+    network: Network,
+) -> Result<(SecretKey, SecretKey)> {
+    let mut xprv = Xpriv::new_master(network, seed)?;
+
+    let keys = derive_keys_from_xprv(xprv, account);
+
+    xprv.private_key.non_secure_erase();
+
+    keys
+}
+
+/// Derive the scan and spend keys of `account` (`m/352'/coin_type'/account'/...`),
+/// so that one seed can back several independent wallets
+pub fn derive_keys_from_xprv(xprv: Xpriv, account: u32) -> Result<(SecretKey, SecretKey)> {
+    let coin_type = match xprv.network {
+        bitcoin::Network::Bitcoin => 0,
+        _ => 1,
     };
 
     let secp = Secp256k1::signing_only();
-    let scan_path = DerivationPath::from_str(scan_path)?;
-    let spend_path = DerivationPath::from_str(spend_path)?;
+    let scan_path = DerivationPath::from_str(&format!("m/352h/{}h/{}h/1h/0", coin_type, account))?;
+    let spend_path = DerivationPath::from_str(&format!("m/352h/{}h/{}h/0h/0", coin_type, account))?;
     let scan_privkey = xprv.derive_priv(&secp, &scan_path)?.private_key;
     let spend_privkey = xprv.derive_priv(&secp, &spend_path)?.private_key;
 
     Ok((scan_privkey, spend_privkey))
 }
+
+/// Compute the tweak data of `tx` from the outputs spent by each of its inputs, in order
+/// Returns None if no input is eligible for silent payments
+pub fn get_tweak_data(tx: &Transaction, prevouts: &[TxOut]) -> Result<Option<PublicKey>> {
+    if prevouts.len() != tx.input.len() {
+        return Err(Error::msg("Missing prevouts"));
+    }
+
+    let mut input_pubkeys: Vec<PublicKey> = vec![];
+    for (txin, prevout) in tx.input.iter().zip(prevouts) {
+        if let Some(pubkey) = sp_utils::receiving::get_pubkey_from_input(
+            txin.script_sig.as_bytes(),
+            &txin.witness.to_vec(),
+            prevout.script_pubkey.as_bytes(),
+        )? {
+            input_pubkeys.push(pubkey);
+        }
+    }
+
+    if input_pubkeys.is_empty() {
+        return Ok(None);
+    }
+
+    let outpoints: Vec<(String, u32)> = tx
+        .input
+        .iter()
+        .map(|i| (i.previous_output.txid.to_string(), i.previous_output.vout))
+        .collect();
+
+    let tweak_data = sp_utils::receiving::calculate_tweak_data(
+        &input_pubkeys.iter().collect::<Vec<&PublicKey>>(),
+        &outpoints,
+    )?;
+
+    Ok(Some(tweak_data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::psbt::PsbtSighashType;
+
+    use crate::confirmations::BroadcastTracker;
+    use crate::psbt_data::{set_psbt_change_output, set_psbt_sp_address};
+    use crate::test_utils::{client_from_seeds, other_client, owned_output, test_client};
+
+    /// Two of our outputs paying another wallet, with our change, fees set
+    fn unsigned_psbt(client: &SpClient) -> Psbt {
+        let utxos = HashMap::from([
+            owned_output(client, 1, Amount::from_sat(50_000)),
+            owned_output(client, 2, Amount::from_sat(30_000)),
+        ]);
+        let other = other_client();
+        let recipients = vec![Recipient {
+            address: other.get_receiving_address(),
+            amount: Amount::from_sat(60_000),
+            nb_outputs: 1,
+        }];
+        let mut psbt = client.create_new_psbt(utxos, recipients, None).unwrap();
+        SpClient::set_fees(
+            &mut psbt,
+            Amount::from_sat(2),
+            client.sp_receiver.get_change_address(),
+        )
+        .unwrap();
+        psbt
+    }
+
+    /// Same as `unsigned_psbt`, signed
+    fn signed_psbt(client: &SpClient) -> Psbt {
+        let mut psbt = unsigned_psbt(client);
+        let partial_secret = client.get_partial_secret_from_psbt(&psbt).unwrap();
+        client.fill_sp_outputs(&mut psbt, partial_secret).unwrap();
+        client.sign_psbt(psbt, &[0u8; 32], None).unwrap()
+    }
+
+    /// Every entry point must return an error on garbage, never panic
+    fn exercise(client: &SpClient, psbt: &Psbt) {
+        let _ = client.sign_psbt(psbt.clone(), &[1u8; 32], None);
+        let _ = SpClient::finalize_psbt(&mut psbt.clone());
+        let _ = SpClient::set_fees(
+            &mut psbt.clone(),
+            Amount::from_sat(1),
+            client.sp_receiver.get_change_address(),
+        );
+        let _ = client.get_partial_secret_from_psbt(psbt);
+        let _ = client.fill_sp_outputs(&mut psbt.clone(), SecretKey::from_slice(&[7; 32]).unwrap());
+        let _ = get_psbt_sp_data(psbt);
+        let _ = predict_psbt_vsize(psbt);
+    }
+
+    #[test]
+    fn signet_survives_watch_only_export() {
+        let client = client_from_seeds(0x11, 0x22, None, Network::Signet);
+        let wallet = SpWallet::new(client, None).unwrap();
+        let package = wallet.export_watch_only().encode();
+        let watch_only = SpClient::from_wallet_type(
+            "watch".to_owned(),
+            WalletType::WatchOnly(package),
+            0,
+            Network::Signet,
+        )
+        .unwrap();
+        assert_eq!(watch_only.get_network(), Network::Signet);
+    }
+
+    #[test]
+    fn sign_and_finalize() {
+        let client = test_client();
+        let mut psbt = signed_psbt(&client);
+        // the output key isn't a BIP341 internal key, the tweak is in our own field
+        assert!(psbt.inputs.iter().all(|i| i.tap_internal_key.is_none()));
+        assert!(get_psbt_sp_data(&psbt)
+            .unwrap()
+            .input_tweaks
+            .iter()
+            .all(Option::is_some));
+        SpClient::finalize_psbt(&mut psbt).unwrap();
+        assert!(psbt.inputs.iter().all(|i| i.final_script_witness.is_some()));
+        psbt.extract_tx().unwrap();
+    }
+
+    #[test]
+    fn cold_device_orders_before_signing() {
+        let mut client = test_client();
+        client.set_tx_ordering(TxOrdering::Bip69);
+        let unsigned = unsigned_psbt(&client);
+        let request = client.export_spend_request(&unsigned).unwrap();
+        let signed = client.complete_spend_request(&request, &[0u8; 32]).unwrap();
+        let values: Vec<Amount> = signed.unsigned_tx.output.iter().map(|o| o.value).collect();
+        let mut sorted = values.clone();
+        sorted.sort();
+        assert_eq!(values, sorted);
+        SpClient::import_signed_psbt(&unsigned, &signed.to_string()).unwrap();
+    }
+
+    #[test]
+    fn change_birthday_drops_later_outputs() {
+        let client = test_client();
+        let mut list = OutputList::new(
+            client.get_scan_key().public_key(&Secp256k1::signing_only()),
+            client.get_spend_key().into(),
+            100,
+        );
+        list.update_last_scan(300);
+        let (early, mut early_output) = owned_output(&client, 1, Amount::from_sat(1_000));
+        early_output.blockheight = 150;
+        let (late, mut late_output) = owned_output(&client, 2, Amount::from_sat(1_000));
+        late_output.blockheight = 200;
+        let (pending, mut pending_output) = owned_output(&client, 3, Amount::from_sat(1_000));
+        pending_output.blockheight = UNCONFIRMED_HEIGHT;
+        list.extend_from(HashMap::from([
+            (early, early_output),
+            (late, late_output),
+            (pending, pending_output),
+        ]));
+
+        list.change_birthday(250, false);
+        assert_eq!(list.to_outpoints_list().len(), 3);
+        assert_eq!(list.get_last_scan(), 300);
+
+        list.change_birthday(200, true);
+        let outputs = list.to_outpoints_list();
+        assert!(outputs.contains_key(&early));
+        assert!(!outputs.contains_key(&late));
+        assert!(outputs.contains_key(&pending));
+        assert_eq!(list.get_birthday(), 200);
+        assert_eq!(list.get_last_scan(), 200);
+    }
+
+    #[test]
+    fn pending_balance_nets_the_change() {
+        let client = test_client();
+        let mut wallet = SpWallet::new(client.clone(), None).unwrap();
+        wallet.get_mut_outputs().extend_from(HashMap::from([
+            owned_output(&client, 1, Amount::from_sat(50_000)),
+            owned_output(&client, 2, Amount::from_sat(30_000)),
+            owned_output(&client, 3, Amount::from_sat(10_000)),
+        ]));
+
+        let mut psbt = signed_psbt(&client);
+        SpClient::finalize_psbt(&mut psbt).unwrap();
+        let tx = psbt.extract_tx().unwrap();
+        for input in tx.input.iter() {
+            wallet
+                .get_mut_outputs()
+                .mark_spent(input.previous_output, tx.txid(), false)
+                .unwrap();
+        }
+        let change = tx
+            .output
+            .iter()
+            .find(|o| o.value != Amount::from_sat(60_000))
+            .unwrap()
+            .value;
+
+        let mut tracker = BroadcastTracker::default();
+        tracker.track(&wallet, tx).unwrap();
+        let balance = tracker.get_balance(&wallet).unwrap();
+        assert_eq!(balance.confirmed, Amount::from_sat(10_000));
+        assert_eq!(balance.pending_incoming, change);
+        assert_eq!(balance.pending_outgoing, Amount::from_sat(80_000) - change);
+    }
+
+    #[test]
+    fn history_rules_need_the_wallet() {
+        let mut client = test_client();
+        client.set_spending_policy(SpendingPolicy {
+            daily_limit: Some(Amount::from_sat(1_000_000)),
+            ..Default::default()
+        });
+        let mut psbt = unsigned_psbt(&client);
+        let partial_secret = client.get_partial_secret_from_psbt(&psbt).unwrap();
+        client.fill_sp_outputs(&mut psbt, partial_secret).unwrap();
+
+        let mut wallet = SpWallet::new(client, None).unwrap();
+        let err = wallet
+            .get_client()
+            .sign_psbt(psbt.clone(), &[0u8; 32], None)
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<PolicyViolation>(),
+            Some(&PolicyViolation::HistoryRequired)
+        );
+        let request = wallet.get_client().export_spend_request(&psbt).unwrap();
+        assert!(wallet
+            .get_client()
+            .complete_spend_request(&request, &[0u8; 32])
+            .is_err());
+
+        wallet.sign_psbt(psbt, &[0u8; 32], None, 1_000).unwrap();
+        assert_eq!(
+            wallet.get_spend_history().get_spent_since(0),
+            Amount::from_sat(60_000)
+        );
+    }
+
+    #[test]
+    fn forged_change_tag_is_a_payment() {
+        let client = test_client();
+        let other = other_client();
+        let mut psbt = unsigned_psbt(&client);
+        let partial_secret = client.get_partial_secret_from_psbt(&psbt).unwrap();
+        client.fill_sp_outputs(&mut psbt, partial_secret).unwrap();
+
+        let whitelist = SpendingPolicy {
+            whitelist: Some(vec![other.get_receiving_address()]),
+            ..Default::default()
+        };
+        assert_eq!(
+            whitelist.check_psbt(&client, &psbt).unwrap(),
+            Amount::from_sat(60_000)
+        );
+
+        // the payment to `other` claims to be our change
+        let vout = get_psbt_sp_data(&psbt)
+            .unwrap()
+            .output_addresses
+            .iter()
+            .position(|a| a.as_deref() == Some(other.get_receiving_address().as_str()))
+            .unwrap();
+        set_psbt_sp_address(&mut psbt, vout, &client.sp_receiver.get_change_address()).unwrap();
+        set_psbt_change_output(&mut psbt, vout).unwrap();
+
+        assert!(whitelist.check_psbt(&client, &psbt).is_err());
+        assert_eq!(
+            SpendingPolicy::default()
+                .check_psbt(&client, &psbt)
+                .unwrap(),
+            Amount::from_sat(60_000)
+        );
+        assert!(client.sign_psbt(psbt, &[0u8; 32], None).is_ok());
+    }
+
+    #[test]
+    fn finalize_changes_nothing_on_failure() {
+        let client = test_client();
+        let mut psbt = signed_psbt(&client);
+        psbt.inputs[1].tap_key_sig = None;
+        let before = psbt.clone();
+        assert!(SpClient::finalize_psbt(&mut psbt).is_err());
+        assert_eq!(psbt, before);
+    }
+
+    /// Our two outputs paying `recipients`, fees set and silent payments outputs filled
+    fn filled_psbt(client: &SpClient, recipients: Vec<Recipient>) -> Psbt {
+        let utxos = HashMap::from([
+            owned_output(client, 1, Amount::from_sat(50_000)),
+            owned_output(client, 2, Amount::from_sat(30_000)),
+        ]);
+        let mut psbt = client.create_new_psbt(utxos, recipients, None).unwrap();
+        SpClient::set_fees(
+            &mut psbt,
+            Amount::from_sat(2),
+            client.sp_receiver.get_change_address(),
+        )
+        .unwrap();
+        let partial_secret = client.get_partial_secret_from_psbt(&psbt).unwrap();
+        client.fill_sp_outputs(&mut psbt, partial_secret).unwrap();
+        psbt
+    }
+
+    #[test]
+    fn intent_matches_split_outputs() {
+        let client = test_client();
+        let recipients = vec![Recipient {
+            address: other_client().get_receiving_address(),
+            amount: Amount::from_sat(60_000),
+            nb_outputs: 2,
+        }];
+        let psbt = filled_psbt(&client, recipients.clone());
+        let intent = SpendIntent::new(&recipients, Amount::from_sat(1_000));
+        client
+            .sign_psbt(psbt.clone(), &[0u8; 32], Some(&intent))
+            .unwrap();
+
+        // one of the outputs going elsewhere is still refused
+        let mut tampered = psbt;
+        tampered.unsigned_tx.output[0].value -= Amount::from_sat(10_000);
+        assert!(client
+            .sign_psbt(tampered, &[0u8; 32], Some(&intent))
+            .is_err());
+    }
+
+    #[test]
+    fn intent_merges_duplicate_recipients() {
+        let client = test_client();
+        let address = other_client().get_receiving_address();
+        let recipients = vec![
+            Recipient {
+                address: address.clone(),
+                amount: Amount::from_sat(20_000),
+                nb_outputs: 1,
+            },
+            Recipient {
+                address,
+                amount: Amount::from_sat(40_000),
+                nb_outputs: 1,
+            },
+        ];
+        let psbt = filled_psbt(&client, recipients.clone());
+        let intent = SpendIntent::new(&recipients, Amount::from_sat(1_000));
+        client.sign_psbt(psbt, &[0u8; 32], Some(&intent)).unwrap();
+    }
+
+    #[test]
+    fn garbage_sp_address_is_an_error() {
+        let client = test_client();
+        let mut psbt = signed_psbt(&client);
+        for output in psbt.outputs.iter_mut() {
+            for value in output.proprietary.values_mut() {
+                *value = serialize(&"sp1notanaddress".to_owned());
+            }
+        }
+        let res = SpClient::set_fees(
+            &mut psbt,
+            Amount::from_sat(1),
+            client.sp_receiver.get_change_address(),
+        );
+        assert!(res.is_err());
+
+        // only the version and checksum are checked before the payload is decoded
+        let empty =
+            bech32::encode("sp", Vec::<bech32::u5>::new(), bech32::Variant::Bech32m).unwrap();
+        assert!(try_parse_sp_address(&empty).is_err());
+    }
+
+    #[test]
+    fn malformed_fields_are_errors() {
+        let client = test_client();
+        let psbt = signed_psbt(&client);
+        let mutations: Vec<fn(&mut Psbt)> = vec![
+            |p| p.inputs[0].witness_utxo = None,
+            |p| p.inputs[0].proprietary.clear(),
+            |p| {
+                for value in p.inputs[0].proprietary.values_mut() {
+                    *value = vec![0xff; 32];
+                }
+            },
+            |p| {
+                for value in p.inputs[0].proprietary.values_mut() {
+                    value.truncate(3);
+                }
+            },
+            |p| p.inputs[0].sighash_type = Some(PsbtSighashType::from_u32(0xdead)),
+            |p| {
+                if let Some(utxo) = p.inputs[0].witness_utxo.as_mut() {
+                    utxo.script_pubkey = ScriptBuf::new();
+                }
+            },
+            |p| {
+                if let Some(utxo) = p.inputs[1].witness_utxo.as_mut() {
+                    utxo.value = Amount::MAX_MONEY;
+                }
+            },
+            |p| {
+                for output in p.outputs.iter_mut() {
+                    for value in output.proprietary.values_mut() {
+                        value.clear();
+                    }
+                }
+            },
+            |p| p.unsigned_tx.output.clear(),
+            |p| p.inputs[0].tap_key_sig = None,
+        ];
+        for mutate in mutations {
+            let mut mutated = psbt.clone();
+            mutate(&mut mutated);
+            exercise(&client, &mutated);
+        }
+    }
+
+    #[test]
+    fn random_bytes_dont_panic() {
+        let client = test_client();
+        let serialized = signed_psbt(&client).serialize();
+        // xorshift, so that failures can be reproduced
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..500 {
+            let mut bytes = serialized.clone();
+            for _ in 0..=next() % 4 {
+                let pos = (next() % bytes.len() as u64) as usize;
+                bytes[pos] = next() as u8;
+            }
+            if next() % 8 == 0 {
+                bytes.truncate((next() % bytes.len() as u64) as usize);
+            }
+            if let Ok(psbt) = Psbt::deserialize(&bytes) {
+                exercise(&client, &psbt);
+            }
+        }
+    }
+
+    fn address_with_version(address: &str, version: u8, extra: &[u8]) -> String {
+        use bech32::ToBase32;
+        let (hrp, data, _) = bech32::decode(address).unwrap();
+        let mut payload = Vec::<u8>::from_base32(&data[1..]).unwrap();
+        payload.extend_from_slice(extra);
+        let mut data = vec![bech32::u5::try_from_u8(version).unwrap()];
+        data.extend(payload.to_base32());
+        bech32::encode(&hrp, data, bech32::Variant::Bech32m).unwrap()
+    }
+
+    #[test]
+    fn later_address_versions_are_paid_as_v0() {
+        let address = test_client().get_receiving_address();
+        for version in 1..SP_ADDRESS_RESERVED_VERSION {
+            for extra in [&[][..], &[0xab; 7][..]] {
+                let later = address_with_version(&address, version, extra);
+                let parsed = try_parse_sp_address(&later).unwrap().unwrap();
+                assert_eq!(parsed.to_string(), address);
+                assert_eq!(parse_sp_address(&later).unwrap().version, version);
+            }
+        }
+
+        let reserved = address_with_version(&address, SP_ADDRESS_RESERVED_VERSION, &[]);
+        let err = try_parse_sp_address(&reserved).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<UnsupportedAddressVersion>(),
+            Some(&UnsupportedAddressVersion(SP_ADDRESS_RESERVED_VERSION))
+        );
+
+        // version 0 has nothing after the keys
+        assert!(try_parse_sp_address(&address_with_version(&address, 0, &[0xab])).is_err());
+    }
+}
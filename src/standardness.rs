@@ -0,0 +1,172 @@
+//! Checks of a final transaction against the default relay policy of Bitcoin Core,
+//! nodes silently drop transactions that break it instead of telling us
+
+use bitcoin::{Amount, Transaction, TxOut};
+
+use anyhow::Result;
+
+use crate::spclient::DustPolicy;
+
+/// sat/vB
+pub const MIN_RELAY_FEE_RATE: Amount = Amount::from_sat(1);
+pub const MAX_STANDARD_TX_WEIGHT: u64 = 400_000;
+/// Smaller transactions could be confused with a 64 bytes merkle tree node
+pub const MIN_STANDARD_TX_NONWITNESS_SIZE: usize = 65;
+/// Whole op_return script, i.e. 80 bytes of data
+pub const MAX_OP_RETURN_RELAY: usize = 83;
+pub const MAX_STANDARD_SCRIPTSIG_SIZE: usize = 1650;
+/// Including the transaction itself
+pub const DEFAULT_ANCESTOR_LIMIT: usize = 25;
+
+/// Returned wrapped in an `anyhow::Error`, use `downcast_ref` to tell the user what to do
+#[derive(Debug, Clone, PartialEq)]
+pub enum StandardnessError {
+    Version(i32),
+    TooBig {
+        weight: u64,
+    },
+    TooSmall {
+        size: usize,
+    },
+    ScriptSig {
+        input: usize,
+    },
+    NonStandardOutput {
+        output: usize,
+    },
+    MultipleOpReturn,
+    Dust {
+        output: usize,
+        amount: Amount,
+        threshold: Amount,
+    },
+    /// The fee rate must be increased
+    FeeTooLow {
+        fee: Amount,
+        min_fee: Amount,
+    },
+    TooManyAncestors {
+        ancestors: usize,
+    },
+}
+
+impl std::fmt::Display for StandardnessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Version(version) => write!(f, "Transaction version {} isn't standard", version),
+            Self::TooBig { weight } => write!(
+                f,
+                "Transaction weight of {} is above the maximum of {}, spend fewer inputs",
+                weight, MAX_STANDARD_TX_WEIGHT
+            ),
+            Self::TooSmall { size } => write!(
+                f,
+                "Transaction of {} bytes without witness is below the minimum of {}",
+                size, MIN_STANDARD_TX_NONWITNESS_SIZE
+            ),
+            Self::ScriptSig { input } => write!(f, "Script sig of input {} isn't standard", input),
+            Self::NonStandardOutput { output } => {
+                write!(f, "Script of output {} isn't standard", output)
+            }
+            Self::MultipleOpReturn => write!(f, "Only one op_return output is standard"),
+            Self::Dust {
+                output,
+                amount,
+                threshold,
+            } => write!(
+                f,
+                "Output {} of {} is below the dust threshold of {}",
+                output, amount, threshold
+            ),
+            Self::FeeTooLow { fee, min_fee } => write!(
+                f,
+                "Fee of {} is below the minimum relay fee of {}, increase the fee rate",
+                fee, min_fee
+            ),
+            Self::TooManyAncestors { ancestors } => write!(
+                f,
+                "Transaction has {} unconfirmed ancestors, wait for some of them to confirm",
+                ancestors
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StandardnessError {}
+
+fn is_standard_script(script_pubkey: &bitcoin::Script) -> bool {
+    script_pubkey.is_p2pkh()
+        || script_pubkey.is_p2sh()
+        || script_pubkey.is_witness_program()
+        || (script_pubkey.is_op_return() && script_pubkey.len() <= MAX_OP_RETURN_RELAY)
+}
+
+/// `prevouts` are the outputs spent by `tx`, in the same order, needed to check the fee
+/// `unconfirmed_ancestors` is the number of unconfirmed transactions `tx` depends on, if known
+pub fn check_standardness(
+    tx: &Transaction,
+    prevouts: Option<&[TxOut]>,
+    unconfirmed_ancestors: Option<usize>,
+) -> Result<()> {
+    if !(1..=2).contains(&tx.version.0) {
+        return Err(StandardnessError::Version(tx.version.0).into());
+    }
+
+    let weight = tx.weight().to_wu();
+    if weight > MAX_STANDARD_TX_WEIGHT {
+        return Err(StandardnessError::TooBig { weight }.into());
+    }
+
+    let size = tx.base_size();
+    if size < MIN_STANDARD_TX_NONWITNESS_SIZE {
+        return Err(StandardnessError::TooSmall { size }.into());
+    }
+
+    for (i, txin) in tx.input.iter().enumerate() {
+        if txin.script_sig.len() > MAX_STANDARD_SCRIPTSIG_SIZE || !txin.script_sig.is_push_only() {
+            return Err(StandardnessError::ScriptSig { input: i }.into());
+        }
+    }
+
+    let dust = DustPolicy::default();
+    let mut op_returns = 0;
+    for (i, txout) in tx.output.iter().enumerate() {
+        if !is_standard_script(&txout.script_pubkey) {
+            return Err(StandardnessError::NonStandardOutput { output: i }.into());
+        }
+        if txout.script_pubkey.is_op_return() {
+            op_returns += 1;
+            continue;
+        }
+        let threshold = dust.get_threshold(&txout.script_pubkey);
+        if txout.value < threshold {
+            return Err(StandardnessError::Dust {
+                output: i,
+                amount: txout.value,
+                threshold,
+            }
+            .into());
+        }
+    }
+    if op_returns > 1 {
+        return Err(StandardnessError::MultipleOpReturn.into());
+    }
+
+    if let Some(prevouts) = prevouts {
+        let input_amt: Amount = prevouts.iter().map(|o| o.value).sum();
+        let output_amt: Amount = tx.output.iter().map(|o| o.value).sum();
+        let fee = input_amt.checked_sub(output_amt).unwrap_or(Amount::ZERO);
+        let min_fee = MIN_RELAY_FEE_RATE * tx.vsize() as u64;
+        if fee < min_fee {
+            return Err(StandardnessError::FeeTooLow { fee, min_fee }.into());
+        }
+    }
+
+    if let Some(ancestors) = unconfirmed_ancestors {
+        if ancestors + 1 > DEFAULT_ANCESTOR_LIMIT {
+            return Err(StandardnessError::TooManyAncestors { ancestors }.into());
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,260 @@
+//! Sync between devices with the same keys, by exchanging what changed instead of each rescanning
+//!
+//! Each device keeps a `SyncCursor` per peer, with what the peer already has. `export_delta` puts
+//! everything that changed since then in a blob encrypted with a key derived from the scan key,
+//! so it can go through any channel, and `import_delta` merges it on the other side with the
+//! rules of `merge`, so that deltas can cross each other.
+
+use std::collections::{HashMap, HashSet};
+
+use bitcoin::{
+    hashes::{sha256, Hash},
+    hex::{DisplayHex, FromHex},
+    OutPoint, Txid,
+};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use serde::{Deserialize, Serialize};
+use silentpayments::receiving::Label;
+
+use anyhow::{Error, Result};
+use zeroize::Zeroizing;
+
+use crate::history::TxHistory;
+use crate::import::{merge_scanned_range, ImportReport};
+use crate::signer::tagged_hash;
+use crate::spclient::{OwnedOutput, SpWallet};
+
+const DELTA_PREFIX: &str = "spdelta:";
+const DELTA_VERSION: u8 = 0;
+
+type Digest = [u8; 8];
+
+fn digest<T: Serialize>(value: &T) -> Result<Digest> {
+    let hash = sha256::Hash::hash(&serde_json::to_vec(value)?);
+    let mut res = [0u8; 8];
+    res.copy_from_slice(&hash.to_byte_array()[..8]);
+    Ok(res)
+}
+
+/// What a peer has, as far as we know
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SyncCursor {
+    outputs: HashMap<OutPoint, Digest>,
+    labels: HashSet<String>,
+    memos: HashMap<Txid, Digest>,
+    last_scan: u32,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+struct StateDelta {
+    wallet_fingerprint: [u8; 8],
+    /// With `last_scan`, the blocks the peer scanned
+    #[serde(default)]
+    birthday: u32,
+    last_scan: u32,
+    outputs: HashMap<OutPoint, OwnedOutput>,
+    labels: Vec<String>,
+    /// Memo and when it was set
+    memos: Vec<(Txid, Option<String>, u64)>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DeltaImport {
+    pub outputs: ImportReport,
+    pub new_labels: Vec<String>,
+    /// Transactions whose memo changed
+    pub memos: Vec<Txid>,
+}
+
+impl SpWallet {
+    fn get_sync_key(&self) -> Zeroizing<[u8; 32]> {
+        Zeroizing::new(tagged_hash(
+            "sp_client/StateSync",
+            &[&self.get_client().get_scan_key().secret_bytes()],
+        ))
+    }
+
+    /// Everything that changed since `cursor`, and the cursor to keep once the peer got the blob
+    pub fn export_delta(
+        &self,
+        history: &TxHistory,
+        cursor: &SyncCursor,
+    ) -> Result<(String, SyncCursor)> {
+        let mut new_cursor = cursor.clone();
+        let outputs = self.get_outputs();
+        let mut delta = StateDelta {
+            wallet_fingerprint: outputs.wallet_fingerprint,
+            birthday: outputs.get_birthday(),
+            last_scan: outputs.get_last_scan(),
+            ..Default::default()
+        };
+        new_cursor.last_scan = new_cursor.last_scan.max(delta.last_scan);
+
+        for (outpoint, output) in outputs.to_outpoints_list() {
+            let d = digest(&output)?;
+            if cursor.outputs.get(&outpoint) != Some(&d) {
+                new_cursor.outputs.insert(outpoint, d);
+                delta.outputs.insert(outpoint, output);
+            }
+        }
+        for label in self.get_client().sp_receiver.list_labels() {
+            let label = label.as_string();
+            if new_cursor.labels.insert(label.clone()) {
+                delta.labels.push(label);
+            }
+        }
+        for record in history.list_records() {
+            let d = digest(&(&record.memo, record.memo_updated_at))?;
+            if cursor.memos.get(&record.txid) != Some(&d) {
+                new_cursor.memos.insert(record.txid, d);
+                delta
+                    .memos
+                    .push((record.txid, record.memo.clone(), record.memo_updated_at));
+            }
+        }
+
+        let plain = Zeroizing::new(serde_json::to_vec(&delta)?);
+        let nonce: [u8; 12] = self.get_client().get_rng().gen_bytes();
+        let encrypted = ChaCha20Poly1305::new(Key::from_slice(self.get_sync_key().as_ref()))
+            .encrypt(Nonce::from_slice(&nonce), plain.as_ref())
+            .map_err(|_| Error::msg("Failed to encrypt the delta"))?;
+
+        let mut data = vec![DELTA_VERSION];
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&encrypted);
+        Ok((
+            format!("{}{}", DELTA_PREFIX, data.to_lower_hex_string()),
+            new_cursor,
+        ))
+    }
+
+    /// Merge a blob of `export_delta` from the peer of `cursor`, which is updated with what it sent
+    /// Persist the outputs, the client (for the labels), `history` and `cursor` afterwards
+    pub fn import_delta(
+        &mut self,
+        history: &mut TxHistory,
+        blob: &str,
+        cursor: &mut SyncCursor,
+    ) -> Result<DeltaImport> {
+        let hex = blob
+            .strip_prefix(DELTA_PREFIX)
+            .ok_or_else(|| Error::msg("Not a state delta"))?;
+        let data = Vec::<u8>::from_hex(hex)?;
+        if data.len() < 13 {
+            return Err(Error::msg("State delta too short"));
+        }
+        if data[0] != DELTA_VERSION {
+            return Err(Error::msg(format!(
+                "Unknown state delta version {}",
+                data[0]
+            )));
+        }
+        let plain = Zeroizing::new(
+            ChaCha20Poly1305::new(Key::from_slice(self.get_sync_key().as_ref()))
+                .decrypt(Nonce::from_slice(&data[1..13]), &data[13..])
+                .map_err(|_| Error::msg("Failed to decrypt the delta, not the same wallet?"))?,
+        );
+        let delta: StateDelta = serde_json::from_slice(&plain)?;
+        if delta.wallet_fingerprint != self.get_outputs().wallet_fingerprint {
+            return Err(Error::msg("State delta is from another wallet"));
+        }
+
+        let mut res = DeltaImport::default();
+
+        // the labels first, outputs paying them are checked against our keys
+        let receiver = &mut self.get_mut_client().sp_receiver;
+        for label in delta.labels {
+            cursor.labels.insert(label.clone());
+            if receiver.add_label(Label::try_from(label.clone())?)? {
+                res.new_labels.push(label);
+            }
+        }
+
+        let imported: Vec<OutPoint> = delta.outputs.keys().copied().collect();
+        res.outputs = self.import_owned_outputs(delta.outputs)?;
+        // what we have now, if it's more advanced than what the peer sent it goes back to it
+        let ours = self.get_outputs().to_outpoints_list();
+        for outpoint in imported {
+            if let Some(output) = ours.get(&outpoint) {
+                cursor.outputs.insert(outpoint, digest(output)?);
+            }
+        }
+
+        // outputs the peer scanned are all in what it sent us since the last delta,
+        // its last scan is only ours if there's no gap between what each of us scanned
+        merge_scanned_range(
+            self.get_mut_outputs(),
+            delta.birthday,
+            delta.last_scan,
+            &mut res.outputs,
+        );
+        cursor.last_scan = cursor.last_scan.max(delta.last_scan);
+
+        for (txid, memo, updated_at) in delta.memos {
+            // a transaction we haven't seen yet, the peer only sends its memo again once edited
+            if history.get_record(&txid).is_none() {
+                continue;
+            }
+            if history.merge_memo(&txid, memo, updated_at)? {
+                res.memos.push(txid);
+            }
+            if let Some(record) = history.get_record(&txid) {
+                cursor
+                    .memos
+                    .insert(txid, digest(&(&record.memo, record.memo_updated_at))?);
+            }
+        }
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::secp256k1::Secp256k1;
+
+    use crate::spclient::OutputList;
+    use crate::test_utils::test_client;
+
+    fn wallet(birthday: u32, last_scan: u32) -> SpWallet {
+        let client = test_client();
+        let mut outputs = OutputList::new(
+            client.get_scan_key().public_key(&Secp256k1::signing_only()),
+            client.get_spend_key().into(),
+            birthday,
+        );
+        outputs.update_last_scan(last_scan);
+        SpWallet::new(client, Some(outputs)).unwrap()
+    }
+
+    #[test]
+    fn last_scan_needs_overlapping_ranges() {
+        let mut history = TxHistory::default();
+
+        // nobody scanned 201 to 249
+        let mut ours = wallet(100, 200);
+        let (blob, _) = wallet(250, 300)
+            .export_delta(&history, &SyncCursor::default())
+            .unwrap();
+        let res = ours
+            .import_delta(&mut history, &blob, &mut SyncCursor::default())
+            .unwrap();
+        assert_eq!(ours.get_outputs().get_last_scan(), 200);
+        assert_eq!(res.outputs.rescan_from, Some(201));
+
+        let mut ours = wallet(100, 200);
+        let (blob, _) = wallet(150, 300)
+            .export_delta(&history, &SyncCursor::default())
+            .unwrap();
+        let res = ours
+            .import_delta(&mut history, &blob, &mut SyncCursor::default())
+            .unwrap();
+        assert_eq!(ours.get_outputs().get_last_scan(), 300);
+        assert_eq!(res.outputs.rescan_from, None);
+    }
+}
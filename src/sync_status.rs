@@ -0,0 +1,76 @@
+//! Where the sync stands, updated by the sync loop, and read by the app as a stream or on demand
+
+use std::sync::{
+    mpsc::{channel, Receiver, Sender},
+    Arc, Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SyncStatus {
+    pub header_height: u32,
+    /// Compact block filters we have
+    pub filter_height: u32,
+    /// Blocks we downloaded because their filter matched
+    pub block_height: u32,
+    /// Last block the wallet was scanned at
+    pub scan_height: u32,
+    pub peer_count: u32,
+    pub scanning: bool,
+}
+
+impl SyncStatus {
+    pub fn is_synced(&self) -> bool {
+        !self.scanning && self.header_height > 0 && self.scan_height >= self.header_height
+    }
+}
+
+/// Cloned in the sync loop and wherever the status is read
+#[derive(Debug, Clone, Default)]
+pub struct SyncTracker {
+    status: Arc<Mutex<SyncStatus>>,
+    subscribers: Arc<Mutex<Vec<Sender<SyncStatus>>>>,
+}
+
+impl SyncTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subscribers only get the status when it changed
+    pub fn update(&self, f: impl FnOnce(&mut SyncStatus)) {
+        let status = {
+            let mut status = self.status.lock().unwrap_or_else(|e| e.into_inner());
+            let old = status.clone();
+            f(&mut status);
+            if *status == old {
+                return;
+            }
+            status.clone()
+        };
+
+        let mut subscribers = self.subscribers.lock().unwrap_or_else(|e| e.into_inner());
+        // dropped receivers are forgotten
+        subscribers.retain(|s| s.send(status.clone()).is_ok());
+    }
+
+    /// For when no stream is attached
+    pub fn get_sync_status(&self) -> SyncStatus {
+        self.status
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Starts with the current status
+    pub fn subscribe(&self) -> Receiver<SyncStatus> {
+        let (sender, receiver) = channel();
+        let _ = sender.send(self.get_sync_status());
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(sender);
+        receiver
+    }
+}
@@ -0,0 +1,69 @@
+//! Fixtures shared by the unit tests
+
+use bitcoin::{
+    hashes::Hash,
+    hex::DisplayHex,
+    key::TweakedPublicKey,
+    secp256k1::{PublicKey, Secp256k1, SecretKey},
+    Amount, Network, OutPoint, ScriptBuf, Txid,
+};
+
+use crate::spclient::{OutputSpendStatus, OwnedOutput, SpClient, SpendKey};
+
+pub(crate) fn client_from_seeds(
+    scan_seed: u8,
+    spend_seed: u8,
+    mnemonic: Option<&str>,
+    network: Network,
+) -> SpClient {
+    SpClient::new(
+        "test".to_owned(),
+        SecretKey::from_slice(&[scan_seed; 32]).unwrap(),
+        SpendKey::Secret(SecretKey::from_slice(&[spend_seed; 32]).unwrap()),
+        mnemonic.map(str::to_owned),
+        network,
+    )
+    .unwrap()
+}
+
+pub(crate) fn test_client() -> SpClient {
+    client_from_seeds(0x11, 0x22, None, Network::Regtest)
+}
+
+/// Another wallet, to pay or be paid by
+pub(crate) fn other_client() -> SpClient {
+    client_from_seeds(0x33, 0x44, None, Network::Regtest)
+}
+
+/// An unspent output for `spend_pk` tweaked with `[seed; 32]`, at outpoint `[seed; 32]:0`
+pub(crate) fn output_for_key(
+    spend_pk: &PublicKey,
+    seed: u8,
+    amount: Amount,
+) -> (OutPoint, OwnedOutput) {
+    let tweak = SecretKey::from_slice(&[seed; 32]).unwrap();
+    let output_key = spend_pk
+        .add_exp_tweak(&Secp256k1::verification_only(), &tweak.into())
+        .unwrap();
+    let script = ScriptBuf::new_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(
+        output_key.x_only_public_key().0,
+    ));
+    (
+        OutPoint::new(Txid::from_byte_array([seed; 32]), 0),
+        OwnedOutput {
+            blockheight: 100,
+            tweak: tweak.secret_bytes().to_lower_hex_string(),
+            amount,
+            script: script.to_hex_string(),
+            label: None,
+            spend_status: OutputSpendStatus::Unspent,
+            quarantined: false,
+            blockhash: None,
+            block_time: None,
+        },
+    )
+}
+
+pub(crate) fn owned_output(client: &SpClient, seed: u8, amount: Amount) -> (OutPoint, OwnedOutput) {
+    output_for_key(&client.get_spend_key().into(), seed, amount)
+}
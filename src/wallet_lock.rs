@@ -0,0 +1,171 @@
+//! Per wallet locking, so that e.g. a scan and a spend of the same wallet can't race on its state
+//!
+//! Reads (balance, building a psbt) run concurrently, writes (scan, marking inputs spent) are exclusive.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock, TryLockError},
+};
+
+use anyhow::{Error, Result};
+
+use crate::spclient::SpWallet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LockMode {
+    /// Queue behind the calls already running
+    #[default]
+    Wait,
+    /// Fail with `WalletBusy` instead of waiting
+    FailIfBusy,
+}
+
+/// Returned wrapped in an `anyhow::Error`, use `downcast_ref` to tell the user to retry later
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalletBusy(pub String);
+
+impl std::fmt::Display for WalletBusy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Wallet {} is busy", self.0)
+    }
+}
+
+impl std::error::Error for WalletBusy {}
+
+/// Returned wrapped in an `anyhow::Error`, use `downcast_ref` to reload the wallet from disk
+/// A call panicked while holding the wallet, what's in memory may be half updated
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalletPoisoned(pub String);
+
+impl std::fmt::Display for WalletPoisoned {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Wallet {} must be reloaded", self.0)
+    }
+}
+
+impl std::error::Error for WalletPoisoned {}
+
+/// The loaded wallets, by label
+#[derive(Default)]
+pub struct WalletRegistry {
+    wallets: Mutex<HashMap<String, Arc<RwLock<SpWallet>>>>,
+}
+
+impl WalletRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn get(&self, label: &str) -> Result<Arc<RwLock<SpWallet>>> {
+        self.wallets
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(label)
+            .cloned()
+            .ok_or_else(|| Error::msg(format!("Unknown wallet {}", label)))
+    }
+
+    pub fn insert(&self, label: String, wallet: SpWallet) -> Result<()> {
+        let mut wallets = self.wallets.lock().unwrap_or_else(|e| e.into_inner());
+        if wallets.contains_key(&label) {
+            return Err(Error::msg(format!("Wallet {} is already loaded", label)));
+        }
+        wallets.insert(label, Arc::new(RwLock::new(wallet)));
+        Ok(())
+    }
+
+    /// Calls still running on the wallet finish on it
+    /// Also how a `WalletPoisoned` wallet is dropped, to `insert` it again as loaded from disk
+    pub fn remove(&self, label: &str) -> Result<()> {
+        self.wallets
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(label)
+            .map(|_| ())
+            .ok_or_else(|| Error::msg(format!("Unknown wallet {}", label)))
+    }
+
+    pub fn list_labels(&self) -> Vec<String> {
+        self.wallets
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    pub fn with_wallet<R>(
+        &self,
+        label: &str,
+        mode: LockMode,
+        f: impl FnOnce(&SpWallet) -> Result<R>,
+    ) -> Result<R> {
+        let wallet = self.get(label)?;
+        // a poisoned lock means a call panicked half way, only the wallet on disk is still consistent
+        let guard = match mode {
+            LockMode::Wait => wallet.read().ok(),
+            LockMode::FailIfBusy => match wallet.try_read() {
+                Ok(guard) => Some(guard),
+                Err(TryLockError::Poisoned(_)) => None,
+                Err(TryLockError::WouldBlock) => return Err(WalletBusy(label.to_owned()).into()),
+            },
+        }
+        .ok_or_else(|| WalletPoisoned(label.to_owned()))?;
+        f(&guard)
+    }
+
+    pub fn with_wallet_mut<R>(
+        &self,
+        label: &str,
+        mode: LockMode,
+        f: impl FnOnce(&mut SpWallet) -> Result<R>,
+    ) -> Result<R> {
+        let wallet = self.get(label)?;
+        let mut guard = match mode {
+            LockMode::Wait => wallet.write().ok(),
+            LockMode::FailIfBusy => match wallet.try_write() {
+                Ok(guard) => Some(guard),
+                Err(TryLockError::Poisoned(_)) => None,
+                Err(TryLockError::WouldBlock) => return Err(WalletBusy(label.to_owned()).into()),
+            },
+        }
+        .ok_or_else(|| WalletPoisoned(label.to_owned()))?;
+        f(&mut guard)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::test_client;
+
+    #[test]
+    fn poisoned_wallet_must_be_reloaded() {
+        let registry = WalletRegistry::new();
+        let wallet = || SpWallet::new(test_client(), None).unwrap();
+        registry.insert("test".to_owned(), wallet()).unwrap();
+
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            registry.with_wallet_mut("test", LockMode::Wait, |_| -> Result<()> {
+                panic!("half way through")
+            })
+        }));
+        assert!(res.is_err());
+
+        for mode in [LockMode::Wait, LockMode::FailIfBusy] {
+            let err = registry.with_wallet("test", mode, |_| Ok(())).unwrap_err();
+            assert!(err.is::<WalletPoisoned>());
+            let err = registry
+                .with_wallet_mut("test", mode, |_| Ok(()))
+                .unwrap_err();
+            assert!(err.is::<WalletPoisoned>());
+        }
+
+        registry.remove("test").unwrap();
+        registry.insert("test".to_owned(), wallet()).unwrap();
+        registry
+            .with_wallet("test", LockMode::Wait, |_| Ok(()))
+            .unwrap();
+    }
+}
@@ -0,0 +1,161 @@
+//! Outputs for a monitoring server of the user to watch with its own node, no keys involved
+//!
+//! The server reports back when an output confirms or gets spent, so that the phone gets
+//! updates without running the scanner. New payments still need a scan.
+
+use bitcoin::{
+    consensus::{deserialize, serialize},
+    hex::{DisplayHex, FromHex},
+    key::TweakedPublicKey,
+    BlockHash, OutPoint, ScriptBuf, Txid, XOnlyPublicKey,
+};
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Error, Result};
+
+use crate::spclient::{OutputSpendStatus, SpWallet, UNCONFIRMED_HEIGHT};
+
+const WATCH_LIST_PREFIX: &str = "spwatchlist:";
+const WATCH_LIST_VERSION: u8 = 0;
+// outpoint + x-only key of the taproot script
+const ENTRY_LEN: usize = 36 + 32;
+
+/// Our outputs that can still change state: unspent, or spent by a transaction that's not mined yet
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct WatchList {
+    pub entries: Vec<(OutPoint, ScriptBuf)>,
+}
+
+impl WatchList {
+    /// Hex, 68 bytes per output
+    pub fn encode(&self) -> Result<String> {
+        let mut data = Vec::with_capacity(1 + self.entries.len() * ENTRY_LEN);
+        data.push(WATCH_LIST_VERSION);
+        for (outpoint, script) in self.entries.iter() {
+            if !script.is_p2tr() {
+                return Err(Error::msg(format!("Output {} isn't taproot", outpoint)));
+            }
+            data.extend_from_slice(&serialize(outpoint));
+            data.extend_from_slice(&script.as_bytes()[2..]);
+        }
+        Ok(format!(
+            "{}{}",
+            WATCH_LIST_PREFIX,
+            data.to_lower_hex_string()
+        ))
+    }
+
+    pub fn decode(encoded: &str) -> Result<Self> {
+        let hex = encoded
+            .strip_prefix(WATCH_LIST_PREFIX)
+            .ok_or_else(|| Error::msg("Not a watch list"))?;
+        let data = Vec::<u8>::from_hex(hex)?;
+
+        let (version, rest) = data
+            .split_first()
+            .ok_or_else(|| Error::msg("Empty watch list"))?;
+        if *version != WATCH_LIST_VERSION {
+            return Err(Error::msg(format!(
+                "Unknown watch list version {}",
+                version
+            )));
+        }
+        if rest.len() % ENTRY_LEN != 0 {
+            return Err(Error::msg("Invalid watch list length"));
+        }
+
+        let entries = rest
+            .chunks(ENTRY_LEN)
+            .map(|entry| {
+                let outpoint: OutPoint = deserialize(&entry[..36])?;
+                let key = XOnlyPublicKey::from_slice(&entry[36..])?;
+                let script =
+                    ScriptBuf::new_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(key));
+                Ok((outpoint, script))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { entries })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum WatchEvent {
+    /// The output itself got mined
+    Confirmed {
+        outpoint: OutPoint,
+        height: u32,
+        blockhash: BlockHash,
+    },
+    /// `blockhash` is None while the spending transaction is in the mempool
+    Spent {
+        outpoint: OutPoint,
+        txid: Txid,
+        blockhash: Option<BlockHash>,
+    },
+}
+
+impl SpWallet {
+    pub fn export_watch_list(&self) -> Result<WatchList> {
+        let mut entries = self
+            .get_outputs()
+            .to_outpoints_list()
+            .into_iter()
+            .filter(|(_, o)| !matches!(o.spend_status, OutputSpendStatus::Mined(_)))
+            .map(|(outpoint, o)| Ok((outpoint, ScriptBuf::from_hex(&o.script)?)))
+            .collect::<Result<Vec<_>>>()?;
+        entries.sort_by_key(|(outpoint, _)| *outpoint);
+        Ok(WatchList { entries })
+    }
+
+    /// Apply what the server saw, events for outputs we don't know are ignored
+    /// Returns the outputs that changed, to persist them
+    pub fn apply_watch_events(&mut self, events: &[WatchEvent]) -> Result<Vec<OutPoint>> {
+        let mut changed = vec![];
+        for event in events {
+            let outputs = self.get_mut_outputs();
+            match event {
+                WatchEvent::Confirmed {
+                    outpoint,
+                    height,
+                    blockhash,
+                } => {
+                    let Ok((_, mut output)) = outputs.get_outpoint(*outpoint) else {
+                        continue;
+                    };
+                    if output.blockheight != UNCONFIRMED_HEIGHT {
+                        continue;
+                    }
+                    output.blockheight = *height;
+                    output.blockhash = Some(*blockhash);
+                    outputs.extend_from([(*outpoint, output)].into());
+                }
+                WatchEvent::Spent {
+                    outpoint,
+                    txid,
+                    blockhash,
+                } => {
+                    let Ok((_, output)) = outputs.get_outpoint(*outpoint) else {
+                        continue;
+                    };
+                    match (blockhash, output.spend_status) {
+                        (_, OutputSpendStatus::Mined(_)) => continue,
+                        (Some(blockhash), _) => outputs.mark_mined(*outpoint, *blockhash)?,
+                        (None, OutputSpendStatus::Spent(spent_by))
+                            if spent_by == txid.to_string() =>
+                        {
+                            continue
+                        }
+                        // e.g. our spend was replaced, the server knows better
+                        (None, _) => outputs.mark_spent(*outpoint, *txid, true)?,
+                    }
+                }
+            }
+            changed.push(match event {
+                WatchEvent::Confirmed { outpoint, .. } | WatchEvent::Spent { outpoint, .. } => {
+                    *outpoint
+                }
+            });
+        }
+        Ok(changed)
+    }
+}
@@ -0,0 +1,234 @@
+use std::str::FromStr;
+
+use bitcoin::{
+    hex::{DisplayHex, FromHex},
+    psbt::raw,
+    secp256k1::{PublicKey, Secp256k1, SecretKey},
+    Network,
+};
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Error, Result};
+
+use crate::constants::{PSBT_SP_ADDRESS_KEY, PSBT_SP_PREFIX, PSBT_SP_SUBTYPE};
+use crate::spclient::{OutputList, Psbt, SpClient, SpWallet, SpendKey};
+
+const WATCH_ONLY_PREFIX: &str = "spwatch:";
+const WATCH_ONLY_VERSION: u8 = 0;
+// version + network + birthday + scan key + spend key
+const WATCH_ONLY_LEN: usize = 1 + 1 + 4 + 32 + 33;
+
+/// Everything a watch-only wallet needs, compact enough to fit in a single QR code
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct WatchOnlyPackage {
+    pub scan_sk: SecretKey,
+    pub spend_pk: PublicKey,
+    pub birthday: u32,
+    pub network: Network,
+}
+
+impl WatchOnlyPackage {
+    pub fn encode(&self) -> String {
+        let mut data = Vec::with_capacity(WATCH_ONLY_LEN);
+        data.push(WATCH_ONLY_VERSION);
+        data.push(match self.network {
+            Network::Bitcoin => 0,
+            Network::Testnet => 1,
+            Network::Signet => 2,
+            _ => 3,
+        });
+        data.extend_from_slice(&self.birthday.to_be_bytes());
+        data.extend_from_slice(&self.scan_sk.secret_bytes());
+        data.extend_from_slice(&self.spend_pk.serialize());
+
+        format!("{}{}", WATCH_ONLY_PREFIX, data.to_lower_hex_string())
+    }
+
+    pub fn decode(encoded: &str) -> Result<Self> {
+        let hex = encoded
+            .strip_prefix(WATCH_ONLY_PREFIX)
+            .ok_or_else(|| Error::msg("Not a watch-only package"))?;
+        let data = Vec::<u8>::from_hex(hex)?;
+
+        if data.len() != WATCH_ONLY_LEN {
+            return Err(Error::msg("Invalid watch-only package length"));
+        }
+        if data[0] != WATCH_ONLY_VERSION {
+            return Err(Error::msg(format!(
+                "Unknown watch-only package version {}",
+                data[0]
+            )));
+        }
+
+        let network = match data[1] {
+            0 => Network::Bitcoin,
+            1 => Network::Testnet,
+            2 => Network::Signet,
+            3 => Network::Regtest,
+            n => return Err(Error::msg(format!("Unknown network {}", n))),
+        };
+        let birthday = u32::from_be_bytes(data[2..6].try_into()?);
+        let scan_sk = SecretKey::from_slice(&data[6..38])?;
+        let spend_pk = PublicKey::from_slice(&data[38..])?;
+
+        Ok(Self {
+            scan_sk,
+            spend_pk,
+            birthday,
+            network,
+        })
+    }
+
+    pub fn to_client(&self, label: String) -> Result<SpClient> {
+        SpClient::new(
+            label,
+            self.scan_sk,
+            SpendKey::Public(self.spend_pk),
+            None,
+            self.network,
+        )
+    }
+
+    /// Build a fresh watch-only wallet, scanning will start at the package birthday
+    pub fn to_wallet(&self, label: String) -> Result<SpWallet> {
+        let client = self.to_client(label)?;
+        let outputs = OutputList::new(
+            self.scan_sk.public_key(&Secp256k1::signing_only()),
+            self.spend_pk,
+            self.birthday,
+        );
+        SpWallet::new(client, Some(outputs))
+    }
+}
+
+impl SpWallet {
+    pub fn export_watch_only(&self) -> WatchOnlyPackage {
+        let client = self.get_client();
+        WatchOnlyPackage {
+            scan_sk: client.get_scan_key(),
+            spend_pk: client.get_spend_key().into(),
+            birthday: self.get_outputs().get_birthday(),
+            network: client.get_network(),
+        }
+    }
+}
+
+/// What a watch-only wallet hands over to the device holding the spend key
+/// The psbt is the output of `create_new_psbt`, silent payment outputs are still placeholders
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SpendRequest {
+    pub wallet_fingerprint: [u8; 8],
+    pub network: Network,
+    pub psbt: String,
+}
+
+impl SpendRequest {
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn get_psbt(&self) -> Result<Psbt> {
+        Ok(Psbt::from_str(&self.psbt)?)
+    }
+}
+
+impl SpClient {
+    /// Watch-only side: package an unsigned psbt for the cold device
+    pub fn export_spend_request(&self, unsigned_psbt: &Psbt) -> Result<SpendRequest> {
+        Ok(SpendRequest {
+            wallet_fingerprint: self.get_fingerprint(),
+            network: self.get_network(),
+            psbt: unsigned_psbt.to_string(),
+        })
+    }
+
+    /// Cold side: fill the silent payment outputs and sign
+    /// Returns the signed psbt, it must then go back to the watch-only wallet
+    pub fn complete_spend_request(
+        &self,
+        request: &SpendRequest,
+        aux_rand: &[u8; 32],
+    ) -> Result<Psbt> {
+        if let SpendKey::Public(_) = self.get_spend_key() {
+            return Err(Error::msg("Watch-only wallet, can't spend"));
+        }
+        if request.wallet_fingerprint != self.get_fingerprint() {
+            return Err(Error::msg("Spend request is for another wallet"));
+        }
+        if request.network != self.get_network() {
+            return Err(Error::msg("Spend request is for another network"));
+        }
+
+        let mut psbt = request.get_psbt()?;
+
+        let partial_secret = self.get_partial_secret_from_psbt(&psbt)?;
+
+        self.fill_and_order(&mut psbt, partial_secret)?;
+
+        self.sign_psbt(psbt, aux_rand, None)
+    }
+
+    /// Watch-only side: check that the psbt we get back is the one we sent, signed
+    /// Only silent payment outputs script pubkeys are allowed to differ,
+    /// and the cold device may have reordered inputs and outputs, see `SpClient::order_tx`
+    pub fn import_signed_psbt(unsigned_psbt: &Psbt, signed_psbt: &str) -> Result<Psbt> {
+        let signed = Psbt::from_str(signed_psbt)?;
+
+        let unsigned_tx = &unsigned_psbt.unsigned_tx;
+        let signed_tx = &signed.unsigned_tx;
+
+        let mut expected_inputs = unsigned_tx.input.clone();
+        let mut actual_inputs = signed_tx.input.clone();
+        expected_inputs.sort();
+        actual_inputs.sort();
+
+        if unsigned_tx.version != signed_tx.version
+            || unsigned_tx.lock_time != signed_tx.lock_time
+            || expected_inputs != actual_inputs
+            || unsigned_tx.output.len() != signed_tx.output.len()
+            || signed.outputs.len() != signed_tx.output.len()
+        {
+            return Err(Error::msg("Signed psbt doesn't match the unsigned one"));
+        }
+
+        let sp_address_key = raw::ProprietaryKey {
+            prefix: PSBT_SP_PREFIX.as_bytes().to_vec(),
+            subtype: PSBT_SP_SUBTYPE,
+            key: PSBT_SP_ADDRESS_KEY.as_bytes().to_vec(),
+        };
+
+        let mut unmatched: Vec<usize> = (0..unsigned_tx.output.len()).collect();
+        for (i, actual) in signed_tx.output.iter().enumerate() {
+            let address = signed.outputs[i].proprietary.get(&sp_address_key);
+            if address.is_some() && !actual.script_pubkey.is_p2tr() {
+                return Err(Error::msg(format!("Output {} is not taproot", i)));
+            }
+            let pos = unmatched
+                .iter()
+                .position(|&j| {
+                    let expected = &unsigned_tx.output[j];
+                    let expected_address =
+                        unsigned_psbt.outputs[j].proprietary.get(&sp_address_key);
+                    expected.value == actual.value
+                        && expected_address == address
+                        && (address.is_some() || expected.script_pubkey == actual.script_pubkey)
+                })
+                .ok_or_else(|| {
+                    Error::msg(format!("Output {} doesn't match the unsigned psbt", i))
+                })?;
+            unmatched.remove(pos);
+        }
+
+        for (i, input) in signed.inputs.iter().enumerate() {
+            if input.tap_key_sig.is_none() {
+                return Err(Error::msg(format!("Missing signature at input {}", i)));
+            }
+        }
+
+        Ok(signed)
+    }
+}
@@ -0,0 +1,192 @@
+//! Webhook notifications for headless use
+//!
+//! We don't do any networking here: `Webhooks` turns wallet events into signed requests,
+//! and the daemon POSTs them with whatever http client it uses.
+
+use std::collections::HashMap;
+
+use bitcoin::{
+    hashes::{hmac, sha256, Hash, HashEngine},
+    hex::DisplayHex,
+    Amount, BlockHash, OutPoint, Txid,
+};
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Error, Result};
+use zeroize::Zeroize;
+
+use crate::spclient::{OutputSpendStatus, OwnedOutput};
+
+/// Header carrying the hex encoded HMAC-SHA256 of the body
+pub const SIGNATURE_HEADER: &str = "X-Sp-Signature";
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum WalletEvent {
+    PaymentReceived {
+        outpoint: OutPoint,
+        amount: Amount,
+        blockheight: u32,
+        label: Option<String>,
+    },
+    SpendConfirmed {
+        txid: Txid,
+        blockhash: BlockHash,
+    },
+    Reorg {
+        /// Height of the last block both chains have in common
+        fork_height: u32,
+        new_tip: u32,
+    },
+}
+
+impl WalletEvent {
+    /// Events for the outputs returned by `update_wallet_with_transaction`,
+    /// outputs that got spent are only reported once their spending tx is confirmed
+    pub fn from_new_outputs(outputs: &HashMap<OutPoint, OwnedOutput>) -> Vec<Self> {
+        outputs
+            .iter()
+            .filter(|(_, o)| o.spend_status == OutputSpendStatus::Unspent)
+            .map(|(outpoint, o)| Self::PaymentReceived {
+                outpoint: *outpoint,
+                amount: o.amount,
+                blockheight: o.blockheight,
+                label: o.label.clone(),
+            })
+            .collect()
+    }
+
+    fn kind(&self) -> WebhookEventKind {
+        match self {
+            Self::PaymentReceived { .. } => WebhookEventKind::PaymentReceived,
+            Self::SpendConfirmed { .. } => WebhookEventKind::SpendConfirmed,
+            Self::Reorg { .. } => WebhookEventKind::Reorg,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WebhookEventKind {
+    PaymentReceived,
+    SpendConfirmed,
+    Reorg,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Webhook {
+    pub url: String,
+    /// Shared with the receiver so it can authenticate our requests
+    secret: Vec<u8>,
+    /// Empty means every event
+    pub events: Vec<WebhookEventKind>,
+}
+
+impl std::fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Webhook")
+            .field("url", &self.url)
+            .field("secret", &"<redacted>")
+            .field("events", &self.events)
+            .finish()
+    }
+}
+
+impl Drop for Webhook {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+    }
+}
+
+impl Webhook {
+    fn wants(&self, kind: WebhookEventKind) -> bool {
+        self.events.is_empty() || self.events.contains(&kind)
+    }
+}
+
+/// What the daemon must POST
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebhookRequest {
+    pub url: String,
+    pub body: String,
+    /// Value of the `SIGNATURE_HEADER` header
+    pub signature: String,
+}
+
+pub fn sign_payload(secret: &[u8], body: &[u8]) -> String {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(secret);
+    engine.input(body);
+    hmac::Hmac::<sha256::Hash>::from_engine(engine)
+        .to_byte_array()
+        .to_lower_hex_string()
+}
+
+/// What the receiving side runs, the comparison is constant time
+pub fn verify_payload(secret: &[u8], body: &[u8], signature: &str) -> bool {
+    let expected = sign_payload(secret, body);
+    expected.len() == signature.len()
+        && expected
+            .bytes()
+            .zip(signature.bytes())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Webhooks {
+    hooks: Vec<Webhook>,
+}
+
+impl Webhooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(
+        &mut self,
+        url: String,
+        secret: Vec<u8>,
+        events: Vec<WebhookEventKind>,
+    ) -> Result<()> {
+        if !(url.starts_with("https://") || url.starts_with("http://")) {
+            return Err(Error::msg(format!("Invalid webhook url {}", url)));
+        }
+        if secret.len() < 16 {
+            return Err(Error::msg("Webhook secret must be at least 16 bytes"));
+        }
+        self.hooks.retain(|h| h.url != url);
+        self.hooks.push(Webhook {
+            url,
+            secret,
+            events,
+        });
+        Ok(())
+    }
+
+    pub fn unregister(&mut self, url: &str) -> Result<()> {
+        let len = self.hooks.len();
+        self.hooks.retain(|h| h.url != url);
+        if self.hooks.len() == len {
+            return Err(Error::msg(format!("No webhook for {}", url)));
+        }
+        Ok(())
+    }
+
+    pub fn list(&self) -> &Vec<Webhook> {
+        &self.hooks
+    }
+
+    /// One request per webhook interested in `event`
+    pub fn get_requests(&self, event: &WalletEvent) -> Result<Vec<WebhookRequest>> {
+        let body = serde_json::to_string(event)?;
+        Ok(self
+            .hooks
+            .iter()
+            .filter(|h| h.wants(event.kind()))
+            .map(|h| WebhookRequest {
+                url: h.url.clone(),
+                signature: sign_payload(&h.secret, body.as_bytes()),
+                body: body.clone(),
+            })
+            .collect())
+    }
+}
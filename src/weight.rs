@@ -0,0 +1,92 @@
+//! Weight of a psbt once signed, from the script type of each input and output
+//!
+//! Computed from the psbt alone, nothing is signed or extracted.
+//! Signatures are counted at their largest size so that the fee is never too low,
+//! inputs that are already finalized are counted exactly.
+
+use bitcoin::{
+    psbt::Input,
+    transaction::{predict_weight, InputWeightPrediction},
+    TapSighashType, TxOut, Weight,
+};
+
+use anyhow::{Error, Result};
+
+use crate::spclient::Psbt;
+
+/// DER signature and sighash byte
+const ECDSA_SIG_MAX_LEN: usize = 73;
+const COMPRESSED_PUBKEY_LEN: usize = 33;
+const SCHNORR_SIG_LEN: usize = 64;
+/// Push of the witness program of a p2sh wrapped p2wpkh
+const P2SH_P2WPKH_SCRIPT_SIG_LEN: usize = 23;
+
+fn predict_input(index: usize, input: &Input, utxo: &TxOut) -> Result<InputWeightPrediction> {
+    if input.final_script_witness.is_some() || input.final_script_sig.is_some() {
+        let script_sig_len = input.final_script_sig.as_ref().map_or(0, |s| s.len());
+        let witness = input.final_script_witness.clone().unwrap_or_default();
+        return Ok(InputWeightPrediction::new(
+            script_sig_len,
+            witness.iter().map(|elem| elem.len()),
+        ));
+    }
+
+    let spk = &utxo.script_pubkey;
+    if spk.is_p2tr() {
+        let sig_len = match input
+            .sighash_type
+            .map(|t| t.taproot_hash_ty())
+            .transpose()?
+        {
+            None | Some(TapSighashType::Default) => SCHNORR_SIG_LEN,
+            Some(_) => SCHNORR_SIG_LEN + 1,
+        };
+        // script path spends are only predicted right for leaves with a single signature
+        Ok(match input.tap_scripts.iter().next() {
+            Some((control_block, (script, _))) => {
+                InputWeightPrediction::new(0, [sig_len, script.len(), control_block.size()])
+            }
+            None => InputWeightPrediction::new(0, [sig_len]),
+        })
+    } else if spk.is_p2wpkh() {
+        Ok(InputWeightPrediction::P2WPKH_MAX)
+    } else if spk.is_p2sh() && input.redeem_script.as_ref().is_none_or(|s| s.is_p2wpkh()) {
+        // without the redeem script, assume p2sh-p2wpkh, the only p2sh we can size
+        Ok(InputWeightPrediction::new(
+            P2SH_P2WPKH_SCRIPT_SIG_LEN,
+            [ECDSA_SIG_MAX_LEN, COMPRESSED_PUBKEY_LEN],
+        ))
+    } else if spk.is_p2pkh() {
+        // each push takes one byte for its length
+        Ok(InputWeightPrediction::from_slice(
+            1 + ECDSA_SIG_MAX_LEN + 1 + COMPRESSED_PUBKEY_LEN,
+            &[],
+        ))
+    } else {
+        Err(Error::msg(format!(
+            "Can't predict the size of input {}, finalize it first",
+            index
+        )))
+    }
+}
+
+/// Weight of the transaction `psbt` will extract to
+pub fn predict_psbt_weight(psbt: &Psbt) -> Result<Weight> {
+    let inputs = psbt
+        .iter_funding_utxos()
+        .zip(psbt.inputs.iter())
+        .enumerate()
+        .map(|(index, (utxo, input))| predict_input(index, input, utxo?))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(predict_weight(
+        inputs,
+        psbt.unsigned_tx
+            .output
+            .iter()
+            .map(|o| o.script_pubkey.len()),
+    ))
+}
+
+pub fn predict_psbt_vsize(psbt: &Psbt) -> Result<u64> {
+    Ok(predict_psbt_weight(psbt)?.to_vbytes_ceil())
+}
@@ -0,0 +1,61 @@
+//! Thread pool for the parallel parts of scanning and signing
+//!
+//! The size can be changed at any time, e.g. lowered when the device is hot or in battery saver,
+//! work already running finishes on the previous pool.
+
+use std::sync::{Arc, RwLock};
+
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use anyhow::{Error, Result};
+
+static POOL: RwLock<Option<Arc<ThreadPool>>> = RwLock::new(None);
+
+/// One core is left for the ui
+pub fn get_default_worker_threads() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get().saturating_sub(1).max(1))
+        .unwrap_or(1)
+}
+
+fn build_pool(threads: usize) -> Result<Arc<ThreadPool>> {
+    Ok(Arc::new(
+        ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("sp-worker-{}", i))
+            .build()?,
+    ))
+}
+
+pub fn set_worker_threads(threads: usize) -> Result<()> {
+    if threads == 0 {
+        return Err(Error::msg("Need at least one worker thread"));
+    }
+    let pool = build_pool(threads)?;
+    *POOL.write().unwrap_or_else(|e| e.into_inner()) = Some(pool);
+    Ok(())
+}
+
+pub fn get_worker_threads() -> usize {
+    POOL.read()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .map(|pool| pool.current_num_threads())
+        .unwrap_or_else(get_default_worker_threads)
+}
+
+fn get_pool() -> Result<Arc<ThreadPool>> {
+    if let Some(pool) = POOL.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        return Ok(pool.clone());
+    }
+    let mut pool = POOL.write().unwrap_or_else(|e| e.into_inner());
+    if pool.is_none() {
+        *pool = Some(build_pool(get_default_worker_threads())?);
+    }
+    Ok(pool.as_ref().expect("just set").clone())
+}
+
+/// Run `f` in the pool, rayon parallel iterators inside it use its threads
+pub(crate) fn install<R: Send>(f: impl FnOnce() -> R + Send) -> Result<R> {
+    Ok(get_pool()?.install(f))
+}
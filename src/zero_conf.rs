@@ -0,0 +1,125 @@
+//! Heuristic risk of accepting an unconfirmed incoming payment, for merchants deciding on 0-conf
+//!
+//! None of this proves the payment will confirm, it only tells how easy a double spend would be.
+
+use bitcoin::{Amount, OutPoint, Transaction};
+use serde::{Deserialize, Serialize};
+
+use anyhow::{Error, Result};
+
+use crate::chain::{ChainBackend, FeeEstimator};
+
+/// Inputs with fewer confirmations than this are considered young
+const MIN_INPUT_CONFIRMATIONS: u32 = 6;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum ZeroConfRisk {
+    /// The sender can replace the transaction with one that doesn't pay us
+    SignalsRbf,
+    /// Below what's needed to get in the next block, in sat/vB
+    LowFeeRate {
+        fee_rate: Amount,
+        next_block: Amount,
+    },
+    /// Inputs still in the mempool, if their transaction is dropped so is ours
+    UnconfirmedInputs(Vec<OutPoint>),
+    /// Inputs with less than `MIN_INPUT_CONFIRMATIONS`, a reorg could invalidate them
+    YoungInputs(Vec<OutPoint>),
+    /// Inputs the backend doesn't know about, we can't tell if they even exist
+    UnknownInputs(Vec<OutPoint>),
+}
+
+impl ZeroConfRisk {
+    fn get_penalty(&self) -> u8 {
+        match self {
+            Self::SignalsRbf => 50,
+            Self::LowFeeRate { .. } => 30,
+            Self::UnconfirmedInputs(_) => 30,
+            Self::YoungInputs(_) => 10,
+            Self::UnknownInputs(_) => 100,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ZeroConfReport {
+    /// 100 when nothing was found, lower is riskier
+    pub score: u8,
+    pub risks: Vec<ZeroConfRisk>,
+    /// None if some inputs are unknown
+    pub fee_rate: Option<Amount>,
+}
+
+/// `tx` is an unconfirmed transaction paying us, e.g. seen by the mempool monitoring
+pub fn assess_zero_conf(
+    tx: &Transaction,
+    backend: &impl ChainBackend,
+    fee_estimator: &impl FeeEstimator,
+) -> Result<ZeroConfReport> {
+    let tip = backend.get_tip_height()?;
+
+    let mut risks = vec![];
+    if tx.is_explicitly_rbf() {
+        risks.push(ZeroConfRisk::SignalsRbf);
+    }
+
+    let mut input_amt = Amount::ZERO;
+    let mut unknown = vec![];
+    let mut unconfirmed = vec![];
+    let mut young = vec![];
+    for txin in tx.input.iter() {
+        let outpoint = txin.previous_output;
+        match backend.get_output(&outpoint)? {
+            // spent is expected, the backend may count our transaction as the spender
+            Some(output) => {
+                input_amt += output.txout.value;
+                match output.blockheight {
+                    None => unconfirmed.push(outpoint),
+                    Some(height) if tip.saturating_sub(height) + 1 < MIN_INPUT_CONFIRMATIONS => {
+                        young.push(outpoint)
+                    }
+                    Some(_) => (),
+                }
+            }
+            None => unknown.push(outpoint),
+        }
+    }
+
+    let fee_rate = if unknown.is_empty() {
+        let output_amt: Amount = tx.output.iter().map(|o| o.value).sum();
+        let fee = input_amt
+            .checked_sub(output_amt)
+            .ok_or_else(|| Error::msg("Outputs exceed inputs"))?;
+        let fee_rate = fee / tx.vsize() as u64;
+        let next_block = fee_estimator.get_fee_rate(1)?;
+        if fee_rate < next_block {
+            risks.push(ZeroConfRisk::LowFeeRate {
+                fee_rate,
+                next_block,
+            });
+        }
+        Some(fee_rate)
+    } else {
+        None
+    };
+
+    if !unconfirmed.is_empty() {
+        risks.push(ZeroConfRisk::UnconfirmedInputs(unconfirmed));
+    }
+    if !young.is_empty() {
+        risks.push(ZeroConfRisk::YoungInputs(young));
+    }
+    if !unknown.is_empty() {
+        risks.push(ZeroConfRisk::UnknownInputs(unknown));
+    }
+
+    let penalty = risks
+        .iter()
+        .fold(0u8, |sum, r| sum.saturating_add(r.get_penalty()));
+
+    Ok(ZeroConfReport {
+        score: 100u8.saturating_sub(penalty),
+        risks,
+        fee_rate,
+    })
+}